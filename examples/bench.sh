@@ -0,0 +1,11 @@
+#!/bin/bash
+
+# Times the compilation of the complete mm1 files, with and without
+# the lisp bytecode compiler. It assumes that 'mm0-rs' is available on the PATH
+
+TIMEFORMAT='%3Rs'
+for f in peano peano_hex mm0 x86 compiler verifier big_unifier; do
+  echo "$f.mm1:"
+  echo -n "  ast:      "; time mm0-rs compile --no-bytecode $f.mm1 >/dev/null 2>&1
+  echo -n "  bytecode: "; time mm0-rs compile $f.mm1 >/dev/null 2>&1
+done
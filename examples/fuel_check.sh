@@ -0,0 +1,16 @@
+#!/bin/bash
+
+# Checks that the fuel used by each declaration (the value of 'get-fuel' at the end,
+# as printed by --report-fuel) is the same with and without the lisp bytecode compiler.
+# It assumes that 'mm0-rs' is available on the PATH
+
+status=0
+for f in peano peano_hex mm0 x86 compiler verifier; do
+  if diff <(mm0-rs compile --report-fuel $f.mm1 2>&1) \
+          <(mm0-rs compile --no-bytecode --report-fuel $f.mm1 2>&1) >/dev/null; then
+    echo "$f.mm1: ok"
+  else
+    echo "$f.mm1: fuel differs"; status=1
+  fi
+done
+exit $status
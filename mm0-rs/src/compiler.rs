@@ -34,6 +34,26 @@ lazy_static! {
   /// The virtual file system of files that have been included via
   /// transitive imports, protected for concurrent access by a mutex.
   static ref VFS_: VFS = VFS(Mutex::new(HashMap::new()));
+  /// The destination of messages printed during elaboration, if not standard output.
+  static ref LOG_SINK: Mutex<Option<LogSink>> = Mutex::new(None);
+}
+
+/// A function that receives printed messages, see [`set_log_sink`].
+type LogSink = Box<dyn Fn(String) + Send>;
+
+/// Send the messages printed during elaboration (progress reports and errors) to `f`
+/// instead of standard output. This is used by the debugger, which needs standard output
+/// for the protocol.
+pub(crate) fn set_log_sink(f: impl Fn(String) + Send + 'static) {
+  *LOG_SINK.ulock() = Some(Box::new(f))
+}
+
+/// Print a message to standard output, or the sink set by [`set_log_sink`].
+fn print_out(s: String) {
+  match &*LOG_SINK.ulock() {
+    Some(f) => f(s),
+    None => println!("{}", s),
+  }
 }
 
 /// The cached [`Environment`](crate::elab::Environment) representing a
@@ -156,6 +176,15 @@ fn mk_to_range() -> impl FnMut(&FileSpan) -> Option<Range> {
   }
 }
 
+/// Color the parts of an error message marked with `«` and `»`, which are the mismatching
+/// subterms in unification errors (see [`Pretty::expr_highlight`]).
+///
+/// [`Pretty::expr_highlight`]: crate::elab::lisp::pretty::Pretty::expr_highlight
+fn highlight(s: String) -> String {
+  if !s.contains('«') {return s}
+  s.replace('«', "\x1b[31m«").replace('»', "»\x1b[39m")
+}
+
 impl ElabErrorKind {
   /// Convert the payload of an elaboration error to the footer data
   /// of a [`Snippet`].
@@ -172,6 +201,7 @@ impl ElabErrorKind {
         info.iter().map(|(fs, e)| Annotation {
           id: None,
           label: Some(arena.alloc({
+            let e = highlight(e.to_string());
             if let Some(Range {start, ..}) = to_range(fs) {
               format!("{}:{}:{}: {}", fs.file.rel(), start.line + 1, start.character + 1, e)
             } else {
@@ -261,7 +291,7 @@ impl ElabError {
   fn to_snippet<T>(&self, path: &FileRef, file: &LinedString,
       to_range: impl FnMut(&FileSpan) -> Option<Range>,
       f: impl for<'a> FnOnce(Snippet<'a>) -> T) -> T {
-    f(make_snippet(path, file, self.pos, &self.kind.msg(), self.level,
+    f(make_snippet(path, file, self.pos, &highlight(self.kind.msg()), self.level,
       self.kind.to_footer(&Arena::new(), to_range)))
   }
 
@@ -301,7 +331,7 @@ fn log_msg(#[allow(unused_mut)] mut s: String) {
       write!(s, ", memory = {}M", n >> 20).expect("writing to a string");
     }
   }
-  println!("{}", s)
+  print_out(s)
 }
 
 /// Elaborate a file for an [`Environment`](crate::elab::Environment) result.
@@ -349,7 +379,7 @@ async fn elaborate(path: FileRef, rd: ArcList<FileRef>) -> io::Result<ElabResult
     if !ast.errors.is_empty() {
       for e in &ast.errors {
         e.to_snippet(&path, &ast.source,
-          |s| println!("{}", DisplayList::from(s).to_string()))
+          |s| print_out(DisplayList::from(s).to_string()))
       }
     }
     let ast = Arc::new(ast);
@@ -376,7 +406,7 @@ async fn elaborate(path: FileRef, rd: ArcList<FileRef>) -> io::Result<ElabResult
   };
   log_msg(format!("elabbed {}", path));
   let errors: Option<Arc<[_]>> = if errors.is_empty() { None } else {
-    fn print(s: Snippet<'_>) { println!("{}\n", DisplayList::from(s).to_string()) }
+    fn print(s: Snippet<'_>) { print_out(format!("{}\n", DisplayList::from(s))) }
     let mut to_range = mk_to_range();
     if let FileContents::Ascii(text) = &file.text {
       for e in &errors { e.to_snippet(&path, text, &mut to_range, print) }
@@ -416,6 +446,11 @@ fn elaborate_and_send(path: FileRef, send: FSender<ElabResult<()>>, rd: ArcList<
   }.boxed()
 }
 
+/// Get the text of a file that has been loaded into the [`VFS`], if it is not a binary file.
+pub(crate) fn get_text(path: &FileRef) -> Option<Arc<LinedString>> {
+  VFS_.0.ulock().get(path)?.text.try_ascii().cloned()
+}
+
 /// Elaborate a file, and return the completed [`FrozenEnv`] result, along with the
 /// file contents.
 pub(crate) fn elab_for_result(path: FileRef) -> io::Result<(FileContents, Option<FrozenEnv>)> {
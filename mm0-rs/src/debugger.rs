@@ -0,0 +1,335 @@
+//! A debugger for MM1 lisp code, speaking the [Debug Adapter Protocol] over stdio.
+//!
+//! `mm0-rs debug` is started by the editor as a debug adapter. The `launch` request
+//! gives the file to elaborate in the `program` field, and elaboration starts after
+//! the client sends `configurationDone`. The evaluator checks in with the debugger
+//! (see [`DebugHook`]) before each function application, `focus` and `match` in
+//! `do` blocks and tactic code, and before each step of a proof, so breakpoints are
+//! placed on source lines and stop at the first of these on the line. When stopped,
+//! the client can look at the call stack, the local variables of each frame, and the
+//! hypotheses and goals of the current proof.
+//!
+//! The elaborator is single-threaded from the point of view of the client: there is
+//! one thread with id 1, even though imports may be elaborated on other threads.
+//!
+//! [Debug Adapter Protocol]: https://microsoft.github.io/debug-adapter-protocol/
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::io::{self, BufRead, Write};
+use std::sync::{Arc, Condvar, Mutex, atomic::Ordering};
+use serde_json::{json, Value};
+use crate::elab::lisp::eval::{DebugHook, DebugStop, DebugVar, set_debug_hook};
+use crate::lined_string::LinedString;
+use crate::util::{FileRef, FileSpan, MutexExt, Position};
+
+/// How execution should proceed after resuming.
+#[derive(Copy, Clone, Debug)]
+enum Step {
+  /// Run until the next breakpoint.
+  Continue,
+  /// Stop at the next line, in any frame.
+  In,
+  /// Stop at the next line in a frame of at most the given depth.
+  Over(usize),
+  /// Stop at the next line in a frame of less than the given depth.
+  Out(usize),
+  /// Stop as soon as possible.
+  Pause,
+}
+
+/// The data for the current stop, in the form we report it to the client.
+#[derive(Debug)]
+struct Stopped {
+  stop: DebugStop,
+  /// The variable lists; `variablesReference` `n` refers to `vars[n-1]`.
+  vars: Vec<Vec<Value>>,
+  /// The scopes of each frame.
+  scopes: Vec<Vec<Value>>,
+}
+
+impl Stopped {
+  fn new(stop: DebugStop) -> Stopped {
+    fn add(vars: &mut Vec<Vec<Value>>, vs: &[DebugVar]) -> usize {
+      let n = vars.len();
+      vars.push(vec![]);
+      let list = vs.iter().map(|v| json!({
+        "name": v.name,
+        "value": v.value,
+        "variablesReference": if v.children.is_empty() {0} else {add(vars, &v.children)},
+      })).collect();
+      vars[n] = list;
+      n + 1
+    }
+    let mut vars = vec![];
+    let scopes = stop.frames.iter().enumerate().map(|(i, f)| {
+      let mut scopes = vec![json!({
+        "name": "Locals",
+        "presentationHint": "locals",
+        "variablesReference": add(&mut vars, &f.locals),
+      })];
+      if i == 0 && !(stop.hyps.is_empty() && stop.goals.is_empty()) {
+        let r = add(&mut vars, &[
+          DebugVar {name: "hyps".into(), value: format!("{} hypotheses", stop.hyps.len()),
+            children: stop.hyps.iter().map(|v| DebugVar {
+              name: v.name.clone(), value: v.value.clone(), children: vec![]}).collect()},
+          DebugVar {name: "goals".into(), value: format!("{} goals", stop.goals.len()),
+            children: stop.goals.iter().map(|v| DebugVar {
+              name: v.name.clone(), value: v.value.clone(), children: vec![]}).collect()},
+        ]);
+        scopes.push(json!({"name": "Proof state", "variablesReference": r}))
+      }
+      scopes
+    }).collect();
+    Stopped {stop, vars, scopes}
+  }
+}
+
+#[derive(Debug, Default)]
+struct State {
+  /// The breakpoint lines (zero-based) in each file.
+  breakpoints: HashMap<FileRef, Vec<u32>>,
+  /// The current stepping mode.
+  step: Option<Step>,
+  /// The last location passed to [`DebugHook::check`].
+  last: Option<(FileRef, u32)>,
+  /// The location and depth of the last stop.
+  stopped_at: Option<(FileRef, u32, usize)>,
+  /// The reason for the pending stop, for the `stopped` event.
+  reason: &'static str,
+  /// The state of the evaluator at the current stop, if we are stopped.
+  stopped: Option<Stopped>,
+  /// The source text of files we have seen, for computing line numbers.
+  texts: HashMap<FileRef, Option<Arc<LinedString>>>,
+}
+
+impl State {
+  fn position(&mut self, fsp: &FileSpan) -> Position {
+    self.texts.entry(fsp.file.clone())
+      .or_insert_with(|| crate::compiler::get_text(&fsp.file))
+      .as_ref().map_or_else(Position::default, |t| t.to_pos(fsp.span.start))
+  }
+}
+
+/// The debug adapter. This is shared between the protocol thread, which handles requests
+/// from the client, and the elaboration threads, which call into it through [`DebugHook`].
+#[derive(Debug, Default)]
+struct Debugger {
+  state: Mutex<State>,
+  /// Signaled when execution resumes after a stop.
+  resume: Condvar,
+  /// Held by the elaboration thread that is stopped, so that other threads wait their turn.
+  gate: Mutex<()>,
+  /// The sequence number of the last message sent.
+  seq: Mutex<u64>,
+}
+
+impl Debugger {
+  fn send(&self, mut msg: Value) {
+    let mut seq = self.seq.ulock();
+    *seq += 1;
+    msg["seq"] = (*seq).into();
+    let msg = msg.to_string();
+    let mut out = io::stdout();
+    let _ = write!(out, "Content-Length: {}\r\n\r\n{}", msg.len(), msg);
+    let _ = out.flush();
+  }
+
+  fn event(&self, event: &str, body: Value) {
+    let mut msg = json!({"type": "event", "event": event});
+    msg["body"] = body;
+    self.send(msg)
+  }
+
+  fn respond(&self, req: &Value, body: Result<Value, String>) {
+    let mut msg = json!({
+      "type": "response",
+      "request_seq": req["seq"],
+      "command": req["command"],
+      "success": body.is_ok(),
+    });
+    match body {
+      Ok(body) => msg["body"] = body,
+      Err(e) => msg["message"] = e.into(),
+    }
+    self.send(msg)
+  }
+
+  fn output(&self, category: &str, s: &str) {
+    self.event("output", json!({"category": category, "output": format!("{}\n", s)}))
+  }
+
+  /// Resume execution with the given stepping mode, measured from the current stop.
+  fn resume(&self, step: impl FnOnce(usize) -> Step) -> Value {
+    let mut g = self.state.ulock();
+    let depth = match &g.stopped_at {Some((_, _, d)) => *d, None => 0};
+    g.step = Some(step(depth));
+    g.stopped = None;
+    self.resume.notify_all();
+    json!({"allThreadsContinued": true})
+  }
+
+  fn stack_trace(&self) -> Result<Value, String> {
+    let mut g = self.state.ulock();
+    let frames = match &g.stopped {
+      Some(s) => s.stop.frames.iter().map(|f| (f.name.clone(), f.pos.clone())).collect::<Vec<_>>(),
+      None => return Err("not stopped".into()),
+    };
+    let frames: Vec<_> = frames.into_iter().enumerate().map(|(i, (name, pos))| {
+      let Position {line, character} = g.position(&pos);
+      json!({
+        "id": i,
+        "name": name,
+        "source": {"name": pos.file.path().file_name().map(|s| s.to_string_lossy()), "path": pos.file.path()},
+        "line": line + 1,
+        "column": character + 1,
+      })
+    }).collect();
+    Ok(json!({"totalFrames": frames.len(), "stackFrames": frames}))
+  }
+
+  fn handle(self: &Arc<Self>, req: &Value, program: &mut Option<FileRef>) -> Result<Value, String> {
+    let args = &req["arguments"];
+    match req["command"].as_str().unwrap_or_default() {
+      "initialize" => Ok(json!({"supportsConfigurationDoneRequest": true})),
+      "launch" => {
+        let path = args["program"].as_str().ok_or("missing 'program'")?;
+        let path = std::fs::canonicalize(path).map_err(|e| format!("{}: {}", path, e))?;
+        *program = Some(path.into());
+        if args["noProofs"].as_bool() == Some(true) {
+          crate::CHECK_PROOFS.store(false, Ordering::Relaxed)
+        }
+        if args["stopOnEntry"].as_bool() == Some(true) {
+          self.state.ulock().step = Some(Step::Pause)
+        }
+        Ok(Value::Null)
+      }
+      "setBreakpoints" => {
+        let path = args["source"]["path"].as_str().ok_or("missing source path")?;
+        let path = std::fs::canonicalize(path).map_err(|e| format!("{}: {}", path, e))?;
+        let lines: Vec<u32> = args["breakpoints"].as_array().map_or_else(Vec::new, |bs| {
+          bs.iter().filter_map(|b| b["line"].as_u64()?.checked_sub(1)?.try_into().ok()).collect()
+        });
+        let bps = lines.iter().map(|l| json!({"verified": true, "line": l + 1})).collect::<Vec<_>>();
+        self.state.ulock().breakpoints.insert(path.into(), lines);
+        Ok(json!({"breakpoints": bps}))
+      }
+      "configurationDone" => {
+        let path = program.clone().ok_or("no program launched")?;
+        let this = self.clone();
+        std::thread::spawn(move || {
+          let code = match crate::compiler::elab_for_result(path) {
+            Ok((_, Some(_))) => 0,
+            Ok((_, None)) => 1,
+            Err(e) => {this.output("stderr", &format!("{}", e)); 1}
+          };
+          this.event("exited", json!({"exitCode": code}));
+          this.event("terminated", json!({}));
+        });
+        Ok(Value::Null)
+      }
+      "threads" => Ok(json!({"threads": [{"id": 1, "name": "elaborator"}]})),
+      "stackTrace" => self.stack_trace(),
+      "scopes" => {
+        let g = self.state.ulock();
+        let s = g.stopped.as_ref().ok_or("not stopped")?;
+        let frame = args["frameId"].as_u64().and_then(|i| s.scopes.get(TryInto::<usize>::try_into(i).ok()?));
+        Ok(json!({"scopes": frame.ok_or("invalid frame")?}))
+      }
+      "variables" => {
+        let g = self.state.ulock();
+        let s = g.stopped.as_ref().ok_or("not stopped")?;
+        let vars = args["variablesReference"].as_u64()
+          .and_then(|i| s.vars.get(TryInto::<usize>::try_into(i).ok()?.checked_sub(1)?));
+        Ok(json!({"variables": vars.ok_or("invalid variable reference")?}))
+      }
+      "continue" => Ok(self.resume(|_| Step::Continue)),
+      "next" => Ok(self.resume(Step::Over)),
+      "stepIn" => Ok(self.resume(|_| Step::In)),
+      "stepOut" => Ok(self.resume(Step::Out)),
+      "pause" => {self.state.ulock().step = Some(Step::Pause); Ok(Value::Null)}
+      "disconnect" | "terminate" => {
+        self.respond(req, Ok(Value::Null));
+        std::process::exit(0)
+      }
+      cmd => Err(format!("unsupported request '{}'", cmd)),
+    }
+  }
+}
+
+impl DebugHook for Debugger {
+  fn check(&self, pos: &FileSpan, depth: usize) -> bool {
+    let mut g = self.state.ulock();
+    let line = g.position(pos).line;
+    let moved = g.last.as_ref().map_or(true, |(f, l)| *f != pos.file || *l != line);
+    let new_line = g.stopped_at.as_ref()
+      .map_or(true, |(f, l, d)| *f != pos.file || *l != line || *d != depth);
+    let reason = match g.step {
+      Some(Step::Pause) => Some("pause"),
+      Some(Step::In) if new_line => Some("step"),
+      Some(Step::Over(d)) if new_line && depth <= d => Some("step"),
+      Some(Step::Out(d)) if depth < d => Some("step"),
+      _ => None,
+    }.or_else(|| if moved && g.breakpoints.get(&pos.file).map_or(false, |bps| bps.contains(&line)) {
+      Some("breakpoint")
+    } else {None});
+    g.last = Some((pos.file.clone(), line));
+    match reason {
+      None => false,
+      Some(reason) => {
+        g.stopped_at = Some((pos.file.clone(), line, depth));
+        g.reason = reason;
+        true
+      }
+    }
+  }
+
+  fn stop(&self, stop: DebugStop) {
+    let _gate = self.gate.ulock();
+    let mut g = self.state.ulock();
+    g.step = None;
+    g.stopped = Some(Stopped::new(stop));
+    self.event("stopped", json!({"reason": g.reason, "threadId": 1, "allThreadsStopped": true}));
+    while g.step.is_none() {
+      g = self.resume.wait(g).expect("poisoned");
+    }
+  }
+}
+
+/// Read a message from the client, or `None` at the end of the input.
+fn read_message(r: &mut impl BufRead) -> io::Result<Option<Value>> {
+  let mut len = None;
+  loop {
+    let mut line = String::new();
+    if r.read_line(&mut line)? == 0 {return Ok(None)}
+    let line = line.trim_end();
+    if line.is_empty() {break}
+    if let Some(n) = line.strip_prefix("Content-Length:") {len = n.trim().parse().ok()}
+  }
+  let len = len.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "missing Content-Length"))?;
+  let mut buf = vec![0; len];
+  r.read_exact(&mut buf)?;
+  serde_json::from_slice(&buf).map(Some)
+    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Main entry point for `mm0-rs debug` subcommand.
+///
+/// This function runs the debug adapter on stdin/stdout until the client disconnects.
+pub fn main() -> io::Result<()> {
+  let dbg = Arc::new(Debugger::default());
+  set_debug_hook(Some(dbg.clone()));
+  {
+    let dbg = dbg.clone();
+    crate::compiler::set_log_sink(move |s| dbg.output("console", &s));
+  }
+  let stdin = io::stdin();
+  let mut stdin = stdin.lock();
+  let mut program = None;
+  while let Some(req) = read_message(&mut stdin)? {
+    if req["type"] != "request" {continue}
+    let res = dbg.handle(&req, &mut program);
+    dbg.respond(&req, res);
+    if req["command"] == "initialize" {dbg.event("initialized", Value::Null)}
+  }
+  Ok(())
+}
@@ -397,6 +397,16 @@ fn render_doc(w: &mut impl Write, doc: &Option<DocComment>) -> io::Result<()> {
   Ok(())
 }
 
+fn render_attrs(w: &mut impl Write, env: &Environment, k: DeclKey) -> io::Result<()> {
+  let attrs = env.attrs_of(k);
+  if !attrs.is_empty() {
+    write!(w, r#"      <div class="attrs">Attributes:"#)?;
+    for a in attrs { write!(w, " <code>{}</code>", env.data[a].name)? }
+    writeln!(w, "</div>")?;
+  }
+  Ok(())
+}
+
 fn disambiguated_anchor(w: &mut impl Write, ad: &AtomData, sort: bool) -> io::Result<()> {
   match ad {
     AtomData {sort: Some(_), decl: Some(_), ..} if sort => write!(w, "{}.sort", ad.name),
@@ -450,6 +460,7 @@ impl<'a, W: Write> BuildDoc<'a, W> {
       &format!(r#"{} <a class="thm" href="">{}</a>"#, kind, thmname),
       &nav, &["../proof.js"])?;
     render_doc(&mut file, &td.doc)?;
+    render_attrs(&mut file, &self.env, DeclKey::Thm(tid))?;
     writeln!(file, "    <pre>{}</pre>", FormatEnv {source: self.source, env: &self.env}.to(td))?;
     if let ThmKind::Thm(Some(pf)) = &td.kind {
       writeln!(file, r#"    <table class="proof">
@@ -513,6 +524,7 @@ impl<'a, W: Write> BuildDoc<'a, W> {
             DeclKey::Term(tid) => {
               let td = &self.env.terms[tid];
               render_doc(&mut file, &td.doc)?;
+              render_attrs(&mut file, &self.env, DeclKey::Term(tid))?;
               write!(file, "      <pre>")?;
               escape_html(&mut file, &format!("{}", fe.to(td)))?;
               writeln!(file, "</pre>\n    </div>")?
@@ -520,6 +532,7 @@ impl<'a, W: Write> BuildDoc<'a, W> {
             DeclKey::Thm(tid) => {
               let td = &self.env.thms[tid];
               render_doc(&mut file, &td.doc)?;
+              render_attrs(&mut file, &self.env, DeclKey::Thm(tid))?;
               self.mangler.mangle(&self.env, tid, |name, mangled|
                 write!(file, r#"      <pre>{}{} <a class="thm" href="thms/{mangled}.html">{name}</a>"#, td.vis,
                   if matches!(td.kind, ThmKind::Axiom) {"axiom"} else {"theorem"},
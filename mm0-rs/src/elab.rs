@@ -13,8 +13,13 @@ pub mod lisp;
 pub mod math_parser;
 pub mod local_context;
 pub mod refine;
+pub mod tauto;
+pub mod search;
+pub mod auto;
 pub mod proof;
+pub mod optimize;
 pub mod inout;
+pub mod repl;
 
 use std::ops::{Deref, DerefMut};
 use std::mem;
@@ -219,10 +224,29 @@ pub struct Elaborator {
   timeout: Option<Duration>,
   /// The time at which the current lisp evaluation will be aborted
   cur_timeout: Option<Instant>,
+  /// The maximum number of evaluation and unification steps for one lisp evaluation,
+  /// if we are in fuel mode. Unlike the timeout, this is deterministic, and the timeout
+  /// is not used in fuel mode.
+  fuel: Option<u64>,
+  /// The number of evaluation and unification steps taken in the current statement
+  fuel_used: u64,
+  /// The value of `fuel_used` at which the current fuel budget started. This is nonzero
+  /// if a `try` caught an out of fuel error, see [`renew_limits`](Self::renew_limits).
+  fuel_start: u64,
+  /// True if we report the fuel used by each declaration and `do` block
+  report_fuel: bool,
+  /// True if theorem proofs are run through the [proof optimizer](optimize)
+  optimize: bool,
+  /// True if we warn about theorem hypotheses that are not used in the proof
+  warn_unused_hyps: bool,
   /// The maximum number of permitted stack frames during elaboration
   stack_limit: usize,
+  /// True if `async` evaluates its argument on a worker thread
+  async_threads: bool,
   /// The current proof context
   lc: LocalContext,
+  /// The lisp module whose body is currently being elaborated, if any
+  lisp_module: Option<AtomID>,
   /// Information attached to spans, used for hover queries
   spans: Spans<ObjectKind>,
   /// True if we are currently elaborating an MM0 file
@@ -235,6 +259,8 @@ pub struct Elaborator {
   inout: InoutHandlers,
   /// The arena for lisp data.
   arena: lisp::LispArena,
+  /// The caches used by unification in `refine`.
+  unify_cache: refine::UnifyCache,
 }
 
 impl Deref for Elaborator {
@@ -259,6 +285,9 @@ impl Elaborator {
   ///   file, which can be changed later using the `(check-proofs)` lisp command.
   /// - `cancel`: An atomic flag that can be flipped in another thread in order to cancel
   ///   the elaboration before completion.
+  ///
+  /// The fuel settings are taken from the `--fuel` and `--report-fuel` command line flags,
+  /// and the proof optimizer is enabled by `--optimize` and `--warn-unused-hyps`.
   #[must_use] pub fn new(ast: Arc<AST>, path: FileRef,
       mm0_mode: bool, check_proofs: bool, cancel: Arc<AtomicBool>) -> Elaborator {
     Elaborator {
@@ -267,14 +296,23 @@ impl Elaborator {
       env: Environment::new(),
       timeout: Some(Duration::from_secs(5)),
       cur_timeout: None,
+      fuel: crate::get_fuel(),
+      fuel_used: 0,
+      fuel_start: 0,
+      report_fuel: crate::get_report_fuel(),
+      optimize: crate::get_optimize(),
+      warn_unused_hyps: crate::get_warn_unused_hyps(),
       stack_limit: 1024,
+      async_threads: false,
       lc: LocalContext::new(),
+      lisp_module: None,
       spans: Spans::new(),
       mm0_mode,
       check_proofs,
       inout: InoutHandlers::default(),
       reporting: ReportMode::new(),
       arena: Default::default(),
+      unify_cache: Default::default(),
     }
   }
 
@@ -288,6 +326,40 @@ impl Elaborator {
   }
   fn catch(&mut self, r: Result<()>) { r.unwrap_or_else(|e| self.report(e)) }
 
+  /// Reset the timeout and fuel counter, at the start of a new statement.
+  fn reset_limits(&mut self) {
+    self.cur_timeout = if self.fuel.is_some() {None}
+      else {self.timeout.and_then(|d| Instant::now().checked_add(d))};
+    self.fuel_used = 0;
+    self.fuel_start = 0;
+  }
+
+  /// Start a new time or fuel budget if the current one has run out. This is used when
+  /// a `try` catches an error, because otherwise after a timeout every later step of
+  /// the statement would also time out.
+  fn renew_limits(&mut self) {
+    if self.cur_timeout.map_or(false, |t| t < Instant::now()) {
+      self.cur_timeout = self.timeout.and_then(|d| Instant::now().checked_add(d))
+    }
+    if self.fuel.map_or(false, |f| self.fuel_used - self.fuel_start > f) {
+      self.fuel_start = self.fuel_used
+    }
+  }
+
+  /// Consume one unit of fuel, failing if the fuel limit is exceeded. Fuel is used by
+  /// every lisp procedure call and every step of unification or proof search.
+  fn use_fuel(&mut self) -> StdResult<(), &'static str> {
+    self.fuel_used += 1;
+    if self.fuel.map_or(false, |f| self.fuel_used - self.fuel_start > f) {Err("out of fuel")} else {Ok(())}
+  }
+
+  /// Report the fuel used by the current statement, if `--report-fuel` is set.
+  fn report_fuel(&mut self, sp: Span) {
+    if self.report_fuel {
+      self.report(ElabError::info(sp, format!("fuel used: {}", self.fuel_used)))
+    }
+  }
+
   fn push_spans(&mut self) {
     self.env.spans.push(mem::take(&mut self.spans));
   }
@@ -301,6 +373,23 @@ impl Elaborator {
     }
   }
 
+  /// If `e` is an `(attr name ...)` annotation, returns the attribute names. The name `attr`
+  /// is reserved, so these annotations are handled here and not passed to `annotate`.
+  fn attr_annot(&mut self, e: &SExpr) -> Result<Option<Vec<AtomID>>> {
+    let es = match &e.k {
+      SExprKind::List(es) => es,
+      _ => return Ok(None)
+    };
+    match es.first() {
+      Some(&SExpr {span, k: SExprKind::Atom(ast::Atom::Ident)}) if self.span(span) == b"attr" => {}
+      _ => return Ok(None)
+    }
+    es[1..].iter().map(|x| match x.k {
+      SExprKind::Atom(ast::Atom::Ident) => Ok(self.env.get_atom(self.ast.span(x.span))),
+      _ => Err(ElabError::new_e(x.span, "attr: expected an attribute name")),
+    }).collect::<Result<_>>().map(Some)
+  }
+
   fn elab_simple_nota(&mut self, n: &SimpleNota) -> Result<()> {
     let a = self.env.get_atom(self.ast.span(n.id));
     let term = self.term(a).ok_or_else(|| ElabError::new_e(n.id, "term not declared"))?;
@@ -497,7 +586,7 @@ impl Elaborator {
       if doc.is_empty() {None} else {Some(doc.into())}
     }
 
-    self.cur_timeout = self.timeout.and_then(|d| Instant::now().checked_add(d));
+    self.reset_limits();
     self.spans.set_stmt(span);
     match &stmt.k {
       &StmtKind::Sort(sp, sd) => {
@@ -506,7 +595,11 @@ impl Elaborator {
         let id = self.add_sort(a, fsp, span, sd, to_doc(doc)).map_err(|e| e.into_elab_error(sp))?;
         self.spans.insert(sp, ObjectKind::Sort(id));
       }
-      StmtKind::Decl(d) => self.elab_decl(span, d, to_doc(doc))?,
+      StmtKind::Decl(d) => {
+        let r = self.elab_decl(span, d, to_doc(doc));
+        self.report_fuel(d.id);
+        r?
+      }
       StmtKind::Delimiter(Delimiter::Both(f)) => self.pe.add_delimiters(f, f),
       StmtKind::Delimiter(Delimiter::LeftRight(ls, rs)) => self.pe.add_delimiters(ls, rs),
       StmtKind::SimpleNota(n) => self.elab_simple_nota(n)?,
@@ -518,8 +611,14 @@ impl Elaborator {
           self.report(ElabError::warn(span, "(MM0 mode) do blocks not allowed"))
         }
         for e in es { self.parse_and_print(e, mem::take(&mut doc))? }
+        self.report_fuel(span)
       }
-      StmtKind::Annot(e, s) => {
+      StmtKind::Annot(e, s) => if let Some(attrs) = self.attr_annot(e)? {
+        self.elab_stmt(doc, s, span)?;
+        let k = self.name_of(s).as_atom().and_then(|a| self.data[a].decl).ok_or_else(||
+          ElabError::new_e(e.span, "attributes can only be added to terms and theorems"))?;
+        for a in attrs {self.env.add_attr(a, k);}
+      } else {
         let v = self.eval_lisp(e)?;
         self.elab_stmt(doc, s, span)?;
         let ann = match &self.data[AtomID::ANNOTATE].lisp {
@@ -527,7 +626,10 @@ impl Elaborator {
           None => return Err(ElabError::new_e(e.span, "define 'annotate' before using annotations")),
         };
         let args = vec![v, self.name_of(s)];
-        self.call_func(e.span, ann, args)?;
+        let r = self.call_func(e.span, ann, args);
+        // the fuel used here includes the fuel used by the declaration itself
+        self.report_fuel(e.span);
+        r?;
       },
       StmtKind::DocComment(doc2, s) => {
         // push an extra newline to separate multiple doc comments
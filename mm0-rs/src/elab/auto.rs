@@ -0,0 +1,249 @@
+//! The `(auto)` tactic, a bounded depth backward proof search.
+//!
+//! The search proves the first open goal either by a hypothesis in the local context, or by
+//! one of the given lemmas, whose conclusion is unified with the goal and whose hypotheses
+//! become new goals. Unlike in `refine`, the unification is syntactic, so definitions are
+//! not unfolded, but a solution is only accepted if it satisfies the disjoint variable
+//! conditions of the lemmas. The choices are backtracked in depth first order, and the
+//! depth limit is increased one step at a time, so that a shortest proof is found first.
+//! The result is a refine script, which can be pasted into the source.
+
+use std::time::Instant;
+use std::collections::HashMap;
+use super::{Elaborator, environment::{AtomID, DeclKey, ExprNode, ThmID, Type}};
+use super::lisp::{LispVal, Uncons, eval::SResult};
+use super::local_context::InferSort;
+use super::search::{Pat, resolve, thm_pat, unify};
+
+/// The default depth limit for `(auto)`.
+pub const DEFAULT_DEPTH: usize = 3;
+/// The default number of lemma applications `(auto)` tries before giving up.
+pub const DEFAULT_FUEL: usize = 10000;
+
+/// A step in a proof found by `(auto)`, which proves the first open goal.
+#[derive(Copy, Clone, Debug)]
+enum Step {
+  /// The goal is proved by the hypothesis with this index.
+  Hyp(usize),
+  /// The goal is proved by a theorem, whose variables start at the given index
+  /// in the substitution, and whose hypotheses are the next goals.
+  Thm(ThmID, usize),
+}
+
+/// The state of the proof search.
+struct Auto<'a> {
+  elab: &'a mut Elaborator,
+  /// The lemmas that can be applied.
+  lemmas: Vec<ThmID>,
+  /// The names and statements of the hypotheses.
+  hyps: Vec<(AtomID, Pat)>,
+  /// The number of lemma applications left to try.
+  fuel: usize,
+  /// The proof found so far, in preorder.
+  steps: Vec<Step>,
+}
+
+impl Auto<'_> {
+  fn use_fuel(&mut self) -> SResult<()> {
+    if self.fuel == 0 {return Err("auto: no proof found before running out of fuel".into())}
+    self.fuel -= 1;
+    self.elab.use_fuel()?;
+    if self.elab.cur_timeout.map_or(false, |t| t < Instant::now()) {return Err("timeout".into())}
+    Ok(())
+  }
+
+  /// Prove the goals in `stack` (the last goal first), where each goal has a limit on the
+  /// depth of its proof. On success, `subst` holds the solution and `steps` the proof.
+  fn search(&mut self, stack: &mut Vec<(Pat, usize)>, subst: &mut Vec<Option<Pat>>) -> SResult<bool> {
+    let (goal, depth) = match stack.pop() {
+      None => return Ok(self.check(subst)),
+      Some(g) => g
+    };
+    for i in 0..self.hyps.len() {
+      let mut s = subst.clone();
+      if unify(&mut s, &goal, &self.hyps[i].1) {
+        self.steps.push(Step::Hyp(i));
+        if self.search(stack, &mut s)? {*subst = s; return Ok(true)}
+        self.steps.pop();
+      }
+    }
+    if depth > 0 {
+      for i in 0..self.lemmas.len() {
+        self.use_fuel()?;
+        let th = self.lemmas[i];
+        let td = &self.elab.thms[th];
+        let (off, nargs) = (subst.len(), td.args.len());
+        let mut s = subst.clone();
+        s.resize(off + nargs, None);
+        if unify(&mut s, &thm_pat(&td.heap, nargs, off, &td.ret), &goal) {
+          let n = stack.len();
+          stack.extend(td.hyps.iter().rev().map(|(_, h)| (thm_pat(&td.heap, nargs, off, h), depth - 1)));
+          self.steps.push(Step::Thm(th, off));
+          if self.search(stack, &mut s)? {*subst = s; return Ok(true)}
+          self.steps.pop();
+          stack.truncate(n);
+        }
+      }
+    }
+    stack.push((goal, depth));
+    Ok(false)
+  }
+
+  /// Check that the bound variables of the theorems in a solution are assigned to distinct
+  /// bound variables in the local context, and that the regular variables do not depend on
+  /// the bound variables they must be disjoint from, so that `refine` accepts the proof.
+  fn check(&self, subst: &[Option<Pat>]) -> bool {
+    self.steps.iter().all(|&step| match step {
+      Step::Hyp(_) => true,
+      Step::Thm(th, off) => {
+        let mut bvs = vec![];
+        self.elab.thms[th].args.iter().enumerate().all(|(i, (_, ty))| match *ty {
+          Type::Bound(_) => match *resolve(subst, &Pat::Var(off + i)) {
+            Pat::Const(a) if !bvs.contains(&a) &&
+              matches!(self.elab.lc.vars.get(&a), Some((_, InferSort::Bound(_)))) => {
+              bvs.push(a);
+              true
+            }
+            _ => false,
+          },
+          Type::Reg(_, deps) => {
+            let mut vs = vec![];
+            self.deps(subst, &Pat::Var(off + i), &mut vs);
+            bvs.iter().enumerate().all(|(k, x)| deps & (1 << k) != 0 || !vs.contains(x))
+          }
+        })
+      }
+    })
+  }
+
+  /// Add the bound variables of the local context that the solved expression `e`
+  /// depends on to `out`.
+  fn deps(&self, subst: &[Option<Pat>], e: &Pat, out: &mut Vec<AtomID>) {
+    match *resolve(subst, e) {
+      Pat::Var(_) => {}
+      Pat::Const(a) => match self.elab.lc.vars.get(&a) {
+        Some((_, InferSort::Bound(_))) => out.push(a),
+        Some((_, InferSort::Reg(_, ds))) => out.extend_from_slice(ds),
+        _ => {}
+      },
+      Pat::App(_, ref es) => for e in &**es { self.deps(subst, e, out) }
+    }
+  }
+
+  /// Convert a solved expression back to lisp, with `_` for unassigned variables.
+  fn expr(&self, subst: &[Option<Pat>], e: &Pat) -> LispVal {
+    match *resolve(subst, e) {
+      Pat::Var(_) => LispVal::atom(AtomID::UNDER),
+      Pat::Const(a) => LispVal::atom(a),
+      Pat::App(t, ref es) => {
+        let mut args = vec![LispVal::atom(self.elab.terms[t].atom)];
+        args.extend(es.iter().map(|e| self.expr(subst, e)));
+        LispVal::list(args)
+      }
+    }
+  }
+
+  /// Build the refine script for the proof starting at step `steps[*i]`.
+  fn script(&self, subst: &[Option<Pat>], i: &mut usize) -> LispVal {
+    let step = self.steps[*i];
+    *i += 1;
+    match step {
+      Step::Hyp(h) => LispVal::atom(self.hyps[h].0),
+      Step::Thm(th, off) => {
+        let td = &self.elab.thms[th];
+        let mut args = vec![LispVal::atom(td.atom)];
+        // The term arguments are given explicitly only if unifying the conclusion
+        // with the goal does not determine all of them.
+        let mut in_ret = vec![false; td.args.len()];
+        vars_of(&td.heap, &td.ret, &mut in_ret);
+        if in_ret.contains(&false) {
+          args.insert(0, LispVal::atom(AtomID::BANG));
+          args.extend((0..td.args.len()).map(|j| self.expr(subst, &Pat::Var(off + j))));
+        }
+        for _ in 0..td.hyps.len() { args.push(self.script(subst, i)) }
+        if args.len() == 1 {args.swap_remove(0)} else {LispVal::list(args)}
+      }
+    }
+  }
+}
+
+/// Mark the variables of a theorem that appear in the expression `e` in `out`.
+fn vars_of(heap: &[ExprNode], e: &ExprNode, out: &mut [bool]) {
+  match *e {
+    ExprNode::Ref(i) if i < out.len() => out[i] = true,
+    ExprNode::Ref(i) => vars_of(heap, &heap[i], out),
+    ExprNode::Dummy(_, _) => {}
+    ExprNode::App(_, ref es) => for e in &**es { vars_of(heap, e, out) }
+  }
+}
+
+impl Elaborator {
+  /// Format a refine script returned by [`auto`](Self::auto) as it would be written in the
+  /// source, with the term arguments of `!` applications as math expressions.
+  #[must_use] pub(crate) fn format_script(&self, e: &LispVal) -> String {
+    let mut u = Uncons::from(e.clone());
+    let head = match u.next() {
+      Some(head) => head,
+      None => return format!("{}", self.print(e)),
+    };
+    let mut out = format!("({}", self.print(&head));
+    let mut nargs = 0;
+    if head.as_atom() == Some(AtomID::BANG) {
+      if let Some(th) = u.next() {
+        if let Some(DeclKey::Thm(t)) = th.as_atom().and_then(|a| self.data[a].decl) {
+          nargs = self.thms[t].args.len()
+        }
+        out = format!("{} {}", out, self.print(&th));
+      }
+    }
+    for (i, e) in u.enumerate() {
+      out.push(' ');
+      if i < nargs {
+        self.format_env().pretty(|p| p.expr(&e).render_fmt(80, &mut out)).expect("impossible");
+      } else {
+        out.push_str(&self.format_script(&e))
+      }
+    }
+    out.push(')');
+    out
+  }
+
+  /// Search for a proof of `tgt` using the hypotheses in the local context and the theorems
+  /// in `lemmas`, which is either a list of theorem names or the name of an attribute,
+  /// with proofs of depth at most `depth` and at most `fuel` lemma applications.
+  /// Returns a refine script for the proof.
+  pub fn auto(&mut self, tgt: &LispVal, lemmas: &LispVal, depth: usize, fuel: usize) -> SResult<LispVal> {
+    let mut ths = vec![];
+    if let Some(a) = lemmas.as_atom() {
+      let ks = self.env.attrs.get(&a).ok_or_else(||
+        format!("auto: unknown attribute '{}'", self.data[a].name))?;
+      ths.extend(ks.iter().filter_map(|&k| if let DeclKey::Thm(t) = k {Some(t)} else {None}))
+    } else {
+      for e in Uncons::from(lemmas.clone()) {
+        match e.as_atom().map(|a| (a, self.data[a].decl)) {
+          Some((_, Some(DeclKey::Thm(t)))) => ths.push(t),
+          Some((a, _)) => return Err(format!("auto: '{}' is not a theorem", self.data[a].name)),
+          None => return Err(format!("auto: expected a theorem name, got {}", self.print(&e))),
+        }
+      }
+    }
+    let mut vars = (0, HashMap::new());
+    let goal = self.env.lisp_pat(Some(&self.lc), &mut vars, tgt).map_err(|e|
+      format!("auto: invalid goal {}", self.print(&e)))?;
+    let mut hyps = vec![];
+    for (i, (a, e, _)) in self.lc.proof_order.iter().enumerate() {
+      if self.lc.proofs.get(a) != Some(&i) {continue}
+      // hypotheses that are not expressions are not useful for the search
+      if let Ok(h) = self.env.lisp_pat(Some(&self.lc), &mut vars, e) { hyps.push((*a, h)) }
+    }
+    let mut au = Auto {elab: self, lemmas: ths, hyps, fuel, steps: vec![]};
+    for d in 0..=depth {
+      let mut subst = vec![None; vars.0];
+      au.steps.clear();
+      if au.search(&mut vec![(goal.clone(), d)], &mut subst)? {
+        return Ok(au.script(&subst, &mut 0))
+      }
+    }
+    Err(format!("auto: no proof found with depth at most {}", depth))
+  }
+}
@@ -9,11 +9,13 @@ use std::rc::Rc;
 use std::sync::Arc;
 use std::fmt::Write;
 use std::hash::Hash;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, hash_map::Entry};
 use super::{ElabError, BoxError, spans::Spans, FrozenEnv, FrozenLispVal};
 use crate::util::{ArcString, FileRef, FileSpan, HashMapExt, Span};
 use super::lisp::{LispVal, RefineSyntax, Syntax};
 use super::frozen::{FrozenLispKind, FrozenLispRef};
+use super::tauto::PropRole;
+use super::search::ThmIndex;
 pub use crate::parser::ast::{Modifiers, Prec};
 
 macro_rules! id_wrapper {
@@ -365,7 +367,7 @@ pub enum StmtTrace {
 /// A declaration is either a [`Term`] or a [`Thm`]. This is done because in MM1
 /// Terms and Thms share a namespace (although they are put in separate number-spaces
 /// for compilation to MM0).
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum DeclKey {
   /// A term or def, with its ID
   Term(TermID),
@@ -484,6 +486,42 @@ impl Deref for LispData {
   fn deref(&self) -> &LispVal { &self.val }
 }
 
+/// A lisp module, declared using `(module foo ...)`. The definitions in the body of
+/// the module are global definitions with qualified names `foo::x`.
+#[derive(Clone, Debug, DeepSizeOf)]
+pub struct LispModule {
+  /// The location of the name in the first `(module)` declaration of this module.
+  pub span: FileSpan,
+  /// The (unqualified) names of the definitions in the module.
+  pub members: HashSet<AtomID>,
+  /// The members that can be referred to outside the module, or `None` if the module
+  /// has no `export` list, in which case all members are exported.
+  pub exports: Option<HashSet<AtomID>>,
+}
+
+/// The equality lemmas for a sort, registered with `(register-eq)` and used by the
+/// `rewrite` tactic. All lemmas are in inference form, with hypotheses rather than
+/// implications.
+#[derive(Copy, Clone, Debug, DeepSizeOf)]
+pub struct EqLemmas {
+  /// The equality relation on the sort, a term `eq: s > s > t` for some sort `t`.
+  pub eq: TermID,
+  /// The reflexivity lemma, `refl: $ eq a a $`.
+  pub refl: ThmID,
+  /// The symmetry lemma, `symm (h: $ eq a b $): $ eq b a $`.
+  pub symm: ThmID,
+  /// For provable sorts, the transport lemma `mp (h1: $ eq a b $) (h2: $ b $): $ a $`,
+  /// which is used to turn a rewrite of the goal into a proof of the goal.
+  pub mp: Option<ThmID>,
+}
+
+impl LispModule {
+  /// Returns true if the member `x` of this module is not visible outside the module.
+  #[must_use] pub fn is_private(&self, x: AtomID) -> bool {
+    self.members.contains(&x) && self.exports.as_ref().map_or(false, |ex| !ex.contains(&x))
+  }
+}
+
 /// The data associated to an atom.
 #[derive(Clone, Debug, DeepSizeOf)]
 pub struct AtomData {
@@ -574,6 +612,19 @@ pub struct Environment {
   pub data: AtomVec<AtomData>,
   /// The global statement order.
   pub stmts: Vec<StmtTrace>,
+  /// The lisp modules, indexed by the name of the module.
+  pub modules: HashMap<AtomID, LispModule>,
+  /// The equality lemmas for each sort, used by the `rewrite` tactic.
+  pub eqs: HashMap<SortID, EqLemmas>,
+  /// The congruence lemma for each term, used by the `rewrite` tactic.
+  pub congrs: HashMap<TermID, ThmID>,
+  /// The propositional connectives and lemmas, used by the `tauto` tactic.
+  pub prop: HashMap<PropRole, DeclKey>,
+  /// The attributes, which are named sets of terms and theorems, in the order they were
+  /// added. These are populated using `@(attr name)` annotations or `add-attr!`.
+  pub attrs: HashMap<AtomID, Vec<DeclKey>>,
+  /// The index of theorem statements, used by `find-thms`.
+  pub thm_index: ThmIndex,
   /// The list of spans that have been collected in the current statement.
   pub spans: Vec<Spans<ObjectKind>>,
 }
@@ -610,6 +661,12 @@ macro_rules! make_atoms {
           terms: Default::default(),
           thms: Default::default(),
           stmts: Default::default(),
+          modules: Default::default(),
+          eqs: Default::default(),
+          congrs: Default::default(),
+          prop: Default::default(),
+          attrs: Default::default(),
+          thm_index: Default::default(),
           spans: Default::default(),
         }
       }
@@ -666,6 +723,12 @@ make_atoms! {
   WARN: "warn",
   /// `info` is an error level recognized by `set-reporting`
   INFO: "info",
+  /// `ltr` is a mode of the `rewrite` tactic, rewriting equations left to right
+  LTR: "ltr",
+  /// `rtl` is a mode of the `rewrite` tactic, rewriting equations right to left
+  RTL: "rtl",
+  /// `everywhere` is a mode of the `rewrite` tactic, rewriting all instances of the equations
+  EVERYWHERE: "everywhere",
   /// The `annotate` function is a callback used to define what happens when an annotation like
   /// `@foo def bar = ...` is used.
   ANNOTATE: "annotate",
@@ -674,6 +737,14 @@ make_atoms! {
   REFINE_EXTRA_ARGS: "refine-extra-args",
   /// `to-expr-fallback` is called when elaborating a term that is not otherwise recognized
   TO_EXPR_FALLBACK: "to-expr-fallback",
+  /// `quote` is used for quotations in macro templates and patterns
+  QUOTE: "quote",
+  /// `unquote` is used for antiquotations in macro templates
+  UNQUOTE: "unquote",
+  /// `begin` is used to combine multiple macro templates
+  BEGIN: "begin",
+  /// `...` is used for repetitions in macro patterns and templates
+  ELLIPSIS: "...",
 }
 
 /// An implementation of a map `u8 -> bool` using a 32 byte array as a bitset.
@@ -723,6 +794,14 @@ pub struct Remapper {
   pub(crate) refs: HashMap<*const FrozenLispRef, LispVal>,
 }
 
+impl Remapper {
+  /// Create a [`Remapper`] for lisp data from an environment with the same sorts, terms
+  /// and theorems as this one, where the atoms are relabeled according to `atom`.
+  #[must_use] pub fn from_atoms(atom: AtomVec<AtomID>) -> Remapper {
+    Remapper {atom, ..Default::default()}
+  }
+}
+
 /// A trait for types that can be remapped.
 /// This is like [`Clone`] except it uses a `&mut R` as auxiliary state.
 pub trait Remap: Sized {
@@ -835,6 +914,36 @@ impl Remap for Term {
     }
   }
 }
+impl Remap for LispModule {
+  type Target = Self;
+  fn remap(&self, r: &mut Remapper) -> Self {
+    LispModule {
+      span: self.span.clone(),
+      members: self.members.iter().map(|x| x.remap(r)).collect(),
+      exports: self.exports.as_ref().map(|ex| ex.iter().map(|x| x.remap(r)).collect()),
+    }
+  }
+}
+impl Remap for DeclKey {
+  type Target = Self;
+  fn remap(&self, r: &mut Remapper) -> Self {
+    match *self {
+      DeclKey::Term(t) => DeclKey::Term(t.remap(r)),
+      DeclKey::Thm(t) => DeclKey::Thm(t.remap(r)),
+    }
+  }
+}
+impl Remap for EqLemmas {
+  type Target = Self;
+  fn remap(&self, r: &mut Remapper) -> Self {
+    EqLemmas {
+      eq: self.eq.remap(r),
+      refl: self.refl.remap(r),
+      symm: self.symm.remap(r),
+      mp: self.mp.remap(r),
+    }
+  }
+}
 impl Remap for OutputString {
   type Target = Self;
   fn remap(&self, r: &mut Remapper) -> Self {
@@ -1230,7 +1339,9 @@ impl Environment {
       }))
     } else {
       data.decl = Some(DeclKey::Thm(new_id));
-      self.thms.push(t());
+      let t = t();
+      self.thm_index.insert(new_id, &t);
+      self.thms.push(t);
       self.stmts.push(StmtTrace::Decl(a));
       Ok(new_id)
     }
@@ -1268,6 +1379,39 @@ impl Environment {
       (AtomID(ctx.len().try_into().expect("too many atoms")), ctx.push(AtomData::new(s))).0)
   }
 
+  /// Add the declaration `k` to the attribute `a`. Returns false if it was already there.
+  pub fn add_attr(&mut self, a: AtomID, k: DeclKey) -> bool {
+    let ks = self.attrs.entry(a).or_default();
+    if ks.contains(&k) {return false}
+    ks.push(k);
+    true
+  }
+
+  /// Get the attributes of the declaration `k`, sorted by name.
+  #[must_use] pub fn attrs_of(&self, k: DeclKey) -> Vec<AtomID> {
+    let mut attrs = self.attrs.iter().filter(|(_, ks)| ks.contains(&k))
+      .map(|(&a, _)| a).collect::<Vec<_>>();
+    attrs.sort_by(|&a, &b| self.data[a].name.cmp(&self.data[b].name));
+    attrs
+  }
+
+  /// Get the atom for the qualified name `m::x` of the definition `x` in module `m`.
+  pub fn qualify(&mut self, m: AtomID, x: AtomID) -> AtomID {
+    let mut s = self.data[m].name.to_vec();
+    s.extend_from_slice(b"::");
+    s.extend_from_slice(&self.data[x].name);
+    self.get_atom(&s)
+  }
+
+  /// If `a` is a qualified name `m::x` where `m` is a lisp module, returns `(m, x)`.
+  #[must_use] pub fn split_qualified(&self, a: AtomID) -> Option<(AtomID, AtomID)> {
+    let s = &*self.data[a].name;
+    let i = s.windows(2).rposition(|w| w == b"::")?;
+    let m = *self.atoms.get(&s[..i])?;
+    if !self.modules.contains_key(&m) {return None}
+    Some((m, *self.atoms.get(&s[i+2..])?))
+  }
+
   /// Merge `other` into this environment. This merges definitions with the same name and type,
   /// and relabels lisp objects with the new [`AtomID`] mapping.
   pub fn merge(&mut self, other: &FrozenEnv, sp: Span, errors: &mut Vec<ElabError>) -> Result<(), ElabError> {
@@ -1278,9 +1422,29 @@ impl Environment {
     #[allow(clippy::cast_possible_truncation)]
     for (i, d) in other.data().iter().enumerate() {
       let data = &mut self.data[remap.atom[AtomID(i as u32)]];
-      data.lisp = d.lisp().as_ref().map(|v| v.remap(remap));
-      if data.lisp.is_none() {
-        data.graveyard = d.graveyard().clone();
+      // Only overwrite the existing definition if the imported file has something to say
+      // about this name, otherwise we would forget the lisp definitions of earlier imports
+      match d.lisp() {
+        Some(v) => data.lisp = Some(v.remap(remap)),
+        None => if d.graveyard().is_some() {
+          data.lisp = None;
+          data.graveyard = d.graveyard().clone();
+        }
+      }
+    }
+    for (&m, md) in other.modules() {
+      let md = md.remap(remap);
+      match self.modules.entry(m.remap(remap)) {
+        Entry::Vacant(e) => {e.insert(md);}
+        Entry::Occupied(mut e) => {
+          let e = e.get_mut();
+          e.members.extend(md.members);
+          match (&mut e.exports, md.exports) {
+            (Some(ex), Some(ex2)) => ex.extend(ex2),
+            (ex @ None, Some(ex2)) => *ex = Some(ex2),
+            (_, None) => {}
+          }
+        }
       }
     }
     for s in other.stmts() {
@@ -1341,9 +1505,59 @@ impl Environment {
       }
     }
     self.pe.merge(other.pe(), remap, sp, &self.sorts, errors);
+    for (&s, eqs) in other.eqs() {self.eqs.insert(s.remap(remap), eqs.remap(remap));}
+    for (&t, &th) in other.congrs() {self.congrs.insert(t.remap(remap), th.remap(remap));}
+    for (&r, k) in other.prop() {self.prop.insert(r, k.remap(remap));}
+    for (&a, ks) in other.attrs() {
+      let a = a.remap(remap);
+      for k in ks {self.add_attr(a, k.remap(remap));}
+    }
     Ok(())
   }
 
+  /// Make a deep copy of this environment, for use by another thread. The copy shares no
+  /// lisp data with the original, and it has the same sorts, terms, theorems and atoms,
+  /// but no [`Spans`] (which are only used by the server). The returned [`Remapper`]
+  /// can be used to copy more lisp values into the new environment, preserving sharing
+  /// with the global definitions.
+  #[must_use] pub fn snapshot(&self) -> (Environment, Remapper) {
+    let mut r = Remapper {
+      sort: self.sorts.enum_iter().map(|(i, _)| i).collect(),
+      term: self.terms.enum_iter().map(|(i, _)| i).collect(),
+      thm: self.thms.enum_iter().map(|(i, _)| i).collect(),
+      atom: self.data.enum_iter().map(|(i, _)| i).collect(),
+      ..Default::default()
+    };
+    let data = self.data.iter().map(|d| AtomData {
+      name: d.name.clone(),
+      lisp: d.lisp.as_ref().map(|l| LispData {
+        src: l.src.clone(),
+        doc: l.doc.clone(),
+        val: l.val.remap(&mut r),
+      }),
+      graveyard: d.graveyard.clone(),
+      sort: d.sort,
+      decl: d.decl,
+    }).collect();
+    let env = Environment {
+      sorts: self.sorts.clone(),
+      pe: self.pe.clone(),
+      terms: self.terms.clone(),
+      thms: self.thms.clone(),
+      atoms: self.atoms.clone(),
+      data,
+      stmts: self.stmts.clone(),
+      modules: self.modules.clone(),
+      eqs: self.eqs.clone(),
+      congrs: self.congrs.clone(),
+      prop: self.prop.clone(),
+      attrs: self.attrs.clone(),
+      thm_index: self.thm_index.clone(),
+      spans: vec![],
+    };
+    (env, r)
+  }
+
   /// Return an error if the term has the wrong number of arguments, based on its declaration.
   pub(crate) fn check_term_nargs(&self, sp: Span, term: TermID, nargs: usize) -> Result<(), ElabError> {
     let td = &self.terms[term];
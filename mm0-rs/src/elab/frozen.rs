@@ -29,6 +29,10 @@
 //! This is safe as long as the object is not mutated (via another shared reference,
 //! or a clone) during the lifetime of the object returned by `freeze`.
 //!
+//! Finally, lisp data can be moved to another thread wholesale if nothing else refers
+//! to it. This is done by `async`, which makes a deep copy of the environment for the
+//! worker thread and receives the result back in an [`Isolated`] wrapper.
+//!
 //! [`freeze`]: LispVal::freeze
 
 use std::cell::{Cell, RefCell};
@@ -39,10 +43,11 @@ use std::collections::{HashMap, hash_map::Entry};
 use num::BigInt;
 use super::{Spans, ObjectKind, Remap, Remapper,
   environment::{Environment, ParserEnv,
-    AtomVec, TermVec, ThmVec, SortVec, DeclKey, StmtTrace, DocComment, LispData,
-    SortID, TermID, ThmID, AtomID, Sort, Term, Thm, AtomData},
+    AtomVec, TermVec, ThmVec, SortVec, DeclKey, StmtTrace, DocComment, LispData, LispModule,
+    EqLemmas, SortID, TermID, ThmID, AtomID, Sort, Term, Thm, AtomData},
   lisp::{LispVal, LispKind, LispRef, LispWeak,
-    InferTarget, Proc, Annot, Syntax, print::FormatEnv}};
+    InferTarget, Proc, AsyncState, Annot, LispHasher, Syntax, print::FormatEnv},
+  tauto::PropRole};
 use crate::util::{ArcString, FileSpan, Span};
 use crate::{lined_string::LinedString, __mk_lisp_kind};
 
@@ -103,6 +108,37 @@ impl FrozenEnv {
   #[must_use] pub fn get_atom(&self, s: &[u8]) -> Option<AtomID> { unsafe { self.thaw() }.atoms.get(s).copied() }
   /// Accessor for [`Environment::pe`]
   #[must_use] pub fn pe(&self) -> &ParserEnv { &unsafe { self.thaw() }.pe }
+  /// Accessor for [`Environment::modules`]
+  #[must_use] pub fn modules(&self) -> &HashMap<AtomID, LispModule> { &unsafe { self.thaw() }.modules }
+  /// Accessor for [`Environment::eqs`]
+  #[must_use] pub fn eqs(&self) -> &HashMap<SortID, EqLemmas> { &unsafe { self.thaw() }.eqs }
+  /// Accessor for [`Environment::congrs`]
+  #[must_use] pub fn congrs(&self) -> &HashMap<TermID, ThmID> { &unsafe { self.thaw() }.congrs }
+  /// Accessor for [`Environment::prop`]
+  #[must_use] pub fn prop(&self) -> &HashMap<PropRole, DeclKey> { &unsafe { self.thaw() }.prop }
+  /// Accessor for [`Environment::attrs`]
+  #[must_use] pub fn attrs(&self) -> &HashMap<AtomID, Vec<DeclKey>> { &unsafe { self.thaw() }.attrs }
+}
+
+/// A wrapper around some data containing lisp values that can be sent to another thread.
+///
+/// The wrapper asserts that the lisp data is not reachable from anywhere else.
+/// Because there are no other references to it,
+/// the reference counts cannot race, and so the whole structure can be moved to another thread.
+#[derive(Debug)]
+pub struct Isolated<T>(T);
+unsafe impl<T> Send for Isolated<T> {}
+
+impl<T> Isolated<T> {
+  /// Create a new [`Isolated`] wrapper.
+  /// # Safety
+  /// No lisp value (including the contents of ref-cells) reachable from `t` may be
+  /// reachable from any value outside `t`, except for values that will never be
+  /// accessed again, on any thread.
+  #[must_use] pub unsafe fn new(t: T) -> Self { Self(t) }
+
+  /// Get the wrapped value, on the thread that now owns it.
+  #[must_use] pub fn into_inner(self) -> T { self.0 }
 }
 
 /// A wrapper around an [`AtomData`] that is frozen.
@@ -327,6 +363,9 @@ impl Remap for FrozenLispKind {
       FrozenLispKind::Annot(sp, m) => LispVal::new(LispKind::Annot(sp.clone(), m.remap(r))),
       FrozenLispKind::Proc(f) => LispVal::proc(f.remap(r)),
       FrozenLispKind::AtomMap(m) => LispVal::new(LispKind::AtomMap(m.remap(r))),
+      FrozenLispKind::HashMap(m) => LispVal::new(LispKind::HashMap(
+        m.iter().map(|(k, v)| (k.remap(r), v.remap(r))).collect())),
+      FrozenLispKind::Vector(v) => LispVal::new(LispKind::Vector(v.remap(r))),
       FrozenLispKind::Ref(m) => match r.refs.entry(m as *const _) {
         Entry::Occupied(e) => e.get().clone(),
         Entry::Vacant(e) => {
@@ -373,6 +412,16 @@ impl Remap for FrozenProc {
         }
       )),
       Proc::MMCCompiler(c) => Proc::MMCCompiler(c.remap(r)),
+      Proc::Macro(x, rules) => Proc::Macro(x.remap(r),
+        rules.iter().map(|(p, t)| (p.remap(r), t.remap(r))).collect()),
+      Proc::Async(m) => Proc::Async(RefCell::new(
+        match unsafe { m.try_borrow_unguarded() }.expect("failed to deref ref") {
+          AsyncState::Done(e) => AsyncState::Done(e.remap(r)),
+          AsyncState::Failed(msg) => AsyncState::Failed(msg.clone()),
+          AsyncState::Running(_) =>
+            AsyncState::Failed("the result of this async call is not available here".into()),
+        }
+      )),
     }
   }
 }
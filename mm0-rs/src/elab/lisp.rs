@@ -5,13 +5,15 @@
 //! [`mm1.md`]: https://github.com/digama0/mm0/blob/master/mm0-hs/mm1.md#evaluation
 
 pub mod parser;
+pub mod bytecode;
 pub mod eval;
 pub mod debug;
 pub mod print;
 pub mod pretty;
 
 use std::ops::{Deref, DerefMut};
-use std::hash::Hash;
+use std::hash::{Hash, Hasher, BuildHasherDefault};
+use std::collections::hash_map::DefaultHasher;
 use std::rc::{Rc, Weak};
 use std::cell::{Cell, RefCell};
 use std::sync::{Arc, Mutex};
@@ -24,6 +26,10 @@ use super::{AtomID, ThmID, Remap, Remapper, Modifiers};
 use parser::IR;
 pub use super::math_parser::{QExpr, QExprKind};
 
+/// The hasher used by [`LispKind::HashMap`]. This uses fixed keys rather than random ones,
+/// so that iterating over a hash map is deterministic.
+pub type LispHasher = BuildHasherDefault<DefaultHasher>;
+
 macro_rules! str_enum {
   ($(#[$doc:meta])* enum $name:ident $($rest:tt)*) => {
     str_enum!{@inner
@@ -96,6 +102,12 @@ str_enum! {
     /// `focus`: a tactic that focuses on the main goal, calls a sequence of `refine` calls,
     /// and then closes the goal.
     Focus: "focus",
+    /// `try`: evaluate an expression, and if it fails, roll back the proof state and
+    /// call the handler (if given) with the error message.
+    Try: "try",
+    /// `fail-if`: evaluate an expression, and fail if it succeeds. The proof state is
+    /// rolled back either way.
+    FailIf: "fail-if",
     /// `let`, aka `let*` in other lisps: define a sequence of variable declarations.
     Let: "let",
     /// `letrec`: define a set of mutually recursive variable declarations.
@@ -106,14 +118,24 @@ str_enum! {
     MatchFn: "match-fn",
     /// `match-fn*`: a lambda taking any number of arguments that pattern matches on the list of arguments.
     MatchFns: "match-fn*",
+    /// `defmacro`: define a macro, given as a list of `[pattern template]` rules, which is
+    /// expanded when lisp code is parsed.
+    DefMacro: "defmacro",
+    /// `module`: a top level form `(module foo es ...)` that evaluates `es` such that
+    /// the definitions in `es` are placed in the namespace `foo::`.
+    Module: "module",
+    /// `export`: a declaration `(export x ...)` in a module, which lists the definitions
+    /// that can be referred to outside the module.
+    Export: "export",
   }
 }
 
 impl Syntax {
   /// Parse a string and atom type pair into a [`Syntax`].
+  /// For [`Macro`](Atom::Macro) atoms, `s` should be the name of the atom.
   pub fn parse(s: &[u8], a: Atom) -> Result<Syntax, &[u8]> {
     match a {
-      Atom::Ident => Syntax::from_bytes(s).ok_or(s),
+      Atom::Ident | Atom::Macro(_, _) => Syntax::from_bytes(s).ok_or(s),
       Atom::Quote => Ok(Syntax::Quote),
       Atom::Unquote => Ok(Syntax::Unquote),
       Atom::Nfx => Err(b":nfx"),
@@ -260,6 +282,13 @@ macro_rules! __mk_lisp_kind {
       /// A map from atoms to values. This can be used as a mutable map if it is behind a
       /// [`Ref`](Self::Ref).
       AtomMap(HashMap<AtomID, $val>),
+      /// A map from arbitrary values to values, where keys are compared using structural
+      /// equality (as in `==`). This can be used as a mutable map if it is behind a
+      /// [`Ref`](Self::Ref).
+      HashMap(HashMap<$val, $val, LispHasher>),
+      /// A growable array of values. This can be used as a mutable vector if it is behind a
+      /// [`Ref`](Self::Ref).
+      Vector(Vec<$val>),
       /// A mutable reference. This is the only way to have mutable values in
       /// client code.
       Ref($ref_),
@@ -519,6 +548,13 @@ impl LispRef {
   }
   /// Get a clone of the stored value.
   pub fn unref(&self) -> LispVal { self.get(Clone::clone) }
+  /// Get the address of the stored value, which changes when the reference is assigned.
+  pub(crate) fn target_ptr(&self) -> *const LispKind {
+    match &*self.get_weak() {
+      LispWeak::Strong(e) => std::ptr::addr_of!(**e),
+      LispWeak::Weak(e) => e.as_ptr(),
+    }
+  }
   /// Consume the reference, yielding the stored value.
   pub fn into_inner(self) -> LispVal { self.0.into_inner().upgrade() }
 
@@ -639,6 +675,14 @@ impl LispKind {
   pub fn is_map(&self) -> bool {
     self.unwrapped(|e| matches!(e, LispKind::AtomMap(_)))
   }
+  /// Returns true if this value is a hash map.
+  pub fn is_hash_map(&self) -> bool {
+    self.unwrapped(|e| matches!(e, LispKind::HashMap(_)))
+  }
+  /// Returns true if this value is a vector.
+  pub fn is_vector(&self) -> bool {
+    self.unwrapped(|e| matches!(e, LispKind::Vector(_)))
+  }
   /// Returns true if this value is not `#undef` or a reference to `#undef`.
   pub fn is_def(&self) -> bool {
     self.unwrapped(|e| !matches!(e, LispKind::Undef))
@@ -799,12 +843,37 @@ impl PartialEq<LispKind> for LispKind {
           }
         }
       }
-      _ => false // Goal, Proc, MVar, AtomMap all have only reference equality
+      _ => false // Goal, Proc, MVar, AtomMap, HashMap, Vector all have only reference equality
     }))
   }
 }
 impl Eq for LispKind {}
 
+impl Hash for LispKind {
+  fn hash<H: Hasher>(&self, state: &mut H) {
+    // This has to be compatible with the equality above, so lists are hashed
+    // element by element regardless of how they are chunked into cons cells,
+    // and values that only have reference equality are not hashed at all.
+    self.unwrapped(|e| match e {
+      &LispKind::Atom(a) => {0u8.hash(state); a.hash(state)}
+      LispKind::Number(n) => {1u8.hash(state); n.hash(state)}
+      LispKind::String(s) => {2u8.hash(state); s.hash(state)}
+      &LispKind::Bool(b) => {3u8.hash(state); b.hash(state)}
+      &LispKind::Syntax(s) => {4u8.hash(state); s.to_str().hash(state)}
+      LispKind::Undef => 5u8.hash(state),
+      LispKind::List(es) => for e in &**es { e.hash(state) },
+      LispKind::DottedList(es, r) => {
+        for e in &**es { e.hash(state) }
+        r.hash(state)
+      }
+      _ => {}
+    })
+  }
+}
+impl Hash for LispVal {
+  fn hash<H: Hasher>(&self, state: &mut H) { (**self).hash(state) }
+}
+
 /// An annotation, which is a tag placed on lisp values that is ignored by all
 /// the basic functions.
 #[derive(Clone, Debug, EnvDebug, DeepSizeOf)]
@@ -888,7 +957,45 @@ pub enum Proc {
   /// internal state here. See [`Compiler::call`].
   ///
   /// [`Compiler::call`]: crate::mmc::Compiler::call
-  MMCCompiler(RefCell<crate::mmc::Compiler>) // TODO: use extern instead
+  MMCCompiler(RefCell<crate::mmc::Compiler>), // TODO: use extern instead
+  /// A macro, created by `defmacro`. This holds the name of the macro and the list of
+  /// `(pattern, template)` rules, as quoted lisp data. Macros are expanded when lisp code
+  /// is parsed, so they cannot be called at run time.
+  Macro(AtomID, Box<[(LispVal, LispVal)]>),
+  /// A computation running on another thread, created by `async`. Calling it with no
+  /// arguments waits for the thread to finish and returns the result.
+  Async(RefCell<AsyncState>),
+}
+
+/// The result of an `async` computation, as sent back from the worker thread.
+#[derive(Debug)]
+pub struct AsyncResult {
+  /// The return value of the function, or the error that stopped it.
+  pub result: super::Result<LispVal>,
+  /// The number of atoms in the environment at the time of the call.
+  pub atoms: usize,
+  /// The names of the atoms that the worker thread allocated after the call,
+  /// used to map the atoms in the result back to the calling environment.
+  pub new_atoms: Vec<ArcString>,
+  /// The messages reported by the worker thread, such as the output of `print`.
+  pub errors: Vec<super::ElabError>,
+}
+
+/// A handle to the worker thread of an `async` computation.
+#[derive(Debug)]
+pub struct AsyncHandle(pub std::thread::JoinHandle<super::frozen::Isolated<AsyncResult>>);
+crate::deep_size_0!({!Copy} AsyncHandle);
+
+/// The state of an `async` computation.
+#[derive(Debug, EnvDebug, DeepSizeOf)]
+pub enum AsyncState {
+  /// The thread is still running, or it has finished but nobody has asked for the result yet.
+  Running(AsyncHandle),
+  /// The computation returned this value.
+  Done(LispVal),
+  /// The computation failed with this error message. The error itself is reported
+  /// the first time the result is requested, and later requests fail with the message.
+  Failed(String),
 }
 
 /// A procedure specification, which defines the number of arguments expected
@@ -921,7 +1028,9 @@ impl Proc {
       Proc::Builtin(p) => p.spec(),
       &Proc::Lambda {spec, ..} => spec,
       Proc::MatchCont(_) |
-      Proc::ProofThunk(_, _) => ProcSpec::AtLeast(0),
+      Proc::ProofThunk(_, _) |
+      Proc::Macro(_, _) => ProcSpec::AtLeast(0),
+      Proc::Async(_) => ProcSpec::Exact(0),
       Proc::RefineCallback |
       Proc::MMCCompiler(_) => ProcSpec::AtLeast(1),
     }
@@ -1068,6 +1177,71 @@ str_enum! {
     /// (list->string '(98 97 114)) -- "bar"
     /// ```
     ListToString: "list->string",
+    /// `(string-split delim s)` splits the string `s` at each occurrence of the nonempty string `delim`,
+    /// returning a list of strings.
+    /// ```metamath-zero
+    /// (string-split "," "a,b,,c") -- ("a" "b" "" "c")
+    /// ```
+    StringSplit: "string-split",
+    /// `(string-find sub s)` returns the index of the first occurrence of `sub` in `s`,
+    /// or `#undef` if there is none. `(string-find sub s start)` only finds occurrences
+    /// that start at or after index `start`.
+    /// ```metamath-zero
+    /// (string-find "o" "hello world")   -- 4
+    /// (string-find "o" "hello world" 5) -- 7
+    /// (string-find "x" "hello world")   -- #undef
+    /// ```
+    StringFind: "string-find",
+    /// `(string-replace from to s)` replaces every occurrence of the nonempty string `from`
+    /// in `s` by `to`.
+    /// ```metamath-zero
+    /// (string-replace "l" "L" "hello") -- "heLLo"
+    /// ```
+    StringReplace: "string-replace",
+    /// `(string-trim s)` removes whitespace from the beginning and end of `s`.
+    /// ```metamath-zero
+    /// (string-trim "  foo bar \n") -- "foo bar"
+    /// ```
+    StringTrim: "string-trim",
+    /// `(string-upcase s)` converts the ASCII letters in `s` to upper case.
+    /// ```metamath-zero
+    /// (string-upcase "foo1") -- "FOO1"
+    /// ```
+    StringUpcase: "string-upcase",
+    /// `(string-downcase s)` converts the ASCII letters in `s` to lower case.
+    /// ```metamath-zero
+    /// (string-downcase "FOO1") -- "foo1"
+    /// ```
+    StringDowncase: "string-downcase",
+    /// `(string->number s)` parses a decimal integer, with an optional sign, returning `#undef`
+    /// if `s` is not a number. The digits can't be separated by `_` or whitespace.
+    /// `(string->number s base)` parses a number in the given base, which should be
+    /// between 2 and 36.
+    /// ```metamath-zero
+    /// (string->number "-42")     -- -42
+    /// (string->number "ff" 16)   -- 255
+    /// (string->number "0x1")     -- #undef
+    /// (string->number "1_000")   -- #undef
+    /// ```
+    StringToNumber: "string->number",
+    /// `(number->string n base)` prints the number `n` in the given base (between 2 and 36),
+    /// using lower case letters for digits above 9. `(number->string n base width)` also
+    /// pads the digits with zeros to at least `width` digits. The width counts only the
+    /// digits, so a negative number is one character longer.
+    /// ```metamath-zero
+    /// (number->string 255 16)   -- "ff"
+    /// (number->string 5 2 8)    -- "00000101"
+    /// (number->string (- 10) 16 4) -- "-000a"
+    /// ```
+    NumberToString: "number->string",
+    /// `(format fmt e1 e2 ...)` replaces each `{}` in the string `fmt` with the next argument.
+    /// Strings are inserted as is, and other values are pretty printed as by `pp`,
+    /// so expressions use the notations of the environment. `{{` and `}}` produce `{` and `}`.
+    /// ```metamath-zero
+    /// (format "{} has {} goals" 'foo 2) -- "foo has 2 goals"
+    /// (format "the goal is {}" $ a -> b $) -- "the goal is a -> b"
+    /// ```
+    Format: "format",
     /// `(not e1 e2 e3)` returns `#f` if any argument is truthy, and `#t` otherwise.
     /// It is not short-circuiting.
     Not: "not",
@@ -1135,14 +1309,22 @@ str_enum! {
     /// which can then be copied to a term using `(copy-span)`.
     /// (Useful for targeted error reporting in scripts.)
     StackSpan: "stack-span",
-    /// `(async f args)` evaluates `(f args)` on another thread, and returns a
-    /// procedure that will join on the thread to wait for the result.
+    /// `(async f args)` evaluates `(f args)`, and returns a procedure that will wait
+    /// for the result, or raise the error that the evaluation failed with. The evaluation
+    /// is done immediately, unless worker threads have been enabled by `set-async-threads`,
+    /// in which case it runs on another thread with a copy of the environment.
     Async: "async",
     /// `(atom-map? m)` is true if the argument is an atom map.
     IsAtomMap: "atom-map?",
     /// `(atom-map! [k1 v1] [k2 v2] ...)` creates a new mutable atom map, a key-value store.
     NewAtomMap: "atom-map!",
-    /// * `(lookup m k)` gets the value stored in the atom map `m` at `k`, or `#undef` if not present.
+    /// `(hash-map? m)` is true if the argument is a hash map.
+    IsHashMap: "hash-map?",
+    /// `(hash-map! [k1 v1] [k2 v2] ...)` creates a new mutable hash map. Unlike an atom map,
+    /// the keys can be any values, which are compared using `==`.
+    NewHashMap: "hash-map!",
+    /// * `(lookup m k)` gets the value stored in the atom map or hash map `m` at `k`,
+    ///   or `#undef` if not present.
     /// * `(lookup m k v)` will return `v` instead if the key is not present,
     ///   unless `v` is a procedure, in which case it will be called with no arguments on lookup failure.
     Lookup: "lookup",
@@ -1155,12 +1337,52 @@ str_enum! {
     ///   with the value `v` inserted at key `k`.
     /// * `(insert m k)` returns `k` erased from `m`.
     InsertNew: "insert",
+    /// `(map-len m)` returns the number of keys in the atom map or hash map `m`.
+    MapLen: "map-len",
+    /// `(map->list m)` returns the list of `(k v)` pairs in the atom map or hash map `m`.
+    /// Atom maps are listed in order of atom creation, and hash maps in an arbitrary
+    /// but deterministic order.
+    MapToList: "map->list",
+    /// `(vector? v)` is true if the argument is a vector.
+    IsVector: "vector?",
+    /// `(vector! e1 e2 ...)` creates a new mutable vector with elements `e1 e2 ...`.
+    NewVector: "vector!",
+    /// `(vector-len v)` returns the number of elements in the vector `v`.
+    VectorLen: "vector-len",
+    /// `(vector-nth v n)` returns the `n`th element of the vector `v` (zero-indexed),
+    /// or `#undef` if `n` is out of range.
+    VectorNth: "vector-nth",
+    /// `(vector-set! v n e)` sets the `n`th element of the mutable vector `v` to `e`.
+    VectorSet: "vector-set!",
+    /// `(vector-push! v e)` adds `e` to the end of the mutable vector `v`.
+    VectorPush: "vector-push!",
+    /// `(vector-pop! v)` removes the last element of the mutable vector `v` and returns it,
+    /// or returns `#undef` if `v` is empty.
+    VectorPop: "vector-pop!",
+    /// `(vector->list v)` returns the elements of the vector `v` as a list.
+    VectorToList: "vector->list",
+    /// `(list->vector l)` creates a new mutable vector with the elements of the list `l`.
+    ListToVector: "list->vector",
     /// `(set-timeout n)` sets the timeout for running individual theorems and
     /// `do` blocks to `n` milliseconds. The default is 5 seconds.
+    /// The timeout has no effect in fuel mode (see `set-fuel`).
     SetTimeout: "set-timeout",
+    /// `(set-fuel n)` switches to fuel mode, where the limit for running individual
+    /// theorems and `do` blocks is `n` steps (lisp procedure calls and steps of
+    /// unification), rather than a time limit. Unlike the timeout, this is deterministic,
+    /// so a proof that succeeds will succeed on any machine. `(set-fuel 0)` turns off fuel
+    /// mode, returning to the timeout. Fuel mode can also be set using the `--fuel`
+    /// command line flag.
+    SetFuel: "set-fuel",
+    /// `(get-fuel)` returns the number of lisp procedure calls and unification steps
+    /// used so far by the current theorem or `do` block.
+    GetFuel: "get-fuel",
     /// `(set-stack-limit n)` sets the maximum number of stack frames used during
     /// evaluation of theorems and `do` blocks to `n`. The default is 1024.
     SetStackLimit: "set-stack-limit",
+    /// `(set-async-threads b)` sets whether `async` evaluates its argument on a
+    /// worker thread (`b = #t`) or immediately (`b = #f`). The default is `#f`.
+    SetAsyncThreads: "set-async-threads",
     /// `(mvar? e)` returns `#t` if `e` is an unsolved metavariable value.
     /// *Note:* Holes in expressions are *not* represented as raw metavariables,
     /// they are ref-cells to metavariables. So to test if a metavariable has not
@@ -1222,6 +1444,138 @@ str_enum! {
     ///   after which `h` may be referred to like any other theorem hypothesis.
     /// * `(have h e p)` is the same except that `p` is elaborated with `e` as the expected type.
     Have: "have",
+    /// `(register-eq s eq refl symm [mp])` registers the relation `eq` as the equality
+    /// on sort `s`, for use by `rewrite`. The lemmas are in inference form:
+    /// `refl: $ eq a a $`, `symm (h: $ eq a b $): $ eq b a $` and, for a provable
+    /// sort, `mp (h1: $ eq a b $) (h2: $ b $): $ a $`, which is needed to rewrite goals.
+    /// ```metamath-zero
+    /// (register-eq 'wff 'iff 'biid 'bicomi 'mpbir)
+    /// (register-eq 'nat 'eq 'eqid 'eqcomi)
+    /// ```
+    RegisterEq: "register-eq",
+    /// `(register-congr t th)` registers `th` as the congruence lemma for the term `t`,
+    /// for use by `rewrite`. It should have the form
+    /// `(h1: $ a1 = b1 $) ... (hn: $ an = bn $): $ t a1 ... an = t b1 ... bn $`,
+    /// using the registered equalities on the argument and result sorts. Arguments
+    /// without a hypothesis (such as bound variables) are the same on both sides.
+    /// ```metamath-zero
+    /// (register-congr 'im 'imeqi)
+    /// ```
+    RegisterCongr: "register-congr",
+    /// `(derive-congr t)` generates and proves the congruence lemmas for the definition `t`,
+    /// by unfolding it and using the lemmas registered with `register-eq` and
+    /// `register-congr`. It adds `teqi`, with a hypothesis `$ a = b $` for each argument
+    /// with a registered equality, and if there are several, `teqNi` for the `N`th such
+    /// argument alone. Variables that depend on a bound variable keep the dependency in
+    /// the copy, as in `aleqi (a b: wff x) (h: $ a <-> b $): $ A. x a <-> A. x b $`.
+    /// Existing lemmas are skipped, `teqi` is registered with `register-congr` if `t`
+    /// has no congruence lemma yet, and the list of new theorems is returned.
+    /// It can also be used as an annotation, if `annotate` calls it:
+    /// ```metamath-zero
+    /// (def (annotate e s) (e s))
+    /// @derive-congr def foo (a b: wff): wff = $ a /\ ~b $;
+    /// ```
+    DeriveCongr: "derive-congr",
+    /// `(rewrite tgt ths mode ...)` rewrites the goal `tgt` using the list `ths` of
+    /// equations `th: $ a = b $`, and returns a refine script `(:verb p)` where `p` proves
+    /// `tgt` from a new goal for the rewritten statement. Instances of `a` are found by
+    /// unification, as in `refine`, so definitions are unfolded where needed to match,
+    /// and the proof is built from the lemmas registered with `register-eq` and
+    /// `register-congr`. The modes are:
+    /// * `'ltr` (the default): rewrite `a` to `b`, using the first instance
+    ///   that is found and all other occurrences of the same instance.
+    /// * `'rtl`: rewrite `b` to `a` instead.
+    /// * `'everywhere`: rewrite all instances of all the equations.
+    ///
+    /// Variables that only appear on the right side become metavariables, and
+    /// hypotheses of the equations become new goals.
+    /// ```metamath-zero
+    /// (focus (refine (rewrite (goal-type (hd (get-goals))) '(addcom) 'everywhere)))
+    /// ```
+    Rewrite: "rewrite",
+    /// `(conv tgt step ...)` changes the goal `tgt` to a definitionally equal one by
+    /// unfolding or folding definitions at chosen subterms, and returns a refine script
+    /// `(:verb p)` where `p` proves `tgt` from a new goal for the changed statement,
+    /// using conversion proofs. The steps are:
+    /// * `(unfold t)`: unfold all occurrences of the definition `t`.
+    /// * `(unfold t loc)`: unfold the application of `t` at `loc`.
+    /// * `(fold t [loc])`: fold the subterm at `loc` into an application of `t`,
+    ///   whose arguments are found by unification.
+    /// * `(change e [loc])`: replace the subterm at `loc` by `e`, which must be
+    ///   definitionally equal to it.
+    ///
+    /// A location `loc` is either a list of argument positions, so that `(2 1)` is the first
+    /// argument of the second argument, or a pattern like `$ _ + 0 $`, which selects the
+    /// first matching subterm. Without a location, `fold` and `change` act on the whole goal.
+    /// ```metamath-zero
+    /// (focus (refine (conv (goal-type (hd (get-goals))) '(unfold sb (2)))))
+    /// ```
+    Conv: "conv",
+    /// `(register-prop '([role x] ...))` registers the propositional connectives and lemmas
+    /// of the library for use by `tauto`. Each `role` is one of the following,
+    /// and `x` is the term or theorem that plays that role:
+    /// * `im`, `not`, `an` (required) and `or`, `iff` (optional):
+    ///   the terms for `a -> b`, `~a`, `a /\ b`, `a \/ b`, `a <-> b`.
+    ///   Other terms are treated as propositional atoms.
+    /// * `mp: (h1: $ a -> b $) (h2: $ a $): $ b $`
+    /// * `id: $ a -> a $`
+    /// * `anr: $ a /\ b -> b $`
+    /// * `anwl (h: $ a -> c $): $ a /\ b -> c $`
+    /// * `cases (h1: $ G /\ a -> b $) (h2: $ G /\ ~a -> b $): $ G -> b $`
+    /// * For each connective, lemmas for proving it or its negation in a context `G`:
+    ///   * `im-intro1 (h: $ G -> ~a $): $ G -> (a -> b) $`
+    ///   * `im-intro2 (h: $ G -> b $): $ G -> (a -> b) $`
+    ///   * `im-neg (h1: $ G -> a $) (h2: $ G -> ~b $): $ G -> ~(a -> b) $`
+    ///   * `not-neg (h: $ G -> a $): $ G -> ~~a $`
+    ///   * `an-intro (h1: $ G -> a $) (h2: $ G -> b $): $ G -> a /\ b $`
+    ///   * `an-neg1 (h: $ G -> ~a $): $ G -> ~(a /\ b) $`
+    ///   * `an-neg2 (h: $ G -> ~b $): $ G -> ~(a /\ b) $`
+    ///   * `or-intro1 (h: $ G -> a $): $ G -> a \/ b $`
+    ///   * `or-intro2 (h: $ G -> b $): $ G -> a \/ b $`
+    ///   * `or-neg (h1: $ G -> ~a $) (h2: $ G -> ~b $): $ G -> ~(a \/ b) $`
+    ///   * `iff-intro1 (h1: $ G -> a $) (h2: $ G -> b $): $ G -> (a <-> b) $`
+    ///   * `iff-intro2 (h1: $ G -> ~a $) (h2: $ G -> ~b $): $ G -> (a <-> b) $`
+    ///   * `iff-neg1 (h1: $ G -> a $) (h2: $ G -> ~b $): $ G -> ~(a <-> b) $`
+    ///   * `iff-neg2 (h1: $ G -> ~a $) (h2: $ G -> b $): $ G -> ~(a <-> b) $`
+    ///
+    /// The hypotheses of the lemmas can be in any order. The registration is carried
+    /// along by `import`, and calling `register-prop` again overrides individual roles.
+    RegisterProp: "register-prop",
+    /// `(tauto tgt)` decides whether the goal `tgt` is a propositional tautology, given the
+    /// hypotheses in the local context, and if so it returns a refine script `(:verb p)`
+    /// where `p` is a proof of `tgt` built from the lemmas registered with `register-prop`.
+    /// The hypotheses that share propositional atoms with the goal are tried first, and
+    /// the others are only used if these are not enough.
+    /// If the goal is not a tautology, the error gives an assignment that falsifies it.
+    /// ```metamath-zero
+    /// (focus (refine (tauto (goal-type (hd (get-goals))))))
+    /// ```
+    Tauto: "tauto",
+    /// `(find-thms pat [hyps])` returns the list of theorems whose conclusion unifies with
+    /// the pattern `pat`, in declaration order. If `hyps` is true, theorems with a
+    /// hypothesis that unifies with `pat` are also returned. In the pattern, `_`,
+    /// metavariables and atoms that are not variables of the current proof match any
+    /// expression (consistently, if an atom is used more than once). Statements that are
+    /// just a variable, like the conclusion of `ax_mp`, are skipped because they match anything.
+    /// ```metamath-zero
+    /// (find-thms $ a + b = b + a $)           -- (eqid eqcomi eqtr4i ... addcom ...)
+    /// (find-thms (goal-type (hd (get-goals))))
+    /// ```
+    FindThms: "find-thms",
+    /// `(auto tgt lemmas [depth] [fuel] [print])` searches for a proof of the goal `tgt`
+    /// by backward chaining, using the hypotheses in the local context and the theorems
+    /// in `lemmas`, which is a list of theorem names or the name of an attribute (see
+    /// `get-attr`). Each theorem is applied by unifying its conclusion with the goal
+    /// (syntactically, without unfolding definitions), and its hypotheses become new
+    /// goals, with backtracking over the choices.
+    /// Proofs are at most `depth` theorems deep (default 3), and the search gives up
+    /// after `fuel` theorem applications (default 10000). On success it returns a refine
+    /// script for the proof, which is also printed if `print` is true, so that it can be
+    /// pasted into the source.
+    /// ```metamath-zero
+    /// (focus (refine (auto (goal-type (hd (get-goals))) '(eqtr4i eqcomi add0))))
+    /// ```
+    Auto: "auto",
     /// `(stat)` prints the current proof state, which consists of a list of
     /// subproofs, a list of goals, and a list of metavariables accompanied by their sorts.
     Stat: "stat",
@@ -1265,6 +1619,18 @@ str_enum! {
     /// * `(add-thm! x bis hyps ret vis vtask)` is the same as
     ///   `(add-decl! 'theorem x bis hyps ret vis vtask)`.
     AddThm: "add-thm!",
+    /// `(get-attr name)` returns the list of terms and theorems with the attribute `name`,
+    /// in the order they were added. Attributes are added with an `@(attr name ...)`
+    /// annotation on a declaration, or with `add-attr!`, and they are carried along by `import`.
+    /// ```metamath-zero
+    /// @(attr simp) theorem add0: $ a + 0 = a $ = ...;
+    /// (get-attr 'simp) -- (add0)
+    /// ```
+    GetAttr: "get-attr",
+    /// `(get-attrs x)` returns the list of attributes of the declaration `x`, sorted by name.
+    GetAttrs: "get-attrs",
+    /// `(add-attr! name x ...)` adds the attribute `name` to the declarations `x ...`.
+    AddAttr: "add-attr!",
     /// * `(dummy! x s)` produces a new dummy variable called `x` with sort `s`, and returns `x`;
     /// * `(dummy! s)` automatically gives the variable a name like `_123` that is guaranteed to be unused.
     NewDummy: "dummy!",
@@ -0,0 +1,285 @@
+//! The lisp bytecode compiler.
+//!
+//! The parser produces [`IR`], a tree that the evaluator can interpret directly, but
+//! walking the tree takes several trips through the main evaluation loop for every
+//! node. This module compiles the parts of the tree that do not manipulate the
+//! evaluation stack in complicated ways (variable access, function application, `if`,
+//! `begin`, `list`, `cons` and `fn`) into a flat sequence of [`Op`]s operating on a
+//! value stack, which the evaluator can execute in a tight loop. The other syntax forms
+//! (`def`, `match`, `focus`, `try` and so on) are left as [`IR`], with their subterms compiled.
+
+use std::{iter, mem};
+use std::sync::Arc;
+use itertools::Itertools;
+use crate::util::Span;
+use super::super::AtomID;
+use super::{LispVal, ProcSpec, Remap, Remapper};
+use super::parser::{IR, Pattern, MVarPattern};
+use super::print::{FormatEnv, EnvDisplay};
+
+/// A bytecode instruction. Instructions pop their inputs from the value stack,
+/// and push their result onto it.
+#[derive(Debug, EnvDebug, DeepSizeOf)]
+pub enum Op {
+  /// Push variable number `n` in the context
+  Local(usize),
+  /// Push the value of the global declaration named `a`
+  Global(Span, AtomID),
+  /// Push a [`LispVal`] literally
+  Const(LispVal),
+  /// Pop `n` values, and push the list of them
+  List(Span, usize),
+  /// Pop `n + 1` values, and push the dotted list of them, where the last value is the tail
+  DottedList(usize),
+  /// `App(sp1, sp2, n, tail)`: Pop `n` arguments and the procedure below them, and call
+  /// the procedure. If `tail` is true then the result of the call is the result of the
+  /// code block, so we do not need to return to the code block after the call.
+  App(Span, Span, usize, bool),
+  /// Pop a value, and jump to the given instruction if it is not truthy
+  JumpUnless(usize),
+  /// Jump to the given instruction
+  Jump(usize),
+  /// Pop a value and discard it
+  Pop,
+  /// `Lambda(sp, n, spec, code, tail)`: Push a closure, as in [`IR::Lambda`].
+  /// If `tail` is true then the closure is the result of the code block, in which case
+  /// it can get its name from an enclosing `def`.
+  Lambda(Span, usize, ProcSpec, Arc<IR>, bool),
+}
+
+/// A compiled code block.
+///
+/// Executing the instructions (starting from an empty value stack) results in exactly
+/// one value on the stack, the result of the block. A jump to the end of the instruction
+/// list returns from the block.
+#[derive(Debug, EnvDebug, DeepSizeOf)]
+pub struct Code {
+  /// The span of the expression that was compiled
+  pub span: Option<Span>,
+  /// The list of instructions
+  pub ops: Box<[Op]>,
+}
+
+impl EnvDisplay for Op {
+  fn fmt(&self, fe: FormatEnv<'_>, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      &Op::Local(i) => write!(f, "x{}", i),
+      &Op::Global(_, a) => a.fmt(fe, f),
+      Op::Const(e) => write!(f, "'{}", fe.to(e)),
+      Op::List(_, n) => write!(f, "list/{}", n),
+      Op::DottedList(n) => write!(f, "cons/{}", n + 1),
+      Op::App(_, _, n, false) => write!(f, "app/{}", n),
+      Op::App(_, _, n, true) => write!(f, "tailapp/{}", n),
+      Op::JumpUnless(i) => write!(f, "unless ->{}", i),
+      Op::Jump(i) => write!(f, "->{}", i),
+      Op::Pop => write!(f, "pop"),
+      Op::Lambda(_, n, sp, e, _) => {
+        write!(f, "(lambda {}:", n)?;
+        match sp {
+          ProcSpec::Exact(n) => write!(f, "{}", n)?,
+          ProcSpec::AtLeast(n) => write!(f, "{}+", n)?,
+        }
+        write!(f, " {})", fe.to(&**e))
+      }
+    }
+  }
+}
+
+impl EnvDisplay for Code {
+  fn fmt(&self, fe: FormatEnv<'_>, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "(code {})", self.ops.iter().map(|op| fe.to(op)).format("; "))
+  }
+}
+
+impl Remap for Op {
+  type Target = Self;
+  fn remap(&self, r: &mut Remapper) -> Self {
+    match self {
+      &Op::Local(i) => Op::Local(i),
+      &Op::Global(sp, a) => Op::Global(sp, a.remap(r)),
+      Op::Const(v) => Op::Const(unsafe { v.freeze() }.remap(r)),
+      &Op::List(sp, n) => Op::List(sp, n),
+      &Op::DottedList(n) => Op::DottedList(n),
+      &Op::App(sp1, sp2, n, tail) => Op::App(sp1, sp2, n, tail),
+      &Op::JumpUnless(i) => Op::JumpUnless(i),
+      &Op::Jump(i) => Op::Jump(i),
+      Op::Pop => Op::Pop,
+      &Op::Lambda(sp, n, spec, ref e, tail) => Op::Lambda(sp, n, spec, e.remap(r), tail),
+    }
+  }
+}
+
+impl Remap for Code {
+  type Target = Self;
+  fn remap(&self, r: &mut Remapper) -> Self {
+    Code { span: self.span, ops: self.ops.remap(r) }
+  }
+}
+
+impl Code {
+  /// Compile an expression. The expression must satisfy [`IR::prepare`].
+  fn new(ir: IR) -> Code {
+    let span = ir.span();
+    let mut ops = vec![];
+    Self::push(&mut ops, ir);
+    // Mark the instructions whose result is the result of the block
+    let returns = |mut i: usize| loop {
+      match ops.get(i) {
+        None => break true,
+        Some(&Op::Jump(j)) => i = j,
+        Some(_) => break false,
+      }
+    };
+    let tail: Vec<_> = (0..ops.len()).filter(|&i| returns(i + 1)).collect();
+    for i in tail {
+      match &mut ops[i] {
+        Op::App(_, _, _, tail) | Op::Lambda(_, _, _, _, tail) => *tail = true,
+        _ => {}
+      }
+    }
+    Code { span, ops: ops.into() }
+  }
+
+  fn push(ops: &mut Vec<Op>, ir: IR) {
+    let op = match ir {
+      IR::Local(i) => Op::Local(i),
+      IR::Global(sp, a) => Op::Global(sp, a),
+      IR::Const(v) => Op::Const(v),
+      IR::List(sp, es) => {
+        let n = es.len();
+        for e in es.into_vec() { Self::push(ops, e) }
+        Op::List(sp, n)
+      }
+      IR::DottedList(es, r) => {
+        let n = es.len();
+        for e in es.into_vec() { Self::push(ops, e) }
+        Self::push(ops, *r);
+        Op::DottedList(n)
+      }
+      IR::App(sp1, sp2, f, es) => {
+        let n = es.len();
+        Self::push(ops, *f);
+        for e in es.into_vec() { Self::push(ops, e) }
+        Op::App(sp1, sp2, n, false)
+      }
+      IR::If(e) => {
+        let (cond, e1, e2) = *e;
+        Self::push(ops, cond);
+        let i = ops.len();
+        ops.push(Op::JumpUnless(0));
+        Self::push(ops, e1);
+        let j = ops.len();
+        ops.push(Op::Jump(0));
+        ops[i] = Op::JumpUnless(ops.len());
+        Self::push(ops, e2);
+        ops[j] = Op::Jump(ops.len());
+        return
+      }
+      IR::Eval(true, es) => {
+        let mut it = es.into_vec().into_iter();
+        match it.next() {
+          None => Op::Const(LispVal::undef()),
+          Some(e) => {
+            Self::push(ops, e);
+            for e in it {
+              ops.push(Op::Pop);
+              Self::push(ops, e)
+            }
+            return
+          }
+        }
+      }
+      IR::Lambda(sp, n, spec, e) => Op::Lambda(sp, n, spec, e, false),
+      _ => unreachable!("not compilable")
+    };
+    ops.push(op)
+  }
+}
+
+impl IR {
+  /// Compile the maximal compilable subterms of this expression to bytecode.
+  pub fn compile(&mut self) {
+    if self.prepare() { self.make_code() }
+  }
+
+  /// Compile the subterms of this expression that cannot be compiled together
+  /// with it, and return true if the expression itself can be compiled.
+  fn prepare(&mut self) -> bool {
+    match self {
+      IR::Local(_) | IR::Global(_, _) | IR::Const(_) => true,
+      IR::Lambda(_, _, _, e) => {
+        // This is only called on freshly parsed code, so we should have the only reference
+        if let Some(e) = Arc::get_mut(e) { e.compile() }
+        true
+      }
+      IR::List(_, es) | IR::Eval(true, es) => prepare_all(es.iter_mut()),
+      IR::DottedList(es, r) => prepare_all(es.iter_mut().chain(Some(&mut **r))),
+      IR::App(_, _, f, es) => prepare_all(iter::once(&mut **f).chain(es.iter_mut())),
+      IR::If(e) => {
+        let (cond, e1, e2) = &mut **e;
+        prepare_all(iter::once(cond).chain(iter::once(e1)).chain(iter::once(e2)))
+      }
+      IR::Focus(_, es) | IR::Eval(false, es) => {
+        for e in &mut **es { e.compile() }
+        false
+      }
+      IR::Try(_, e, h) => {
+        e.compile();
+        if let Some(h) = h { h.compile() }
+        false
+      }
+      IR::FailIf(_, e) | IR::Def(_, _, e) => { e.compile(); false }
+      IR::Match(_, e, brs) => {
+        e.compile();
+        for br in &mut **brs {
+          br.pat.compile();
+          br.eval.compile()
+        }
+        false
+      }
+      IR::NoTailRec | IR::Code(_) => false,
+    }
+  }
+
+  /// Replace a compilable expression by a code block. Variables and constants are
+  /// already as fast as they can be, so they are left alone.
+  fn make_code(&mut self) {
+    if let IR::Local(_) | IR::Global(_, _) | IR::Const(_) | IR::Lambda(_, _, _, _) = self {return}
+    let ir = mem::replace(self, IR::NoTailRec);
+    *self = IR::Code(Box::new(Code::new(ir)))
+  }
+}
+
+/// Prepare a list of subterms that are evaluated in sequence. If they are all compilable
+/// then we return true, otherwise the compilable ones are compiled separately.
+fn prepare_all<'a>(es: impl Iterator<Item=&'a mut IR>) -> bool {
+  let es: Vec<_> = es.map(|e| (e.prepare(), e)).collect();
+  if es.iter().all(|p| p.0) {return true}
+  for (ok, e) in es {
+    if ok { e.make_code() }
+  }
+  false
+}
+
+impl Pattern {
+  /// Compile the expressions in `(? f ps)` patterns.
+  fn compile(&mut self) {
+    match self {
+      Pattern::Skip | Pattern::Atom(_) | Pattern::QuoteAtom(_) | Pattern::String(_) |
+      Pattern::Bool(_) | Pattern::Undef | Pattern::Number(_) | Pattern::QExprAtom(_) |
+      Pattern::MVar(MVarPattern::Unknown) | Pattern::MVar(MVarPattern::Any) => {}
+      Pattern::MVar(MVarPattern::Simple(p)) => { p.0.compile(); p.1.compile() }
+      Pattern::Goal(p) => p.compile(),
+      Pattern::DottedList(ps, p) => {
+        for p in &mut **ps { p.compile() }
+        p.compile()
+      }
+      Pattern::List(ps, _) | Pattern::And(ps) | Pattern::Or(ps) | Pattern::Not(ps) =>
+        for p in &mut **ps { p.compile() },
+      Pattern::Test(_, ir, ps) => {
+        ir.compile();
+        for p in &mut **ps { p.compile() }
+      }
+    }
+  }
+}
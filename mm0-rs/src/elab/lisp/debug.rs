@@ -254,6 +254,7 @@ env_debug! {
   crate::mmc::nameck::ProcTC,
   crate::mmc::Compiler,
   crate::elab::lisp::BuiltinProc,
+  crate::elab::lisp::AsyncHandle,
   crate::elab::lisp::ProcSpec,
   crate::parser::ast::Prec,
   crate::elab::environment::Literal,
@@ -273,6 +274,7 @@ env_debug_seq! {
 
 env_debug_map! {
   (K, V) -> std::collections::HashMap<K, V>
+  (K, V) -> std::collections::HashMap<K, V, crate::elab::lisp::LispHasher>
 }
 
 env_debug_as_ref! {
@@ -7,21 +7,24 @@
 use std::ops::{Deref, DerefMut};
 use std::mem;
 use std::time::{Instant, Duration};
-use std::sync::atomic::Ordering;
+use std::sync::{Mutex, atomic::Ordering};
 use std::collections::HashMap;
 use std::convert::TryInto;
-use num::{BigInt, ToPrimitive};
-use crate::util::{ArcString, FileRef, FileSpan, SliceExt, Span};
-use crate::parser::ast::SExpr;
+use num::{BigInt, Signed, ToPrimitive};
+use crate::util::{ArcString, FileRef, FileSpan, MutexExt, SliceExt, Span};
+use crate::parser::ast::{SExpr, SExprKind, Atom};
 use super::super::{Result, Elaborator, LispData,
   AtomID, Environment, AtomData, DeclKey, StmtTrace,
-  ElabError, ElabErrorKind, ErrorLevel, BoxError, ObjectKind,
-  refine::{RStack, RState, RefineResult}};
+  ElabError, ElabErrorKind, ErrorLevel, BoxError, ObjectKind, Remap, Remapper,
+  frozen::Isolated,
+  refine::{RStack, RState, RefineResult, RewriteMode}, tauto::PropRole, auto};
 use super::{Arc, BuiltinProc, Cell, InferTarget, LispKind, LispRef, LispVal,
-  Modifiers, Proc, ProcPos, ProcSpec, QExpr, Rc, RefCell, ThmID, Uncons};
+  LispHasher, Modifiers, Proc, ProcPos, AsyncState, AsyncHandle, AsyncResult, ProcSpec, QExpr, Rc, RefCell, Syntax, ThmID, Uncons};
 use super::parser::{IR, Branch, Pattern, MVarPattern, DefTarget};
-use super::super::local_context::{InferSort, AwaitingProof, try_get_span};
-use super::super::environment::{TermKind, ThmKind, ExprNode, ProofNode};
+use super::bytecode::{Code, Op};
+use super::super::local_context::{InferSort, AwaitingProof, LocalContextSnapshot, try_get_span};
+use super::super::environment::{TermKind, ThmKind, ExprNode, ProofNode, LispModule,
+  EqLemmas, TermID, Type};
 use super::print::{FormatEnv, EnvDisplay};
 
 #[derive(Debug)]
@@ -48,6 +51,19 @@ enum Stack<'a> {
   Refine {sp: Span, stack: Vec<RStack>},
   Focus(Span, bool, Vec<LispVal>),
   Have(Span, LispVal, AtomID),
+  Try(Span, Option<&'a IR>, Box<Checkpoint>),
+  FailIf(Span, Box<Checkpoint>),
+  Code(&'a Code, usize, Vec<LispVal>),
+  Async(Box<Checkpoint>),
+}
+
+/// The state saved by `try`, `fail-if` and `async`, which is restored if an error is thrown.
+#[derive(Debug)]
+struct Checkpoint {
+  ctx: Vec<LispVal>,
+  file: FileRef,
+  lc: LocalContextSnapshot,
+  errors: usize,
 }
 
 impl<'a> EnvDisplay for Stack<'a> {
@@ -85,6 +101,11 @@ impl<'a> EnvDisplay for Stack<'a> {
       Stack::Refine {..} => write!(f, "(refine _)"),
       &Stack::Focus(_, cl, ref es) => write!(f, "(focus {} _)\n  ->{}", cl, fe.to(es)),
       Stack::Have(_, _, a) => write!(f, "(have {} _)", fe.to(a)),
+      Stack::Try(_, None, _) => write!(f, "(try _)"),
+      &Stack::Try(_, Some(h), _) => write!(f, "(try _ {})", fe.to(h)),
+      Stack::FailIf(_, _) => write!(f, "(fail-if _)"),
+      &Stack::Code(c, pc, ref vs) => write!(f, "{}\n  ->{} {}", fe.to(c), pc, fe.to(vs)),
+      Stack::Async(_) => write!(f, "(async _)"),
     }
   }
 }
@@ -139,35 +160,57 @@ impl LispKind {
     }
   }
 
-  fn make_map_mut<T>(&self, f: impl FnOnce(&mut HashMap<AtomID, LispVal>) -> T) -> (Option<T>, Option<LispVal>) {
-    match self {
-      LispKind::AtomMap(m) => {
-        let mut m = m.clone();
-        (Some(f(&mut m)), Some(LispVal::new(LispKind::AtomMap(m))))
-      }
-      LispKind::Annot(sp, e) => match e.make_map_mut(f) {
+  /// Copy a shared collection (an atom map, hash map or vector) so that it can be modified.
+  fn make_coll_mut<T>(&self, f: impl FnOnce(&mut LispKind) -> Option<T>) -> (Option<T>, Option<LispVal>) {
+    let mut e = match self {
+      LispKind::AtomMap(m) => LispKind::AtomMap(m.clone()),
+      LispKind::HashMap(m) => LispKind::HashMap(m.clone()),
+      LispKind::Vector(v) => LispKind::Vector(v.clone()),
+      LispKind::Annot(sp, e) => return match e.make_coll_mut(f) {
         (r, None) => (r, None),
         (r, Some(e)) => (r, Some(LispVal::new(LispKind::Annot(sp.clone(), e)))),
       },
-      LispKind::Ref(m) => (m.get_mut(|e| e.as_map_mut(f)), None),
-      _ => (None, None)
-    }
+      LispKind::Ref(m) => return (m.get_mut(|e| e.as_coll_mut(f)), None),
+      _ => return (None, None)
+    };
+    (f(&mut e), Some(LispVal::new(e)))
   }
 }
+
+/// A mutable reference to one of the two kinds of map.
+enum MapMut<'a> {
+  /// An atom map
+  Atom(&'a mut HashMap<AtomID, LispVal>),
+  /// A hash map
+  Hash(&'a mut HashMap<LispVal, LispVal, LispHasher>),
+}
+
 impl LispVal {
-  fn as_map_mut<T>(&mut self, f: impl FnOnce(&mut HashMap<AtomID, LispVal>) -> T) -> Option<T> {
+  /// Get mutable access to a collection, copying it first if it is shared.
+  fn as_coll_mut<T>(&mut self, f: impl FnOnce(&mut LispKind) -> Option<T>) -> Option<T> {
     match self.get_mut() {
       None => {
-        let (r, new) = self.make_map_mut(f);
+        let (r, new) = self.make_coll_mut(f);
         if let Some(e) = new {*self = e}
         r
       }
-      Some(LispKind::AtomMap(m)) => Some(f(m)),
-      Some(LispKind::Annot(_, e)) => Self::as_map_mut(e, f),
-      Some(LispKind::Ref(m)) => m.get_mut(|e| Self::as_map_mut(e, f)),
-      Some(_) => None
+      Some(LispKind::Annot(_, e)) => Self::as_coll_mut(e, f),
+      Some(LispKind::Ref(m)) => m.get_mut(|e| Self::as_coll_mut(e, f)),
+      Some(e) => f(e)
     }
   }
+
+  fn as_map_mut<T>(&mut self, f: impl FnOnce(MapMut<'_>) -> T) -> Option<T> {
+    self.as_coll_mut(|e| match e {
+      LispKind::AtomMap(m) => Some(f(MapMut::Atom(m))),
+      LispKind::HashMap(m) => Some(f(MapMut::Hash(m))),
+      _ => None
+    })
+  }
+
+  fn as_vec_mut<T>(&mut self, f: impl FnOnce(&mut Vec<LispVal>) -> T) -> Option<T> {
+    self.as_coll_mut(|e| if let LispKind::Vector(v) = e {Some(f(v))} else {None})
+  }
 }
 
 #[derive(Debug)]
@@ -321,11 +364,93 @@ impl Elaborator {
   /// Parse and evaluate a lisp expression, with the given doc comment.
   pub fn eval_lisp_doc(&mut self, e: &SExpr, doc: String) -> Result<LispVal> {
     let sp = e.span;
+    if let Some(es) = self.as_syntax(Syntax::Module, e) {return self.eval_module(sp, es)}
     let ir = self.parse_lisp_doc(e, doc)?;
     // println!("{}", self.print(&ir));
     self.evaluate(sp, &ir)
   }
 
+  /// If `e` is an application `(stx es ...)` of the given syntax form, returns the list `stx es ...`.
+  fn as_syntax<'b>(&self, stx: Syntax, e: &'b SExpr) -> Option<&'b [SExpr]> {
+    match &e.k {
+      SExprKind::List(es) => match es.first()?.k {
+        SExprKind::Atom(a @ Atom::Ident) if
+          Syntax::parse(self.ast.span(es[0].span), a) == Ok(stx) => Some(es),
+        _ => None
+      },
+      SExprKind::DocComment(_, e) => self.as_syntax(stx, e),
+      _ => None
+    }
+  }
+
+  /// The name defined by a top level `(def x ...)`, `(def (x args) ...)` or
+  /// `(defmacro x ...)`, if `e` is such a definition.
+  fn defined_name(&mut self, e: &SExpr) -> Option<AtomID> {
+    let mut e = if let Some(es) = self.as_syntax(Syntax::Define, e) {es.get(1)?}
+      else {self.as_syntax(Syntax::DefMacro, e)?.get(1)?};
+    loop {
+      match &e.k {
+        SExprKind::Atom(Atom::Ident) => return Some(self.env.get_atom(self.ast.span(e.span))),
+        SExprKind::List(es) | SExprKind::DottedList(es, _) => e = es.first()?,
+        _ => return None
+      }
+    }
+  }
+
+  /// Evaluate a module declaration `(module m es ...)`. Each expression in `es` is
+  /// evaluated as if it were at the top level, except that global definitions `x`
+  /// are stored under the qualified name `m::x`, and references to members of the
+  /// module can use the unqualified name. The module can be reopened later, in this
+  /// file or a file that imports it.
+  fn eval_module(&mut self, sp: Span, es: &[SExpr]) -> Result<LispVal> {
+    if self.lisp_module.is_some() {
+      return Err(ElabError::new_e(es[0].span, "modules cannot be nested"))
+    }
+    let m = match es.get(1) {
+      Some(&SExpr {span, k: SExprKind::Atom(Atom::Ident)}) =>
+        self.env.get_atom(self.ast.span(span)),
+      _ => return Err(ElabError::new_e(sp, "expected a module name")),
+    };
+    let mut members = vec![];
+    let mut exports = None;
+    for e in &es[2..] {
+      if let Some(xs) = self.as_syntax(Syntax::Export, e) {
+        let exports = exports.get_or_insert_with(Vec::new);
+        for x in &xs[1..] {
+          match x.k {
+            SExprKind::Atom(Atom::Ident) =>
+              exports.push((x.span, self.env.get_atom(self.ast.span(x.span)))),
+            _ => return Err(ElabError::new_e(x.span, "export: expected an identifier")),
+          }
+        }
+      } else {members.extend(self.defined_name(e))}
+    }
+    let fsp = self.fspan(es[1].span);
+    let md = self.env.modules.entry(m).or_insert_with(|| LispModule {
+      span: fsp, members: Default::default(), exports: None
+    });
+    md.members.extend(members);
+    if let Some(ex) = &exports {
+      md.exports.get_or_insert_with(Default::default).extend(ex.iter().map(|p| p.1));
+    }
+    self.lisp_module = Some(m);
+    let mut res = Ok(());
+    for e in &es[2..] {
+      if self.as_syntax(Syntax::Export, e).is_some() {continue}
+      res = self.eval_lisp(e).map(|val| if val.is_def() {self.print_lisp(e.span, &val)});
+      if res.is_err() {break}
+    }
+    self.lisp_module = None;
+    res?;
+    for (sp, x) in exports.into_iter().flatten() {
+      if !self.env.modules[&m].members.contains(&x) {
+        self.report(ElabError::new_e(sp, format!("'{}' is not defined in module '{}'",
+          self.data[x].name, self.data[m].name)))
+      }
+    }
+    Ok(LispVal::undef())
+  }
+
   /// Parse and evaluate a math formula.
   pub fn eval_qexpr(&mut self, e: QExpr) -> Result<LispVal> {
     let sp = e.span;
@@ -363,6 +488,84 @@ impl Elaborator {
     self.call_func(sp, val, es)
   }
 
+  /// Start evaluating `(f args)` on a new thread, for `async`. The thread gets a
+  /// [snapshot](Environment::snapshot) of the environment and an empty local context,
+  /// with the same limits as the current statement. Because the ref-cells are copied too,
+  /// the computation fails if it assigns to any ref-cell that it shares with the caller,
+  /// or modifies a mutable atom map, hash map or vector (which are stored in ref-cells).
+  fn spawn_async(&self, sp: Span, f: &LispVal, args: &[LispVal]) -> AsyncHandle {
+    let (env, mut r) = self.env.snapshot();
+    let (f, args) = (f.remap(&mut r), args.iter().map(|e| e.remap(&mut r)).collect::<Vec<_>>());
+    let refs = r.lisp.into_values()
+      .filter(|e| matches!(**e, LispKind::Ref(_))).collect::<Vec<_>>();
+    // Safety: the snapshot and the remapped values share nothing with the current environment
+    let job = unsafe { Isolated::new((env, f, args, refs)) };
+    let (ast, path, cancel) = (self.ast.clone(), self.path.clone(), self.cancel.clone());
+    let (mm0_mode, check_proofs) = (self.mm0_mode, self.check_proofs);
+    let (timeout, fuel, stack_limit) = (self.timeout, self.fuel, self.stack_limit);
+    AsyncHandle(std::thread::spawn(move || {
+      let (env, f, args, refs) = job.into_inner();
+      let target = |e: &LispVal| e.as_lref(LispRef::target_ptr).expect("impossible");
+      // We also hold on to the old values, so that they are not uniquely owned by the
+      // ref-cells. Modifying a collection in place then copies it first, which changes
+      // the target of the ref-cell.
+      let old = refs.iter().map(|e| (target(e), e.as_lref(LispRef::unref))).collect::<Vec<_>>();
+      let atoms = env.data.len();
+      let mut elab = Elaborator::new(ast, path, mm0_mode, check_proofs, cancel);
+      elab.env = env;
+      elab.timeout = timeout;
+      elab.fuel = fuel;
+      elab.stack_limit = stack_limit;
+      elab.reset_limits();
+      let mut result = elab.call_func(sp, f, args);
+      if result.is_ok() && refs.iter().zip(&old).any(|(e, &(p, _))| target(e) != p) {
+        result = Err(ElabError::new_e(sp,
+          "async: the computation assigned to a ref-cell or modified a map or vector \
+          shared with the caller, which is not visible outside the worker thread \
+          (see set-async-threads)"))
+      }
+      let new_atoms = elab.data.iter().skip(atoms).map(|d| d.name.clone()).collect();
+      let errors = mem::take(&mut elab.errors);
+      // Drop the environment here, so that the result is the only remaining reference
+      // to the lisp data that it contains
+      drop((elab, refs, old));
+      unsafe { Isolated::new(AsyncResult {result, atoms, new_atoms, errors}) }
+    }))
+  }
+
+  /// Wait for an `async` computation to finish, and return its result.
+  fn await_async(&mut self, sp: Span, m: &RefCell<AsyncState>) -> Result<LispVal> {
+    let mut g = m.borrow_mut();
+    let h = match mem::replace(&mut *g, AsyncState::Failed("async thread panicked".into())) {
+      AsyncState::Running(h) => h,
+      AsyncState::Done(e) => {*g = AsyncState::Done(e.clone()); return Ok(e)}
+      AsyncState::Failed(msg) => {
+        let err = ElabError::new_e(sp, msg.clone());
+        *g = AsyncState::Failed(msg);
+        return Err(err)
+      }
+    };
+    let AsyncResult {result, atoms, new_atoms, errors} = h.0.join()
+      .map_err(|_| ElabError::new_e(sp, "async thread panicked"))?.into_inner();
+    for e in errors { self.report(e) }
+    match result {
+      Ok(e) => {
+        let e = if new_atoms.is_empty() {e} else {
+          #[allow(clippy::cast_possible_truncation)]
+          let atom = (0..atoms).map(|i| AtomID(i as u32))
+            .chain(new_atoms.into_iter().map(|s| self.get_atom_arc(s))).collect();
+          e.remap(&mut Remapper::from_atoms(atom))
+        };
+        *g = AsyncState::Done(e.clone());
+        Ok(e)
+      }
+      Err(err) => {
+        *g = AsyncState::Failed(err.kind.msg());
+        Err(err)
+      }
+    }
+  }
+
   fn as_string(&self, e: &LispVal) -> SResult<ArcString> {
     e.unwrapped(|e| if let LispKind::String(s) = e {Ok(s.clone())} else {
       Err(format!("expected a string, got {}", self.print(e)))
@@ -387,6 +590,54 @@ impl Elaborator {
     self.with_int(e, |n| Ok(n.clone()))
   }
 
+  fn as_base(&self, e: &LispVal) -> SResult<u32> {
+    self.with_int(e, |n| match n.to_u32() {
+      Some(b) if (2..=36).contains(&b) => Ok(b),
+      _ => Err(format!("invalid base {}, expected 2 to 36", n))
+    })
+  }
+
+  fn as_term(&self, e: &LispVal) -> SResult<TermID> {
+    let a = e.as_atom().ok_or("expected an atom")?;
+    self.term(a).ok_or_else(|| format!("unknown term '{}'", self.data[a].name))
+  }
+
+  fn as_thm(&self, e: &LispVal, nhyps: Option<usize>) -> SResult<ThmID> {
+    let a = e.as_atom().ok_or("expected an atom")?;
+    let th = self.thm(a).ok_or_else(|| format!("unknown theorem '{}'", self.data[a].name))?;
+    match nhyps {
+      Some(n) if self.thms[th].hyps.len() != n => Err(format!(
+        "theorem '{}' should have {} hypotheses", self.data[a].name, n)),
+      _ => Ok(th)
+    }
+  }
+
+  /// Replace the `{}` placeholders in `fmt` by the arguments, for `format`.
+  fn format(&self, fmt: &[u8], args: &[LispVal]) -> SResult<Vec<u8>> {
+    let (mut out, mut it, mut i) = (vec![], args.iter(), 0);
+    while let Some(&c) = fmt.get(i) {
+      match (c, fmt.get(i + 1)) {
+        (b'{', Some(b'{')) | (b'}', Some(b'}')) => {out.push(c); i += 2}
+        (b'{', Some(b'}')) => {
+          let e = it.next().ok_or_else(|| format!(
+            "format: not enough arguments, got {}", args.len()))?;
+          if let Some(s) = e.unwrapped(|e| if let LispKind::String(s) = e {Some(s.clone())} else {None}) {
+            out.extend_from_slice(&s)
+          } else {
+            out.extend_from_slice(format!("{}", self.format_env().pp(e, 80)).as_bytes())
+          }
+          i += 2
+        }
+        (b'{', _) | (b'}', _) => return Err(format!("format: unmatched '{}' at index {}", c as char, i)),
+        _ => {out.push(c); i += 1}
+      }
+    }
+    if it.next().is_some() {
+      return Err(format!("format: too many arguments, expected {}", args.len() - it.len() - 1))
+    }
+    Ok(out)
+  }
+
   fn as_lref<T>(&self, e: &LispKind, f: impl FnOnce(&LispRef) -> SResult<T>) -> SResult<T> {
     e.as_lref(f).unwrap_or_else(|| Err(format!("not a ref-cell: {}", self.print(e))))
   }
@@ -395,10 +646,52 @@ impl Elaborator {
     self.as_lref(e, |m| m.get_mut(f))
   }
 
-  fn as_map<T>(&self, e: &LispKind, f: impl FnOnce(&HashMap<AtomID, LispVal>) -> SResult<T>) -> SResult<T> {
+  fn as_vec<T>(&self, e: &LispKind, f: impl FnOnce(&[LispVal]) -> T) -> SResult<T> {
     e.unwrapped(|e| match e {
-      LispKind::AtomMap(m) => f(m),
-      _ => Err(format!("not an atom map: {}", self.print(e)))
+      LispKind::Vector(v) => Ok(f(v)),
+      _ => Err(format!("not a vector: {}", self.print(e)))
+    })
+  }
+
+  /// Look up a key in an atom map or hash map.
+  fn map_get(&mut self, m: &LispVal, k: &LispVal) -> SResult<Option<LispVal>> {
+    m.unwrapped(|e| match e {
+      LispKind::AtomMap(m) => Ok(self.as_string_atom(k).and_then(|k| m.get(&k).cloned())),
+      LispKind::HashMap(m) => Ok(m.get(k).cloned()),
+      _ => Err(format!("not a map: {}", self.print(e)))
+    })
+  }
+
+  /// Insert a value into a map, or remove the key from the map if `v` is `None`.
+  fn map_insert(&mut self, m: MapMut<'_>, k: &LispVal, v: Option<LispVal>) -> SResult<()> {
+    match m {
+      MapMut::Atom(m) => {
+        let k = self.as_string_atom(k)
+          .ok_or_else(|| format!("expected an atom, got {}", self.print(k)))?;
+        match v {
+          Some(v) => {m.insert(k, v);}
+          None => {m.remove(&k);}
+        }
+      }
+      MapMut::Hash(m) => match v {
+        Some(v) => {m.insert(k.clone(), v);}
+        None => {m.remove(k);}
+      }
+    }
+    Ok(())
+  }
+
+  /// Get the list of key-value pairs in a map.
+  fn map_to_list(&self, e: &LispKind) -> SResult<Vec<LispVal>> {
+    e.unwrapped(|e| match e {
+      LispKind::AtomMap(m) => {
+        let mut es: Vec<_> = m.iter().collect();
+        es.sort_by_key(|p| *p.0);
+        Ok(es.into_iter().map(|(&a, v)| LispVal::list(vec![LispVal::atom(a), v.clone()])).collect())
+      }
+      LispKind::HashMap(m) =>
+        Ok(m.iter().map(|(k, v)| LispVal::list(vec![k.clone(), v.clone()])).collect()),
+      _ => Err(format!("not a map: {}", self.print(e)))
     })
   }
 
@@ -612,6 +905,87 @@ impl Elaborator {
   }
 }
 
+/// A lisp value or proof state item, printed for display in a debugger.
+///
+/// Lisp values cannot be sent to other threads, so everything is converted to strings
+/// when evaluation stops.
+#[derive(Debug)]
+pub struct DebugVar {
+  /// The name of the variable (or list index, or hypothesis name).
+  pub name: String,
+  /// The printed value.
+  pub value: String,
+  /// The elements of the value, if it is a list.
+  pub children: Vec<DebugVar>,
+}
+
+impl DebugVar {
+  /// The maximum depth of lists that are expanded into children.
+  const MAX_DEPTH: usize = 3;
+
+  fn new(fe: FormatEnv<'_>, name: String, e: &LispVal, depth: usize) -> DebugVar {
+    let value = format!("{}", fe.to(e));
+    let mut children = vec![];
+    if depth < Self::MAX_DEPTH &&
+        e.unwrapped(|r| matches!(r, LispKind::List(_) | LispKind::DottedList(_, _))) {
+      let mut u = Uncons::from(e.clone());
+      for i in 0.. {
+        match u.next() {
+          Some(e) => children.push(DebugVar::new(fe, format!("[{}]", i), &e, depth + 1)),
+          None => break
+        }
+      }
+      if !u.is_empty() {
+        let tail = LispVal::from(u);
+        children.push(DebugVar::new(fe, ".".into(), &tail, depth + 1))
+      }
+    }
+    DebugVar {name, value, children}
+  }
+}
+
+/// A stack frame in a [`DebugStop`].
+#[derive(Debug)]
+pub struct DebugFrame {
+  /// The name of the procedure running in this frame, or `<top>` for the outermost frame.
+  pub name: String,
+  /// The location being evaluated in this frame.
+  pub pos: FileSpan,
+  /// The local variables in this frame, named `x0, x1, ...` as in the [`IR`] printer.
+  pub locals: Vec<DebugVar>,
+}
+
+/// The state of the evaluator when it stops at a breakpoint or after a step.
+#[derive(Debug)]
+pub struct DebugStop {
+  /// The stack frames, innermost first.
+  pub frames: Vec<DebugFrame>,
+  /// The hypotheses in the current proof context.
+  pub hyps: Vec<DebugVar>,
+  /// The current goals.
+  pub goals: Vec<DebugVar>,
+}
+
+/// An interactive debugger attached to the lisp evaluator.
+///
+/// It is installed with [`set_debug_hook`]. The evaluator calls [`check`](Self::check) before each
+/// function application, `focus`, `match` and `refine` step, and
+/// [`stop`](Self::stop) if it returns true.
+pub trait DebugHook: std::fmt::Debug + Send + Sync {
+  /// Returns true if evaluation should stop before evaluating `pos`. `depth` is the
+  /// number of procedure calls on the stack.
+  fn check(&self, pos: &FileSpan, depth: usize) -> bool;
+  /// Report the evaluator state. This blocks until the debugger resumes execution.
+  fn stop(&self, stop: DebugStop);
+}
+
+lazy_static! {
+  static ref DEBUG_HOOK: Mutex<Option<Arc<dyn DebugHook>>> = Mutex::new(None);
+}
+
+/// Install (or remove) the debugger hook. This affects all evaluations started after this call.
+pub fn set_debug_hook(hook: Option<Arc<dyn DebugHook>>) { *DEBUG_HOOK.ulock() = hook }
+
 /// The lisp evaluation context, representing a lisp evaluation in progress.
 /// This is an explicitly unfolled state machine (rather than using recursive functions)
 /// so that we can explicitly manipulate the program stack for error reporting purposes.
@@ -631,6 +1005,8 @@ pub struct Evaluator<'a> {
   /// The evaluation stack. This is a structured object containing a stack of continuations
   /// each of which represent a context which awaiting a value from a sub-computation.
   stack: Vec<Stack<'a>>,
+  /// The debugger, if one is attached.
+  debug: Option<Arc<dyn DebugHook>>,
 }
 impl<'a> Deref for Evaluator<'a> {
   type Target = Elaborator;
@@ -643,7 +1019,8 @@ impl<'a> DerefMut for Evaluator<'a> {
 impl<'a> Evaluator<'a> {
   fn new(elab: &'a mut Elaborator, orig_span: Span) -> Evaluator<'a> {
     let file = elab.path.clone();
-    Evaluator {elab, ctx: vec![], file, orig_span, stack: vec![]}
+    let debug = DEBUG_HOOK.ulock().clone();
+    Evaluator {elab, ctx: vec![], file, orig_span, stack: vec![], debug}
   }
 
   fn fspan_base(&mut self, sp: Span) -> FileSpan {
@@ -691,6 +1068,42 @@ impl<'a> Evaluator<'a> {
     None
   }
 
+  /// Check for a breakpoint at `sp`, and if we stop, send the current state to the debugger
+  /// and wait for it to resume.
+  fn debug_point(&self, hook: &dyn DebugHook, sp: Span) {
+    let pos = self.fspan(sp);
+    let depth = self.stack.iter().filter(|s| matches!(s, Stack::Ret(..))).count();
+    if !hook.check(&pos, depth) {return}
+    let fe = self.format_env();
+    let locals = |ctx: &[LispVal]| ctx.iter().enumerate()
+      .map(|(i, e)| DebugVar::new(fe, format!("x{}", i), e, 0)).collect();
+    let name = |p: &ProcPos| match p {
+      ProcPos::Named(_, _, a) => format!("({})", self.data[*a].name),
+      ProcPos::Unnamed(_) => "[fn]".into(),
+    };
+    let mut frames = vec![];
+    let mut cur = (pos, locals(&self.ctx));
+    for s in self.stack.iter().rev() {
+      if let Stack::Ret(fsp, p, old, _) = s {
+        let (pos, locals) = mem::replace(&mut cur, (fsp.clone(), locals(old)));
+        frames.push(DebugFrame {name: name(p), pos, locals})
+      }
+    }
+    frames.push(DebugFrame {name: "<top>".into(), pos: cur.0, locals: cur.1});
+    let hyps = self.lc.proof_order.iter().map(|(a, e, _)| DebugVar {
+      name: format!("{}", self.print(a)),
+      value: format!("{}", fe.pp(e, 80)),
+      children: vec![],
+    }).collect();
+    let goals = self.lc.goals.iter().enumerate().map(|(i, g)| DebugVar {
+      name: format!("?{}", i),
+      value: g.goal_type().map_or_else(|| format!("{}", fe.to(g)),
+        |ty| format!("|- {}", fe.pp(&ty, 80))),
+      children: vec![],
+    }).collect();
+    hook.stop(DebugStop {frames, hyps, goals})
+  }
+
   fn info(&mut self, sp: Span, good: bool, base: &str, msg: impl Into<BoxError>) {
     let msg = self.make_stack_err(Some((sp, good)), ErrorLevel::Info, base.into(), msg);
     self.report(msg)
@@ -964,6 +1377,70 @@ make_builtins! { self, sp1, sp2, args,
     }
     LispVal::string(out.into())
   },
+  StringSplit: Exact(2) => {
+    let (delim, s) = (try1!(self.as_string(&args[0])), try1!(self.as_string(&args[1])));
+    if delim.is_empty() {try1!(Err("string-split: empty delimiter"))}
+    let (mut out, mut start) = (vec![], 0);
+    while let Some(i) = find_bytes(&s, &delim, start) {
+      out.push(LispVal::string(s[start..i].into()));
+      start = i + delim.len();
+    }
+    out.push(LispVal::string(s[start..].into()));
+    LispVal::list(out)
+  },
+  StringFind: AtLeast(2) => {
+    let (sub, s) = (try1!(self.as_string(&args[0])), try1!(self.as_string(&args[1])));
+    let start = match args.get(2) {
+      None => 0,
+      Some(e) => try1!(self.with_int(e,
+        |n| n.try_into().map_err(|_| format!("index out of range: start {}", n)))),
+    };
+    find_bytes(&s, &sub, start).map_or_else(LispVal::undef, |i| LispVal::number(i.into()))
+  },
+  StringReplace: Exact(3) => {
+    let from = try1!(self.as_string(&args[0]));
+    let (to, s) = (try1!(self.as_string(&args[1])), try1!(self.as_string(&args[2])));
+    if from.is_empty() {try1!(Err("string-replace: empty search string"))}
+    let (mut out, mut start) = (vec![], 0);
+    while let Some(i) = find_bytes(&s, &from, start) {
+      out.extend_from_slice(&s[start..i]);
+      out.extend_from_slice(&to);
+      start = i + from.len();
+    }
+    out.extend_from_slice(&s[start..]);
+    LispVal::string(out.into())
+  },
+  StringTrim: Exact(1) => {
+    let s = try1!(self.as_string(&args[0]));
+    let start = s.iter().position(|c| !c.is_ascii_whitespace()).unwrap_or(s.len());
+    let end = s.iter().rposition(|c| !c.is_ascii_whitespace()).map_or(start, |i| i + 1);
+    LispVal::string(s[start..end].into())
+  },
+  StringUpcase: Exact(1) => LispVal::string(try1!(self.as_string(&args[0])).to_ascii_uppercase().into()),
+  StringDowncase: Exact(1) => LispVal::string(try1!(self.as_string(&args[0])).to_ascii_lowercase().into()),
+  StringToNumber: AtLeast(1) => {
+    let s = try1!(self.as_string(&args[0]));
+    let base = match args.get(1) { None => 10, Some(e) => try1!(self.as_base(e)) };
+    // `parse_bytes` allows `_` between digits, which we don't
+    if s.contains(&b'_') {LispVal::undef()}
+    else {BigInt::parse_bytes(&s, base).map_or_else(LispVal::undef, LispVal::number)}
+  },
+  NumberToString: AtLeast(2) => {
+    let n = try1!(self.as_int(&args[0]));
+    let base = try1!(self.as_base(&args[1]));
+    let width: usize = match args.get(2) {
+      None => 0,
+      Some(e) => try1!(self.with_int(e,
+        |n| n.try_into().map_err(|_| format!("width out of range: {}", n)))),
+    };
+    let digits = n.magnitude().to_str_radix(base);
+    let sign = if n.is_negative() {"-"} else {""};
+    LispVal::string(format!("{}{:0>width$}", sign, digits, width = width).into())
+  },
+  Format: AtLeast(1) => {
+    let fmt = try1!(self.as_string(&args[0]));
+    LispVal::string(try1!(self.format(&fmt, &args[1..])).into())
+  },
   Not: AtLeast(0) => LispVal::bool(!args.iter().any(|e| e.truthy())),
   And: AtLeast(0) => LispVal::bool(args.iter().all(|e| e.truthy())),
   Or: AtLeast(0) => LispVal::bool(args.iter().any(|e| e.truthy())),
@@ -1027,8 +1504,13 @@ make_builtins! { self, sp1, sp2, args,
   Async: AtLeast(1) => {
     let proc = args.remove(0);
     let sp = proc.fspan().map_or(sp2, |fsp| fsp.span);
-    // TODO: actually async this
-    return Ok(State::App(sp1, sp, proc, args, [].iter()))
+    if !proc.is_proc() {try1!(Err("expected a procedure"))}
+    if !self.async_threads {
+      // errors are caught by `unwind` and stored in the promise, like on a worker thread
+      self.stack.push(Stack::Async(self.checkpoint()));
+      return Ok(State::App(sp1, sp, proc, args, [].iter()))
+    }
+    LispVal::proc(Proc::Async(RefCell::new(AsyncState::Running(self.spawn_async(sp, &proc, &args)))))
   },
   IsAtomMap: Exact(1) => LispVal::bool(args[0].is_map()),
   NewAtomMap: AtLeast(0) => {
@@ -1044,32 +1526,33 @@ make_builtins! { self, sp1, sp2, args,
     }
     LispVal::new_ref(LispVal::new(LispKind::AtomMap(m)))
   },
+  IsHashMap: Exact(1) => LispVal::bool(args[0].is_hash_map()),
+  NewHashMap: AtLeast(0) => {
+    // Keys are hashed by value, so a key containing a ref-cell should not be mutated
+    #[allow(clippy::mutable_key_type)]
+    let mut m = HashMap::default();
+    for e in args {
+      let mut u = Uncons::from(e);
+      let k = try1!(u.next().ok_or("invalid arguments"));
+      let ret = u.next();
+      if !u.exactly(0) {try1!(Err("invalid arguments"))}
+      if let Some(v) = ret {m.insert(k, v);} else {m.remove(&k);}
+    }
+    LispVal::new_ref(LispVal::new(LispKind::HashMap(m)))
+  },
   Lookup: AtLeast(2) => {
-    match self.as_string_atom(&args[1]) {
-      None => LispVal::undef(),
-      Some(k) => {
-        let e = try1!(self.as_map(&args[0], |m| Ok(m.get(&k).cloned())));
-        if let Some(e) = e {e} else {
-          let v = args.get(2).cloned().unwrap_or_else(LispVal::undef);
-          if v.is_proc() {
-            let sp = v.fspan().map_or(sp2, |fsp| fsp.span);
-            return Ok(State::App(sp1, sp, v, vec![], [].iter()))
-          } else {v}
-        }
+    if let Some(e) = try1!(self.map_get(&args[0], &args[1])) {e} else {
+      let v = args.get(2).cloned().unwrap_or_else(LispVal::undef);
+      if v.is_proc() {
+        let sp = v.fspan().map_or(sp2, |fsp| fsp.span);
+        return Ok(State::App(sp1, sp, v, vec![], [].iter()))
       }
+      v
     }
   },
   Insert: AtLeast(2) => {
     try1!(try1!(args[0].as_ref_mut(|r| {
-      r.as_map_mut(|m| -> SResult<_> {
-        let k = self.as_string_atom(&args[1])
-          .ok_or_else(|| format!("expected an atom, got {}", self.print(&args[1])))?;
-        match args.get(2) {
-          Some(v) => {m.insert(k, v.clone());}
-          None => {m.remove(&k);}
-        }
-        Ok(())
-      })
+      r.as_map_mut(|m| self.map_insert(m, &args[1], args.get(2).cloned()))
     }).unwrap_or(None).ok_or("expected a mutable map")));
     LispVal::undef()
   },
@@ -1077,28 +1560,71 @@ make_builtins! { self, sp1, sp2, args,
     let mut it = args.into_iter();
     let mut m = it.next().unwrap();
     let k = it.next().unwrap();
-    let k = self.as_string_atom(&k)
-      .ok_or_else(|| format!("expected an atom, got {}", self.print(&k)));
-    try1!(try1!(m.as_map_mut(|m| -> SResult<_> {
-      match it.next() {
-        Some(v) => {m.insert(k?, v);}
-        None => {m.remove(&k?);}
-      }
+    try1!(try1!(m.as_map_mut(|m| self.map_insert(m, &k, it.next())).ok_or("expected a map")));
+    m
+  },
+  MapLen: Exact(1) => LispVal::number(try1!(args[0].unwrapped(|e| match e {
+    LispKind::AtomMap(m) => Ok(m.len()),
+    LispKind::HashMap(m) => Ok(m.len()),
+    _ => Err(format!("not a map: {}", self.print(e)))
+  })).into()),
+  MapToList: Exact(1) => LispVal::list(try1!(self.map_to_list(&args[0]))),
+  IsVector: Exact(1) => LispVal::bool(args[0].is_vector()),
+  NewVector: AtLeast(0) => LispVal::new_ref(LispVal::new(LispKind::Vector(args))),
+  VectorLen: Exact(1) => LispVal::number(try1!(self.as_vec(&args[0], <[_]>::len)).into()),
+  VectorNth: Exact(2) => {
+    let i = try1!(args[1].as_int(|n| n.to_usize().unwrap_or(usize::MAX)).ok_or("expected a number"));
+    try1!(self.as_vec(&args[0], |v| v.get(i).cloned())).unwrap_or_else(LispVal::undef)
+  },
+  VectorSet: Exact(3) => {
+    let i = try1!(args[1].as_int(|n| n.to_usize().unwrap_or(usize::MAX)).ok_or("expected a number"));
+    let e = args.pop().expect("impossible");
+    try1!(try1!(args[0].as_ref_mut(|r| r.as_vec_mut(|v| -> SResult<_> {
+      let n = v.len();
+      *v.get_mut(i).ok_or_else(|| format!("index {} out of range for vector of length {}", i, n))? = e;
       Ok(())
-    }).ok_or("expected a map")));
+    })).unwrap_or(None).ok_or("expected a mutable vector")));
+    LispVal::undef()
+  },
+  VectorPush: Exact(2) => {
+    let e = args.pop().expect("impossible");
+    try1!(args[0].as_ref_mut(|r| r.as_vec_mut(|v| v.push(e)))
+      .unwrap_or(None).ok_or("expected a mutable vector"));
     LispVal::undef()
   },
+  VectorPop: Exact(1) => try1!(args[0].as_ref_mut(|r| r.as_vec_mut(Vec::pop))
+    .unwrap_or(None).ok_or("expected a mutable vector")).unwrap_or_else(LispVal::undef),
+  VectorToList: Exact(1) => LispVal::list(try1!(self.as_vec(&args[0], <[_]>::to_vec))),
+  ListToVector: Exact(1) => {
+    let u = Uncons::from(args.pop().expect("impossible"));
+    LispVal::new_ref(LispVal::new(LispKind::Vector(u.collect())))
+  },
   SetTimeout: Exact(1) => {
     match try1!(args[0].as_int(|n| n.to_u64()).ok_or("expected a number")) {
       None | Some(0) => {self.timeout = None; self.cur_timeout = None},
       Some(n) => {
         let d = Duration::from_millis(n);
         self.timeout = Some(d);
-        self.cur_timeout = Instant::now().checked_add(d)
+        if self.fuel.is_none() {self.cur_timeout = Instant::now().checked_add(d)}
       }
     }
     LispVal::undef()
   },
+  SetFuel: Exact(1) => {
+    match try1!(args[0].as_int(ToPrimitive::to_u64).ok_or("expected a number")) {
+      None | Some(0) => {
+        self.fuel = None;
+        self.cur_timeout = self.timeout.and_then(|d| Instant::now().checked_add(d))
+      }
+      Some(n) => {self.fuel = Some(n); self.cur_timeout = None}
+    }
+    LispVal::undef()
+  },
+  SetAsyncThreads: Exact(1) => {
+    self.async_threads = try1!(args[0].as_bool().ok_or("expected a bool"));
+    LispVal::undef()
+  },
+  GetFuel: Exact(0) => LispVal::number(self.fuel_used.into()),
   SetStackLimit: Exact(1) => {
     self.stack_limit =
       try1!(args[0].as_int(|n| n.to_usize()).ok_or("expected a number"))
@@ -1172,6 +1698,95 @@ make_builtins! { self, sp1, sp2, args,
     };
     return Ok(State::Refine {sp: sp1, stack, state})
   },
+  RegisterEq: AtLeast(4) => {
+    if args.len() > 5 {try1!(Err("expected 4 or 5 arguments"))}
+    let s = try1!(args[0].as_atom().and_then(|s| self.data[s].sort).ok_or("expected a sort"));
+    let eq = try1!(self.as_term(&args[1]));
+    if !matches!(*self.terms[eq].args, [(_, Type::Reg(s1, _)), (_, Type::Reg(s2, _))] if s1 == s && s2 == s) {
+      try1!(Err(format!("'{}' is not a binary relation on '{}'",
+        self.print(&eq), self.print(&s))))
+    }
+    let lems = EqLemmas {
+      eq,
+      refl: try1!(self.as_thm(&args[2], Some(0))),
+      symm: try1!(self.as_thm(&args[3], Some(1))),
+      mp: match args.get(4) {None => None, Some(e) => Some(try1!(self.as_thm(e, Some(2))))},
+    };
+    self.env.eqs.insert(s, lems);
+    LispVal::undef()
+  },
+  RegisterCongr: Exact(2) => {
+    let t = try1!(self.as_term(&args[0]));
+    let th = try1!(self.as_thm(&args[1], None));
+    if !matches!(self.thms[th].ret, ExprNode::App(_, ref es)
+      if matches!(**es, [ExprNode::App(t1, _), ExprNode::App(t2, _)] if t1 == t && t2 == t)) {
+      try1!(Err(format!("'{}' is not a congruence lemma for '{}'",
+        self.print(&th), self.print(&t))))
+    }
+    self.env.congrs.insert(t, th);
+    LispVal::undef()
+  },
+  DeriveCongr: Exact(1) => {
+    let t = try1!(self.as_term(&args[0]));
+    let fsp = self.fspan_base(sp1);
+    self.derive_congr(&fsp, t)?
+  },
+  Rewrite: AtLeast(2) => {
+    let mut mode = RewriteMode::default();
+    for e in &args[2..] {
+      match try1!(e.as_atom().ok_or("expected an atom")) {
+        AtomID::LTR => mode.rtl = false,
+        AtomID::RTL => mode.rtl = true,
+        AtomID::EVERYWHERE => mode.everywhere = true,
+        a => try1!(Err(format!("unknown rewrite mode '{}'", self.print(&a))))
+      }
+    }
+    let mut ths = vec![];
+    for e in Uncons::from(args[1].clone()) { ths.push(try1!(self.as_thm(&e, None))) }
+    try1!(self.rewrite(sp1, &args[0], &ths, mode))
+  },
+  Conv: AtLeast(1) => {
+    let res = self.conv(sp1, &args[0], &args[1..]);
+    self.unify_cache.clear();
+    try1!(res)
+  },
+  RegisterProp: Exact(1) => {
+    for e in Uncons::from(args[0].clone()) {
+      let mut u = Uncons::from(e.clone());
+      let (r, x) = match (u.next(), u.next(), u.is_empty()) {
+        (Some(r), Some(x), true) => (r, x),
+        _ => try1!(Err(format!("expected a pair [role decl], got {}", self.print(&e))))
+      };
+      let r = try1!(r.as_atom().ok_or("expected an atom"));
+      let r = try1!(PropRole::from_bytes(&self.data[r].name).ok_or_else(||
+        format!("unknown propositional role '{}'", self.data[r].name)));
+      let k = if r.is_term() {
+        DeclKey::Term(try1!(self.as_term(&x)))
+      } else {
+        DeclKey::Thm(try1!(self.as_thm(&x, None)))
+      };
+      self.env.prop.insert(r, k);
+    }
+    LispVal::undef()
+  },
+  Tauto: Exact(1) => try1!(self.tauto(&args[0])),
+  FindThms: AtLeast(1) => {
+    let hyps = args.get(1).map_or(false, |e| e.truthy());
+    let ths = try1!(self.env.find_thms(Some(&self.lc), &args[0], hyps).map_err(|e|
+      format!("invalid pattern: {}", self.print(&e))));
+    LispVal::list(ths.into_iter().map(|th| LispVal::atom(self.thms[th].atom)).collect::<Vec<_>>())
+  },
+  Auto: AtLeast(2) => {
+    let num = |e: Option<&LispVal>, default| e.map_or(Some(default),
+      |e| e.as_int(|n| n.to_usize().unwrap_or(usize::MAX)));
+    let depth = try1!(num(args.get(2), auto::DEFAULT_DEPTH).ok_or("expected a number"));
+    let fuel = try1!(num(args.get(3), auto::DEFAULT_FUEL).ok_or("expected a number"));
+    let script = try1!(self.auto(&args[0], &args[1], depth, fuel));
+    if args.get(4).map_or(false, |e| e.truthy()) {
+      print!(sp1, self.format_script(&script))
+    }
+    script
+  },
   Stat: Exact(0) => {print!(sp1, self.stat()); LispVal::undef()},
   GetDecl: Exact(1) => {
     let x = try1!(args[0].as_atom().ok_or("expected an atom"));
@@ -1195,6 +1810,27 @@ make_builtins! { self, sp1, sp2, args,
     let fsp = self.fspan_base(sp1);
     return self.add_thm(fsp, &args)
   },
+  GetAttr: Exact(1) => {
+    let a = try1!(args[0].as_atom().ok_or("expected an atom"));
+    LispVal::list(self.env.attrs.get(&a).map_or_else(Vec::new, |ks| ks.iter().map(|&k| LispVal::atom(match k {
+      DeclKey::Term(t) => self.terms[t].atom,
+      DeclKey::Thm(t) => self.thms[t].atom,
+    })).collect()))
+  },
+  GetAttrs: Exact(1) => {
+    let x = try1!(args[0].as_atom().ok_or("expected an atom"));
+    let k = try1!(self.data[x].decl.ok_or_else(|| format!("unknown declaration '{}'", self.data[x].name)));
+    LispVal::list(self.env.attrs_of(k).into_iter().map(LispVal::atom).collect::<Vec<_>>())
+  },
+  AddAttr: AtLeast(1) => {
+    let a = try1!(args[0].as_atom().ok_or("expected an atom"));
+    for e in &args[1..] {
+      let x = try1!(e.as_atom().ok_or("expected an atom"));
+      let k = try1!(self.data[x].decl.ok_or_else(|| format!("unknown declaration '{}'", self.data[x].name)));
+      self.env.add_attr(a, k);
+    }
+    LispVal::undef()
+  },
   NewDummy: AtLeast(1) => {
     if args.len() > 2 {try1!(Err("expected 1 or 2 armuments"))}
     let (x, s) = match args.get(1) {
@@ -1249,6 +1885,23 @@ make_builtins! { self, sp1, sp2, args,
     RefCell::new(crate::mmc::Compiler::new(self)))),
 }
 
+/// Find the first occurrence of `sub` in `s` that starts at or after `start`.
+fn find_bytes(s: &[u8], sub: &[u8], start: usize) -> Option<usize> {
+  if sub.is_empty() {return if start <= s.len() {Some(start)} else {None}}
+  s.get(start..)?.windows(sub.len()).position(|w| w == sub).map(|i| i + start)
+}
+
+/// Construct the dotted list `(es ... . r)`, flattening `r` if it is a list.
+fn dotted_list(mut es: Vec<LispVal>, r: LispVal) -> LispVal {
+  if es.is_empty() {return r}
+  match r.try_unwrap() {
+    Ok(LispKind::List(rs)) => { es.extend::<Vec<_>>(rs.into()); LispVal::list(es) }
+    Ok(LispKind::DottedList(rs, r)) => { es.extend::<Vec<_>>(rs.into()); LispVal::dotted_list(es, r) }
+    Ok(r) => LispVal::dotted_list(es, LispVal::new(r)),
+    Err(r) => LispVal::dotted_list(es, r),
+  }
+}
+
 impl<'a> Evaluator<'a> {
   fn fspan(&self, span: Span) -> FileSpan {
     FileSpan {file: self.file.clone(), span}
@@ -1262,8 +1915,165 @@ impl<'a> Evaluator<'a> {
     }
   }
 
-  #[allow(clippy::never_loop)]
+  /// Get the value of a global variable.
+  fn global(&mut self, sp: Span, a: AtomID) -> Result<LispVal> {
+    Ok(match &self.data[a] {
+      AtomData {name, lisp: None, ..} => match BuiltinProc::from_bytes(name) {
+        None => {
+          let err = format!("Reference to unbound variable '{}'", name);
+          return Err(self.err(Some((sp, false)), err))
+        }
+        Some(p) => {
+          let s = name.clone();
+          let a = self.get_atom(&s);
+          let ret = LispVal::proc(Proc::Builtin(p));
+          self.data[a].lisp = Some(LispData {src: None, doc: None, val: ret.clone()});
+          ret
+        }
+      },
+      AtomData {lisp: Some(x), ..} => x.val.clone(),
+    })
+  }
+
+  /// Check that a procedure with the given spec can be called with `n` arguments.
+  fn check_spec(&mut self, sp: Span, spec: ProcSpec, n: usize) -> Result<()> {
+    if spec.valid(n) {return Ok(())}
+    let err = match spec {
+      ProcSpec::Exact(n) => format!("expected {} argument(s)", n),
+      ProcSpec::AtLeast(n) => format!("expected at least {} argument(s)", n),
+    };
+    Err(self.err(Some((sp, false)), err))
+  }
+
+  /// Execute a code block starting from instruction `pc`, with the value stack `vs`.
+  /// Execution continues until the block returns, or it calls a procedure that needs the
+  /// main evaluation loop, in which case the block is suspended on the stack and the
+  /// state for the call is returned.
+  fn run_code(&mut self, code: &'a Code, mut pc: usize, mut vs: Vec<LispVal>) -> Result<State<'a>> {
+    while let Some(op) = code.ops.get(pc) {
+      pc += 1;
+      match *op {
+        Op::Local(i) => vs.push(self.ctx[i].clone()),
+        Op::Global(sp, a) => {let v = self.global(sp, a)?; vs.push(v)}
+        Op::Const(ref v) => vs.push(v.clone()),
+        Op::List(sp, n) => {
+          let es = vs.split_off(vs.len() - n);
+          vs.push(LispVal::list(es).span(self.fspan(sp)))
+        }
+        Op::DottedList(n) => {
+          let r = vs.pop().expect("stack underflow");
+          let es = vs.split_off(vs.len() - n);
+          vs.push(dotted_list(es, r))
+        }
+        Op::App(sp1, sp2, n, tail) => {
+          let args = vs.split_off(vs.len() - n);
+          let f = vs.pop().expect("stack underflow");
+          let p = f.unwrapped(|e| if let LispKind::Proc(Proc::Builtin(p)) = *e {Some(p)} else {None});
+          // calls to builtins are done directly, because most of them return immediately
+          let p = if let Some(p) = p {p} else {
+            if !tail {self.stack.push(Stack::Code(code, pc, vs))}
+            return Ok(State::App(sp1, sp2, f, args, [].iter()))
+          };
+          self.check_spec(sp1, p.spec(), args.len())?;
+          // this is the same charge as for the call in `State::App`, so that
+          // the fuel used does not depend on whether the code was compiled
+          if let Err(e) = self.use_fuel() {return Err(self.err(None, e))}
+          if tail {return self.evaluate_builtin(sp1, sp2, p, args)}
+          let depth = self.stack.len();
+          self.stack.push(Stack::Code(code, pc, vs));
+          match self.evaluate_builtin(sp1, sp2, p, args)? {
+            State::Ret(ret) if self.stack.len() == depth + 1 => match self.stack.pop() {
+              Some(Stack::Code(_, _, vs2)) => {vs = vs2; vs.push(ret)}
+              _ => unreachable!()
+            },
+            st => return Ok(st)
+          }
+        }
+        Op::JumpUnless(i) => if !vs.pop().expect("stack underflow").truthy() {pc = i},
+        Op::Jump(i) => pc = i,
+        Op::Pop => {vs.pop();}
+        Op::Lambda(sp, n, spec, ref e, tail) => {
+          assert!(self.ctx.len() == n);
+          vs.push(LispVal::proc(Proc::Lambda {
+            pos: if tail {self.proc_pos(sp)} else {ProcPos::Unnamed(self.fspan(sp))},
+            env: self.ctx.clone().into(),
+            spec,
+            code: e.clone()
+          }))
+        }
+      }
+    }
+    Ok(State::Ret(vs.pop().expect("stack underflow")))
+  }
+
+  fn checkpoint(&self) -> Box<Checkpoint> {
+    Box::new(Checkpoint {
+      ctx: self.ctx.clone(),
+      file: self.file.clone(),
+      lc: self.lc.save(),
+      errors: self.errors.len(),
+    })
+  }
+
+  /// Restore the state saved by [`checkpoint`](Self::checkpoint), except for the
+  /// reported errors, after an error was caught.
+  fn restore(&mut self, cp: Checkpoint) -> usize {
+    let Checkpoint {ctx, file, lc, errors} = cp;
+    self.ctx = ctx;
+    self.file = file;
+    self.lc.restore(lc);
+    self.renew_limits();
+    errors
+  }
+
+  /// Handle an error thrown during evaluation. If there is an enclosing `try` or `fail-if`,
+  /// the stack is unwound to it and the saved state is restored, and we return the state
+  /// to continue evaluation from. If there is an enclosing (synchronous) `async`, the
+  /// error is stored in the returned promise. Otherwise the error is passed on.
+  fn unwind(&mut self, err: ElabError) -> Result<State<'a>> {
+    if self.cancel.load(Ordering::Relaxed) {return Err(err)}
+    let i = match self.stack.iter().rposition(|s|
+      matches!(s, Stack::Try(..) | Stack::FailIf(..) | Stack::Async(..))) {
+      Some(i) => i,
+      None => return Err(err),
+    };
+    for s in self.stack.drain(i + 1..) {
+      if let Stack::MatchCont(_, _, _, valid) = s {valid.set(false)}
+    }
+    let (handler, cp) = match self.stack.pop() {
+      Some(Stack::Try(sp, h, cp)) => (h.map(|h| (sp, h)), cp),
+      Some(Stack::FailIf(_, cp)) => (None, cp),
+      Some(Stack::Async(cp)) => {
+        // Messages reported by the computation are kept, as they would be on a worker thread
+        self.restore(*cp);
+        let st = AsyncState::Failed(err.kind.msg());
+        return Ok(State::Ret(LispVal::proc(Proc::Async(RefCell::new(st)))))
+      }
+      _ => unreachable!(),
+    };
+    let errors = self.restore(*cp);
+    self.errors.truncate(errors);
+    Ok(match handler {
+      None => State::Ret(LispVal::undef()),
+      Some((sp, h)) => {
+        let msg = LispVal::string(err.kind.msg().into());
+        self.stack.push(Stack::AppHead(sp, sp, msg));
+        State::Eval(h)
+      }
+    })
+  }
+
   fn run(&mut self, mut active: State<'a>) -> Result<LispVal> {
+    loop {
+      match self.run_until_error(active) {
+        Ok(ret) => return Ok(ret),
+        Err(err) => active = self.unwind(err)?,
+      }
+    }
+  }
+
+  #[allow(clippy::never_loop)]
+  fn run_until_error(&mut self, mut active: State<'a>) -> Result<LispVal> {
     macro_rules! throw {($sp:expr, $e:expr) => {{
       let err = $e;
       return Err(self.err(Some(($sp, false)), err))
@@ -1288,6 +2098,19 @@ impl<'a> Evaluator<'a> {
       if self.stack.len() >= self.stack_limit {
         return Err(self.err(None, "stack overflow"))
       }
+      if let Some(hook) = &self.debug {
+        let sp = match &active {
+          State::Eval(IR::App(sp, _, _, _)) |
+          State::Eval(IR::Focus(sp, _)) |
+          State::Eval(IR::Match(sp, _, _)) => Some(*sp),
+          State::Refines(sp, it) => it.as_slice().first().map(|e| match e {
+            IR::Const(v) => v.fspan().filter(|fsp| fsp.file == self.file).map_or(*sp, |fsp| fsp.span),
+            _ => e.span().unwrap_or(*sp),
+          }),
+          _ => None
+        };
+        if let Some(sp) = sp {self.debug_point(&**hook, sp)}
+      }
       // if self.check_proofs {
       //   if self.stack.len() < stacklen {
       //     println!("stack -= {}", stacklen - self.stack.len());
@@ -1306,19 +2129,7 @@ impl<'a> Evaluator<'a> {
       active = match active {
         State::Eval(ir) => match ir {
           &IR::Local(i) => State::Ret(self.ctx[i].clone()),
-          &IR::Global(sp, a) => State::Ret(match &self.data[a] {
-            AtomData {name, lisp: None, ..} => match BuiltinProc::from_bytes(name) {
-              None => throw!(sp, format!("Reference to unbound variable '{}'", name)),
-              Some(p) => {
-                let s = name.clone();
-                let a = self.get_atom(&s);
-                let ret = LispVal::proc(Proc::Builtin(p));
-                self.data[a].lisp = Some(LispData {src: None, doc: None, val: ret.clone()});
-                ret
-              }
-            },
-            AtomData {lisp: Some(x), ..} => x.val.clone(),
-          }),
+          &IR::Global(sp, a) => State::Ret(self.global(sp, a)?),
           IR::Const(val) => State::Ret(val.clone()),
           IR::List(sp, ls) => State::List(*sp, vec![], ls.iter()),
           IR::DottedList(ls, e) => State::DottedList(vec![], ls.iter(), e),
@@ -1334,6 +2145,8 @@ impl<'a> Evaluator<'a> {
             let gs = self.lc.goals.drain(1..).collect();
             push!(Focus(sp, true, gs); Refines(sp, irs.iter()))
           }
+          &IR::Try(sp, ref e, ref h) => push!(Try(sp, h.as_deref(), self.checkpoint()); Eval(e)),
+          IR::FailIf(sp, e) => push!(FailIf(*sp, self.checkpoint()); Eval(e)),
           &IR::Def(n, ref x, ref val) => {
             assert!(self.ctx.len() == n);
             push!(Def(Some(x)); Eval(val))
@@ -1359,23 +2172,20 @@ impl<'a> Evaluator<'a> {
             }))
           }
           &IR::Match(sp, ref e, ref brs) => push!(Match(sp, brs.iter()); Eval(e)),
+          IR::Code(c) => self.run_code(c, 0, vec![])?,
         },
         State::Ret(ret) => match self.stack.pop() {
           None => return Ok(ret),
           Some(Stack::List(sp, mut vec, it)) => { vec.push(ret); State::List(sp, vec, it) }
           Some(Stack::DottedList(mut vec, it, e)) => { vec.push(ret); State::DottedList(vec, it, e) }
-          Some(Stack::DottedList2(vec)) if vec.is_empty() => State::Ret(ret),
-          Some(Stack::DottedList2(mut vec)) => State::Ret(match ret.try_unwrap() {
-            Ok(LispKind::List(es)) => { vec.extend::<Vec<_>>(es.into()); LispVal::list(vec) }
-            Ok(LispKind::DottedList(es, e)) => { vec.extend::<Vec<_>>(es.into()); LispVal::dotted_list(vec, e) }
-            Ok(e) => LispVal::dotted_list(vec, LispVal::new(e)),
-            Err(ret) => LispVal::dotted_list(vec, ret),
-          }),
+          Some(Stack::DottedList2(vec)) => State::Ret(dotted_list(vec, ret)),
           Some(Stack::App(sp1, sp2, es)) => State::App(sp1, sp2, ret, vec![], es.iter()),
           Some(Stack::App2(sp1, sp2, f, mut vec, it)) => { vec.push(ret); State::App(sp1, sp2, f, vec, it) }
           Some(Stack::AppHead(sp1, sp2, e)) => State::App(sp1, sp2, ret, vec![e], [].iter()),
           Some(Stack::If(e1, e2)) => State::Eval(if ret.truthy() {e1} else {e2}),
-          Some(Stack::NoTailRec) => State::Ret(ret),
+          Some(Stack::NoTailRec) | Some(Stack::Try(_, _, _)) => State::Ret(ret),
+          Some(Stack::Async(_)) =>
+            State::Ret(LispVal::proc(Proc::Async(RefCell::new(AsyncState::Done(ret))))),
           Some(Stack::Def(x)) => if let Some(s) = self.stack.pop() {
             macro_rules! push_ret {($e:expr) => {{
               if x.is_some() {
@@ -1442,8 +2252,10 @@ impl<'a> Evaluator<'a> {
                 let stat = self.stat();
                 let span = self.fspan(sp);
                 for g in mem::take(&mut self.lc.goals) {
-                  let err = ElabError::new_e(try_get_span(&span, &g),
-                    format!("|- {}", self.format_env().pp(&g.goal_type().expect("expected a goal"), 80)));
+                  let sp = try_get_span(&span, &g);
+                  let ty = g.goal_type().expect("expected a goal");
+                  self.spans.insert_if(sp, || ObjectKind::expr(ty.clone()));
+                  let err = ElabError::new_e(sp, format!("|- {}", self.format_env().pp(&ty, 80)));
                   self.report(err)
                 }
                 throw!(sp, format!("focused goal has not been solved\n\n{}", stat))
@@ -1463,6 +2275,8 @@ impl<'a> Evaluator<'a> {
             }
             State::Ret(LispVal::undef())
           },
+          Some(Stack::FailIf(sp, _)) => throw!(sp, "fail-if: expression succeeded"),
+          Some(Stack::Code(c, pc, mut vs)) => {vs.push(ret); self.run_code(c, pc, vs)?}
         },
         State::Evals(e, mut it) => match it.next() {
           None => State::Eval(e),
@@ -1482,12 +2296,9 @@ impl<'a> Evaluator<'a> {
             let func = if let LispKind::Proc(f) = func { f }
             else { throw!(sp1, "not a function, cannot apply") };
             let spec = func.spec();
-            if !spec.valid(args.len()) {
-              match spec {
-                ProcSpec::Exact(n) => throw!(sp1, format!("expected {} argument(s)", n)),
-                ProcSpec::AtLeast(n) => throw!(sp1, format!("expected at least {} argument(s)", n)),
-              }
-            }
+            self.check_spec(sp1, spec, args.len())?;
+            // fuel is charged per procedure call, in both the AST and bytecode evaluators
+            if let Err(e) = self.use_fuel() {return Err(self.err(None, e))}
             Ok(match func {
               &Proc::Builtin(func) => self.evaluate_builtin(sp1, sp2, func, args)?,
               Proc::Lambda {pos, env, code, ..} => {
@@ -1582,6 +2393,9 @@ impl<'a> Evaluator<'a> {
                 let fsp = self.fspan(sp1);
                 State::Ret(c.borrow_mut().call(self, fsp, args)?)
               }
+              Proc::Async(m) => State::Ret(self.await_async(sp1, m)?),
+              &Proc::Macro(x, _) => throw!(sp1,
+                format!("'{}' is a macro, and cannot be called at run time", self.data[x].name)),
             })
           })?,
         }
@@ -1637,8 +2451,17 @@ impl<'a> Evaluator<'a> {
           Some(e) => push!(Refines(sp, Some(e.span().unwrap_or(sp)), it); Eval(e))
         },
         State::Refine {sp, mut stack, state} => {
-          let res = self.elab.run_refine(self.orig_span, &mut stack, state)
-            .map_err(|e| self.err(Some((e.pos, true)), e.kind.msg()))?;
+          let res = self.elab.run_refine(self.orig_span, &mut stack, state);
+          self.elab.unify_cache.clear();
+          let res = res.map_err(|e| {
+            let mut err = self.err(Some((e.pos, true)), e.kind.msg());
+            // keep the related information of the error, like the sides of a failed unification
+            if let (ElabErrorKind::Boxed(_, Some(info)), ElabErrorKind::Boxed(_, Some(info2))) =
+              (e.kind, &mut err.kind) {
+              info2.splice(0..0, info);
+            }
+            err
+          })?;
           match res {
             RefineResult::Ret(e) => {self.lc.clean_mvars(); State::Ret(e)}
             RefineResult::RefineExtraArgs(tgt, e, u) => {
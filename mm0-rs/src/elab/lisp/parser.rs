@@ -14,6 +14,7 @@ use super::{BuiltinProc, FileSpan, LispKind, LispVal, Proc, ProcSpec,
   Remap, Remapper, Syntax};
 use super::super::math_parser::{QExpr, QExprKind};
 use super::print::{FormatEnv, EnvDisplay};
+use super::bytecode::Code;
 
 /// The target of a `def` command is either `_`, or a variable `x` with a
 /// `span, full` pair (for the span of the identifier and the span of the full statement)
@@ -45,6 +46,13 @@ pub enum IR {
   /// The `(focus es)` syntax form. This should be a regular function, but it does some
   /// preparation work before it starts executing the list of arguments.
   Focus(Span, Box<[IR]>),
+  /// The `(try e h)` syntax form: evaluate `e`, and if it throws an error, restore the
+  /// local context to its state before `e` and call `h` on the error message.
+  /// If there is no handler, `#undef` is returned on error.
+  Try(Span, Box<IR>, Option<Box<IR>>),
+  /// The `(fail-if e)` syntax form: evaluate `e`, and return `#undef` if it throws an error,
+  /// or throw an error if it succeeds. The local context is restored in the first case.
+  FailIf(Span, Box<IR>),
   /// The `(def x e)` syntax form. Call the argument, and extend the context with the result.
   /// The `usize` argument indicates the number of the variable that was just declared,
   /// but it is only there for sanity checking - there is only one valid value for this field.
@@ -72,6 +80,8 @@ pub enum IR {
   Lambda(Span, usize, ProcSpec, Arc<IR>),
   /// The `(match e bs)` syntax form. Evaluate `e`, and then match it against the branches.
   Match(Span, Box<IR>, Box<[Branch]>),
+  /// An expression that has been compiled to bytecode. See [`bytecode`](super::bytecode).
+  Code(Box<Code>),
 }
 
 impl<'a> EnvDisplay for IR {
@@ -88,6 +98,9 @@ impl<'a> EnvDisplay for IR {
       IR::If(es) => write!(f, "(if {} {} {})",
         fe.to(&es.0), fe.to(&es.1), fe.to(&es.2)),
       IR::Focus(_, es) => write!(f, "(focus {})", es.iter().map(|ir| fe.to(ir)).format(" ")),
+      IR::Try(_, e, None) => write!(f, "(try {})", fe.to(e)),
+      IR::Try(_, e, Some(h)) => write!(f, "(try {} {})", fe.to(e), fe.to(h)),
+      IR::FailIf(_, e) => write!(f, "(fail-if {})", fe.to(e)),
       IR::NoTailRec => write!(f, "(no-tail-rec)"),
       IR::Def(n, a, e) => write!(f, "(def {}:{} {})",
         n, fe.to(&a.as_ref().map_or(AtomID::UNDER, |&(_, _, _, a)| a)), fe.to(e)),
@@ -101,7 +114,8 @@ impl<'a> EnvDisplay for IR {
         }
         write!(f, " {})", fe.to(e))
       }
-      IR::Match(_, e, bs) => write!(f, "(match {} {})", fe.to(e), fe.to(&**bs))
+      IR::Match(_, e, bs) => write!(f, "(match {} {})", fe.to(e), fe.to(&**bs)),
+      IR::Code(c) => c.fmt(fe, f),
     }
   }
 }
@@ -137,8 +151,11 @@ impl IR {
       &IR::List(sp, _) |
       &IR::App(sp, _, _, _) |
       &IR::Focus(sp, _) |
+      &IR::Try(sp, _, _) |
+      &IR::FailIf(sp, _) |
       &IR::Lambda(sp, _, _, _) |
       &IR::Match(sp, _, _) => Some(sp),
+      IR::Code(c) => c.span,
       _ => None
     }
   }
@@ -285,12 +302,15 @@ impl Remap for IR {
       IR::If(e) => IR::If(e.remap(r)),
       IR::NoTailRec => IR::NoTailRec,
       IR::Focus(sp, e) => IR::Focus(*sp, e.remap(r)),
+      &IR::Try(sp, ref e, ref h) => IR::Try(sp, e.remap(r), h.remap(r)),
+      IR::FailIf(sp, e) => IR::FailIf(*sp, e.remap(r)),
       &IR::Def(n, ref a, ref e) => IR::Def(n,
         a.as_ref().map(|&(sp1, sp2, ref doc, a)| (sp1, sp2, doc.clone(), a.remap(r))),
         e.remap(r)),
       &IR::Eval(b, ref e) => IR::Eval(b, e.remap(r)),
       &IR::Lambda(sp, n, spec, ref e) => IR::Lambda(sp, n, spec, e.remap(r)),
       &IR::Match(sp, ref e, ref br) => IR::Match(sp, e.remap(r), br.remap(r)),
+      IR::Code(c) => IR::Code(c.remap(r)),
     }
   }
 }
@@ -386,35 +406,46 @@ impl IR {
   }
 }
 
+/// The name of a local variable. Identifiers that come from the template of a macro
+/// are tagged with the number of the macro expansion (and other identifiers have `0`),
+/// so that local variables introduced by a macro are not visible at the call site and
+/// vice versa.
+type Name = (AtomID, u32);
+
+/// The expansion number of an atom, see [`Name`].
+fn mark(a: Atom) -> u32 {
+  if let Atom::Macro(_, m) = a {m} else {0}
+}
+
 struct LocalCtx {
-  names: HashMap<AtomID, Vec<usize>>,
-  ctx: Vec<AtomID>,
+  names: HashMap<Name, Vec<usize>>,
+  ctx: Vec<Name>,
 }
 
 impl LocalCtx {
   fn new() -> Self { Self {names: HashMap::new(), ctx: vec![]} }
   fn len(&self) -> usize { self.ctx.len() }
-  fn get(&self, x: AtomID) -> Option<usize> {
+  fn get(&self, x: Name) -> Option<usize> {
     self.names.get(&x).and_then(|v| v.last().cloned())
   }
-  fn push(&mut self, x: AtomID) -> usize {
+  fn push(&mut self, x: Name) -> usize {
     let old = self.ctx.len();
-    if x != AtomID::UNDER { self.names.entry(x).or_insert_with(Vec::new).push(old) }
+    if x.0 != AtomID::UNDER { self.names.entry(x).or_insert_with(Vec::new).push(old) }
     self.ctx.push(x);
     old
   }
-  fn push_list(&mut self, xs: &[AtomID]) -> usize {
+  fn push_list(&mut self, xs: &[Name]) -> usize {
     let old = self.ctx.len();
     for &x in xs { self.push(x); }
     old
   }
-  fn get_or_push(&mut self, x: AtomID) -> usize {
+  fn get_or_push(&mut self, x: Name) -> usize {
     self.get(x).unwrap_or_else(|| self.push(x))
   }
 
   fn pop(&mut self) {
     let x = self.ctx.pop().expect("context underflow");
-    if x != AtomID::UNDER {self.names.get_mut(&x).expect("missing name").pop();}
+    if x.0 != AtomID::UNDER {self.names.get_mut(&x).expect("missing name").pop();}
   }
   fn restore(&mut self, n: usize) {
     while self.ctx.len() > n { self.pop() }
@@ -424,6 +455,13 @@ impl LocalCtx {
 struct LispParser<'a> {
   elab: &'a mut Elaborator,
   ctx: LocalCtx,
+  /// The number of macro expansions so far, used to generate expansion numbers.
+  expansions: u32,
+  /// The number of nested macro expansions we are currently inside.
+  depth: usize,
+  /// The module of the macro that produced each expansion number, for expansions of
+  /// macros that were defined in a module.
+  macro_modules: HashMap<u32, AtomID>,
 }
 impl<'a> Deref for LispParser<'a> {
   type Target = Elaborator;
@@ -438,14 +476,14 @@ enum Item<'a> {
   DottedList(&'a [SExpr], &'a SExpr),
 }
 
-type Var<'a> = (Span, AtomID, Vec<Item<'a>>);
+type Var<'a> = (Span, Name, Vec<Item<'a>>);
 
 impl<'a> LispParser<'a> {
   fn def_var<'c>(&mut self, mut e: &'c SExpr) -> Result<Var<'c>, ElabError> {
     let mut stack = vec![];
     loop {
       match &e.k {
-        &SExprKind::Atom(a) => break Ok((e.span, self.parse_atom(e.span, a)?, stack)),
+        &SExprKind::Atom(a) => break Ok((e.span, (self.parse_atom(e.span, a)?, mark(a)), stack)),
         SExprKind::List(xs) if !xs.is_empty() =>
           {stack.push(Item::List(&xs[1..])); e = &xs[0]}
         SExprKind::DottedList(xs, y) if !xs.is_empty() =>
@@ -488,18 +526,72 @@ impl<'a> LispParser<'a> {
     Ok(ir)
   }
 
-  fn def(&mut self, e: &SExpr, es: &[SExpr]) -> Result<(Span, AtomID, Vec<IR>), ElabError> {
-    let (sp, x, stack) = self.def_var(e)?;
+  fn def(&mut self, e: &SExpr, es: &[SExpr]) -> Result<(Span, Name, Vec<IR>), ElabError> {
+    let (sp, mut x, stack) = self.def_var(e)?;
+    if self.ctx.len() == 0 {x.0 = self.module_def(sp, x.0)?}
     let ir = self.def_ir(sp, es, stack)?;
-    if self.ctx.len() == 0 {
-      self.spans.insert(sp, ObjectKind::Global(x));
+    if self.ctx.len() == 0 && x.1 == 0 {
+      self.spans.insert(sp, ObjectKind::Global(x.0));
     }
     Ok((sp, x, ir))
   }
 }
 
 impl<'a> LispParser<'a> {
+  /// The name under which a top level definition of `x` is stored. Inside a module `m`,
+  /// this is the qualified name `m::x`, otherwise it is just `x`. Qualified names can't be
+  /// defined directly, because that would bypass the member and export lists of the module.
+  fn module_def(&mut self, sp: Span, x: AtomID) -> Result<AtomID, ElabError> {
+    let s = &self.data[x].name;
+    if let Some(i) = s.windows(2).rposition(|w| w == b"::") {
+      if self.atoms.get(&s[..i]).map_or(false, |md| self.modules.contains_key(md)) {
+        let (m, y) = (String::from_utf8_lossy(&s[..i]), String::from_utf8_lossy(&s[i+2..]));
+        return Err(ElabError::new_e(sp, format!(
+          "cannot define the qualified name '{}::{}', use (module {} (def {} ...)) instead",
+          m, y, m, y)))
+      }
+    }
+    Ok(match self.lisp_module {
+      Some(m) if x != AtomID::UNDER => {
+        self.modules.get_mut(&m).expect("module not declared").members.insert(x);
+        self.qualify(m, x)
+      }
+      _ => x
+    })
+  }
+
+  /// Resolve a reference to the global variable `x`. Inside a module (or in the expansion
+  /// of a macro defined in a module), the members of the module are referred to by their
+  /// unqualified names. Qualified names `m::x` are checked against the export list of `m`.
+  fn resolve_global(&mut self, sp: Span, x: Name) -> Result<AtomID, ElabError> {
+    let m = if x.1 == 0 {self.lisp_module} else {self.macro_modules.get(&x.1).copied()};
+    if let Some(m) = m {
+      if self.modules[&m].members.contains(&x.0) {return Ok(self.qualify(m, x.0))}
+    }
+    if let Some((md, y)) = self.split_qualified(x.0) {
+      if Some(md) != m && self.modules[&md].is_private(y) {
+        return Err(ElabError::new_e(sp, format!("'{}' is not exported from module '{}'",
+          self.data[y].name, self.data[md].name)))
+      }
+    }
+    Ok(x.0)
+  }
+
+  /// The text of an atom.
+  fn atom_name(&self, sp: Span, a: Atom) -> &[u8] {
+    match a {
+      Atom::Macro(x, _) => &self.data[x].name,
+      _ => self.ast.span_atom(sp, a)
+    }
+  }
+
   fn parse_ident_or_syntax(&mut self, sp: Span, a: Atom) -> Result<AtomID, Syntax> {
+    if let Atom::Macro(x, _) = a {
+      return match Syntax::parse(&self.data[x].name, a) {
+        Ok(s) => Err(s),
+        Err(_) => Ok(x)
+      }
+    }
     match Syntax::parse(self.ast.clone().span(sp), a) {
       Ok(s) => Err(s),
       Err(s) => Ok(self.get_atom(s))
@@ -511,15 +603,15 @@ impl<'a> LispParser<'a> {
       ElabError::new_e(sp, "keyword in invalid position"))
   }
 
-  fn parse_ident(&mut self, e: &SExpr) -> Result<AtomID, ElabError> {
+  fn parse_ident(&mut self, e: &SExpr) -> Result<Name, ElabError> {
     if let SExprKind::Atom(a) = e.k {
-      self.parse_atom(e.span, a)
+      Ok((self.parse_atom(e.span, a)?, mark(a)))
     } else {
       Err(ElabError::new_e(e.span, "expected an identifier"))
     }
   }
 
-  fn parse_idents(&mut self, es: &[SExpr]) -> Result<Vec<AtomID>, ElabError> {
+  fn parse_idents(&mut self, es: &[SExpr]) -> Result<Vec<Name>, ElabError> {
     let mut xs = vec![];
     for e in es {xs.push(self.parse_ident(e)?)}
     Ok(xs)
@@ -575,7 +667,7 @@ impl<'a> LispParser<'a> {
       for l in ls {
         let ((sp, x, stk), e2) = self.let_var(l)?;
         let n = self.ctx.push(x);
-        let sps = if x == AtomID::UNDER {None} else {Some((l.span, sp, None, x))};
+        let sps = if x.0 == AtomID::UNDER {None} else {Some((l.span, sp, None, x.0))};
         cs.push(IR::Def(n, sps.clone(),
           Box::new(IR::new_ref(sp, sp, IR::Const(LispVal::undef())))));
         ds.push((sp, x, stk, e2, n, sps));
@@ -594,10 +686,10 @@ impl<'a> LispParser<'a> {
       for l in ls {
         let ((sp, x, stk), e2) = self.let_var(l)?;
         let v = self.def_ir(sp, e2, stk)?;
-        if x == AtomID::UNDER {
+        if x.0 == AtomID::UNDER {
           cs.push(IR::Eval(false, v.into()))
         } else {
-          cs.push(IR::Def(self.ctx.push(x), Some((l.span, sp, None, x)), IR::eval(v).into()))
+          cs.push(IR::Def(self.ctx.push(x), Some((l.span, sp, None, x.0)), IR::eval(v).into()))
         }
       }
     }
@@ -612,12 +704,12 @@ impl<'a> LispParser<'a> {
       match es {
         [] => return Ok(Pattern::List(pfx.into(), None)),
         &[SExpr {span, k: SExprKind::Atom(a)}, ref e] if quote =>
-          if self.ast.span_atom(span, a) == b"unquote" {
+          if self.atom_name(span, a) == b"unquote" {
             break self.pattern(ctx, code, false, e)?
           },
         _ if quote => {},
         [head, args @ ..] => if let SExprKind::Atom(a) = head.k {
-          match self.ast.span_atom(head.span, a) {
+          match self.atom_name(head.span, a) {
             b"quote" => match args {
               [e] => break self.pattern(ctx, code, true, e)?,
               _ => return Err(ElabError::new_e(head.span, "expected one argument")),
@@ -625,7 +717,7 @@ impl<'a> LispParser<'a> {
             b"mvar" => match args {
               [] => break Pattern::MVar(MVarPattern::Unknown),
               &[SExpr {span, k: SExprKind::Atom(a)}]
-                if matches!(self.ast.span_atom(span, a), b"___" | b"...") =>
+                if matches!(self.atom_name(span, a), b"___" | b"...") =>
                 break Pattern::MVar(MVarPattern::Any),
               [bd, s] => {
                 let bd = self.pattern(ctx, code, quote, bd)?;
@@ -718,11 +810,11 @@ impl<'a> LispParser<'a> {
     match &e.k {
       &SExprKind::Atom(a) => Ok(
         if quote {
-          Pattern::QuoteAtom(self.elab.env.get_atom(self.elab.ast.span_atom(e.span, a)))
+          Pattern::QuoteAtom(self.get_atom_of(e.span, a))
         } else {
           let x = self.parse_atom(e.span, a)?;
           if x == AtomID::UNDER {Pattern::Skip}
-          else {Pattern::Atom(ctx.get_or_push(x))}
+          else {Pattern::Atom(ctx.get_or_push((x, mark(a))))}
         }
       ),
       SExprKind::DottedList(es, e) => Ok(Pattern::DottedList(
@@ -746,11 +838,11 @@ impl<'a> LispParser<'a> {
       SExprKind::List(es) if !es.is_empty() => (&es[0], &es[1..]),
       _ => return Err(ElabError::new_e(e.span, "match: improper syntax"))
     };
-    let mut cont = AtomID::UNDER;
+    let mut cont = (AtomID::UNDER, 0);
     if let Some(e2) = es.get(0) {
       if let SExprKind::List(v) = &e2.k {
         if let [SExpr {span, k: SExprKind::Atom(a)}, ref x] = **v {
-          if let b"=>" = self.ast.span_atom(span, a) {
+          if let b"=>" = self.atom_name(span, a) {
             cont = self.parse_ident(x)?;
             es = &es[1..];
           }
@@ -761,10 +853,10 @@ impl<'a> LispParser<'a> {
     let pat = self.pattern(&mut ctx, code, false, e)?;
     let vars = ctx.ctx.len();
     let start = self.ctx.push_list(&ctx.ctx);
-    if cont != AtomID::UNDER {self.ctx.push(cont);}
+    if cont.0 != AtomID::UNDER {self.ctx.push(cont);}
     let eval = Box::new(IR::eval(self.exprs(false, es)?));
     self.ctx.restore(start);
-    Ok(Branch {pat, vars, cont: cont != AtomID::UNDER, eval})
+    Ok(Branch {pat, vars, cont: cont.0 != AtomID::UNDER, eval})
   }
   fn branches(&mut self, code: &mut Vec<IR>, es: &[SExpr]) -> Result<Box<[Branch]>, ElabError> {
     let mut bs = vec![];
@@ -782,14 +874,15 @@ impl<'a> LispParser<'a> {
     }
   }
 
-  fn eval_atom(&mut self, sp: Span, x: AtomID) -> IR {
-    match self.ctx.get(x) {
+  fn eval_atom(&mut self, sp: Span, x: Name) -> Result<IR, ElabError> {
+    Ok(match self.ctx.get(x) {
       None => {
-        self.spans.insert(sp, ObjectKind::Global(x));
-        IR::Global(sp, x)
+        let a = self.resolve_global(sp, x)?;
+        if x.1 == 0 {self.spans.insert(sp, ObjectKind::Global(a));}
+        IR::Global(sp, a)
       },
       Some(i) => IR::Local(i)
-    }
+    })
   }
 
   fn expr(&mut self, quote: bool, e: &SExpr) -> Result<IR, ElabError> {
@@ -810,7 +903,7 @@ impl<'a> LispParser<'a> {
       } else {
         Ok(match self.parse_atom(e.span, a)? {
           AtomID::UNDER => IR::Const(span!(e.span, LispVal::atom(AtomID::UNDER))),
-          x => self.eval_atom(e.span, x),
+          x => self.eval_atom(e.span, (x, mark(a)))?,
         })
       },
       SExprKind::DottedList(es, e) => {
@@ -820,7 +913,7 @@ impl<'a> LispParser<'a> {
         let mut cs = vec![];
         for e in es {
           if let SExprKind::Atom(a) = es[0].k {
-            if let Ok(Syntax::Unquote) = Syntax::parse(self.ast.span(e.span), a) {
+            if let Ok(Syntax::Unquote) = Syntax::parse(self.atom_name(e.span, a), a) {
               return Err(ElabError::new_e(e.span, "cannot evaluate an improper list"))
             }
           }
@@ -845,7 +938,7 @@ impl<'a> LispParser<'a> {
         Ok(loop {
           if let Some(arg) = it.next() {
             if let SExprKind::Atom(a) = arg.k {
-              if let Ok(Syntax::Unquote) = Syntax::parse(self.ast.span(arg.span), a) {
+              if let Ok(Syntax::Unquote) = Syntax::parse(self.atom_name(arg.span, a), a) {
                 let r = it.next().ok_or_else(||
                   ElabError::new_e(arg.span, "expected at least one argument"))?;
                 break IR::dotted_list(e.span, cs, self.expr(false, r)?)
@@ -856,24 +949,62 @@ impl<'a> LispParser<'a> {
       } else if let SExprKind::Atom(a) = es[0].k {
         match self.parse_ident_or_syntax(es[0].span, a) {
           Ok(AtomID::UNDER) => return Err(ElabError::new_e(es[0].span, "'_' is not a function")),
-          Ok(x) =>
+          Ok(x) => {
+            let x = (x, mark(a));
+            if self.ctx.get(x).is_none() {
+              let g = self.resolve_global(es[0].span, x)?;
+              if let Some(rules) = self.get_macro(g) {
+                if x.1 == 0 {self.spans.insert(es[0].span, ObjectKind::Global(g));}
+                let e = self.expand(e.span, &rules, es)?;
+                // the expansion may be a `def`, in which case the context should not be restored
+                self.depth += 1;
+                let res = self.expr_doc(doc, false, &e);
+                self.depth -= 1;
+                return res
+              }
+            }
             Ok(IR::App(e.span, es[0].span,
-              Box::new(self.eval_atom(es[0].span, x)), self.exprs(false, &es[1..])?.into())),
+              Box::new(self.eval_atom(es[0].span, x)?), self.exprs(false, &es[1..])?.into()))
+          }
           Err(stx) => {
-            self.spans.insert_if(es[0].span, || ObjectKind::Syntax(stx));
+            if mark(a) == 0 {self.spans.insert_if(es[0].span, || ObjectKind::Syntax(stx))}
             match stx {
               Syntax::Begin => Ok(IR::Eval(true, self.exprs(false, &es[1..])?.into())),
               Syntax::Define if es.len() < 2 => return Err(
                 ElabError::new_e(es[0].span, "expected at least one argument")),
               Syntax::Define =>
                 Ok(match self.def(&es[1], &es[2..])? {
-                  (_, AtomID::UNDER, cs) => IR::Eval(false, cs.into()),
+                  (_, (AtomID::UNDER, _), cs) => IR::Eval(false, cs.into()),
                   (sp, x, cs) => {
                     restore = None;
                     let doc = if doc.is_empty() {None} else {Some(doc.into())};
-                    IR::Def(self.ctx.push(x), Some((e.span, sp, doc, x)), IR::eval(cs).into())
+                    IR::Def(self.ctx.push(x), Some((e.span, sp, doc, x.0)), IR::eval(cs).into())
                   }
                 }),
+              Syntax::DefMacro if es.len() < 2 => return Err(
+                ElabError::new_e(es[0].span, "expected at least one argument")),
+              Syntax::DefMacro => {
+                if self.ctx.len() != 0 {
+                  return Err(ElabError::new_e(es[0].span, "defmacro is only allowed at top level"))
+                }
+                let mut x = self.parse_ident(&es[1])?;
+                if x.0 == AtomID::UNDER {
+                  return Err(ElabError::new_e(es[1].span, "expected a macro name"))
+                }
+                x.0 = self.module_def(es[1].span, x.0)?;
+                if x.1 == 0 {self.spans.insert(es[1].span, ObjectKind::Global(x.0));}
+                let mut rules = vec![];
+                for r in &es[2..] { rules.push(self.macro_rule(r)?) }
+                let mac = LispVal::proc(Proc::Macro(x.0, rules.into()));
+                restore = None;
+                let doc = if doc.is_empty() {None} else {Some(doc.into())};
+                Ok(IR::Def(self.ctx.push(x), Some((e.span, es[1].span, doc, x.0)),
+                  Box::new(IR::Const(mac))))
+              }
+              Syntax::Module => return Err(
+                ElabError::new_e(es[0].span, "module is only allowed at top level")),
+              Syntax::Export => return Err(
+                ElabError::new_e(es[0].span, "export is only allowed at the top level of a module")),
               Syntax::Lambda if es.len() < 2 => return Err(
                 ElabError::new_e(es[0].span, "expected at least one argument")),
               Syntax::Lambda => match &es[1].k {
@@ -913,6 +1044,21 @@ impl<'a> LispParser<'a> {
               Syntax::If => return Err(
                 ElabError::new_e(es[0].span, "expected two or three arguments")),
               Syntax::Focus => Ok(IR::Focus(es[0].span, self.exprs(false, &es[1..])?.into())),
+              Syntax::Try if 2 <= es.len() && es.len() <= 3 => Ok(IR::Try(es[0].span,
+                Box::new(self.expr(false, &es[1])?),
+                match es.get(2) {
+                  Some(h) => {
+                    self.ctx.restore(unsafe {restore.unwrap_unchecked()});
+                    Some(Box::new(self.expr(false, h)?))
+                  }
+                  None => None
+                })),
+              Syntax::Try => return Err(
+                ElabError::new_e(es[0].span, "expected one or two arguments")),
+              Syntax::FailIf if es.len() == 2 =>
+                Ok(IR::FailIf(es[0].span, Box::new(self.expr(false, &es[1])?))),
+              Syntax::FailIf => return Err(
+                ElabError::new_e(es[0].span, "expected one argument")),
               Syntax::Let => self.let_(false, &es[1..]),
               Syntax::Letrec => self.let_(true, &es[1..]),
               Syntax::Match if es.len() < 2 => return Err(
@@ -923,12 +1069,12 @@ impl<'a> LispParser<'a> {
                 self.match_(&es[2..], |m| IR::Match(es[0].span, Box::new(e), m))
               },
               Syntax::MatchFn => {
-                let i = self.ctx.push(AtomID::UNDER);
+                let i = self.ctx.push((AtomID::UNDER, 0));
                 Ok(IR::Lambda(es[0].span, i, ProcSpec::Exact(1),
                   Arc::new(self.match_(&es[1..], |m| IR::match_fn_body(es[0].span, i, m))?)))
               }
               Syntax::MatchFns => {
-                let i = self.ctx.push(AtomID::UNDER);
+                let i = self.ctx.push((AtomID::UNDER, 0));
                 Ok(IR::Lambda(es[0].span, i, ProcSpec::AtLeast(0),
                   Arc::new(self.match_(&es[1..], |m| IR::match_fn_body(es[0].span, i, m))?)))
               }
@@ -946,6 +1092,282 @@ impl<'a> LispParser<'a> {
   }
 }
 
+/// The maximum number of nested macro expansions.
+const MAX_MACRO_DEPTH: usize = 128;
+
+/// The value of a pattern variable after matching a macro pattern.
+#[derive(Clone)]
+enum Binding {
+  /// The variable matched this expression.
+  One(SExpr),
+  /// The variable is under a `...` in the pattern, and matched this list of values.
+  Many(Vec<Binding>),
+}
+
+type Bindings = HashMap<AtomID, Binding>;
+
+impl<'a> LispParser<'a> {
+  fn get_atom_of(&mut self, sp: Span, a: Atom) -> AtomID {
+    if let Atom::Macro(x, _) = a {return x}
+    let ast = self.ast.clone();
+    self.get_atom(ast.span_atom(sp, a))
+  }
+
+  /// Returns the macro that the global `x` refers to, if it is a macro.
+  fn get_macro(&self, x: AtomID) -> Option<LispVal> {
+    let val = &self.data[x].lisp.as_ref()?.val;
+    if val.unwrapped(|e| matches!(e, LispKind::Proc(Proc::Macro(_, _)))) {
+      Some(val.clone())
+    } else {None}
+  }
+
+  /// Convert a macro pattern or template to lisp data. Formulas in templates
+  /// are parsed and converted to quoted expressions, so that the macro does not
+  /// depend on the source text of the file it was defined in.
+  fn syntax_val(&mut self, pat: bool, e: &SExpr) -> Result<LispVal, ElabError> {
+    Ok(match &e.k {
+      &SExprKind::Atom(a) => LispVal::atom(self.get_atom_of(e.span, a)),
+      SExprKind::List(es) => LispVal::list(self.syntax_vals(pat, es)?),
+      SExprKind::DottedList(es, r) =>
+        LispVal::dotted_list(self.syntax_vals(pat, es)?, self.syntax_val(pat, r)?),
+      SExprKind::Number(n) => LispVal::number(n.clone().into()),
+      SExprKind::String(s) => LispVal::string(s.clone()),
+      &SExprKind::Bool(b) => LispVal::bool(b),
+      SExprKind::Undef => LispVal::undef(),
+      SExprKind::DocComment(_, e) => self.syntax_val(pat, e)?,
+      &SExprKind::Formula(_) if pat => return Err(
+        ElabError::new_e(e.span, "formulas are not supported in macro patterns")),
+      &SExprKind::Formula(f) => {
+        let q = self.parse_formula(f)?;
+        LispVal::list(vec![LispVal::atom(AtomID::QUOTE), self.qexpr_val(q)?])
+      }
+    })
+  }
+
+  fn syntax_vals(&mut self, pat: bool, es: &[SExpr]) -> Result<Vec<LispVal>, ElabError> {
+    let mut vs = vec![];
+    for e in es { vs.push(self.syntax_val(pat, e)?) }
+    Ok(vs)
+  }
+
+  /// Convert a formula to quoted lisp data, the same way as [`qexpr`](Self::qexpr).
+  fn qexpr_val(&mut self, e: QExpr) -> Result<LispVal, ElabError> {
+    Ok(match e.k {
+      QExprKind::IdentApp(sp, es) => {
+        let head = LispVal::atom(self.elab.env.get_atom(self.ast.clone().span(sp)));
+        if es.is_empty() {head} else {
+          let mut cs = vec![head];
+          for e in es.into_vec() { cs.push(self.qexpr_val(e)?) }
+          LispVal::list(cs)
+        }
+      }
+      QExprKind::App(_, t, es) => {
+        let mut cs = vec![LispVal::atom(self.terms[t].atom)];
+        for e in es.into_vec() { cs.push(self.qexpr_val(e)?) }
+        LispVal::list(cs)
+      }
+      QExprKind::Unquote(e) =>
+        LispVal::list(vec![LispVal::atom(AtomID::UNQUOTE), self.syntax_val(false, &e)?]),
+    })
+  }
+
+  /// Parse a `[(_ pat ...) template ...]` rule of a `defmacro`. The head of the pattern
+  /// is ignored, and multiple templates are combined using `begin`.
+  fn macro_rule(&mut self, e: &SExpr) -> Result<(LispVal, LispVal), ElabError> {
+    let (pat, tmpl) = match &e.k {
+      SExprKind::List(es) if es.len() >= 2 => (&es[0], &es[1..]),
+      _ => return Err(ElabError::new_e(e.span, "defmacro: expected [pattern template]"))
+    };
+    let pat = match &pat.k {
+      SExprKind::List(ps) if !ps.is_empty() => LispVal::list(self.syntax_vals(true, &ps[1..])?),
+      SExprKind::DottedList(ps, r) =>
+        LispVal::dotted_list(self.syntax_vals(true, &ps[1..])?, self.syntax_val(true, r)?),
+      _ => return Err(ElabError::new_e(pat.span, "defmacro: expected a pattern (_ args ...)"))
+    };
+    let tmpl = if let [t] = tmpl {self.syntax_val(false, t)?} else {
+      let mut ts = vec![LispVal::atom(AtomID::BEGIN)];
+      ts.extend(self.syntax_vals(false, tmpl)?);
+      LispVal::list(ts)
+    };
+    Ok((pat, tmpl))
+  }
+
+  /// Expand a macro call `(f es ...)`, using the first rule of the macro that matches.
+  fn expand(&mut self, sp: Span, mac: &LispVal, es: &[SExpr]) -> Result<SExpr, ElabError> {
+    if self.depth >= MAX_MACRO_DEPTH {
+      return Err(ElabError::new_e(sp, "macro expansion depth exceeded"))
+    }
+    self.expansions += 1;
+    let m = self.expansions;
+    mac.unwrapped(|e| {
+      let (x, rules) = if let LispKind::Proc(Proc::Macro(x, rules)) = e {(*x, rules)} else {
+        unreachable!("not a macro")
+      };
+      if let Some((md, _)) = self.split_qualified(x) {self.macro_modules.insert(m, md);}
+      for (pat, tmpl) in &**rules {
+        let mut binds = HashMap::new();
+        if self.match_list(sp, pat, &es[1..], &mut binds) {
+          return self.instantiate(sp, m, tmpl, &binds)
+        }
+      }
+      Err(ElabError::new_e(sp, format!("no rule of macro '{}' matches", self.data[x].name)))
+    })
+  }
+
+  /// Collect the variables in a macro pattern (if `pat` is true), or the atoms in a
+  /// template, which may refer to pattern variables.
+  fn pattern_vars(pat: bool, e: &LispVal, vars: &mut Vec<AtomID>) {
+    e.unwrapped(|e| match e {
+      &LispKind::Atom(x) if x != AtomID::UNDER && x != AtomID::ELLIPSIS => vars.push(x),
+      LispKind::List(ps) if pat && ps.len() == 2 && ps[0].as_atom() == Some(AtomID::QUOTE) => {}
+      LispKind::List(ps) => for p in &**ps {Self::pattern_vars(pat, p, vars)},
+      LispKind::DottedList(ps, r) => {
+        for p in &**ps {Self::pattern_vars(pat, p, vars)}
+        Self::pattern_vars(pat, r, vars)
+      }
+      _ => {}
+    })
+  }
+
+  /// Match a list pattern against a list of expressions. The list pattern may end
+  /// in `p ...`, which matches any number of expressions against `p`.
+  fn match_list(&mut self, sp: Span, pat: &LispVal, es: &[SExpr], binds: &mut Bindings) -> bool {
+    pat.unwrapped(|e| match e {
+      LispKind::List(ps) => {
+        if let [ps @ .., rep, dots] = &**ps {
+          if dots.as_atom() == Some(AtomID::ELLIPSIS) {
+            if es.len() < ps.len() || !self.match_all(ps, &es[..ps.len()], binds) {return false}
+            let mut vars = vec![];
+            Self::pattern_vars(true, rep, &mut vars);
+            let mut reps: Vec<Vec<Binding>> = vars.iter().map(|_| vec![]).collect();
+            for e in &es[ps.len()..] {
+              let mut b = HashMap::new();
+              if !self.match_one(sp, rep, e, &mut b) {return false}
+              for (v, r) in vars.iter().zip(&mut reps) {
+                if let Some(e) = b.remove(v) {r.push(e)}
+              }
+            }
+            for (v, r) in vars.into_iter().zip(reps) {binds.insert(v, Binding::Many(r));}
+            return true
+          }
+        }
+        es.len() == ps.len() && self.match_all(ps, es, binds)
+      }
+      LispKind::DottedList(ps, r) => {
+        if es.len() < ps.len() || !self.match_all(ps, &es[..ps.len()], binds) {return false}
+        let rest = &es[ps.len()..];
+        match r.as_atom() {
+          Some(AtomID::UNDER) => true,
+          Some(x) => {
+            let sp = match rest {
+              [] => sp,
+              [e1, .., e2] => (e1.span.start..e2.span.end).into(),
+              [e] => e.span,
+            };
+            binds.insert(x, Binding::One(SExpr::list(sp, rest.to_vec())));
+            true
+          }
+          None => self.match_list(sp, r, rest, binds)
+        }
+      }
+      _ => false
+    })
+  }
+
+  fn match_all(&mut self, ps: &[LispVal], es: &[SExpr], binds: &mut Bindings) -> bool {
+    ps.iter().zip(es).all(|(p, e)| self.match_one(e.span, p, e, binds))
+  }
+
+  /// Match a macro pattern against an expression, adding the values of the pattern
+  /// variables to `binds`.
+  fn match_one(&mut self, sp: Span, pat: &LispVal, e: &SExpr, binds: &mut Bindings) -> bool {
+    if let SExprKind::DocComment(_, e) = &e.k {return self.match_one(sp, pat, e, binds)}
+    pat.unwrapped(|p| match (p, &e.k) {
+      (&LispKind::Atom(AtomID::UNDER), _) | (LispKind::Undef, SExprKind::Undef) => true,
+      (&LispKind::Atom(x), _) => {binds.insert(x, Binding::One(e.clone())); true}
+      (LispKind::List(ps), _) if ps.len() == 2 && ps[0].as_atom() == Some(AtomID::QUOTE) =>
+        match (ps[1].as_atom(), &e.k) {
+          (Some(y), &SExprKind::Atom(a)) => self.get_atom_of(e.span, a) == y,
+          _ => false
+        },
+      (LispKind::List(_), SExprKind::List(es)) |
+      (LispKind::DottedList(_, _), SExprKind::List(es)) => self.match_list(sp, pat, es, binds),
+      (LispKind::Number(n), SExprKind::Number(m)) => *n == m.clone().into(),
+      (LispKind::String(s), SExprKind::String(t)) => s == t,
+      (&LispKind::Bool(b), &SExprKind::Bool(c)) => b == c,
+      _ => false
+    })
+  }
+
+  /// Instantiate a macro template, replacing pattern variables with the expressions
+  /// they matched. Atoms from the template are given the span `sp` of the macro call
+  /// and the expansion number `m`.
+  fn instantiate(&mut self, sp: Span, m: u32,
+      tmpl: &LispVal, binds: &Bindings) -> Result<SExpr, ElabError> {
+    tmpl.unwrapped(|t| Ok(SExpr {span: sp, k: match t {
+      &LispKind::Atom(x) => match binds.get(&x) {
+        Some(Binding::One(e)) => return Ok(e.clone()),
+        Some(Binding::Many(_)) => return Err(ElabError::new_e(sp, format!(
+          "macro variable '{}' must be followed by '...'", self.data[x].name))),
+        None => SExprKind::Atom(Atom::Macro(x, m)),
+      },
+      LispKind::List(ts) => SExprKind::List(self.instantiate_list(sp, m, ts, binds)?),
+      LispKind::DottedList(ts, r) => {
+        let mut es = self.instantiate_list(sp, m, ts, binds)?;
+        let r = self.instantiate(sp, m, r, binds)?;
+        match r.k {
+          SExprKind::List(rs) => {es.extend(rs); SExprKind::List(es)}
+          k => SExprKind::DottedList(es, Box::new(SExpr {span: r.span, k})),
+        }
+      }
+      LispKind::Number(n) => SExprKind::Number(n.to_biguint().ok_or_else(||
+        ElabError::new_e(sp, "invalid macro template"))?),
+      LispKind::String(s) => SExprKind::String(s.clone()),
+      &LispKind::Bool(b) => SExprKind::Bool(b),
+      LispKind::Undef => SExprKind::Undef,
+      _ => return Err(ElabError::new_e(sp, "invalid macro template")),
+    }}))
+  }
+
+  /// Instantiate a list of templates, where `t ...` is replaced by one copy of `t` for
+  /// each value of the repeated pattern variables in `t`.
+  fn instantiate_list(&mut self, sp: Span, m: u32,
+      ts: &[LispVal], binds: &Bindings) -> Result<Vec<SExpr>, ElabError> {
+    let mut es = vec![];
+    let mut it = ts.iter().peekable();
+    while let Some(t) = it.next() {
+      if it.peek().and_then(|t| t.as_atom()) != Some(AtomID::ELLIPSIS) {
+        es.push(self.instantiate(sp, m, t, binds)?);
+        continue
+      }
+      it.next();
+      let mut vars = vec![];
+      Self::pattern_vars(false, t, &mut vars);
+      let mut n = None;
+      for v in &vars {
+        if let Some(Binding::Many(bs)) = binds.get(v) {
+          if n.map_or(false, |n| n != bs.len()) {
+            return Err(ElabError::new_e(sp, "macro variables under '...' have different lengths"))
+          }
+          n = Some(bs.len())
+        }
+      }
+      let n = n.ok_or_else(|| ElabError::new_e(sp, "no macro variables under '...'"))?;
+      for i in 0..n {
+        let mut binds = binds.clone();
+        for v in &vars {
+          if let Some(Binding::Many(bs)) = binds.get(v) {
+            let b = bs[i].clone();
+            binds.insert(*v, b);
+          }
+        }
+        es.push(self.instantiate(sp, m, t, &binds)?);
+      }
+    }
+    Ok(es)
+  }
+}
+
 impl Elaborator {
   /// Parse a lisp `SExpr` from the surface syntax into an `IR` object suitable for evaluation.
   pub fn parse_lisp(&mut self, e: &SExpr) -> Result<IR, ElabError> {
@@ -955,13 +1377,23 @@ impl Elaborator {
   /// Parse a lisp `SExpr` from the surface syntax into an `IR` object suitable for evaluation.
   /// The `doc` argument is an additional doc string, if applicable.
   pub fn parse_lisp_doc(&mut self, e: &SExpr, doc: String) -> Result<IR, ElabError> {
-    LispParser {elab: &mut *self, ctx: LocalCtx::new()}.expr_doc(doc, false, e)
+    let ir = LispParser {elab: &mut *self, ctx: LocalCtx::new(), expansions: 0, depth: 0,
+      macro_modules: HashMap::new()}.expr_doc(doc, false, e)?;
+    Ok(compile(ir))
   }
 
   /// Parse a `QExpr`, the result of parsing a math formula,
   /// into an `IR` object suitable for evaluation. (Usually this will be a `IR::Const`,
   /// but `QExpr`'s can contain antiquotations which require evaluation.)
   pub fn parse_qexpr(&mut self, e: QExpr) -> Result<IR, ElabError> {
-    LispParser {elab: &mut *self, ctx: LocalCtx::new()}.qexpr(e)
+    let ir = LispParser {elab: &mut *self, ctx: LocalCtx::new(), expansions: 0, depth: 0,
+      macro_modules: HashMap::new()}.qexpr(e)?;
+    Ok(compile(ir))
   }
+}
+
+/// Compile the parsed expression to bytecode, unless this is disabled by `--no-bytecode`.
+fn compile(mut ir: IR) -> IR {
+  if crate::get_bytecode() { ir.compile() }
+  ir
 }
\ No newline at end of file
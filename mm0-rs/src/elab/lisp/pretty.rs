@@ -4,7 +4,7 @@
 //! [`Pretty`] arena on which various methods exist to print different kinds of object.
 
 use std::collections::HashMap;
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
 use std::{mem, fmt::{self, Write}};
 use std::borrow::Cow;
 use pretty::{DocAllocator, Doc, RefDoc, Arena};
@@ -55,6 +55,8 @@ impl LispKind {
       LispKind::List(es) => es.is_empty(),
       LispKind::DottedList(_, _) |
       LispKind::AtomMap(_) |
+      LispKind::HashMap(_) |
+      LispKind::Vector(_) |
       LispKind::Goal(_) => false,
       LispKind::Atom(_) |
       LispKind::MVar(_, _) |
@@ -78,6 +80,8 @@ pub struct Pretty<'a> {
   fe: FormatEnv<'a>,
   alloc: &'a Arena<'a, ()>,
   hash: RefCell<HashMap<*const LispKind, PrettyCache<'a>>>,
+  /// The subterm to highlight, see [`expr_highlight`](Self::expr_highlight).
+  highlight: Cell<*const LispKind>,
   lparen: PP<'a>,
   rparen: PP<'a>,
 }
@@ -113,7 +117,8 @@ impl<'a> Pretty<'a> {
     Pretty {
       lparen: PP::token(alloc, fe.env, "("),
       rparen: PP::token(alloc, fe.env, ")"),
-      fe, alloc, hash: RefCell::new(HashMap::new())
+      fe, alloc, hash: RefCell::new(HashMap::new()),
+      highlight: Cell::new(std::ptr::null()),
     }
   }
 
@@ -178,10 +183,14 @@ impl<'a> Pretty<'a> {
     }
   }
 
+  fn highlighted(&self, e: &LispVal) -> bool {
+    std::ptr::eq(std::ptr::addr_of!(**e), self.highlight.get())
+  }
+
   fn infixl(&'a self, t: TermID, info: &'a NotaInfo, args: &[LispVal]) -> Option<PP<'a>> {
     if let Literal::Var(i, q) = info.lits[0] {
       let doc = match self.get_term_args(&args[i]) {
-        Some((_, t2, args2)) if t == t2 => self.infixl(t, info, &args2),
+        Some((_, t2, args2)) if t == t2 && !self.highlighted(&args[i]) => self.infixl(t, info, &args2),
         _ => None,
       }.unwrap_or_else(|| self.group(self.expr_paren(&args[i], q)));
       let mut doc = self.append_with(doc, Self::softline(), self.lit(&info.lits[1], args));
@@ -199,7 +208,7 @@ impl<'a> Pretty<'a> {
     if let (&Literal::Var(i, q), most) = info.lits[2..].split_last()? {
       for lit in most {doc = self.append(doc, self.group(self.lit(lit, args)))}
       let end = match self.get_term_args(&args[i]) {
-        Some((_, t2, args2)) if t == t2 => self.infixr(t, info, &args2),
+        Some((_, t2, args2)) if t == t2 && !self.highlighted(&args[i]) => self.infixr(t, info, &args2),
         _ => None,
       }.unwrap_or_else(|| self.group(self.expr_paren(&args[i], q)));
       Some(self.append_with(doc, Self::line(), end))
@@ -259,6 +268,11 @@ impl<'a> Pretty<'a> {
       left: false, right: false, small: e.small(),
       doc: self.pp_lisp(e)
     }));
+    let v = if self.highlighted(e) {
+      let doc = self.append_doc(self.alloc(Doc::text("«")),
+        self.append_doc(v.1.doc, self.alloc(Doc::text("»"))));
+      (v.0, PP {doc, ..v.1})
+    } else {v};
     self.hash.borrow_mut().entry(p).or_insert_with(|| (e.clone(), v)).1
   }
 
@@ -276,6 +290,18 @@ impl<'a> Pretty<'a> {
     self.expr_delimited(e, "$ ", " $")
   }
 
+  /// Pretty-prints a math formula like [`expr`](Self::expr), with the subterm `hl`
+  /// surrounded by `«` and `»`. The subterm is found by pointer equality, so it
+  /// should be obtained from `e` itself, and not be shared with other parts of `e`.
+  pub fn expr_highlight(&'a self, e: &LispVal, hl: &LispVal) -> RefDoc<'a, ()> {
+    self.hash.borrow_mut().clear();
+    self.highlight.set(std::ptr::addr_of!(**hl));
+    let doc = self.expr(e);
+    self.hash.borrow_mut().clear();
+    self.highlight.set(std::ptr::null());
+    doc
+  }
+
 
   fn get_thm_args(&'a self, u: &mut Uncons, args: &mut Vec<LispVal>) -> Option<(&'a AtomData, &'a Thm)> {
     let env = self.fe.env;
@@ -186,11 +186,23 @@ impl EnvDisplay for LispKind {
       LispKind::Proc(Proc::RefineCallback) => write!(f, "#[refine]"),
       LispKind::Proc(Proc::ProofThunk(x, _)) => write!(f, "#[proof of {}]", fe.to(x)),
       LispKind::Proc(Proc::MMCCompiler(_)) => write!(f, "#[mmc-compiler]"),
+      LispKind::Proc(Proc::Macro(x, _)) => write!(f, "#[macro {}]", fe.to(x)),
+      LispKind::Proc(Proc::Async(_)) => write!(f, "#[async]"),
       LispKind::AtomMap(m) => {
         write!(f, "(atom-map!")?;
         for (a, v) in m {write!(f, " [{} {}]", fe.data[*a].name, fe.to(v))?}
         write!(f, ")")
       }
+      LispKind::HashMap(m) => {
+        write!(f, "(hash-map!")?;
+        for (k, v) in m {write!(f, " [{} {}]", fe.to(k), fe.to(v))?}
+        write!(f, ")")
+      }
+      LispKind::Vector(v) => {
+        write!(f, "(vector!")?;
+        for e in v {write!(f, " {}", fe.to(e))?}
+        write!(f, ")")
+      }
       LispKind::Ref(m) if m.too_many_readers() => write!(f, "#[ref]"),
       LispKind::Ref(m) => m.get(|e| e.fmt(fe, f)),
       &LispKind::MVar(n, _) => write!(f, "?{}", alphanumber(n)),
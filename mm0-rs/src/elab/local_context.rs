@@ -7,7 +7,7 @@ use std::result::Result as StdResult;
 use std::collections::{HashMap, hash_map::Entry};
 use itertools::Itertools;
 use super::environment::{AtomID, TermKind, ThmKind, Type as EType};
-use crate::parser::ast::{Decl, Type, DepType, LocalKind};
+use crate::parser::ast::{Decl, Type, DepType, LocalKind, SExpr};
 use super::{Coe, DeclKind, DerefMut, DocComment, ElabError, Elaborator, Environment,
   Expr, Modifiers, ObjectKind, Proof, Result, SExprKind, SortID, Term, TermID, Thm};
 use super::lisp::{LispVal, LispKind, Uncons, InferTarget, print::FormatEnv};
@@ -19,7 +19,7 @@ use crate::util::{Span, FileSpan, BoxError};
 /// but is known to be bound, `y` is not declared at all but known to be a bound non-dummy,
 /// and `z` is not declared and must be a bound dummy of type `var` (assuming
 /// that `all` has type `var` for its first argument).
-#[derive(Debug, EnvDebug, DeepSizeOf)]
+#[derive(Clone, Debug, EnvDebug, DeepSizeOf)]
 pub enum InferSort {
   /// This is a declared bound variable with the given sort.
   Bound(SortID),
@@ -68,7 +68,7 @@ impl InferSort {
 
 /// The local context is the collection of proof-local data. This is manipulated
 /// by lisp tactics in order to keep track of the proof state and eventually produce a proof.
-#[derive(Default, Debug, EnvDebug, DeepSizeOf)]
+#[derive(Clone, Default, Debug, EnvDebug, DeepSizeOf)]
 pub struct LocalContext {
   /// The collection of local variables. The key is the name of the variable, and the
   /// value is `(dummy, is)` where `dummy` is true if this is a dummy variable
@@ -80,6 +80,9 @@ pub struct LocalContext {
   /// The [`InferSort`] contains the inferred type of the variable, but only for
   /// variables that are not in the `vars` hashmap because they are shadowed or anonymous.
   pub var_order: Vec<(Span, Option<AtomID>, Option<InferSort>)>,
+  /// The variables that did not have a declared type, together with the span of
+  /// their first occurrence. The inferred sort can be found in `vars`.
+  pub inferred: Vec<(Span, AtomID)>,
   /// The list of active metavariables. `refine` will add metavariables to this list when
   /// creating them during elaboration, and it is periodically cleaned to remove assigned
   /// metavariables. The `usize` field of a [`MVar`](LispKind::MVar) will refer to the
@@ -104,6 +107,16 @@ pub struct LocalContext {
   pub closer: LispVal,
 }
 
+/// A saved copy of the [`LocalContext`], produced by [`LocalContext::save`].
+///
+/// Besides the fields of the local context, this records the current values of the goal and
+/// metavariable references, because `refine` assigns metavariables by mutating them in place.
+#[derive(Debug)]
+pub struct LocalContextSnapshot {
+  lc: LocalContext,
+  refs: Vec<(LispVal, LispVal)>,
+}
+
 fn new_mvar(mvars: &mut Vec<LispVal>, tgt: InferTarget, sp: Option<FileSpan>) -> LispVal {
   let n = mvars.len();
   let e = LispVal::new(LispKind::MVar(n, tgt));
@@ -121,6 +134,7 @@ impl LocalContext {
   pub fn clear(&mut self) {
     self.vars.clear();
     self.var_order.clear();
+    self.inferred.clear();
     self.mvars.clear();
     self.goals.clear();
     self.proofs.clear();
@@ -128,6 +142,21 @@ impl LocalContext {
     self.closer = LispVal::undef();
   }
 
+  /// Save the current state of the local context, so that it can be rolled back later
+  /// using [`restore`](Self::restore).
+  #[must_use] pub fn save(&self) -> LocalContextSnapshot {
+    let refs = self.mvars.iter().chain(&self.goals)
+      .filter_map(|r| r.as_ref_(|e| (r.clone(), e.clone()))).collect();
+    LocalContextSnapshot {lc: self.clone(), refs}
+  }
+
+  /// Restore a state of the local context saved by [`save`](Self::save), undoing all
+  /// changes to goals and metavariable assignments made since then.
+  pub fn restore(&mut self, snap: LocalContextSnapshot) {
+    for (r, e) in snap.refs { r.as_ref_(|v| *v = e); }
+    *self = snap.lc;
+  }
+
   /// Set the list of goals to `gs`, after filtering the elements that are not
   /// goals or are already instantiated.
   pub fn set_goals(&mut self, gs: impl IntoIterator<Item=LispVal>) {
@@ -626,6 +655,7 @@ impl Elaborator {
           })
         } {
           Ok(sort) => {
+            self.lc.inferred.push((src, a));
             for (s, e) in &**sorts {
               let mut val = LispVal::atom(a);
               if let Some(s) = *s {
@@ -652,6 +682,7 @@ impl Elaborator {
       }
     }
     newvars.sort_by_key(|&(_, a)| &*self.env.data[a].name);
+    self.lc.inferred.sort_by_key(|&(sp, _)| sp.start);
     let mut vec: Vec<_> = newvars.into_iter().map(|(src, a)| (src, Some(a), None)).collect();
     vec.append(&mut self.lc.var_order);
     self.lc.var_order = vec;
@@ -660,6 +691,14 @@ impl Elaborator {
 
   /// Elaborate a declaration (`term`, `axiom`, `def`, `theorem`).
   pub fn elab_decl(&mut self, full: Span, d: &Decl, doc: Option<DocComment>) -> Result<()> {
+    self.elab_decl_with(full, d, doc, |elab, e| elab.elab_lisp(e).map(drop))
+  }
+
+  /// Elaborate a declaration, using `prove` in place of evaluating the value of a `theorem`.
+  /// It is called with the goal and hypotheses set up in the local context, and should
+  /// solve the goals using the same tactics as the proof would.
+  pub fn elab_decl_with(&mut self, full: Span, d: &Decl, doc: Option<DocComment>,
+      mut prove: impl FnMut(&mut Self, &SExpr) -> Result<()>) -> Result<()> {
     let mut e_hyps = Vec::new();
     let mut error = false;
     macro_rules! report {
@@ -844,6 +883,8 @@ impl Elaborator {
               (|| -> Result<Option<Proof>> {
                 let mut de: Dedup<ProofHash> = de.map_proof();
                 let mut is2 = Vec::new();
+                let hyp_sps: Vec<_> = e_hyps.iter().map(|&(bi, a, _)|
+                  (bi.local.unwrap_or(bi.span), a)).collect();
                 for (i, (_, a, e)) in e_hyps.into_iter().enumerate() {
                   if let Some(a) = a {
                     let p = LispVal::atom(a);
@@ -853,17 +894,20 @@ impl Elaborator {
                 }
                 let g = LispVal::new_ref(LispVal::goal(self.fspan(e.span), e_ret));
                 self.lc.goals = vec![g.clone()];
-                self.elab_lisp(e)?;
+                prove(self, e)?;
                 for g in mem::take(&mut self.lc.goals) {
-                  report!(try_get_span(&span, &g),
-                    format!("|- {}", self.format_env().pp(&g.goal_type().expect("expected a goal"), 80)))
+                  let sp = try_get_span(&span, &g);
+                  let ty = g.goal_type().expect("expected a goal");
+                  self.spans.insert_if(sp, || ObjectKind::expr(ty.clone()));
+                  report!(sp, format!("|- {}", self.format_env().pp(&ty, 80)))
                 }
                 if error {return Ok(None)}
                 let nh = NodeHasher {var_map, fsp, fe: self.format_env(), lc: &self.lc};
                 let ip = de.dedup(&nh, &g)?;
                 let (mut ids, heap) = build(&de);
                 let hyps = is2.into_iter().map(|i| ids[i].take()).collect();
-                Ok(Some(Proof {heap, hyps, head: ids[ip].take()}))
+                let pf = Proof {heap, hyps, head: ids[ip].take()};
+                Ok(Some(self.finish_proof(pf, &args, &hyp_sps)))
               })().unwrap_or_else(|e| {self.report(e); None})
             } else {None}
           })
@@ -894,6 +938,7 @@ pub struct AwaitingProof {
   var_map: HashMap<AtomID, usize>,
   lc: Box<LocalContext>,
   is: Vec<usize>,
+  hyp_sps: Vec<Span>,
 }
 
 impl AwaitingProof {
@@ -903,9 +948,9 @@ impl AwaitingProof {
   /// Once the user closure completes, we can call this function to consume the suspended
   /// computation and finish adding the theorem.
   pub fn finish(self, elab: &mut Elaborator, fsp: &FileSpan, proof: LispVal) -> Result<()> {
-    let AwaitingProof {thm, de, var_map, mut lc, is} = self;
+    let AwaitingProof {thm, de, var_map, mut lc, is, hyp_sps} = self;
     mem::swap(&mut elab.lc, &mut lc);
-    elab.finish_add_thm(fsp, thm, Some(Some(ThmVal {de, var_map, lc: Some(lc), is, proof})))
+    elab.finish_add_thm(fsp, thm, Some(Some(ThmVal {de, var_map, lc: Some(lc), is, hyp_sps, proof})))
   }
 }
 
@@ -915,6 +960,7 @@ struct ThmVal {
   var_map: HashMap<AtomID, usize>,
   lc: Option<Box<LocalContext>>,
   is: Vec<usize>,
+  hyp_sps: Vec<Span>,
   proof: LispVal
 }
 
@@ -1089,7 +1135,7 @@ impl Elaborator {
     }
     let mut vars = (HashMap::new(), 1);
     let (mut lc, args) = self.binders(&fsp, Uncons::from(args.clone()), &mut vars)?;
-    let mut is = Vec::new();
+    let (mut is, mut hyp_sps) = (Vec::new(), Vec::new());
     mem::swap(&mut self.lc, &mut lc);
     let e_ret = (|| -> Result<_> {
       for e in Uncons::from(hyps.clone()) {
@@ -1099,6 +1145,7 @@ impl Elaborator {
           let a = if x == AtomID::UNDER {None} else {Some(x)};
           let ty = self.elaborate_term(sp!(ty), &ty, InferTarget::Provable)?;
           is.push((a, ty));
+          hyp_sps.push(sp!(ex));
         } else {
           return Err(ElabError::new_e(sp!(hyps), format!("syntax error: {}", self.print(hyps))))
         }
@@ -1142,21 +1189,42 @@ impl Elaborator {
         if proof.is_proc() {
           lc.set_goals(Some(LispVal::goal(fsp, e_ret)).into_iter());
           let lc = Box::new(mem::replace(&mut self.lc, lc));
-          return Ok(Err((AwaitingProof {thm, de, var_map, lc, is}, proof)))
+          return Ok(Err((AwaitingProof {thm, de, var_map, lc, is, hyp_sps}, proof)))
         }
-        Some(ThmVal {de, var_map, lc: Some(Box::new(lc)), is, proof})
+        Some(ThmVal {de, var_map, lc: Some(Box::new(lc)), is, hyp_sps, proof})
       } else {None})
     } else {None};
     self.finish_add_thm(&fsp, thm, out)?;
     Ok(Ok(()))
   }
 
+  /// Finish a theorem proof: run the [proof optimizer](Proof::optimize) if `--optimize`
+  /// is set, and warn about unused hypotheses if `--warn-unused-hyps` is set. `hyps` gives
+  /// the span and name of each hypothesis of the theorem, whose arguments are `args`.
+  fn finish_proof(&mut self,
+    pf: Proof, args: &[(Option<AtomID>, EType)], hyps: &[(Span, Option<AtomID>)]
+  ) -> Proof {
+    if !self.optimize && !self.warn_unused_hyps {return pf}
+    let (pf2, unused) = pf.optimize(args);
+    if self.warn_unused_hyps {
+      for i in unused {
+        // hypotheses whose name starts with `_` are expected to be unused
+        if let (sp, Some(a)) = hyps[i] {
+          if !self.data[a].name.starts_with(b"_") {
+            self.report(ElabError::warn(sp, format!("unused hypothesis '{}'", self.print(&a))))
+          }
+        }
+      }
+    }
+    if self.optimize {pf2} else {pf}
+  }
+
   #[allow(clippy::option_option)]
   fn finish_add_thm(&mut self, fsp: &FileSpan, mut t: Thm, res: Option<Option<ThmVal>>) -> Result<()> {
     macro_rules! sp {($e:expr) => {$e.fspan().unwrap_or(fsp.clone()).span}}
     t.kind = match res {
       None => ThmKind::Axiom,
-      Some(res) => ThmKind::Thm(res.and_then(|ThmVal {mut de, var_map, mut lc, is: is2, proof: e}| {
+      Some(res) => ThmKind::Thm(res.and_then(|ThmVal {mut de, var_map, mut lc, is: is2, hyp_sps, proof: e}| {
         (|| -> Result<Option<Proof>> {
           let mut u = Uncons::from(e.clone());
           let (ds, pf) = match (u.next(), u.next(), u.exactly(0)) {
@@ -1170,7 +1238,9 @@ impl Elaborator {
           let ip = de.dedup(&nh, &pf)?;
           let (mut ids, heap) = build(&de);
           let hyps = is2.into_iter().map(|i| ids[i].take()).collect();
-          Ok(Some(Proof {heap, hyps, head: ids[ip].take()}))
+          let hyp_sps = hyp_sps.into_iter().zip(&*t.hyps).map(|(sp, &(a, _))| (sp, a)).collect::<Vec<_>>();
+          let pf = Proof {heap, hyps, head: ids[ip].take()};
+          Ok(Some(self.finish_proof(pf, &t.args, &hyp_sps)))
         })().unwrap_or_else(|e| {
           self.report(ElabError::new_e(e.pos,
             format!("while adding {}: {}", self.print(&t.atom), e.kind.msg())));
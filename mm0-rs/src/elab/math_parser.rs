@@ -97,6 +97,27 @@ impl Elaborator {
   }
 }
 
+/// Parse the math string at `sp` (including the `$` delimiters) of `source` outside an
+/// elaborator, for the formatter. Returns `None` if there are any parse errors.
+#[must_use] pub fn parse_math(pe: &ParserEnv, source: &[u8], sp: Span) -> Option<QExpr> {
+  let mut spans = Spans::new();
+  let mut p = MathParser {
+    pe,
+    p: Parser {
+      source,
+      errors: vec![],
+      imports: vec![],
+      idx: sp.start + 1,
+      restart_pos: Some(0),
+    },
+    spans: &mut spans,
+  };
+  p.ws();
+  let expr = p.expr(Prec::Prec(0)).ok()?;
+  if p.token().is_some() || p.idx + 1 != sp.end || !p.p.errors.is_empty() {return None}
+  Some(expr)
+}
+
 /// The precedence of application, `1024`. This determines whether
 /// `f x + y` is interpreted as `f (x + y)` or `(f x) + y`,
 /// by comparing the precedence of `+` to [`APP_PREC`].
@@ -0,0 +1,164 @@
+//! The proof optimizer, which cleans up a [`Proof`] before it is stored in the environment.
+//!
+//! Tactics often produce proofs with redundant conversions, like a `:conv` by reflexivity
+//! or a congruence whose arguments are all reflexivity, and subproofs that become unused
+//! once these are removed. The optimizer rebuilds the proof through a [`Dedup`], which
+//! simplifies the conversions and merges equal subproofs, and then keeps only the heap
+//! elements reachable from the proof, which drops unused dummy variables. It also reports
+//! the hypotheses that are not used in the proof.
+
+use super::environment::{AtomID, Proof, ProofNode, Type};
+use super::proof::{Dedup, IDedup, Node, ProofHash, Val};
+
+/// The state of the optimizer.
+struct Optimizer<'a> {
+  /// The heap of the input proof.
+  heap: &'a [ProofNode],
+  /// The optimized proof, as hash objects.
+  de: Dedup<ProofHash>,
+  /// The index in `de` of each element of `heap` that has been optimized already.
+  ids: Vec<Option<usize>>,
+}
+
+impl Optimizer<'_> {
+  /// Returns true if the conversion at index `i` in `de` is reflexivity.
+  fn is_refl(&self, i: usize) -> bool { matches!(self.de[i], ProofHash::Refl(_)) }
+
+  fn nodes(&mut self, es: &[ProofNode]) -> Box<[usize]> {
+    es.iter().map(|e| self.node(e)).collect()
+  }
+
+  /// Optimize a proof node, and return its index in `de`.
+  fn node(&mut self, e: &ProofNode) -> usize {
+    match *e {
+      ProofNode::Ref(i) => if let Some(n) = self.ids[i] {n} else {
+        let heap = self.heap;
+        let n = self.node(&heap[i]);
+        self.ids[i] = Some(n);
+        n
+      },
+      ProofNode::Dummy(a, s) => self.de.add_direct(ProofHash::Dummy(a, s)),
+      ProofNode::Term {term, ref args} => {
+        let ns = self.nodes(args);
+        self.de.add_direct(ProofHash::Term(term, ns))
+      }
+      ProofNode::Hyp(i, ref e) => {
+        let e = self.node(e);
+        self.de.add_direct(ProofHash::Hyp(i, e))
+      }
+      ProofNode::Thm {thm, ref args, ref res} => {
+        let ns = self.nodes(args);
+        let res = self.node(res);
+        self.de.add_direct(ProofHash::Thm(thm, ns, res))
+      }
+      ProofNode::Conv(ref p) => {
+        let (tgt, c, p) = &**p;
+        let c = self.node(c);
+        // A conversion by reflexivity means the target is the same as the proved statement.
+        if self.is_refl(c) {return self.node(p)}
+        let tgt = self.node(tgt);
+        let p = self.node(p);
+        self.de.add_direct(ProofHash::Conv(tgt, c, p))
+      }
+      ProofNode::Refl(ref e) => {
+        let e = self.node(e);
+        self.de.add_direct(ProofHash::Refl(e))
+      }
+      ProofNode::Sym(ref c) => {
+        let c = self.node(c);
+        match self.de[c] {
+          ProofHash::Refl(_) => c,
+          ProofHash::Sym(c2) => c2,
+          _ => self.de.add_direct(ProofHash::Sym(c)),
+        }
+      }
+      ProofNode::Cong {term, ref args} => {
+        let ns = self.nodes(args);
+        if ns.iter().all(|&c| self.is_refl(c)) {
+          let es = ns.iter().map(|&c| match self.de[c] {
+            ProofHash::Refl(e) => e,
+            _ => unreachable!(),
+          }).collect();
+          let e = self.de.add_direct(ProofHash::Term(term, es));
+          self.de.add_direct(ProofHash::Refl(e))
+        } else {
+          self.de.add_direct(ProofHash::Cong(term, ns.into()))
+        }
+      }
+      ProofNode::Unfold {term, ref args, ref res} => {
+        let ns = self.nodes(args);
+        let (lhs, sub_lhs, c) = &**res;
+        let lhs = self.node(lhs);
+        let sub_lhs = self.node(sub_lhs);
+        let c = self.node(c);
+        self.de.add_direct(ProofHash::Unfold(term, ns, lhs, sub_lhs, c))
+      }
+    }
+  }
+
+  /// Count the references to the elements of `de` in the proof at index `i`,
+  /// visiting the children of each element once.
+  fn count(&self, refs: &mut [u32], i: usize) {
+    refs[i] += 1;
+    if refs[i] != 1 {return}
+    match self.de[i] {
+      ProofHash::Ref(_) | ProofHash::Dummy(_, _) => {}
+      ProofHash::Term(_, ref ns) => for &j in &**ns {self.count(refs, j)},
+      ProofHash::Cong(_, ref ns) => for &j in &**ns {self.count(refs, j)},
+      ProofHash::Hyp(_, j) | ProofHash::Refl(j) | ProofHash::Sym(j) => self.count(refs, j),
+      ProofHash::Thm(_, ref ns, r) => {
+        for &j in &**ns {self.count(refs, j)}
+        self.count(refs, r)
+      }
+      ProofHash::Conv(a, b, c) => {
+        self.count(refs, a);
+        self.count(refs, b);
+        self.count(refs, c)
+      }
+      ProofHash::Unfold(_, ref ns, a, b, c) => {
+        for &j in &**ns {self.count(refs, j)}
+        self.count(refs, a);
+        self.count(refs, b);
+        self.count(refs, c)
+      }
+    }
+  }
+}
+
+impl Proof {
+  /// Optimize a proof of a theorem with arguments `args`: trivial conversions are removed,
+  /// equal subproofs are shared, and the heap is reduced to the elements used by the proof.
+  /// Returns the new proof, and the indexes of the theorem hypotheses that it does not use.
+  #[must_use] pub fn optimize(&self, args: &[(Option<AtomID>, Type)]) -> (Proof, Vec<usize>) {
+    let nargs = args.len();
+    let mut heap_ids = vec![None; self.heap.len()];
+    for (i, id) in heap_ids.iter_mut().enumerate().take(nargs) {*id = Some(i)}
+    let mut opt = Optimizer {heap: &self.heap, de: Dedup::new(args), ids: heap_ids};
+    let hyps: Vec<_> = self.hyps.iter().map(|h| opt.node(h)).collect();
+    let ip = opt.node(&self.head);
+    let mut refs = vec![0; opt.de.vec.len()];
+    for &i in &hyps {opt.count(&mut refs, i)}
+    opt.count(&mut refs, ip);
+    // A hypothesis is used if it is referenced by the proof, and not only by `hyps`.
+    let unused = hyps.iter().filter(|&&i| refs[i] == 1).filter_map(|&i| match opt.de[i] {
+      ProofHash::Hyp(h, _) => Some(h),
+      _ => None,
+    }).collect();
+    // Like `build`, except that unreachable elements are skipped, and elements are put on
+    // the heap according to the reference counts from the roots of the proof.
+    let mut ids: Vec<Val<ProofNode>> = Vec::with_capacity(refs.len());
+    let mut heap = vec![];
+    for (i, (e, _)) in (&opt.de).into_iter().enumerate() {
+      if i >= nargs && refs[i] == 0 {ids.push(Val::Done); continue}
+      let node = Node::from(e, &mut ids);
+      if i < nargs || refs[i] > 1 {
+        ids.push(Val::Ref(heap.len()));
+        heap.push(node);
+      } else {
+        ids.push(Val::Built(node))
+      }
+    }
+    let hyps = hyps.into_iter().map(|i| ids[i].take()).collect();
+    (Proof {heap: heap.into(), hyps, head: ids[ip].take()}, unused)
+  }
+}
@@ -783,4 +783,24 @@ impl<'a> Subst<'a> {
       }
     }
   }
+
+  /// Substitute in an [`ExprNode`]. This version replaces [`Dummy`](ExprNode::Dummy) nodes
+  /// with the names given by `dummy`, so that the result can be used in an `:unfold` proof.
+  pub fn subst_dummies(&mut self, dummy: &impl Fn(AtomID) -> AtomID, e: &ExprNode) -> LispVal {
+    match *e {
+      ExprNode::Ref(i) => {
+        let e = &self.subst[i];
+        if e.is_def() {return e.clone()}
+        let e = self.subst_dummies(dummy, &self.heap[i]);
+        self.subst[i] = e.clone();
+        e
+      }
+      ExprNode::Dummy(a, _) => LispVal::atom(dummy(a)),
+      ExprNode::App(t, ref es) => {
+        let mut args = vec![LispVal::atom(self.env.terms[t].atom)];
+        args.extend(es.iter().map(|e| self.subst_dummies(dummy, e)));
+        LispVal::list(args)
+      }
+    }
+  }
 }
@@ -6,14 +6,17 @@
 //! [`mm1.md`]: https://github.com/digama0/mm0/blob/master/mm0-hs/mm1.md#pre-expressions
 
 use std::result::Result as StdResult;
+use std::collections::HashMap;
+use num::ToPrimitive;
 use crate::util::{FileSpan, Span};
 use super::{Elaborator, ElabError, Result};
-use super::environment::{AtomID, TermKind, DeclKey, Modifiers,
-  ObjectKind, SortID, TermID, ThmID, Type};
+use super::environment::{AtomID, Environment, TermKind, DeclKey, Modifiers,
+  ObjectKind, SortID, TermID, ThmID, Type, ExprNode, EqLemmas};
 use super::lisp::{InferTarget, LispKind, LispRef, LispVal, Uncons, RefineSyntax,
   print::{FormatEnv, EnvDisplay}, eval::SResult};
 use super::local_context::{InferSort, try_get_span, try_get_span_opt};
 use super::proof::Subst;
+use super::search::{Pat, unify};
 
 /// The inference mode on an application, which determines which arguments are being
 /// omitted and which provided explicitly.
@@ -382,6 +385,112 @@ impl LispVal {
 #[derive(Debug)]
 enum AssignError { Cyclic, BoundVar }
 
+/// A step on the way from the root of a unification problem to a mismatch.
+#[derive(Copy, Clone, Debug)]
+enum UnifyStep {
+  /// Unification of two applications of the same term descended into the argument
+  /// with this (0-based) index.
+  Arg(usize),
+  /// The definition was unfolded, on the right side if the flag is true and otherwise on
+  /// the left side.
+  Unfold(AtomID, bool),
+}
+
+/// The reason for a unification failure.
+#[derive(Debug)]
+enum Mismatch {
+  /// Two different variables.
+  Vars(AtomID, AtomID),
+  /// Applications of two different terms, which could not be unfolded to match.
+  Terms(AtomID, AtomID),
+  /// A variable and an application.
+  VarTerm,
+  /// Any other error, such as a failed occurs check.
+  Other(String),
+}
+
+/// A unification failure, at the smallest mismatching subterms.
+#[derive(Debug)]
+struct UnifyError {
+  /// The steps from the root to the mismatch, in reverse order
+  /// (they are added as the error propagates up).
+  path: Vec<UnifyStep>,
+  /// The left side of the mismatch.
+  e1: LispVal,
+  /// The right side of the mismatch.
+  e2: LispVal,
+  /// The reason for the mismatch.
+  msg: Mismatch,
+  /// The definitions on the way that were not unfolded: abstract definitions,
+  /// and definitions on both sides, whose arguments were unified instead.
+  stuck: Vec<AtomID>,
+}
+
+impl UnifyError {
+  /// A mismatch between `e1` and `e2` themselves.
+  fn new(e1: &LispVal, e2: &LispVal, msg: Mismatch) -> Self {
+    UnifyError {path: vec![], e1: e1.clone(), e2: e2.clone(), msg, stuck: vec![]}
+  }
+
+  /// Swap the two sides of the error.
+  fn sym(mut self) -> Self {
+    std::mem::swap(&mut self.e1, &mut self.e2);
+    match &mut self.msg {
+      Mismatch::Vars(a, b) | Mismatch::Terms(a, b) => std::mem::swap(a, b),
+      Mismatch::VarTerm | Mismatch::Other(_) => {}
+    }
+    for step in &mut self.path {
+      if let UnifyStep::Unfold(_, sym) = step {*sym = !*sym}
+    }
+    self
+  }
+}
+
+/// The caches used by unification. The results and the ground subterms are only valid
+/// while the metavariables are not changed except by assignment, so they are kept for
+/// a whole call to `run_refine` or `conv` and cleared when control returns to lisp code,
+/// and `rewrite_thm` clears them after each attempt, since a failed attempt is undone.
+#[derive(Debug, Default)]
+pub(crate) struct UnifyCache {
+  /// The conversion proofs of successful unifications of applications, keyed on the
+  /// addresses of the two sides. The sides are kept to make sure the addresses are not reused.
+  results: HashMap<(usize, usize), (LispVal, LispVal, LispVal)>,
+  /// The subterms that contain no unassigned metavariables, keyed on their address.
+  /// The occurs check does not need to traverse these.
+  ground: HashMap<usize, LispVal>,
+  /// The heights of the definitions (see [`Elaborator::def_height`]). These never change,
+  /// so they are not cleared.
+  heights: HashMap<TermID, u32>,
+}
+
+impl UnifyCache {
+  /// Clear the cached unification results and ground subterms.
+  pub(crate) fn clear(&mut self) {
+    self.results.clear();
+    self.ground.clear();
+  }
+}
+
+/// The address of a lisp value, used as a key in the [`UnifyCache`].
+fn addr(e: &LispVal) -> usize { std::ptr::addr_of!(**e) as usize }
+
+/// Collect the terms appearing in an expression from a definition body in `out`.
+fn terms_of(e: &ExprNode, out: &mut Vec<TermID>) {
+  if let ExprNode::App(t, ref es) = *e {
+    out.push(t);
+    for e in &**es {terms_of(e, out)}
+  }
+}
+
+/// Collect the dummy variables of an expression from a definition body in `out`.
+fn dummies(e: &ExprNode, out: &mut Vec<(AtomID, SortID)>) {
+  match *e {
+    ExprNode::Ref(_) => {}
+    ExprNode::Dummy(a, s) => if !out.iter().any(|&(b, _)| a == b) {out.push((a, s))},
+    ExprNode::App(_, ref es) => for e in &**es {dummies(e, out)}
+  }
+}
+
 impl Elaborator {
 
   fn parse_refine(&mut self, fsp: &FileSpan, e: &LispVal) -> Result<RefineExpr> {
@@ -541,15 +650,34 @@ impl Elaborator {
 
   /// Return true if `e` contains an occurrence of the metavariable `mv`.
   /// We use this before assigning `mv := e` to ensure acyclicity.
-  fn occurs(&mut self, mv: &LispVal, e: &LispVal) -> bool {
-    match &**e {
-      LispKind::Annot(_, e) => self.occurs(mv, e),
-      LispKind::Ref(m) => mv.ptr_eq(e) || m.get(|e2| self.occurs(mv, e2)),
-      LispKind::List(es) => es.iter().any(|e| self.occurs(mv, e)),
-      LispKind::DottedList(es, r) =>
-        es.iter().any(|e| self.occurs(mv, e)) && self.occurs(mv, r),
-      _ => false,
-    }
+  fn occurs(&mut self, mv: &LispVal, e: &LispVal) -> bool { self.occurs_core(mv, e).is_none() }
+
+  /// Returns `None` if `e` contains an occurrence of the metavariable `mv`, and otherwise
+  /// whether `e` is ground, that is, has no unassigned metavariables. Since metavariables are
+  /// only ever assigned during unification, ground subterms are recorded and skipped later.
+  fn occurs_core(&mut self, mv: &LispVal, e: &LispVal) -> Option<bool> {
+    if self.unify_cache.ground.contains_key(&addr(e)) {return Some(true)}
+    let ground = match &**e {
+      LispKind::Annot(_, e) => return self.occurs_core(mv, e),
+      LispKind::Ref(m) => {
+        if mv.ptr_eq(e) {return None}
+        m.get(|e2| self.occurs_core(mv, e2))?
+      }
+      LispKind::MVar(_, _) => false,
+      LispKind::List(es) => {
+        let mut ground = true;
+        for e in &**es {ground &= self.occurs_core(mv, e)?}
+        ground
+      }
+      LispKind::DottedList(es, r) => {
+        let mut ground = self.occurs_core(mv, r)?;
+        for e in &**es {ground &= self.occurs_core(mv, e)?}
+        ground
+      }
+      _ => return Some(true),
+    };
+    if ground {self.unify_cache.ground.insert(addr(e), e.clone());}
+    Some(ground)
   }
 
   /// Assign metavariable `mv` to `e` (or fail). `m` is the underlying reference of `mv`.
@@ -579,91 +707,126 @@ impl Elaborator {
 
   /// Unify expressions `e1` and `e2`. Returns a conversion proof
   /// `u: e1 = e2`, with `#undef` meaning that `e1` and `e2` are equal after unification.
+  /// On failure, the two sides are attached as related information, with the
+  /// mismatching subterms highlighted.
   fn unify(&mut self, sp: Span, e1: &LispVal, e2: &LispVal) -> Result<LispVal> {
-    self.unify1(e1, e2).map_err(|e| ElabError::new_e(sp, e))
+    self.unify_core(e1, e2).map_err(|err| {
+      let (msg, sides) = self.format_unify_err(e1, e2, &err);
+      let fsp = self.fspan(sp);
+      ElabError::with_info(sp, msg.into(), IntoIterator::into_iter(sides)
+        .map(|side| (fsp.clone(), side.into())).collect())
+    })
   }
 
   /// Unify expressions `e1` and `e2`. Returns a conversion proof
   /// `u: e1 = e2`, with `#undef` meaning that `e1` and `e2` are equal after unification.
   fn unify1(&mut self, e1: &LispVal, e2: &LispVal) -> SResult<LispVal> {
-    self.unify_core(e1, e2).map_err(|e| self.format_env().pretty(|p|
-      format!("{}\n{}", p.unify_err(e1, e2).pretty(80).to_string(), e)))
+    self.unify_core(e1, e2).map_err(|err| {
+      let (msg, [s1, s2]) = self.format_unify_err(e1, e2, &err);
+      format!("{}\n{}\n{}", msg, s1, s2)
+    })
+  }
+
+  /// Describe the unification failure `err` of `e1 =?= e2`. Returns the main message,
+  /// which gives the mismatching subterms, the path to them and the definitions on the way
+  /// that were not unfolded, and the two sides, where the subterms containing the
+  /// mismatch are surrounded by `«` and `»`.
+  fn format_unify_err(&self, e1: &LispVal, e2: &LispVal, err: &UnifyError) -> (String, [String; 2]) {
+    use std::fmt::Write;
+    // Rebuild `e` along `path`, so that the subterm at the end of the path
+    // is a new object, which is also returned.
+    fn mark(e: &LispVal, path: &[usize]) -> (LispVal, LispVal) {
+      if let Some((&n, path)) = path.split_first() {
+        let mut es = Uncons::from(e.clone()).collect::<Vec<_>>();
+        if n + 1 < es.len() {
+          let (e2, hl) = mark(&es[n + 1], path);
+          es[n + 1] = e2;
+          return (LispVal::list(es), hl)
+        }
+      }
+      let hl = LispVal::new_ref(e.clone());
+      (hl.clone(), hl)
+    }
+    let fe = self.format_env();
+    let mut msg = fe.pretty(|p| p.unify_err(&err.e1, &err.e2).pretty(80).to_string());
+    match err.msg {
+      Mismatch::Vars(a, b) => write!(msg, "\nvariables do not match: {} != {}",
+        self.data[a].name, self.data[b].name),
+      Mismatch::Terms(a, b) => write!(msg, "\nterms do not match: {} != {}",
+        self.data[a].name, self.data[b].name),
+      Mismatch::VarTerm => write!(msg, "\nvariable vs term"),
+      Mismatch::Other(ref e) => write!(msg, "\n{}", e),
+    }.expect("writing to a string");
+    if !err.path.is_empty() {
+      msg.push_str("\nat: ");
+      for (i, step) in err.path.iter().rev().enumerate() {
+        if i != 0 {msg.push_str(" > ")}
+        match *step {
+          UnifyStep::Arg(n) => write!(msg, "argument {}", n + 1),
+          UnifyStep::Unfold(a, sym) => write!(msg, "unfold '{}' ({})",
+            self.data[a].name, if sym {"right"} else {"left"}),
+        }.expect("writing to a string")
+      }
+    }
+    if !err.stuck.is_empty() {
+      msg.push_str("\nnot unfolded:");
+      for &a in &err.stuck {write!(msg, " '{}'", self.data[a].name).expect("writing to a string")}
+    }
+    let side = |right: bool, e: &LispVal| {
+      let mut path = vec![];
+      for step in err.path.iter().rev() {
+        match *step {
+          UnifyStep::Arg(n) => path.push(n),
+          UnifyStep::Unfold(_, sym) => if sym == right {break}
+        }
+      }
+      fe.pretty(|p| if path.is_empty() {p.expr(e)} else {
+        let (e, hl) = mark(e, &path);
+        p.expr_highlight(&e, &hl)
+      }.pretty(80).to_string())
+    };
+    let sides = [
+      format!("expected: {}", side(false, e1)),
+      format!("actual: {}", side(true, e2))];
+    (msg, sides)
   }
 
   /// Unify expressions `e1` and `e2`. Returns a conversion proof
   /// `u: e1 = e2`, with `#undef` meaning that `e1` and `e2` are equal after unification.
-  fn unify_core(&mut self, e1: &LispVal, e2: &LispVal) -> SResult<LispVal> {
+  fn unify_core(&mut self, e1: &LispVal, e2: &LispVal) -> StdResult<LispVal, UnifyError> {
     // println!("{} =?= {}", self.format_env().pp(e1, 80), self.format_env().pp(e2, 80));
     // (|| {
+    let err = |msg: Mismatch| UnifyError::new(e1, e2, msg);
+    self.use_fuel().map_err(|e| err(Mismatch::Other(e.into())))?;
     if e1.ptr_eq(e2) {return Ok(LispVal::undef())}
+    // Expressions are DAGs, and the same pair of subterms is often reached along many paths,
+    // so without this check unification can take exponential time.
+    if let Some((_, _, c)) = self.unify_cache.results.get(&(addr(e1), addr(e2))) {return Ok(c.clone())}
     match e1.as_mvar(|e1, m| self.assign(false, e1, m, e2)) {
       Some(Ok(())) => return Ok(LispVal::undef()),
       Some(Err(AssignError::Cyclic)) =>
-        return Err("occurs-check failed, can't build infinite assignment".into()),
+        return Err(err(Mismatch::Other("occurs-check failed, can't build infinite assignment".into()))),
       r1 => match (r1, e2.as_mvar(|e2, m| self.assign(true, e2, m, e1))) {
         (_, Some(Ok(()))) => return Ok(LispVal::undef()),
         (_, Some(Err(AssignError::Cyclic))) =>
-          return Err("occurs-check failed, can't build infinite assignment".into()),
+          return Err(err(Mismatch::Other("occurs-check failed, can't build infinite assignment".into()))),
         (Some(Err(AssignError::BoundVar)), None) =>
-          return Err(format!("type error: expected bound var, got {}", self.print(e2))),
+          return Err(err(Mismatch::Other(format!("type error: expected bound var, got {}", self.print(e2))))),
         (None, Some(Err(AssignError::BoundVar))) =>
-          return Err(format!("type error: expected bound var, got {}", self.print(e1))),
+          return Err(err(Mismatch::Other(format!("type error: expected bound var, got {}", self.print(e1))))),
         (None, None) => {},
         _ => unreachable!()
       }
     }
     match (e1.as_atom(), e2.as_atom()) {
       (Some(a1), Some(a2)) if a1 == a2 => Ok(LispVal::undef()),
-      (Some(a1), Some(a2)) => Err(format!(
-        "variables do not match: {} != {}", self.data[a1].name, self.data[a2].name)),
+      (Some(a1), Some(a2)) => Err(err(Mismatch::Vars(a1, a2))),
       (None, None) => {
-        let mut u1 = Uncons::from(e1.clone());
-        let mut u2 = Uncons::from(e2.clone());
-        let e_t1 = u1.next().ok_or_else(||
-          format!("bad term: {}", self.print(e1)))?;
-        let a_t1 = e_t1.as_atom().ok_or_else(||
-          format!("bad term: {}", self.print(e1)))?;
-        let a_t2 = u2.next().and_then(|a| a.as_atom()).ok_or_else(||
-          format!("bad term: {}", self.print(e2)))?;
-        if a_t1 == a_t2 {
-          let mut cs = vec![e_t1];
-          let u3 = u1.clone();
-          while let (Some(x1), Some(x2)) = (u1.next(), u2.next()) {
-            cs.push(self.unify_core(&x1, &x2)?);
-          }
-          if u1.is_empty() && u2.is_empty() {
-            if cs[1..].iter().any(|c| c.is_def()) {
-              for (c, x) in cs[1..].iter_mut().zip(u3) {
-                if !c.is_def() {*c = x}
-              }
-              Ok(LispVal::list(cs))
-            } else {
-              Ok(LispVal::undef())
-            }
-          } else {
-            Err(format!("bad terms: {}, {}", self.print(e1), self.print(e2)))
-          }
-        } else {
-          let t1 = self.term(a_t1).ok_or_else(||
-            format!("bad term: {}", self.print(e1)))?;
-          let tdata1 = &self.terms[t1];
-          let t2 = self.term(a_t2).ok_or_else(||
-            format!("bad term: {}", self.print(e2)))?;
-          let tdata2 = &self.terms[t2];
-          macro_rules! s {() => {format!(
-            "terms do not match: {} != {}", self.data[a_t1].name, self.data[a_t2].name)
-          }}
-
-          match (&tdata1.kind, &tdata2.kind) {
-            (_, TermKind::Def(_)) if t1 < t2 => self.unfold(true, t2, &u2, e1).map_err(|e| format!("{}\n{}", s!(), e)),
-            (TermKind::Def(_), _) => self.unfold(false, t1, &u1, e2).map_err(|e| format!("{}\n{}", s!(), e)),
-            (_, TermKind::Def(_)) => self.unfold(true, t2, &u2, e1).map_err(|e| format!("{}\n{}", s!(), e)),
-            _ => Err(s!())
-          }
-        }
+        let c = self.unify_app(e1, e2)?;
+        self.unify_cache.results.insert((addr(e1), addr(e2)), (e1.clone(), e2.clone(), c.clone()));
+        Ok(c)
       }
-      _ => Err(format!(
-        "variable vs term: {} != {}", self.print(e1), self.print(e2))),
+      _ => Err(err(Mismatch::VarTerm)),
     }
     // })().map(|r| {
     //   let fe = self.format_env();
@@ -672,23 +835,110 @@ impl Elaborator {
     // })
   }
 
+  /// The height of a definition, which is 0 for terms and abstract definitions, and
+  /// otherwise one more than the largest height of a term in the body of the definition.
+  fn def_height(&mut self, t: TermID) -> u32 {
+    if let Some(&h) = self.unify_cache.heights.get(&t) {return h}
+    let mut ts = vec![];
+    if let TermKind::Def(Some(val)) = &self.env.terms[t].kind {
+      for e in &val.heap {terms_of(e, &mut ts)}
+      terms_of(&val.head, &mut ts);
+    } else {
+      self.unify_cache.heights.insert(t, 0);
+      return 0
+    }
+    let mut h = 0;
+    for t in ts {h = h.max(self.def_height(t))}
+    self.unify_cache.heights.insert(t, h + 1);
+    h + 1
+  }
+
+  /// Unify the applications `e1` and `e2`, which are not metavariables. If the heads are
+  /// the same, the arguments are unified, and otherwise the side with the greater
+  /// [height](Self::def_height) is unfolded, or the later definition if the heights are equal.
+  fn unify_app(&mut self, e1: &LispVal, e2: &LispVal) -> StdResult<LispVal, UnifyError> {
+    let err = |msg: Mismatch| UnifyError::new(e1, e2, msg);
+    let mut u1 = Uncons::from(e1.clone());
+    let mut u2 = Uncons::from(e2.clone());
+    let e_t1 = u1.next().ok_or_else(||
+      err(Mismatch::Other(format!("bad term: {}", self.print(e1)))))?;
+    let a_t1 = e_t1.as_atom().ok_or_else(||
+      err(Mismatch::Other(format!("bad term: {}", self.print(e1)))))?;
+    let a_t2 = u2.next().and_then(|a| a.as_atom()).ok_or_else(||
+      err(Mismatch::Other(format!("bad term: {}", self.print(e2)))))?;
+    if a_t1 == a_t2 {
+      let mut cs = vec![e_t1];
+      let u3 = u1.clone();
+      while let (Some(x1), Some(x2)) = (u1.next(), u2.next()) {
+        let n = cs.len() - 1;
+        cs.push(self.unify_core(&x1, &x2).map_err(|mut e| {
+          e.path.push(UnifyStep::Arg(n));
+          // Unification of applications of the same definition
+          // compares the arguments instead of unfolding it.
+          if let Some(TermKind::Def(_)) = self.term(a_t1).map(|t| &self.terms[t].kind) {
+            if !e.stuck.contains(&a_t1) {e.stuck.push(a_t1)}
+          }
+          e
+        })?);
+      }
+      if u1.is_empty() && u2.is_empty() {
+        if cs[1..].iter().any(|c| c.is_def()) {
+          for (c, x) in cs[1..].iter_mut().zip(u3) {
+            if !c.is_def() {*c = x}
+          }
+          Ok(LispVal::list(cs))
+        } else {
+          Ok(LispVal::undef())
+        }
+      } else {
+        Err(err(Mismatch::Other(format!("bad terms: {}, {}", self.print(e1), self.print(e2)))))
+      }
+    } else {
+      let t1 = self.term(a_t1).ok_or_else(||
+        err(Mismatch::Other(format!("bad term: {}", self.print(e1)))))?;
+      let t2 = self.term(a_t2).ok_or_else(||
+        err(Mismatch::Other(format!("bad term: {}", self.print(e2)))))?;
+      macro_rules! s {() => {err(Mismatch::Terms(a_t1, a_t2))}}
+
+      let (h1, h2) = (self.def_height(t1), self.def_height(t2));
+      let (sym, t, a, u, e) = if h1 == 0 && h2 == 0 {
+        let mut e = s!();
+        for &(t, a) in &[(t1, a_t1), (t2, a_t2)] {
+          if let TermKind::Def(None) = self.terms[t].kind {e.stuck.push(a)}
+        }
+        return Err(e)
+      } else if h1 > h2 || (h1 == h2 && t1 > t2) {
+        (false, t1, a_t1, &u1, e2)
+      } else {
+        (true, t2, a_t2, &u2, e1)
+      };
+      self.unfold(sym, t, u, e).map_err(|e| e.unwrap_or_else(|| {
+        let mut e = s!();
+        e.stuck.push(a);
+        e
+      }))
+    }
+  }
+
   /// Produce a proof that `(tid u1) = e2` if `sym` is false, or `e2 = (tid u1)` if `sym` is true.
-  fn unfold(&mut self, sym: bool, tid: TermID, u1: &Uncons, e2: &LispVal) -> SResult<LispVal> {
+  /// Returns `Err(None)` if `tid` cannot be unfolded.
+  fn unfold(&mut self, sym: bool, tid: TermID, u1: &Uncons, e2: &LispVal) -> StdResult<LispVal, Option<UnifyError>> {
     let tdata = &self.env.terms[tid];
     let a = tdata.atom;
     let nargs = tdata.args.len();
     if let TermKind::Def(Some(val)) = &tdata.kind {
       let mut args = Vec::with_capacity(nargs);
-      if !u1.extend_into(nargs, &mut args) {
-        return Err(format!("bad term: {}", self.print(u1)))
-      }
+      if !u1.extend_into(nargs, &mut args) {return Err(None)}
       let e1_unfolded = Subst::new(&self.env, &val.heap, args.clone())
         .subst_mut(&mut self.lc, &val.head);
-      let conv = self.unify1(&e1_unfolded, e2)?;
+      let conv = self.unify_core(&e1_unfolded, e2).map_err(|mut e| {
+        e.path.push(UnifyStep::Unfold(a, false));
+        Some(if sym {e.sym()} else {e})
+      })?;
       let conv = LispVal::unfold(a, args, if conv.is_def() {conv} else {e1_unfolded});
       Ok(if sym {LispVal::sym(conv)} else {conv})
     } else {
-      Err(format!("not a definition: {}", self.print(&a)))
+      Err(None)
     }
   }
 
@@ -923,3 +1173,604 @@ impl Elaborator {
     }
   }
 }
+
+/// The options for the `rewrite` tactic.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct RewriteMode {
+  /// Use the equations right to left, rewriting `b` to `a` using `th: $ a = b $`.
+  pub rtl: bool,
+  /// Rewrite all instances of all the equations. By default, only the first match
+  /// is used, and after that only subterms equal to that instance are rewritten.
+  pub everywhere: bool,
+}
+
+/// A rewrite rule in the `rewrite` tactic.
+#[derive(Debug)]
+enum RewriteRule {
+  /// An equation `th: $ a = b $`, which rewrites instances of `a` to `b`.
+  Thm(ThmID),
+  /// A concrete instance of an equation: rewrite `e1` to `e2` using the proof `p: e1 = e2`.
+  Inst(LispVal, LispVal, LispVal),
+}
+
+impl Elaborator {
+  /// Get the sort and the equality lemmas for the equality relation `eq`.
+  fn eq_lemmas(&self, eq: TermID) -> Option<(SortID, EqLemmas)> {
+    self.env.eqs.iter().find(|(_, l)| l.eq == eq).map(|(&s, &l)| (s, l))
+  }
+
+  /// Match the pattern `p`, from the statement of a theorem with heap `heap`, against the
+  /// expression `e`, assigning the theorem variables in `args` (which are `#undef` if not
+  /// yet assigned). The matching is syntactic, so definitions are not unfolded; it is used
+  /// for lemmas of a known shape, like the registered equality and congruence lemmas.
+  /// `#undef` can be used as a wildcard in `e`.
+  pub(crate) fn match_pattern(&self, heap: &[ExprNode], args: &mut [LispVal], p: &ExprNode, e: &LispVal) -> bool {
+    if !e.is_def() {return true}
+    match *p {
+      ExprNode::Ref(i) if i < args.len() => {
+        if args[i].is_def() {return args[i] == *e}
+        args[i] = e.clone();
+        true
+      }
+      ExprNode::Ref(i) => self.match_pattern(heap, args, &heap[i], e),
+      ExprNode::Dummy(_, _) => false,
+      ExprNode::App(t, ref ps) => {
+        let mut u = Uncons::from(e.clone());
+        u.next().and_then(|h| h.as_atom()) == Some(self.env.terms[t].atom) &&
+        ps.iter().all(|p| u.next().map_or(false, |e| self.match_pattern(heap, args, p, &e))) &&
+        u.is_empty()
+      }
+    }
+  }
+
+  /// Instantiate the variables of theorem `th` by matching its conclusion against `ret`
+  /// and its first hypotheses against `hyps`. Returns `None` if the match fails;
+  /// variables that are not determined by the match are left as `#undef`.
+  fn match_thm(&self, th: ThmID, ret: &LispVal, hyps: &[LispVal]) -> Option<Vec<LispVal>> {
+    let td = &self.env.thms[th];
+    let mut args = vec![LispVal::undef(); td.args.len()];
+    if !self.match_pattern(&td.heap, &mut args, &td.ret, ret) {return None}
+    for (h, (_, p)) in hyps.iter().zip(&*td.hyps) {
+      if !self.match_pattern(&td.heap, &mut args, p, h) {return None}
+    }
+    Some(args)
+  }
+
+  /// Build a proof using theorem `th`, with the variable assignment `args` from
+  /// [`match_thm`](Self::match_thm) and the proofs `ps` of the first hypotheses.
+  /// Unassigned variables become new metavariables and the remaining hypotheses become
+  /// new goals. Returns the proof and its statement.
+  fn apply_thm(&mut self, sp: Span, th: ThmID, mut args: Vec<LispVal>, ps: Vec<LispVal>) -> (LispVal, LispVal) {
+    let td = &self.env.thms[th];
+    for (a, (_, ty)) in args.iter_mut().zip(&*td.args) {
+      if !a.is_def() {
+        let tgt = self.type_target(ty);
+        *a = self.lc.new_mvar(tgt, None)
+      }
+    }
+    let mut subst = Subst::new(&self.env, &td.heap, args.clone());
+    let hyps = td.hyps[ps.len()..].iter().map(|(_, h)| subst.subst(h)).collect::<Vec<_>>();
+    let ret = subst.subst(&td.ret);
+    let mut proof = vec![LispVal::atom(td.atom)];
+    proof.extend(args);
+    proof.extend(ps);
+    for h in hyps { proof.push(self.new_goal(sp, h)) }
+    (LispVal::list(proof), ret)
+  }
+
+  /// Check that `th` is an equation `a = b` for a registered equality, and return the
+  /// equality relation.
+  fn rewrite_eqn(&self, th: ThmID, rtl: bool) -> SResult<TermID> {
+    let td = &self.env.thms[th];
+    if let ExprNode::App(eq, ref es) = td.ret {
+      if es.len() == 2 && self.eq_lemmas(eq).is_some() {
+        if let ExprNode::Ref(i) = es[usize::from(rtl)] {
+          if i < td.args.len() {
+            return Err(format!("rewrite: the {} side of '{}' is a variable",
+              if rtl {"right"} else {"left"}, self.data[td.atom].name))
+          }
+        }
+        return Ok(eq)
+      }
+    }
+    Err(format!("rewrite: '{}' is not an equation", self.data[td.atom].name))
+  }
+
+  /// A quick check for [`rewrite_thm`](Self::rewrite_thm), which returns false if `e`
+  /// cannot unify with the side of the equation `th` that is being rewritten, because
+  /// the head terms are different and neither of them is a definition.
+  fn may_rewrite(&mut self, th: ThmID, rtl: bool, e: &LispVal) -> bool {
+    let t1 = {
+      let td = &self.env.thms[th];
+      let mut p = if let ExprNode::App(_, ref es) = td.ret {&es[usize::from(rtl)]} else {return false};
+      while let ExprNode::Ref(i) = *p {
+        if i < td.args.len() {return true}
+        p = &td.heap[i]
+      }
+      if let ExprNode::App(t, _) = *p {t} else {return false}
+    };
+    let t2 = match Uncons::from(e.clone()).next().and_then(|h| h.as_atom()).and_then(|a| self.term(a)) {
+      Some(t2) => t2,
+      None => return false
+    };
+    t1 == t2 || self.def_height(t1) != 0 || self.def_height(t2) != 0
+  }
+
+  /// Try to rewrite `e` using the equation `th`. The theorem is applied to new
+  /// metavariables, and the side of the equation is unified with `e` as in `refine`,
+  /// unfolding definitions if necessary. Returns the rewritten expression `e'`
+  /// and a proof of `e = e'`.
+  fn rewrite_thm(&mut self, sp: Span, th: ThmID, rtl: bool, e: &LispVal) -> SResult<Option<(LispVal, LispVal)>> {
+    let eq = self.rewrite_eqn(th, rtl)?;
+    if !self.may_rewrite(th, rtl, e) {return Ok(None)}
+    let snap = self.lc.save();
+    let args = vec![LispVal::undef(); self.env.thms[th].args.len()];
+    let (p, stmt) = self.apply_thm(sp, th, args, vec![]);
+    let mut u = Uncons::from(stmt.clone());
+    u.next();
+    let (e1, e2) = (u.next().expect("equation"), u.next().expect("equation"));
+    let eq_a = LispVal::atom(self.env.terms[eq].atom);
+    let tgt = if rtl {
+      LispVal::list(vec![eq_a, e1.clone(), e.clone()])
+    } else {
+      LispVal::list(vec![eq_a, e.clone(), e2.clone()])
+    };
+    // The theorem goes on the left, so that its metavariables are assigned
+    // in preference to the metavariables in `e`.
+    let res = self.unify_core(&stmt, &tgt);
+    self.unify_cache.clear();
+    let c = if let Ok(c) = res {c} else {
+      self.lc.restore(snap);
+      return Ok(None)
+    };
+    let p = LispVal::apply_conv(if c.is_def() {LispVal::sym(c)} else {c}, tgt.clone(), p);
+    if !rtl {return Ok(Some((e2, p)))}
+    let symm = self.eq_lemmas(eq).expect("equation").1.symm;
+    let args = self.match_thm(symm, &LispVal::undef(), &[tgt]).ok_or_else(||
+      format!("rewrite: symmetry lemma '{}' does not apply", self.print(&symm)))?;
+    Ok(Some((e1, self.apply_thm(sp, symm, args, vec![p]).0)))
+  }
+
+  /// Rewrite the subterms of `e` using `rules`. Returns `None` if nothing was rewritten,
+  /// or else the new expression `e'` and a proof of `e = e'`, built from the
+  /// registered congruence lemmas.
+  fn rewrite_core(&mut self, sp: Span,
+    rules: &mut Vec<RewriteRule>, mode: RewriteMode, e: &LispVal
+  ) -> SResult<Option<(LispVal, LispVal)>> {
+    self.use_fuel()?;
+    for i in 0..rules.len() {
+      match rules[i] {
+        RewriteRule::Inst(ref e1, ref e2, ref p) =>
+          if e1 == e {return Ok(Some((e2.clone(), p.clone())))},
+        RewriteRule::Thm(th) => if let Some((e2, p)) = self.rewrite_thm(sp, th, mode.rtl, e)? {
+          if !mode.everywhere {
+            *rules = vec![RewriteRule::Inst(e.clone(), e2.clone(), p.clone())]
+          }
+          return Ok(Some((e2, p)))
+        }
+      }
+    }
+    let mut u = Uncons::from(e.clone());
+    let tid = match u.next().and_then(|h| h.as_atom()).and_then(|a| self.term(a)) {
+      Some(tid) => tid,
+      None => return Ok(None)
+    };
+    let mut args2 = vec![LispVal::atom(self.env.terms[tid].atom)];
+    let mut proofs = vec![];
+    for arg in u {
+      match self.rewrite_core(sp, rules, mode, &arg)? {
+        None => args2.push(arg),
+        Some((a2, p)) => {
+          proofs.push((arg, a2.clone(), p));
+          args2.push(a2)
+        }
+      }
+    }
+    if proofs.is_empty() {return Ok(None)}
+    let tdata = &self.env.terms[tid];
+    let th = *self.env.congrs.get(&tid).ok_or_else(|| format!(
+      "rewrite: no congruence lemma registered for '{}'", self.data[tdata.atom].name))?;
+    let eq = self.env.eqs.get(&tdata.ret.0).ok_or_else(|| format!(
+      "rewrite: no equality registered for sort '{}'", self.print(&tdata.ret.0)))?.eq;
+    let e2 = LispVal::list(args2);
+    let tgt = LispVal::list(vec![LispVal::atom(self.env.terms[eq].atom), e.clone(), e2.clone()]);
+    let cargs = self.match_thm(th, &tgt, &[])
+      .filter(|args| args.iter().all(|a| a.is_def()))
+      .ok_or_else(|| format!("rewrite: congruence lemma '{}' does not apply to {}",
+        self.print(&th), self.print(&tgt)))?;
+    let td = &self.env.thms[th];
+    let mut subst = Subst::new(&self.env, &td.heap, cargs.clone());
+    let hyps = td.hyps.iter().map(|(_, h)| subst.subst(h)).collect::<Vec<_>>();
+    let mut ps = Vec::with_capacity(hyps.len());
+    for h in hyps {
+      let mut u = Uncons::from(h.clone());
+      let p = match (u.next().and_then(|h| h.as_atom()).and_then(|a| self.term(a)), u.next(), u.next()) {
+        (Some(eq), Some(x), Some(y)) if x == y => {
+          let refl = self.eq_lemmas(eq).map(|(_, l)| l.refl);
+          let args = refl.and_then(|refl| Some((refl, self.match_thm(refl, &h, &[])?)));
+          let (refl, args) = args.ok_or_else(|| format!(
+            "rewrite: no reflexivity lemma applies to {}", self.print(&h)))?;
+          self.apply_thm(sp, refl, args, vec![]).0
+        }
+        (Some(_), Some(x), Some(y)) =>
+          if let Some((_, _, p)) = proofs.iter().find(|(a, a2, _)| *a == x && *a2 == y) {
+            p.clone()
+          } else {
+            return Err(format!("rewrite: unexpected hypothesis {} in congruence lemma '{}'",
+              self.print(&h), self.print(&th)))
+          },
+        _ => return Err(format!("rewrite: unexpected hypothesis {} in congruence lemma '{}'",
+          self.print(&h), self.print(&th)))
+      };
+      ps.push(p);
+    }
+    Ok(Some((e2, self.apply_thm(sp, th, cargs, ps).0)))
+  }
+
+  /// The `rewrite` tactic. Given a goal `tgt` and a list of equations `ths`, each of which
+  /// has the form `th: $ a = b $` for some registered equality, this finds instances of
+  /// the equations in `tgt`, rewrites them, and returns a refine script `(:verb p)`, where
+  /// `p` is a proof of `tgt` from a new goal for the rewritten statement.
+  pub fn rewrite(&mut self, sp: Span, tgt: &LispVal, ths: &[ThmID], mode: RewriteMode) -> SResult<LispVal> {
+    for &th in ths { self.rewrite_eqn(th, mode.rtl)?; }
+    let mut rules = ths.iter().map(|&th| RewriteRule::Thm(th)).collect();
+    let s = Uncons::from(tgt.clone()).next().and_then(|h| h.as_atom()).and_then(|a| self.term(a))
+      .map(|t| self.env.terms[t].ret.0)
+      .ok_or_else(|| format!("rewrite: expected a term, got {}", self.print(tgt)))?;
+    let lems = *self.env.eqs.get(&s).ok_or_else(|| format!(
+      "rewrite: no equality registered for sort '{}'", self.print(&s)))?;
+    let mp = lems.mp.ok_or_else(|| format!(
+      "rewrite: no transport lemma registered for sort '{}'", self.print(&s)))?;
+    let (tgt2, c) = self.rewrite_core(sp, &mut rules, mode, tgt)?
+      .ok_or_else(|| format!("rewrite: no instances found in {}", self.print(tgt)))?;
+    let eq = LispVal::list(vec![LispVal::atom(self.env.terms[lems.eq].atom), tgt.clone(), tgt2]);
+    let args = self.match_thm(mp, tgt, &[eq]).ok_or_else(||
+      format!("rewrite: transport lemma '{}' does not apply", self.print(&mp)))?;
+    let p = self.apply_thm(sp, mp, args, vec![c]).0;
+    Ok(LispVal::list(vec![LispVal::atom(AtomID::VERB), p]))
+  }
+
+  /// If `e` is an application of the definition `t`, return its arguments and the
+  /// unfolded expression. Dummy variables in the body become new dummy variables
+  /// in the local context.
+  fn unfold_def(&mut self, t: TermID, e: &LispVal) -> Option<(Vec<LispVal>, LispVal)> {
+    let tdata = &self.env.terms[t];
+    let mut u = Uncons::from(e.clone());
+    if u.next().and_then(|h| h.as_atom()) != Some(tdata.atom) {return None}
+    let val = if let TermKind::Def(Some(val)) = &tdata.kind {val.clone()} else {return None};
+    let args = u.collect::<Vec<_>>();
+    if args.len() != tdata.args.len() {return None}
+    let mut ds = vec![];
+    for e in val.heap[args.len()..].iter().chain(Some(&val.head)) {dummies(e, &mut ds)}
+    let ds = ds.into_iter().map(|(a, s)| {
+      let base = self.env.data[a].name.clone();
+      let mut x = a;
+      for n in 2.. {
+        if !self.lc.vars.contains_key(&x) {break}
+        let mut name = base.to_vec();
+        name.extend_from_slice(n.to_string().as_bytes());
+        x = self.env.get_atom(&name);
+      }
+      self.lc.vars.insert(x, (true, InferSort::Bound(s)));
+      (a, x)
+    }).collect::<Vec<_>>();
+    let body = Subst::new(&self.env, &val.heap, args.clone()).subst_dummies(&|a|
+      ds.iter().find(|&&(b, _)| a == b).map_or(a, |&(_, x)| x), &val.head);
+    Some((args, body))
+  }
+
+  /// Unfold all occurrences of the definition `t` in `e`, including the ones that appear
+  /// after unfolding. Returns `None` if there are none, or else the new expression `e'`
+  /// and a conversion proof of `e = e'`.
+  fn conv_unfold_all(&mut self, t: TermID, e: &LispVal) -> SResult<Option<(LispVal, LispVal)>> {
+    self.use_fuel()?;
+    if let Some((args, body)) = self.unfold_def(t, e) {
+      let a = self.env.terms[t].atom;
+      return Ok(Some(match self.conv_unfold_all(t, &body)? {
+        Some((e2, c)) => (e2, LispVal::unfold(a, args, c)),
+        None => (body.clone(), LispVal::unfold(a, args, body)),
+      }))
+    }
+    let mut u = Uncons::from(e.clone());
+    let head = match u.next() {
+      Some(h) if h.as_atom().and_then(|a| self.term(a)).is_some() => h,
+      _ => return Ok(None)
+    };
+    let (mut es, mut cs, mut changed) = (vec![head.clone()], vec![head], false);
+    for arg in u {
+      if let Some((e2, c)) = self.conv_unfold_all(t, &arg)? {
+        es.push(e2);
+        cs.push(c);
+        changed = true;
+      } else {
+        es.push(arg.clone());
+        cs.push(arg);
+      }
+    }
+    Ok(if changed {Some((LispVal::list(es), LispVal::list(cs)))} else {None})
+  }
+
+  /// Fold the expression `e` into an application of the definition `t`, whose arguments
+  /// are found by unification. Returns the folded expression `e'` and a conversion proof
+  /// of `e = e'`.
+  fn conv_fold(&mut self, t: TermID, e: &LispVal) -> SResult<(LispVal, LispVal)> {
+    let tdata = &self.env.terms[t];
+    let a = tdata.atom;
+    let val = if let TermKind::Def(Some(val)) = &tdata.kind {val.clone()} else {
+      return Err(format!("conv: '{}' is not a definition", self.print(&t)))
+    };
+    let mut args = Vec::with_capacity(tdata.args.len());
+    for (_, ty) in &*tdata.args {
+      let tgt = self.type_target(ty);
+      args.push(self.lc.new_mvar(tgt, None))
+    }
+    let body = Subst::new(&self.env, &val.heap, args.clone()).subst_mut(&mut self.lc, &val.head);
+    let c = self.unify1(&body, e).map_err(|err| format!(
+      "conv: {} is not definitionally equal to an instance of '{}'\n{}",
+      self.print(e), self.print(&t), err))?;
+    if args.iter().any(|a| a.as_mvar(|_, _| ()).is_some()) {
+      return Err(format!("conv: could not determine the arguments of '{}' in {}",
+        self.print(&t), self.print(e)))
+    }
+    let mut e2 = vec![LispVal::atom(a)];
+    e2.extend_from_slice(&args);
+    Ok((LispVal::list(e2), LispVal::sym(LispVal::unfold(a, args, if c.is_def() {c} else {body}))))
+  }
+
+  /// Find the subterm of `e` selected by `loc`, which is either a list of 1-based
+  /// argument positions, or a pattern, which selects the first matching subterm in
+  /// preorder. Returns the path to the subterm as a list of 0-based positions.
+  fn conv_path(&self, e: &LispVal, loc: &LispVal) -> SResult<Vec<usize>> {
+    fn find(subst: &[Option<Pat>], pat: &Pat, e: &Pat, path: &mut Vec<usize>) -> bool {
+      if unify(&mut subst.to_vec(), pat, e) {return true}
+      if let Pat::App(_, es) = e {
+        for (i, e) in es.iter().enumerate() {
+          path.push(i);
+          if find(subst, pat, e, path) {return true}
+          path.pop();
+        }
+      }
+      false
+    }
+    let mut path = vec![];
+    if loc.is_list() && Uncons::from(loc.clone()).all(|n| n.as_int(|_| ()).is_some()) {
+      for n in Uncons::from(loc.clone()) {
+        match n.as_int(ToPrimitive::to_usize).flatten() {
+          Some(n) if n > 0 => path.push(n - 1),
+          _ => return Err(format!("conv: invalid position {}", self.print(&n)))
+        }
+      }
+      return Ok(path)
+    }
+    let mut vars = (0, HashMap::new());
+    let pat = self.env.lisp_pat(Some(&self.lc), &mut vars, loc).map_err(|e|
+      format!("conv: invalid pattern {}", self.print(&e)))?;
+    let e2 = self.env.lisp_pat(Some(&self.lc), &mut vars, e).map_err(|e|
+      format!("conv: invalid goal {}", self.print(&e)))?;
+    if find(&vec![None; vars.0], &pat, &e2, &mut path) {return Ok(path)}
+    Err(format!("conv: pattern {} not found in {}", self.print(loc), self.print(e)))
+  }
+
+  /// Apply `f` to the subterm of `e` at `path`. The function `f` returns the new subterm
+  /// and a conversion proof, and this returns the new expression `e'` and a conversion
+  /// proof of `e = e'`, built by congruence.
+  fn conv_at(&mut self, e: &LispVal, path: &[usize],
+    f: &mut impl FnMut(&mut Self, &LispVal) -> SResult<(LispVal, LispVal)>
+  ) -> SResult<(LispVal, LispVal)> {
+    let (i, path) = match path.split_first() {
+      Some((&i, path)) => (i + 1, path),
+      None => return f(self, e)
+    };
+    let mut es = Uncons::from(e.clone()).collect::<Vec<_>>();
+    if es.first().and_then(|h| h.as_atom()).and_then(|a| self.term(a)).is_none() || i >= es.len() {
+      return Err(format!("conv: no argument {} in {}", i, self.print(e)))
+    }
+    let (e2, c) = self.conv_at(&es[i], path, f)?;
+    let c = if c.is_def() {
+      let mut cs = es.clone();
+      cs[i] = c;
+      LispVal::list(cs)
+    } else {c};
+    es[i] = e2;
+    Ok((LispVal::list(es), c))
+  }
+
+  /// The `conv` tactic. Given a goal `tgt` and a list of steps, each of which unfolds,
+  /// folds or changes a subterm of the goal, this returns a refine script `(:verb p)`,
+  /// where `p` is a proof of `tgt` from a new goal for the changed statement, using
+  /// conversion proofs. The steps are:
+  /// * `(unfold t)`: unfold all occurrences of the definition `t`.
+  /// * `(unfold t loc)`: unfold the application of `t` at `loc`.
+  /// * `(fold t [loc])`: fold the subterm at `loc` (by default the whole goal)
+  ///   into an application of `t`.
+  /// * `(change e [loc])`: replace the subterm at `loc` by `e`, which must be
+  ///   definitionally equal to it.
+  ///
+  /// See [`conv_path`](Self::conv_path) for the syntax of locations.
+  pub fn conv(&mut self, sp: Span, tgt: &LispVal, steps: &[LispVal]) -> SResult<LispVal> {
+    let mut e = tgt.clone();
+    let mut convs = vec![];
+    for step in steps {
+      let mut u = Uncons::from(step.clone());
+      let (op, arg, loc) = match (u.next().and_then(|h| h.as_atom()), u.next(), u.next(), u.is_empty()) {
+        (Some(op), Some(arg), loc, true) => (op, arg, loc),
+        _ => return Err(format!("conv: invalid step {}", self.print(step)))
+      };
+      let path = match loc {
+        Some(loc) => Some(self.conv_path(&e, &loc)?),
+        None => None
+      };
+      let def = |this: &Self| match arg.as_atom().and_then(|a| this.term(a)) {
+        Some(t) if matches!(this.env.terms[t].kind, TermKind::Def(Some(_))) => Ok(t),
+        _ => Err(format!("conv: {} is not a definition", this.print(&arg)))
+      };
+      let (e2, c) = match (&*self.data[op].name, path) {
+        (b"unfold", None) => {
+          let t = def(self)?;
+          self.conv_unfold_all(t, &e)?.ok_or_else(|| format!(
+            "conv: no occurrences of '{}' in {}", self.print(&t), self.print(&e)))?
+        }
+        (b"unfold", Some(path)) => {
+          let t = def(self)?;
+          let a = self.env.terms[t].atom;
+          self.conv_at(&e, &path, &mut |this, e| match this.unfold_def(t, e) {
+            Some((args, body)) => Ok((body.clone(), LispVal::unfold(a, args, body))),
+            None => Err(format!("conv: expected an application of '{}', got {}",
+              this.print(&t), this.print(e)))
+          })?
+        }
+        (b"fold", path) => {
+          let t = def(self)?;
+          self.conv_at(&e, &path.unwrap_or_default(), &mut |this, e| this.conv_fold(t, e))?
+        }
+        (b"change", path) => self.conv_at(&e, &path.unwrap_or_default(), &mut |this, e| {
+          let c = this.unify1(e, &arg).map_err(|err| format!(
+            "conv: {} is not definitionally equal to {}\n{}", this.print(e), this.print(&arg), err))?;
+          Ok((arg.clone(), c))
+        })?,
+        _ => return Err(format!("conv: unknown step '{}'", self.print(&op)))
+      };
+      convs.push((e, c));
+      e = e2;
+    }
+    let mut p = self.new_goal(sp, e);
+    for (tgt, c) in convs.into_iter().rev() { p = LispVal::apply_conv(c, tgt, p) }
+    Ok(LispVal::list(vec![LispVal::atom(AtomID::VERB), p]))
+  }
+
+  /// Generate and prove the congruence lemmas for the definition `t`. This adds `teqi`,
+  /// with a hypothesis `$ a = b $` for every argument whose sort has a registered equality,
+  /// and if there is more than one such argument, `teqNi` with a hypothesis only for the
+  /// `N`th one. The proofs unfold `t` and use the lemmas registered with `register-eq`
+  /// and `register-congr`. Lemmas that already exist are skipped, and `teqi` becomes the
+  /// congruence lemma for `t` if none is registered yet. Returns the list of new theorems.
+  pub fn derive_congr(&mut self, fsp: &FileSpan, t: TermID) -> Result<LispVal> {
+    fn fresh(env: &mut Environment, used: &mut Vec<AtomID>, base: &[u8]) -> AtomID {
+      let mut s = base.to_vec();
+      for n in 2.. {
+        let a = env.get_atom(&s);
+        if !used.contains(&a) {used.push(a); return a}
+        s = base.to_vec();
+        s.extend_from_slice(n.to_string().as_bytes());
+      }
+      unreachable!()
+    }
+    let err = |msg: String| ElabError::new_e(fsp.span, msg);
+    let tdata = &self.env.terms[t];
+    let (tatom, args, ret) = (tdata.atom, tdata.args.clone(), tdata.ret.0);
+    let span = if tdata.span.file == fsp.file {tdata.span.clone()} else {fsp.clone()};
+    let val = match &tdata.kind {
+      TermKind::Def(Some(val)) => val.clone(),
+      _ => return Err(err(format!("derive-congr: '{}' is not a definition", self.print(&t))))
+    };
+    let lems = *self.env.eqs.get(&ret).ok_or_else(|| err(format!(
+      "derive-congr: no equality registered for sort '{}'", self.print(&ret))))?;
+    let eq = LispVal::atom(self.env.terms[lems.eq].atom);
+    let mut used = vec![];
+    let names = args.iter().map(|(a, _)| match *a {
+      Some(a) => {
+        let name = self.env.data[a].name.clone();
+        fresh(&mut self.env, &mut used, &name)
+      }
+      None => fresh(&mut self.env, &mut used, b"v"),
+    }).collect::<Vec<_>>();
+    // The arguments with a registered equality, with the name of the second copy
+    // and the equality on the sort.
+    let mut cong = vec![];
+    for (i, (_, ty)) in args.iter().enumerate() {
+      if let Type::Reg(s, _) = *ty {
+        if let Some(l) = self.env.eqs.get(&s) {
+          let eq = LispVal::atom(self.env.terms[l.eq].atom);
+          let name = self.env.data[names[i]].name.clone();
+          cong.push((i, fresh(&mut self.env, &mut used, &name), eq))
+        }
+      }
+    }
+    if cong.is_empty() {
+      return Err(err(format!("derive-congr: no argument of '{}' has a registered equality",
+        self.print(&t))))
+    }
+    let mut ds = vec![];
+    for e in val.heap[args.len()..].iter().chain(Some(&val.head)) {dummies(e, &mut ds)}
+    let ds = ds.into_iter().map(|(a, s)| {
+      let name = self.env.data[a].name.clone();
+      (a, fresh(&mut self.env, &mut used, &name), s)
+    }).collect::<Vec<_>>();
+    let bvs = args.iter().zip(&names)
+      .filter(|((_, ty), _)| matches!(ty, Type::Bound(_))).map(|(_, &a)| a).collect::<Vec<_>>();
+    let binder = |this: &Self, a: AtomID, ty: &Type| match *ty {
+      Type::Bound(s) => LispVal::list(vec![LispVal::atom(a), LispVal::atom(this.sorts[s].atom)]),
+      Type::Reg(s, deps) => LispVal::list(vec![LispVal::atom(a), LispVal::atom(this.sorts[s].atom),
+        LispVal::list(bvs.iter().enumerate().filter(|&(i, _)| deps & (1 << i) != 0)
+          .map(|(_, &x)| LispVal::atom(x)).collect::<Vec<_>>())]),
+    };
+    let mut lemmas = vec![(b"eqi".to_vec(), (0..cong.len()).collect::<Vec<_>>())];
+    if cong.len() > 1 {
+      lemmas.extend((0..cong.len()).map(|k| (format!("eq{}i", k + 1).into_bytes(), vec![k])))
+    }
+    let mut out = vec![];
+    for (suffix, changed) in lemmas {
+      let mut name = self.env.data[tatom].name.to_vec();
+      name.extend_from_slice(&suffix);
+      let x = self.env.get_atom(&name);
+      if self.env.data[x].decl.is_some() {continue}
+      let mut used = used.clone();
+      let (mut bis, mut hyps, mut es2, mut rules) = (vec![], vec![], names.clone(), vec![]);
+      for (i, (_, ty)) in args.iter().enumerate() {
+        bis.push(binder(self, names[i], ty));
+        if let Some(k) = changed.iter().position(|&k| cong[k].0 == i) {
+          let (_, b, ref eq) = cong[changed[k]];
+          bis.push(binder(self, b, ty));
+          let hyp = if changed.len() == 1 {b"h".to_vec()} else {format!("h{}", k + 1).into_bytes()};
+          let hyp = LispVal::atom(fresh(&mut self.env, &mut used, &hyp));
+          let (lhs, rhs) = (LispVal::atom(names[i]), LispVal::atom(b));
+          hyps.push(LispVal::list(vec![hyp.clone(),
+            LispVal::list(vec![eq.clone(), lhs.clone(), rhs.clone()])]));
+          rules.push(RewriteRule::Inst(lhs, rhs, hyp));
+          es2[i] = cong[changed[k]].1;
+        }
+      }
+      let dummy = |a| ds.iter().find(|&&(b, _, _)| a == b).map_or(a, |&(_, b, _)| b);
+      let es1 = names.iter().map(|&a| LispVal::atom(a)).collect::<Vec<_>>();
+      let es2 = es2.into_iter().map(LispVal::atom).collect::<Vec<_>>();
+      let b1 = Subst::new(&self.env, &val.heap, es1.clone()).subst_dummies(&dummy, &val.head);
+      let b2 = Subst::new(&self.env, &val.heap, es2.clone()).subst_dummies(&dummy, &val.head);
+      let mode = RewriteMode {rtl: false, everywhere: true};
+      let pf = if let Some((_, pf)) = self.rewrite_core(fsp.span, &mut rules, mode, &b1)
+        .map_err(|e| err(format!("derive-congr: {}", e)))? {pf} else {
+        let refl = LispVal::list(vec![eq.clone(), b1.clone(), b1.clone()]);
+        let args = self.match_thm(lems.refl, &refl, &[]).ok_or_else(|| err(format!(
+          "derive-congr: reflexivity lemma '{}' does not apply", self.print(&lems.refl))))?;
+        self.apply_thm(fsp.span, lems.refl, args, vec![]).0
+      };
+      let app = |es: &[LispVal]| {
+        let mut e = vec![LispVal::atom(tatom)];
+        e.extend_from_slice(es);
+        LispVal::list(e)
+      };
+      let tgt = LispVal::list(vec![eq.clone(), app(&es1), app(&es2)]);
+      let unfolded = LispVal::list(vec![eq.clone(),
+        LispVal::unfold(tatom, es1, b1), LispVal::unfold(tatom, es2, b2)]);
+      let ds = LispVal::list(ds.iter().map(|&(_, b, s)|
+        LispVal::list(vec![LispVal::atom(b), LispVal::atom(self.sorts[s].atom)])).collect::<Vec<_>>());
+      let proof = LispVal::list(vec![ds, LispVal::conv(tgt.clone(), unfolded, pf)]);
+      let decl = [LispVal::atom(x).span(span.clone()), LispVal::list(bis), LispVal::list(hyps),
+        tgt, LispVal::nil(), proof];
+      if self.add_thm(fsp.clone(), &decl)?.is_err() {
+        return Err(err("derive-congr: unexpected proof task".into()))
+      }
+      if let Some(DeclKey::Thm(th)) = self.env.data[x].decl {
+        let doc = if changed.len() == cong.len() {
+          self.env.congrs.entry(t).or_insert(th);
+          format!("Congruence for `{}`, generated by `derive-congr`.", self.print(&t))
+        } else {
+          format!("Congruence for `{}` in the argument `{}`, generated by `derive-congr`.",
+            self.print(&t), self.print(&names[cong[changed[0]].0]))
+        };
+        self.env.thms[th].doc = Some(doc.into());
+      }
+      out.push(LispVal::atom(x));
+    }
+    Ok(LispVal::list(out))
+  }
+}
@@ -0,0 +1,150 @@
+//! Elaborator support for the interactive REPL (`mm0-rs repl`).
+//!
+//! A REPL session is an [`Elaborator`] working on a virtual file that contains all the
+//! input entered so far. New input is appended to the end of this file and parsed
+//! incrementally, so the spans stored by earlier definitions remain valid.
+
+use std::fmt::Write;
+use std::mem;
+use std::path::PathBuf;
+use std::result::Result as StdResult;
+use std::sync::Arc;
+use crate::lined_string::LinedString;
+use crate::parser::{parse, ParseError, ast::{AST, Formula, SExpr, Stmt, StmtKind}};
+use crate::util::{FileRef, Span};
+use super::{Elaborator, ElabError, ElabStmt, FrozenEnv, Result};
+use super::lisp::{LispKind, LispVal};
+
+/// The result of [`Elaborator::repl_stmt`].
+#[derive(Debug)]
+pub enum ReplResult {
+  /// The statement was elaborated. For `do` blocks, this contains the values
+  /// of the expressions that are not `#undef`, to be printed by the caller.
+  Ok(Vec<LispVal>),
+  /// The statement is an `import`, of the given (resolved) file. The caller should
+  /// elaborate the file and add it to the session using [`Elaborator::repl_import`].
+  Import(Span, FileRef),
+}
+
+impl Elaborator {
+  /// Create an elaborator for a REPL session, with no input yet. `path` is the name of
+  /// the virtual file holding the input, and `import` statements are resolved relative to it.
+  #[must_use] pub fn new_repl(path: FileRef, check_proofs: bool) -> Elaborator {
+    let ast = parse(Arc::default(), None).1;
+    Elaborator::new(Arc::new(ast), path, false, check_proofs, Arc::default())
+  }
+
+  /// The AST of the input so far.
+  #[must_use] pub fn repl_ast(&self) -> &Arc<AST> { &self.ast }
+
+  /// Append `input` to the session and parse it, returning the new statements.
+  /// If there are parse errors in the new input, the session is left unchanged.
+  pub fn repl_parse(&mut self, input: &str) -> StdResult<Vec<Stmt>, Vec<ParseError>> {
+    let start = self.ast.source.len();
+    let mut text = LinedString::clone(&self.ast.source);
+    let pos = text.end();
+    text.extend(input);
+    text.extend("\n");
+    let (_, ast) = parse(Arc::new(text), Some((pos, self.ast.clone())));
+    let errors: Vec<_> = ast.errors.iter().filter(|e| e.pos.start >= start).cloned().collect();
+    if !errors.is_empty() {return Err(errors)}
+    let stmts = ast.stmts.iter().filter(|s| s.span.start >= start).cloned().collect();
+    self.ast = Arc::new(ast);
+    Ok(stmts)
+  }
+
+  /// Append the math formula `$ input $` to the session, and evaluate it like a `$ ... $`
+  /// expression in lisp. The input is only parsed as a formula, so it may not contain `$`.
+  pub fn repl_formula(&mut self, input: &str) -> Result<LispVal> {
+    let start = self.ast.source.len();
+    let mut text = LinedString::clone(&self.ast.source);
+    text.extend("$ ");
+    text.extend(input);
+    text.extend(" $\n");
+    let end = text.len() - 1;
+    self.ast = Arc::new(AST {
+      source: Arc::new(text),
+      imports: self.ast.imports.clone(),
+      stmts: self.ast.stmts.clone(),
+      errors: self.ast.errors.clone(),
+    });
+    if let Some(i) = input.find('$') {
+      let pos = start + 2 + i;
+      return Err(ElabError::new_e(pos..pos + 1, "unexpected '$' in formula"))
+    }
+    self.reset_limits();
+    let e = self.parse_formula(Formula((start..end).into()))?;
+    self.eval_qexpr(e)
+  }
+
+  /// Take the errors reported since the last call.
+  pub fn take_errors(&mut self) -> Vec<ElabError> { mem::take(&mut self.errors) }
+
+  /// Elaborate a statement entered at the REPL. Theorem proofs are elaborated using `prove`
+  /// (see [`elab_decl_with`](Self::elab_decl_with)).
+  pub fn repl_stmt(&mut self, stmt: &Stmt,
+      prove: impl FnMut(&mut Self, &SExpr) -> Result<()>) -> Result<ReplResult> {
+    let res = match &stmt.k {
+      StmtKind::Do(es) => {
+        self.reset_limits();
+        self.spans.set_stmt(stmt.span);
+        let mut vals = vec![];
+        for e in es {
+          let v = self.eval_lisp(e)?;
+          if v.is_def() {vals.push(v)}
+        }
+        ReplResult::Ok(vals)
+      }
+      StmtKind::Decl(d) => {
+        self.reset_limits();
+        self.spans.set_stmt(stmt.span);
+        self.elab_decl_with(stmt.span, d, None, prove)?;
+        ReplResult::Ok(vec![])
+      }
+      &StmtKind::Import(sp, ref f) => {
+        let f = std::str::from_utf8(f).map_err(|e| ElabError::new_e(sp, e))?;
+        let path = self.path.path().parent().map_or_else(|| PathBuf::from(f), |p| p.join(f));
+        let r: FileRef = path.canonicalize().map_err(|e| ElabError::new_e(sp, e))?.into();
+        return Ok(ReplResult::Import(sp, r))
+      }
+      _ => match self.elab_stmt(String::new(), stmt, stmt.span)? {
+        ElabStmt::Ok => ReplResult::Ok(vec![]),
+        ElabStmt::Import(_) => unreachable!(),
+      }
+    };
+    self.push_spans();
+    Ok(res)
+  }
+
+  /// Add the environment of an imported file to the session.
+  pub fn repl_import(&mut self, sp: Span, env: &FrozenEnv) {
+    let r = self.env.merge(env, sp, &mut self.errors);
+    self.catch(r)
+  }
+
+  /// Run a tactic against the current goals, as one step of a proof. This is the same as
+  /// evaluating `(refine e)`, except that `#undef` results are ignored.
+  pub fn repl_tactic(&mut self, e: &SExpr) -> Result<()> {
+    self.reset_limits();
+    self.elab_lisp(e).map(drop)
+  }
+
+  /// Print the current proof state: the hypotheses and the goals.
+  #[must_use] pub fn repl_goals(&self, width: usize) -> String {
+    let fe = self.format_env();
+    let mut s = String::new();
+    for (a, e, _) in &self.lc.proof_order {
+      writeln!(s, "{}: {}", fe.to(a), fe.pp(e, width)).expect("writing to a string")
+    }
+    if self.lc.goals.is_empty() {
+      s.push_str("no goals\n")
+    } else {
+      for g in &self.lc.goals {
+        g.unwrapped(|r| if let LispKind::Goal(e) = r {
+          writeln!(s, "|- {}", fe.pp(e, width)).expect("writing to a string")
+        })
+      }
+    }
+    s
+  }
+}
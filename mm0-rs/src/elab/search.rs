@@ -0,0 +1,214 @@
+//! Theorem search by statement pattern (`find-thms`).
+//!
+//! The environment keeps a discrimination tree over the conclusions and hypotheses of all
+//! theorems, which is updated as theorems are added. A query walks the tree to find the
+//! candidate statements whose shape is compatible with the pattern, and then checks them by
+//! unification, so that repeated variables are handled correctly.
+
+use std::collections::HashMap;
+use super::environment::{AtomID, DeclKey, Environment, ExprNode, TermID, Thm, ThmID};
+use super::local_context::LocalContext;
+use super::lisp::{LispKind, LispVal, Uncons};
+
+/// The place in a theorem statement where a search pattern matched.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ThmLoc {
+  /// The conclusion of the theorem.
+  Ret,
+  /// The hypothesis with the given index.
+  Hyp(usize),
+}
+crate::deep_size_0!(ThmLoc);
+
+/// A node of the discrimination tree. The keys are the nodes of the indexed expressions in
+/// preorder, where a variable of the theorem is the wildcard `star`, and a term application
+/// is the term (whose arity determines how many of the following keys are its arguments).
+#[derive(Clone, Debug, Default, DeepSizeOf)]
+struct Node {
+  /// The subtree for a theorem variable in this position.
+  star: Option<Box<Node>>,
+  /// The subtrees for an application of each term in this position.
+  terms: HashMap<TermID, Node>,
+  /// The statements that end at this node.
+  thms: Vec<(ThmID, ThmLoc)>,
+}
+
+/// An index over the statements of all theorems in the environment,
+/// used by the `find-thms` function.
+#[derive(Clone, Debug, Default, DeepSizeOf)]
+pub struct ThmIndex {
+  root: Node,
+}
+
+/// An expression on either side of a search, with variables numbered from 0 for the variables
+/// of the pattern, and after that for the variables of the theorem.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum Pat {
+  /// A unification variable.
+  Var(usize),
+  /// A local variable of the pattern, which only matches itself or a theorem variable.
+  Const(AtomID),
+  /// A term application.
+  App(TermID, Box<[Pat]>),
+}
+
+impl ThmIndex {
+  /// Add the statement of theorem `th` to the index.
+  pub fn insert(&mut self, th: ThmID, td: &Thm) {
+    fn keys<'a>(heap: &[ExprNode], nargs: usize, e: &ExprNode, node: &'a mut Node) -> &'a mut Node {
+      match *e {
+        ExprNode::Ref(i) if i < nargs => node.star.get_or_insert_with(Default::default),
+        ExprNode::Ref(i) => keys(heap, nargs, &heap[i], node),
+        ExprNode::Dummy(_, _) => node.star.get_or_insert_with(Default::default),
+        ExprNode::App(t, ref es) =>
+          es.iter().fold(node.terms.entry(t).or_default(), |node, e| keys(heap, nargs, e, node)),
+      }
+    }
+    let nargs = td.args.len();
+    keys(&td.heap, nargs, &td.ret, &mut self.root).thms.push((th, ThmLoc::Ret));
+    for (i, (_, h)) in td.hyps.iter().enumerate() {
+      keys(&td.heap, nargs, h, &mut self.root).thms.push((th, ThmLoc::Hyp(i)));
+    }
+  }
+
+  /// Get the statements in the index whose shape is compatible with `pat`.
+  fn candidates<'a>(&'a self, env: &Environment, pat: &Pat, out: &mut Vec<&'a (ThmID, ThmLoc)>) {
+    /// Skip `n` complete expressions in the tree starting from `node`.
+    fn skip<'a>(env: &Environment, node: &'a Node, n: usize, out: &mut Vec<&'a Node>) {
+      if n == 0 {return out.push(node)}
+      if let Some(star) = &node.star {skip(env, star, n - 1, out)}
+      for (&t, node) in &node.terms {skip(env, node, n - 1 + env.terms[t].args.len(), out)}
+    }
+    fn go<'a>(env: &Environment, node: &'a Node, stack: &mut Vec<&Pat>, out: &mut Vec<&'a (ThmID, ThmLoc)>) {
+      let pat = match stack.pop() {
+        None => return out.extend(&node.thms),
+        Some(pat) => pat
+      };
+      if let Pat::Var(_) = pat {
+        let mut nodes = vec![];
+        skip(env, node, 1, &mut nodes);
+        for node in nodes {go(env, node, stack, out)}
+      } else {
+        if let Some(star) = &node.star {go(env, star, stack, out)}
+        if let Pat::App(t, es) = pat {
+          if let Some(node) = node.terms.get(t) {
+            let n = stack.len();
+            stack.extend(es.iter().rev());
+            go(env, node, stack, out);
+            stack.truncate(n);
+          }
+        }
+      }
+      stack.push(pat)
+    }
+    go(env, &self.root, &mut vec![pat], out)
+  }
+}
+
+/// Convert the expression `e` in the statement of a theorem to a [`Pat`], where the
+/// variables of the theorem are numbered from `off`.
+pub(crate) fn thm_pat(heap: &[ExprNode], nargs: usize, off: usize, e: &ExprNode) -> Pat {
+  match *e {
+    ExprNode::Ref(i) if i < nargs => Pat::Var(off + i),
+    ExprNode::Ref(i) => thm_pat(heap, nargs, off, &heap[i]),
+    ExprNode::Dummy(_, _) => unreachable!("dummy in theorem statement"),
+    ExprNode::App(t, ref es) =>
+      Pat::App(t, es.iter().map(|e| thm_pat(heap, nargs, off, e)).collect()),
+  }
+}
+
+/// Follow the assignments of variables in `subst` until reaching an unassigned variable
+/// or a non-variable. (This only resolves the head of `e`.)
+pub(crate) fn resolve<'a>(subst: &'a [Option<Pat>], mut e: &'a Pat) -> &'a Pat {
+  while let Pat::Var(v) = *e {
+    match &subst[v] {
+      Some(e2) => e = e2,
+      None => break
+    }
+  }
+  e
+}
+
+/// Syntactic first order unification, where `subst` holds the assignments of the variables.
+pub(crate) fn unify(subst: &mut Vec<Option<Pat>>, a: &Pat, b: &Pat) -> bool {
+  fn occurs(subst: &[Option<Pat>], v: usize, e: &Pat) -> bool {
+    match e {
+      &Pat::Var(w) => v == w || subst[w].as_ref().map_or(false, |e| occurs(subst, v, e)),
+      Pat::Const(_) => false,
+      Pat::App(_, es) => es.iter().any(|e| occurs(subst, v, e)),
+    }
+  }
+  match (resolve(subst, a).clone(), resolve(subst, b).clone()) {
+    (Pat::Var(v), Pat::Var(w)) if v == w => true,
+    (Pat::Var(v), e) | (e, Pat::Var(v)) => {
+      if occurs(subst, v, &e) {return false}
+      subst[v] = Some(e);
+      true
+    }
+    (Pat::Const(x), Pat::Const(y)) => x == y,
+    (Pat::App(t1, es1), Pat::App(t2, es2)) =>
+      t1 == t2 && es1.iter().zip(&*es2).all(|(e1, e2)| unify(subst, e1, e2)),
+    _ => false,
+  }
+}
+
+impl Environment {
+  /// Convert a lisp pattern to a [`Pat`]. Atoms that are variables in `lc` are constants,
+  /// and other atoms, `_` and metavariables are unification variables, counted by `vars`.
+  /// On failure, returns the subexpression that is not a valid pattern.
+  pub(crate) fn lisp_pat(&self, lc: Option<&LocalContext>,
+    vars: &mut (usize, HashMap<AtomID, usize>), e: &LispVal
+  ) -> Result<Pat, LispVal> {
+    e.unwrapped(|r| Ok(match r {
+      &LispKind::Atom(AtomID::UNDER) | LispKind::MVar(_, _) => {vars.0 += 1; Pat::Var(vars.0 - 1)}
+      &LispKind::Atom(a) => {
+        if lc.map_or(false, |lc| lc.vars.contains_key(&a)) {return Ok(Pat::Const(a))}
+        match self.data[a].decl {
+          Some(DeclKey::Term(t)) if self.terms[t].args.is_empty() => Pat::App(t, Box::new([])),
+          _ => {
+            let n = &mut vars.0;
+            Pat::Var(*vars.1.entry(a).or_insert_with(|| {*n += 1; *n - 1}))
+          }
+        }
+      }
+      LispKind::Goal(e) => return self.lisp_pat(lc, vars, e),
+      LispKind::List(_) | LispKind::DottedList(_, _) => {
+        let mut u = Uncons::from(e.clone());
+        let t = match u.next().and_then(|h| h.as_atom()).map(|a| self.data[a].decl) {
+          Some(Some(DeclKey::Term(t))) => t,
+          _ => return Err(e.clone())
+        };
+        let mut es = Vec::with_capacity(self.terms[t].args.len());
+        for e in &mut u {es.push(self.lisp_pat(lc, vars, &e)?)}
+        if es.len() != self.terms[t].args.len() || !u.is_empty() {return Err(e.clone())}
+        Pat::App(t, es.into())
+      }
+      _ => return Err(e.clone())
+    }))
+  }
+
+  /// Find the theorems whose conclusion (or a hypothesis, if `hyps` is true) unifies with
+  /// the pattern `pat`, in declaration order. Atoms in the pattern that are variables in
+  /// `lc` (usually the local context of the current proof) only match themselves, and
+  /// other atoms, `_` and metavariables can match anything. Statements that are just a
+  /// variable (like the conclusion of `ax_mp`) match everything, so they are skipped.
+  /// On failure, returns the subexpression that is not a valid pattern.
+  pub fn find_thms(&self, lc: Option<&LocalContext>, pat: &LispVal, hyps: bool) -> Result<Vec<ThmID>, LispVal> {
+    let mut vars = (0, HashMap::new());
+    let pat = self.lisp_pat(lc, &mut vars, pat)?;
+    let mut cands = vec![];
+    self.thm_index.candidates(self, &pat, &mut cands);
+    cands.sort_by_key(|&&(th, _)| th);
+    let mut out: Vec<ThmID> = vec![];
+    for &(th, loc) in cands {
+      if out.last() == Some(&th) || !hyps && loc != ThmLoc::Ret {continue}
+      let td = &self.thms[th];
+      let e = match loc {ThmLoc::Ret => &td.ret, ThmLoc::Hyp(i) => &td.hyps[i].1};
+      let e = thm_pat(&td.heap, td.args.len(), vars.0, e);
+      if let Pat::Var(_) = e {continue}
+      let mut subst = vec![None; vars.0 + td.args.len()];
+      if unify(&mut subst, &e, &pat) {out.push(th)}
+    }
+    Ok(out)
+  }
+}
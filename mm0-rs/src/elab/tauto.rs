@@ -0,0 +1,427 @@
+//! The `(tauto)` tactic, a decision procedure for propositional tautologies.
+//!
+//! The tactic knows nothing about the logic of the library, so the library has to register
+//! its propositional connectives and a set of basic lemmas using `(register-prop)`, once.
+//! The goal is decided by splitting on the propositional atoms, and the proof is built by
+//! [Kalmár's method]: in a context `G` of literals, every formula whose truth value is
+//! determined by the literals is proved, or disproved, by induction on the formula.
+//!
+//! [Kalmár's method]: https://en.wikipedia.org/wiki/Propositional_calculus#Completeness_of_the_axioms
+
+use std::sync::atomic::Ordering;
+use std::time::Instant;
+use super::Elaborator;
+use super::environment::{DeclKey, ExprNode, TermID, ThmID};
+use super::lisp::{LispVal, Uncons, eval::SResult};
+
+macro_rules! make_roles {
+  {$($(#[$attr:meta])* $x:ident: $e:expr,)*} => {
+    /// The roles of the terms and theorems in the propositional theory of a library,
+    /// registered with `(register-prop)` and used by the `(tauto)` tactic.
+    #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+    pub enum PropRole { $($(#[$attr])* $x),* }
+    crate::deep_size_0!(PropRole);
+
+    impl PropRole {
+      /// Convert a [`PropRole`] to the name used in `(register-prop)`.
+      #[must_use] pub fn to_str(self) -> &'static str {
+        match self { $(PropRole::$x => $e),* }
+      }
+      /// Parse the name of a [`PropRole`].
+      #[allow(clippy::string_lit_as_bytes)]
+      #[must_use] pub fn from_bytes(s: &[u8]) -> Option<Self> {
+        $(if s == $e.as_bytes() {return Some(PropRole::$x)})*
+        None
+      }
+    }
+  }
+}
+
+make_roles! {
+  /// The implication connective, `a -> b`.
+  Im: "im",
+  /// The negation connective, `~a`.
+  Not: "not",
+  /// The conjunction connective, `a /\ b`.
+  An: "an",
+  /// The disjunction connective, `a \/ b` (optional).
+  Or: "or",
+  /// The biconditional connective, `a <-> b` (optional).
+  Iff: "iff",
+  /// Modus ponens: `(h1: $ a -> b $) (h2: $ a $): $ b $`.
+  Mp: "mp",
+  /// `$ a -> a $`
+  Id: "id",
+  /// `$ a /\ b -> b $`
+  Anr: "anr",
+  /// `(h: $ a -> c $): $ a /\ b -> c $`
+  Anwl: "anwl",
+  /// Case analysis: `(h1: $ G /\ a -> b $) (h2: $ G /\ ~a -> b $): $ G -> b $`.
+  Cases: "cases",
+  /// `(h: $ G -> ~a $): $ G -> (a -> b) $`
+  ImI1: "im-intro1",
+  /// `(h: $ G -> b $): $ G -> (a -> b) $`
+  ImI2: "im-intro2",
+  /// `(h1: $ G -> a $) (h2: $ G -> ~b $): $ G -> ~(a -> b) $`
+  ImN: "im-neg",
+  /// `(h: $ G -> a $): $ G -> ~~a $`
+  NotN: "not-neg",
+  /// `(h1: $ G -> a $) (h2: $ G -> b $): $ G -> a /\ b $`
+  AnI: "an-intro",
+  /// `(h: $ G -> ~a $): $ G -> ~(a /\ b) $`
+  AnN1: "an-neg1",
+  /// `(h: $ G -> ~b $): $ G -> ~(a /\ b) $`
+  AnN2: "an-neg2",
+  /// `(h: $ G -> a $): $ G -> a \/ b $`
+  OrI1: "or-intro1",
+  /// `(h: $ G -> b $): $ G -> a \/ b $`
+  OrI2: "or-intro2",
+  /// `(h1: $ G -> ~a $) (h2: $ G -> ~b $): $ G -> ~(a \/ b) $`
+  OrN: "or-neg",
+  /// `(h1: $ G -> a $) (h2: $ G -> b $): $ G -> (a <-> b) $`
+  IffI1: "iff-intro1",
+  /// `(h1: $ G -> ~a $) (h2: $ G -> ~b $): $ G -> (a <-> b) $`
+  IffI2: "iff-intro2",
+  /// `(h1: $ G -> a $) (h2: $ G -> ~b $): $ G -> ~(a <-> b) $`
+  IffN1: "iff-neg1",
+  /// `(h1: $ G -> ~a $) (h2: $ G -> b $): $ G -> ~(a <-> b) $`
+  IffN2: "iff-neg2",
+}
+
+impl PropRole {
+  /// Returns true if this role is played by a term (a connective) rather than a theorem.
+  #[must_use] pub fn is_term(self) -> bool {
+    matches!(self, PropRole::Im | PropRole::Not | PropRole::An | PropRole::Or | PropRole::Iff)
+  }
+}
+
+/// A propositional formula, with the connectives that are not registered treated as atoms.
+#[derive(Clone, Debug)]
+enum Formula {
+  /// An atom, indexed into the list of atoms.
+  Atom(usize),
+  /// `~a`
+  Not(Box<Formula>),
+  /// A binary connective, `Im`, `An`, `Or` or `Iff`.
+  Bin(PropRole, Box<(Formula, Formula)>),
+}
+
+/// The working state of the `(tauto)` tactic.
+struct Tauto<'a> {
+  /// The elaborator.
+  elab: &'a mut Elaborator,
+  /// The propositional atoms.
+  atoms: Vec<LispVal>,
+  /// The implication connective, for building formulas.
+  im: LispVal,
+  /// The negation connective, for building formulas.
+  not: LispVal,
+  /// The conjunction connective, for building contexts.
+  an: LispVal,
+}
+
+impl Formula {
+  /// Evaluate a formula in a partial assignment, using three-valued logic.
+  fn eval(&self, asn: &[Option<bool>]) -> Option<bool> {
+    match self {
+      &Formula::Atom(i) => asn[i],
+      Formula::Not(a) => a.eval(asn).map(|b| !b),
+      Formula::Bin(r, ab) => match (r, ab.0.eval(asn), ab.1.eval(asn)) {
+        (PropRole::Im, Some(false), _) | (PropRole::Im, _, Some(true)) |
+        (PropRole::An, Some(true), Some(true)) |
+        (PropRole::Or, Some(true), _) | (PropRole::Or, _, Some(true)) => Some(true),
+        (PropRole::Im, Some(true), Some(false)) |
+        (PropRole::An, Some(false), _) | (PropRole::An, _, Some(false)) |
+        (PropRole::Or, Some(false), Some(false)) => Some(false),
+        (PropRole::Iff, Some(a), Some(b)) => Some(a == b),
+        _ => None,
+      }
+    }
+  }
+
+  /// The first atom in the formula which is not yet assigned.
+  fn unassigned(&self, asn: &[Option<bool>]) -> Option<usize> {
+    match self {
+      &Formula::Atom(i) => if asn[i].is_none() {Some(i)} else {None},
+      Formula::Not(a) => a.unassigned(asn),
+      Formula::Bin(_, ab) => ab.0.unassigned(asn).or_else(|| ab.1.unassigned(asn)),
+    }
+  }
+}
+
+impl Tauto<'_> {
+  /// Convert an expression to a [`Formula`], adding new atoms to the list.
+  fn parse(&mut self, e: &LispVal) -> Formula {
+    let mut u = Uncons::from(e.clone());
+    if let Some(t) = u.next().and_then(|h| h.as_atom()).and_then(|a| self.elab.term(a)) {
+      if let Some(r) = self.elab.env.prop.iter().find_map(|(&r, &k)|
+        if k == DeclKey::Term(t) && r.is_term() {Some(r)} else {None}) {
+        match (r, u.next(), u.next(), u.is_empty()) {
+          (PropRole::Not, Some(a), None, true) => return Formula::Not(Box::new(self.parse(&a))),
+          (PropRole::Not, _, _, _) => {}
+          (_, Some(a), Some(b), true) => {
+            let a = self.parse(&a);
+            return Formula::Bin(r, Box::new((a, self.parse(&b))))
+          }
+          _ => {}
+        }
+      }
+    }
+    Formula::Atom(self.atoms.iter().position(|a| a == e).unwrap_or_else(|| {
+      self.atoms.push(e.clone());
+      self.atoms.len() - 1
+    }))
+  }
+
+  /// Convert a [`Formula`] back to an expression.
+  fn expr(&self, f: &Formula) -> LispVal {
+    match f {
+      &Formula::Atom(i) => self.atoms[i].clone(),
+      Formula::Not(a) => self.not_(self.expr(a)),
+      Formula::Bin(r, ab) => LispVal::list(vec![self.term(*r), self.expr(&ab.0), self.expr(&ab.1)]),
+    }
+  }
+
+  fn term(&self, r: PropRole) -> LispVal {
+    match self.elab.env.prop.get(&r) {
+      Some(&DeclKey::Term(t)) => LispVal::atom(self.elab.env.terms[t].atom),
+      _ => unreachable!(),
+    }
+  }
+
+  fn im_(&self, a: LispVal, b: LispVal) -> LispVal { LispVal::list(vec![self.im.clone(), a, b]) }
+  fn not_(&self, a: LispVal) -> LispVal { LispVal::list(vec![self.not.clone(), a]) }
+  fn an_(&self, a: LispVal, b: LispVal) -> LispVal { LispVal::list(vec![self.an.clone(), a, b]) }
+
+  /// Apply the lemma for role `r` to get a proof of `ret`, from the given hypotheses,
+  /// which are `(type, proof)` pairs in any order.
+  fn apply(&mut self, r: PropRole, ret: &LispVal, hyps: &[(LispVal, LispVal)]) -> SResult<LispVal> {
+    self.elab.use_fuel()?;
+    let th = self.elab.prop_thm(r)?;
+    let td = &self.elab.env.thms[th];
+    let mut args = vec![LispVal::undef(); td.args.len()];
+    let mut order = vec![];
+    if td.hyps.len() == hyps.len() &&
+      self.elab.match_pattern(&td.heap, &mut args, &td.ret, ret) &&
+      self.elab.match_hyps(&td.heap, &td.hyps, &mut args, hyps, &mut order) &&
+      args.iter().all(|a| a.is_def()) {
+      let mut proof = vec![LispVal::atom(td.atom)];
+      proof.extend(args);
+      proof.extend(order.into_iter().map(|i| hyps[i].1.clone()));
+      Ok(LispVal::list(proof))
+    } else {
+      Err(format!("tauto: lemma '{}' for '{}' does not apply to {}",
+        self.elab.print(&th), r.to_str(), self.elab.print(ret)))
+    }
+  }
+
+  /// Prove `G -> l`, where `l` is the `i`th literal in the context `ctx`, which is a list
+  /// of pairs `(G, l)` where `G` is the formula `G0 /\ l1 /\ ... /\ l` for the context so far.
+  fn proj(&mut self, ctx: &[(LispVal, LispVal)], i: usize) -> SResult<LispVal> {
+    let ret = self.im_(ctx.last().expect("nonempty context").0.clone(), ctx[i].1.clone());
+    if i + 1 == ctx.len() { return self.apply(PropRole::Anr, &ret, &[]) }
+    let ctx2 = &ctx[..ctx.len() - 1];
+    let h = self.im_(ctx2.last().expect("nonempty context").0.clone(), ctx[i].1.clone());
+    let p = self.proj(ctx2, i)?;
+    self.apply(PropRole::Anwl, &ret, &[(h, p)])
+  }
+
+  /// Prove `G -> f` if `b` is true, or `G -> ~f` if `b` is false, where the truth value
+  /// of `f` in the assignment `asn` is `b`, and `ctx` is the context for the assignment.
+  fn kalmar(&mut self, ctx: &[(LispVal, LispVal)], asn: &[Option<bool>], f: &Formula, b: bool) -> SResult<(LispVal, LispVal)> {
+    let hyp = ctx.last().expect("nonempty context").0.clone();
+    let e = self.expr(f);
+    let tgt = if b {e} else {self.not_(e)};
+    let ret = self.im_(hyp, tgt);
+    let proof = match f {
+      &Formula::Atom(i) => {
+        let lit = if b {self.atoms[i].clone()} else {self.not_(self.atoms[i].clone())};
+        let pos = ctx.iter().rposition(|(_, l2)| *l2 == lit).expect("assigned atom");
+        self.proj(ctx, pos)?
+      }
+      Formula::Not(a) if b => return self.kalmar(ctx, asn, a, false),
+      Formula::Not(a) => {
+        let sub = self.kalmar(ctx, asn, a, true)?;
+        self.apply(PropRole::NotN, &ret, &[sub])?
+      }
+      Formula::Bin(r, ab) => {
+        let (ea, eb) = (ab.0.eval(asn), ab.1.eval(asn));
+        let (r2, hs) = match (r, b, ea, eb) {
+          (PropRole::Im, true, Some(false), _) => (PropRole::ImI1, vec![(&ab.0, false)]),
+          (PropRole::Im, true, _, _) => (PropRole::ImI2, vec![(&ab.1, true)]),
+          (PropRole::Im, false, _, _) => (PropRole::ImN, vec![(&ab.0, true), (&ab.1, false)]),
+          (PropRole::An, true, _, _) => (PropRole::AnI, vec![(&ab.0, true), (&ab.1, true)]),
+          (PropRole::An, false, Some(false), _) => (PropRole::AnN1, vec![(&ab.0, false)]),
+          (PropRole::An, false, _, _) => (PropRole::AnN2, vec![(&ab.1, false)]),
+          (PropRole::Or, true, Some(true), _) => (PropRole::OrI1, vec![(&ab.0, true)]),
+          (PropRole::Or, true, _, _) => (PropRole::OrI2, vec![(&ab.1, true)]),
+          (PropRole::Or, false, _, _) => (PropRole::OrN, vec![(&ab.0, false), (&ab.1, false)]),
+          (_, true, Some(true), _) => (PropRole::IffI1, vec![(&ab.0, true), (&ab.1, true)]),
+          (_, true, _, _) => (PropRole::IffI2, vec![(&ab.0, false), (&ab.1, false)]),
+          (_, false, Some(true), _) => (PropRole::IffN1, vec![(&ab.0, true), (&ab.1, false)]),
+          (_, false, _, _) => (PropRole::IffN2, vec![(&ab.0, false), (&ab.1, true)]),
+        };
+        let mut hyps = Vec::with_capacity(hs.len());
+        for (f, b) in hs { hyps.push(self.kalmar(ctx, asn, f, b)?) }
+        self.apply(r2, &ret, &hyps)?
+      }
+    };
+    Ok((ret, proof))
+  }
+
+  /// Prove `G -> f` for all extensions of the assignment `asn`, by splitting on the atoms
+  /// until the value of `f` is determined.
+  fn split(&mut self, ctx: &mut Vec<(LispVal, LispVal)>, asn: &mut Vec<Option<bool>>, f: &Formula) -> SResult<(LispVal, LispVal)> {
+    match f.eval(asn) {
+      Some(true) => self.kalmar(ctx, asn, f, true),
+      Some(false) => {
+        let fe = self.elab.format_env();
+        let lits = ctx[1..].iter().map(|(_, l)| format!("{}", fe.pp(l, 80))).collect::<Vec<_>>();
+        Err(if lits.is_empty() {"tauto: not a tautology".into()} else {
+          format!("tauto: not a tautology, it is false when {}", lits.join(", "))
+        })
+      }
+      None => {
+        let i = f.unassigned(asn).expect("undetermined formula has an unassigned atom");
+        let hyp = ctx.last().expect("nonempty context").0.clone();
+        let mut hyps = Vec::with_capacity(2);
+        for &b in &[true, false] {
+          if self.elab.cur_timeout.map_or(false, |t| t < Instant::now()) {return Err("timeout".into())}
+          if self.elab.cancel.load(Ordering::Relaxed) {return Err("cancelled".into())}
+          let lit = if b {self.atoms[i].clone()} else {self.not_(self.atoms[i].clone())};
+          ctx.push((self.an_(hyp.clone(), lit.clone()), lit));
+          asn[i] = Some(b);
+          let res = self.split(ctx, asn, f);
+          asn[i] = None;
+          ctx.pop();
+          hyps.push(res?);
+        }
+        let ret = self.im_(hyp, self.expr(f));
+        let proof = self.apply(PropRole::Cases, &ret, &hyps)?;
+        Ok((ret, proof))
+      }
+    }
+  }
+
+  /// Prove the formula `goal` from the hypotheses `hyps`, which are `(type, proof)` pairs.
+  fn prove(&mut self, goal: &Formula, hyps: &[(LispVal, LispVal)]) -> SResult<LispVal> {
+    // `es` holds the expressions for the intermediate formulas `Hi -> ... -> goal`.
+    let (mut f, mut es) = (goal.clone(), vec![]);
+    for (h, _) in hyps.iter().rev() {
+      es.push(self.expr(&f));
+      f = Formula::Bin(PropRole::Im, Box::new((self.parse(h), f)));
+    }
+    let a0 = f.unassigned(&vec![None; self.atoms.len()]).ok_or("tauto: formula has no atoms")?;
+    let e0 = self.im_(self.atoms[a0].clone(), self.atoms[a0].clone());
+    let p0 = self.apply(PropRole::Id, &e0, &[])?;
+    let mut ctx = vec![(e0.clone(), e0.clone())];
+    let mut asn = vec![None; self.atoms.len()];
+    let (ret, p) = self.split(&mut ctx, &mut asn, &f)?;
+    let mut e = self.expr(&f);
+    let mut p = self.apply(PropRole::Mp, &e, &[(ret, p), (e0, p0)])?;
+    for ((h, hp), e2) in hyps.iter().zip(es.into_iter().rev()) {
+      p = self.apply(PropRole::Mp, &e2, &[(e, p), (h.clone(), hp.clone())])?;
+      e = e2;
+    }
+    Ok(p)
+  }
+}
+
+impl Elaborator {
+  /// Get the theorem registered for role `r`.
+  fn prop_thm(&self, r: PropRole) -> SResult<ThmID> {
+    match self.env.prop.get(&r) {
+      Some(&DeclKey::Thm(th)) => Ok(th),
+      _ => Err(format!("tauto: no theorem registered for '{}'", r.to_str())),
+    }
+  }
+
+  /// Get the term registered for role `r`.
+  fn prop_term(&self, r: PropRole) -> SResult<TermID> {
+    match self.env.prop.get(&r) {
+      Some(&DeclKey::Term(t)) => Ok(t),
+      _ => Err(format!("tauto: no term registered for '{}'", r.to_str())),
+    }
+  }
+
+  /// Match the hypotheses `pats` of a theorem against the `(type, proof)` pairs in `hyps`,
+  /// in any order, extending the assignment `args`. On success, `order` maps each
+  /// hypothesis of the theorem to an index into `hyps`.
+  fn match_hyps(&self, heap: &[ExprNode], pats: &[(Option<super::AtomID>, ExprNode)],
+    args: &mut Vec<LispVal>, hyps: &[(LispVal, LispVal)], order: &mut Vec<usize>
+  ) -> bool {
+    let i = order.len();
+    if i == pats.len() {return true}
+    for j in 0..hyps.len() {
+      if order.contains(&j) {continue}
+      let mut args2 = args.clone();
+      if self.match_pattern(heap, &mut args2, &pats[i].1, &hyps[j].0) {
+        order.push(j);
+        if self.match_hyps(heap, pats, &mut args2, hyps, order) {
+          *args = args2;
+          return true
+        }
+        order.pop();
+      }
+    }
+    false
+  }
+
+  /// The `(tauto)` tactic. Decide whether the goal `tgt` follows propositionally from the
+  /// hypotheses in the local context, and if so return a refine script `(:verb p)` where
+  /// `p` is a proof of `tgt` built from the registered lemmas.
+  pub fn tauto(&mut self, tgt: &LispVal) -> SResult<LispVal> {
+    let (im, not, an) = (self.prop_term(PropRole::Im)?, self.prop_term(PropRole::Not)?,
+      self.prop_term(PropRole::An)?);
+    let hyps = self.lc.proof_order.iter().enumerate()
+      .filter(|&(i, (a, _, _))| self.lc.proofs.get(a) == Some(&i))
+      .map(|(_, (_, e, p))| (e.clone(), p.clone())).collect::<Vec<_>>();
+    let mut tc = Tauto {
+      atoms: vec![],
+      im: LispVal::atom(self.env.terms[im].atom),
+      not: LispVal::atom(self.env.terms[not].atom),
+      an: LispVal::atom(self.env.terms[an].atom),
+      elab: self,
+    };
+    let goal = tc.parse(tgt);
+    let mut relevant = vec![true; tc.atoms.len()];
+    let mut hfs = hyps.iter().map(|h| Some(tc.parse(&h.0))).collect::<Vec<_>>();
+    // First try only the hypotheses that are connected to the goal through shared atoms.
+    // The others can only matter if they are contradictory, so they are used only if
+    // this fails.
+    relevant.resize(tc.atoms.len(), false);
+    loop {
+      let mut changed = false;
+      for f in &mut hfs {
+        if let Some(atoms) = f.as_ref().map(atoms_of) {
+          if atoms.iter().any(|&i| relevant[i]) {
+            for i in atoms { relevant[i] = true }
+            *f = None;
+            changed = true;
+          }
+        }
+      }
+      if !changed {break}
+    }
+    let used = hyps.iter().zip(hfs).filter(|(_, f)| f.is_none()).map(|(h, _)| h.clone()).collect::<Vec<_>>();
+    let p = match tc.prove(&goal, &used) {
+      Err(e) if used.len() < hyps.len() => tc.prove(&goal, &hyps).map_err(|_| e)?,
+      res => res?
+    };
+    Ok(LispVal::list(vec![LispVal::atom(super::AtomID::VERB), p]))
+  }
+}
+
+/// The atoms that occur in a formula.
+fn atoms_of(f: &Formula) -> Vec<usize> {
+  fn rec(f: &Formula, out: &mut Vec<usize>) {
+    match f {
+      &Formula::Atom(i) => out.push(i),
+      Formula::Not(a) => rec(a, out),
+      Formula::Bin(_, ab) => {rec(&ab.0, out); rec(&ab.1, out)}
+    }
+  }
+  let mut out = vec![];
+  rec(f, &mut out);
+  out
+}
@@ -0,0 +1,698 @@
+//! Source formatter for MM0/MM1 files, used by `mm0-rs fmt` and the LSP formatting requests.
+//!
+//! The formatter works on the token stream rather than reprinting the [`AST`], so that
+//! comments, doc comments and the spelling of every token are kept exactly as written.
+//! Only the whitespace between tokens is changed:
+//!
+//! * Statements start at column 0, with at most one blank line between them.
+//! * Declaration headers are filled up to [`WIDTH`] columns, and continuation lines
+//!   are indented by two spaces.
+//! * Math strings are parsed and reprinted by the pretty printer (see [`Pretty`]), so that
+//!   they use the notations of the elaborated file and only the necessary parentheses.
+//!   This is skipped if the file has not been elaborated, the formula contains
+//!   antiquotations, it would use a notation declared after it, or the reprinted
+//!   formula does not parse to the same expression. In that case whitespace inside
+//!   the math string is collapsed, and spaces just inside bracket delimiters
+//!   (according to the [`ParserEnv`]) are removed. Line breaks inside such a math
+//!   string are kept, but the continuation lines are reindented.
+//! * Lisp expressions keep their line breaks and relative indentation, since the layout
+//!   of `@` chains is up to the author, but spacing within each line is normalized and
+//!   the expression is moved as a block to the indentation of its statement.
+//!
+//! The result is parsed again and compared token by token against the input (and by
+//! parsed expression for math strings), and nothing is returned if the parse would change.
+//!
+//!     mm0-rs fmt foo.mm1 -i
+//!
+//! [`Pretty`]: crate::elab::lisp::pretty::Pretty
+
+use std::fs;
+use std::io;
+use std::fmt::Write;
+use std::path::Path;
+use std::sync::Arc;
+use std::collections::HashMap;
+use clap::ArgMatches;
+use crate::util::{FileRef, Span};
+use crate::lined_string::LinedString;
+use crate::elab::environment::{DeclKey, Environment, ParserEnv};
+use crate::elab::lisp::{LispVal, print::FormatEnv};
+use crate::elab::math_parser::{QExpr, QExprKind, parse_math};
+use crate::parser::{AST, ErrorLevel, parse, ident_rest, lisp_ident,
+  ast::{Delimiter, SExpr, StmtKind, Stmt}};
+
+/// The target line width of the formatter.
+pub const WIDTH: usize = 100;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum TokKind { Word, Punct, Str, Math, Comment, Doc, Open, Close, Prefix }
+
+/// A token, together with the number of newlines between it and the previous token.
+#[derive(Copy, Clone, Debug)]
+struct Tok { k: TokKind, span: Span, nl: usize }
+
+/// Split the region `sp` into tokens. `lisp` selects the lisp tokenizer, which differs from
+/// the statement level tokenizer in the set of identifier characters and has quotations.
+/// Also returns the number of newlines after the last token.
+fn lex(src: &[u8], sp: Span, lisp: bool) -> (Vec<Tok>, usize) {
+  let (mut toks, mut i, mut nl) = (vec![], sp.start, 0);
+  let end = sp.end;
+  let scan = |mut j: usize, f: fn(u8) -> bool| {
+    while j < end && f(src[j]) {j += 1}
+    j
+  };
+  while i < end {
+    let (k, j) = match src[i] {
+      b' ' => {i += 1; continue}
+      b'\n' => {nl += 1; i += 1; continue}
+      b'-' if src.get(i + 1) == Some(&b'-') => {
+        let k = if src.get(i + 2) == Some(&b'|') {TokKind::Doc} else {TokKind::Comment};
+        (k, src[i..end].iter().position(|&c| c == b'\n').map_or(end, |n| i + n))
+      }
+      b'$' => (TokKind::Math,
+        src[i + 1..end].iter().position(|&c| c == b'$').map_or(end, |n| i + n + 2)),
+      b'"' => {
+        let mut j = i + 1;
+        while j < end {
+          match src[j] {
+            b'\\' => j += 2,
+            b'"' => {j += 1; break}
+            _ => j += 1,
+          }
+        }
+        (TokKind::Str, j.min(end))
+      }
+      b'(' | b'[' | b'{' => (TokKind::Open, i + 1),
+      b')' | b']' | b'}' => (TokKind::Close, i + 1),
+      b'\'' | b',' if lisp => (TokKind::Prefix, i + 1),
+      b'#' if lisp => (TokKind::Word, scan(i + 1, ident_rest)),
+      c if lisp && lisp_ident(c) => (TokKind::Word, scan(i, lisp_ident)),
+      c if !lisp && ident_rest(c) => (TokKind::Word, scan(i, ident_rest)),
+      _ => (TokKind::Punct, i + 1),
+    };
+    toks.push(Tok {k, span: (i..j).into(), nl: std::mem::take(&mut nl)});
+    i = j;
+  }
+  (toks, nl)
+}
+
+/// Split the math string `s` (without the `$` delimiters) into tokens, in the same way as
+/// the math parser does it.
+fn math_tokens<'a>(pe: &ParserEnv, s: &'a str) -> Vec<&'a str> {
+  let mut res = vec![];
+  for w in s.split(&[' ', '\n'][..]).filter(|w| !w.is_empty()) {
+    let (b, mut start, mut i) = (w.as_bytes(), 0, 0);
+    while i < b.len() {
+      if pe.delims_r.get(b[i]) && i != start {
+        res.push(&w[start..i]);
+        start = i;
+      } else if pe.delims_l.get(b[i]) {
+        res.push(&w[start..=i]);
+        i += 1;
+        start = i;
+      } else {i += 1}
+    }
+    if start < b.len() {res.push(&w[start..])}
+  }
+  res
+}
+
+/// Write a key for the parsed math expression `e` to `out`, such that two formulas have the
+/// same key iff they parse to the same expression. Returns `None` on antiquotations.
+fn qexpr_key(src: &str, env: &Environment, e: &QExpr, out: &mut String) -> Option<()> {
+  let (head, es) = match &e.k {
+    &QExprKind::IdentApp(sp, ref es) => {
+      let x = &src[sp.start..sp.end];
+      match env.atoms.get(x.as_bytes()).and_then(|&a| env.data[a].decl) {
+        Some(DeclKey::Term(t)) if env.terms[t].args.len() == es.len() => (format!("#{}", t.0), es),
+        _ if es.is_empty() => {out.push_str(x); return Some(())}
+        _ => (x.to_owned(), es),
+      }
+    }
+    QExprKind::App(_, t, es) => (format!("#{}", t.0), es),
+    QExprKind::Unquote(_) => return None,
+  };
+  write!(out, "({}", head).expect("writing to a string");
+  for e in &**es {out.push(' '); qexpr_key(src, env, e, out)?}
+  out.push(')');
+  Some(())
+}
+
+/// The fingerprint of the math string `sp`. If the environment is available and the formula
+/// parses, this is the parsed expression, otherwise it is the list of math tokens.
+fn math_key(src: &str, pe: &ParserEnv, env: Option<&Environment>, sp: Span) -> String {
+  let mut out = String::new();
+  if let Some(env) = env {
+    if parse_math(pe, src.as_bytes(), sp)
+        .and_then(|e| qexpr_key(src, env, &e, &mut out)).is_some() {
+      return out
+    }
+  }
+  math_tokens(pe, &src[sp.start + 1..sp.end - 1]).join(" ")
+}
+
+/// Returns the lisp expressions directly contained in a statement (not counting those in
+/// statements nested inside a doc comment or annotation).
+fn lisp_exprs(k: &StmtKind) -> Vec<&SExpr> {
+  match k {
+    StmtKind::Decl(d) => d.val.iter().collect(),
+    StmtKind::Do(es) | StmtKind::Inout {hs: es, ..} => es.iter().collect(),
+    StmtKind::Annot(e, _) => vec![e],
+    _ => vec![],
+  }
+}
+
+/// Returns true for statements whose math strings are notation constants or delimiters,
+/// which we print as written.
+fn raw_math(k: &StmtKind) -> bool {
+  matches!(k, StmtKind::Delimiter(_) | StmtKind::SimpleNota(_) | StmtKind::Notation(_))
+}
+
+/// Split `sp` into alternating statement level and lisp regions. The `bool` is true for
+/// lisp regions.
+fn segments(sp: Span, es: &[&SExpr]) -> Vec<(Span, bool)> {
+  let mut res = vec![];
+  let mut pos = sp.start;
+  for e in es {
+    if pos < e.span.start {res.push(((pos..e.span.start).into(), false))}
+    res.push((e.span, true));
+    pos = e.span.end;
+  }
+  if pos < sp.end {res.push(((pos..sp.end).into(), false))}
+  res
+}
+
+/// Collect the significant tokens of a statement, to check that formatting did not change
+/// the parse. Comments are dropped and math strings are replaced by their [`math_key`].
+fn fingerprint(src: &str, pe: &ParserEnv, env: Option<&Environment>, s: &Stmt, out: &mut Vec<String>) {
+  let (sp, es, inner) = match &s.k {
+    StmtKind::DocComment(_, s2) => ((s.span.start..s2.span.start).into(), vec![], Some(s2)),
+    StmtKind::Annot(e, s2) => ((s.span.start..s2.span.start).into(), vec![e], Some(s2)),
+    k => (s.span, lisp_exprs(k), None),
+  };
+  for (sp, lisp) in segments(sp, &es) {
+    for t in lex(src.as_bytes(), sp, lisp).0 {
+      match t.k {
+        TokKind::Comment => {}
+        TokKind::Math => out.push(math_key(src, pe, env, t.span)),
+        _ => out.push(src[t.span.start..t.span.end].to_owned()),
+      }
+    }
+  }
+  if let Some(s2) = inner {fingerprint(src, pe, env, s2, out)}
+}
+
+/// A layout unit at the statement level.
+#[derive(Debug)]
+enum ChunkKind {
+  /// A token or binder group, which may span several lines if it contains a formula.
+  Text(Vec<String>),
+  /// A comment, which must be followed by a line break.
+  Comment(String),
+  /// A lisp expression, including any doc comments before it.
+  Lisp(Vec<Tok>),
+}
+
+#[derive(Debug)]
+struct Chunk {
+  k: ChunkKind,
+  /// True if there should be a space between this chunk and the last one.
+  space: bool,
+  /// The number of newlines before this chunk in the input.
+  nl: usize,
+}
+
+/// Collect the names of the terms that have a notation declared in `ast`, with the position
+/// of the last such declaration. The pretty printer can't use these notations before that.
+fn notation_decls(ast: &AST) -> HashMap<&[u8], usize> {
+  fn go<'a>(src: &'a [u8], s: &'a Stmt, res: &mut HashMap<&'a [u8], usize>) {
+    let id = match &s.k {
+      StmtKind::SimpleNota(n) => n.id,
+      StmtKind::Notation(n) => n.id,
+      StmtKind::Coercion {id, ..} => *id,
+      StmtKind::DocComment(_, s) | StmtKind::Annot(_, s) => return go(src, s, res),
+      _ => return
+    };
+    res.insert(&src[id.start..id.end], s.span.start);
+  }
+  let mut res = HashMap::new();
+  for s in &ast.stmts {go(ast.source.as_bytes(), s, &mut res)}
+  res
+}
+
+/// The formatter state.
+struct Formatter<'a> {
+  src: &'a str,
+  pe: &'a ParserEnv,
+  /// The elaborated environment, used to pretty print math strings.
+  fe: Option<FormatEnv<'a>>,
+  /// The notations declared in this file, see [`notation_decls`].
+  notas: &'a HashMap<&'a [u8], usize>,
+  out: String,
+  col: usize,
+}
+
+impl<'a> Formatter<'a> {
+  fn text(&self, sp: Span) -> &'a str { &self.src[sp.start..sp.end] }
+
+  fn push(&mut self, s: &str) {
+    self.out.push_str(s);
+    match s.rfind('\n') {
+      Some(n) => self.col = s.len() - n - 1,
+      None => self.col += s.len(),
+    }
+  }
+
+  /// Start a new line with the given indentation, removing trailing spaces on the current line.
+  fn newline(&mut self, indent: usize) {
+    self.out.truncate(self.out.trim_end_matches(' ').len());
+    self.out.push('\n');
+    for _ in 0..indent {self.out.push(' ')}
+    self.col = indent;
+  }
+
+  /// Start a new line, unless we are already at the start of a line.
+  fn end_line(&mut self) {
+    if self.line_start() {
+      self.out.truncate(self.out.trim_end_matches(' ').len());
+      self.col = 0;
+    } else {self.newline(0)}
+  }
+
+  fn last_line(&self) -> &str { self.out.rsplit('\n').next().unwrap_or_default() }
+
+  fn line_start(&self) -> bool { self.last_line().bytes().all(|c| c == b' ') }
+
+  fn line_indent(&self) -> usize { self.last_line().bytes().take_while(|&c| c == b' ').count() }
+
+  /// Print lines, indenting continuation lines relative to the current column.
+  fn lines(&mut self, lines: &[String]) {
+    let indent = self.col + 2;
+    for (i, l) in lines.iter().enumerate() {
+      if i != 0 {self.newline(indent)}
+      self.push(l)
+    }
+  }
+
+  /// Convert a parsed formula at position `pos` to an expression for the pretty printer.
+  fn qexpr_to_lisp(&self, env: &Environment, e: &QExpr, pos: usize) -> Option<LispVal> {
+    let (t, es) = match &e.k {
+      &QExprKind::IdentApp(sp, ref es) => {
+        let a = *env.atoms.get(self.text(sp).as_bytes())?;
+        if es.is_empty() {return Some(LispVal::atom(a))}
+        match env.data[a].decl {
+          Some(DeclKey::Term(t)) if env.terms[t].args.len() == es.len() => (t, es),
+          _ => return None
+        }
+      }
+      QExprKind::App(_, t, es) => (*t, es),
+      QExprKind::Unquote(_) => return None,
+    };
+    let a = env.terms[t].atom;
+    if env.pe.decl_nota.contains_key(&t) &&
+      self.notas.get(&*env.data[a].name).map_or(false, |&p| p > pos) {return None}
+    let mut args = vec![LispVal::atom(a)];
+    for e in &**es {args.push(self.qexpr_to_lisp(env, e, pos)?)}
+    Some(LispVal::list(args))
+  }
+
+  /// Print a formula using the pretty printer at the given width, if possible.
+  fn pretty_math(&self, t: &Tok, width: usize) -> Option<Vec<String>> {
+    let fe = self.fe?;
+    let e = parse_math(self.pe, self.src.as_bytes(), t.span)?;
+    let val = self.qexpr_to_lisp(fe.env, &e, t.span.start)?;
+    let mut s = String::new();
+    fe.pretty(|p| p.expr(&val).render_fmt(width, &mut s)).ok()?;
+    let (mut old, mut new) = (String::new(), String::new());
+    qexpr_key(self.src, fe.env, &e, &mut old)?;
+    qexpr_key(&s, fe.env, &parse_math(self.pe, s.as_bytes(), (0..s.len()).into())?, &mut new)?;
+    if old != new {return None}
+    let mut lines: Vec<_> = s.split('\n').map(str::trim_end).collect();
+    // Continuation lines are indented by the caller, so only the relative indentation is kept
+    let indent = lines[1..].iter().map(|l| l.len() - l.trim_start().len()).min().unwrap_or(0);
+    for l in &mut lines[1..] {*l = &l[indent..]}
+    Some(lines.into_iter().map(String::from).collect())
+  }
+
+  /// Print a formula with the pretty printer if possible, otherwise normalize the whitespace
+  /// in the formula, keeping line breaks.
+  fn math(&self, t: &Tok, width: usize) -> Vec<String> {
+    if let Some(lines) = self.pretty_math(t, width) {return lines}
+    let pe = self.pe;
+    let tight_l = |c: u8| b"([{".contains(&c) && pe.delims_l.get(c);
+    let tight_r = |c: u8| b")]}".contains(&c) && pe.delims_r.get(c);
+    let mut lines = vec![];
+    for line in self.src[t.span.start + 1..t.span.end - 1].split('\n') {
+      let mut s = String::new();
+      let mut space = false;
+      for c in line.chars() {
+        if c == ' ' {space = !s.is_empty(); continue}
+        if space && !(c.is_ascii() && tight_r(c as u8)) &&
+          !s.as_bytes().last().map_or(false, |&p| tight_l(p)) {s.push(' ')}
+        space = false;
+        s.push(c);
+      }
+      if !s.is_empty() {lines.push(s)}
+    }
+    match lines.len() {
+      0 => lines.push("$ $".into()),
+      n => {
+        lines[0].insert_str(0, "$ ");
+        lines[n - 1].push_str(" $");
+      }
+    }
+    lines
+  }
+
+  fn token_lines(&self, t: &Tok, raw: bool) -> Vec<String> {
+    // Formulas in declaration headers are put on a new line with indentation 2 if they don't fit
+    if t.k == TokKind::Math && !raw {self.math(t, WIDTH - 4)} else {vec![self.text(t.span).to_owned()]}
+  }
+
+  /// The text of a comment. Trailing spaces are removed from ordinary comments, but not
+  /// from doc comments, because they are part of the parsed doc string.
+  fn comment(&self, t: &Tok) -> String {
+    let s = self.text(t.span);
+    if t.k == TokKind::Comment {s.trim_end().into()} else {s.into()}
+  }
+
+  /// Split the region `sp` into layout chunks, where `es` are the lisp expressions in the region.
+  fn chunks(&self, sp: Span, es: &[&SExpr], raw: bool) -> Vec<Chunk> {
+    let mut res: Vec<Chunk> = vec![];
+    let mut prev: Option<(TokKind, &str)> = None;
+    let (mut tight_colon, mut after_lisp, mut pending) = (false, false, 0);
+    for (sp, lisp) in segments(sp, es) {
+      let (toks, nl) = lex(self.src.as_bytes(), sp, lisp);
+      if lisp {
+        let space = !matches!(prev, None | Some((_, "@")));
+        res.push(Chunk {k: ChunkKind::Lisp(toks), space, nl: pending});
+        after_lisp = true;
+        continue
+      }
+      pending = nl;
+      let mut i = 0;
+      while let Some(t) = toks.get(i) {
+        i += 1;
+        if matches!(t.k, TokKind::Comment | TokKind::Doc) {
+          res.push(Chunk {k: ChunkKind::Comment(self.comment(t)), space: true, nl: t.nl});
+          prev = None;
+          continue
+        }
+        let s = self.text(t.span);
+        let space = if std::mem::take(&mut after_lisp) {s != ";"} else {
+          match (prev, t.k, s) {
+            (None, _, _) | (Some((TokKind::Open, _)), _, _) |
+            (Some((_, ".")), _, _) | (Some((_, "@")), _, _) |
+            (_, TokKind::Close, _) | (_, _, ";") | (_, _, ":") | (_, _, ",") => false,
+            (Some((_, ":")), _, _) => !tight_colon,
+            _ => true,
+          }
+        };
+        tight_colon = s == ":" && raw && matches!(prev, Some((TokKind::Math, _)));
+        let mut lines = self.token_lines(t, raw);
+        prev = Some((t.k, s));
+        // Keep binder groups and other bracketed groups together, if they have no comments
+        if t.k == TokKind::Open {
+          let mut depth = 1;
+          let mut j = i;
+          while let Some(t2) = toks.get(j) {
+            match t2.k {
+              TokKind::Open => depth += 1,
+              TokKind::Close => {depth -= 1; if depth == 0 {break}}
+              _ => {}
+            }
+            j += 1;
+          }
+          if j < toks.len() &&
+              !toks[i..j].iter().any(|t| matches!(t.k, TokKind::Comment | TokKind::Doc)) {
+            let mut inner = self.chunks((t.span.end..toks[j].span.end).into(), &[], raw);
+            if let Some(c) = inner.first_mut() {c.space = false}
+            for c in inner {
+              if let ChunkKind::Text(ls) = c.k {
+                let last = lines.last_mut().expect("nonempty");
+                if c.space {last.push(' ')}
+                let mut it = ls.into_iter();
+                if let Some(l) = it.next() {last.push_str(&l)}
+                lines.extend(it);
+              }
+            }
+            prev = Some((TokKind::Close, self.text(toks[j].span)));
+            i = j + 1;
+          }
+        }
+        res.push(Chunk {k: ChunkKind::Text(lines), space, nl: t.nl});
+      }
+    }
+    res
+  }
+
+  /// The indentation of the source line containing position `pos`.
+  fn src_indent(&self, pos: usize) -> usize {
+    let start = self.src[..pos].rfind('\n').map_or(0, |n| n + 1);
+    self.src[start..].bytes().take_while(|&c| c == b' ').count()
+  }
+
+  /// Print a lisp expression. Line breaks are kept as written, and lines are indented
+  /// relative to the first line in the same way as in the source.
+  fn lisp(&mut self, toks: &[Tok]) {
+    let base = self.line_indent();
+    let src_base = toks.first().map_or(0, |t| self.src_indent(t.span.start));
+    let mut prev = None;
+    for t in toks {
+      if prev.is_some() && t.nl > 0 {
+        if t.nl >= 2 {self.newline(0)}
+        self.newline((base + self.src_indent(t.span.start)).saturating_sub(src_base));
+      } else if !matches!(prev, None | Some(TokKind::Open) | Some(TokKind::Prefix)) &&
+          t.k != TokKind::Close {
+        self.push(" ")
+      } else {}
+      match t.k {
+        TokKind::Math => {
+          let ls = self.math(t, WIDTH.saturating_sub(self.col + 2).max(WIDTH - 40));
+          self.lines(&ls)
+        }
+        TokKind::Comment | TokKind::Doc => {let c = self.comment(t); self.push(&c)}
+        _ => self.push(self.text(t.span)),
+      }
+      prev = Some(t.k);
+    }
+  }
+
+  /// The lisp expression printed on one line, if it was written on one line.
+  fn lisp_flat(&self, toks: &[Tok]) -> Option<String> {
+    if toks.iter().skip(1).any(|t| t.nl > 0) {return None}
+    let mut f = Formatter {src: self.src, pe: self.pe, fe: self.fe, notas: self.notas,
+      out: String::new(), col: 0};
+    f.lisp(toks);
+    if f.out.contains('\n') {None} else {Some(f.out)}
+  }
+
+  /// Print a sequence of chunks, filling lines up to [`WIDTH`] and indenting
+  /// continuation lines to `indent`. Lisp expressions that started on a new line
+  /// are put on a new line with indentation `lisp_indent`.
+  fn fill(&mut self, chunks: &[Chunk], indent: usize, lisp_indent: usize) {
+    let mut after_lisp = false;
+    for c in chunks {
+      match &c.k {
+        ChunkKind::Comment(s) => {
+          if !self.line_start() {
+            if c.nl == 0 {self.push(" ")} else {self.newline(indent)}
+          }
+          self.push(s);
+          self.newline(indent);
+        }
+        ChunkKind::Text(lines) => {
+          if after_lisp && c.nl > 0 {
+            self.newline(0)
+          } else if c.space && !self.line_start() {
+            // continuation lines are indented by 2 relative to the start of the chunk
+            let fits = self.col + 1 + lines[0].len() <= WIDTH &&
+              lines[1..].iter().all(|l| self.col + 3 + l.len() <= WIDTH);
+            if fits {self.push(" ")} else {self.newline(indent)}
+          } else {}
+          self.lines(lines)
+        }
+        ChunkKind::Lisp(toks) => {
+          if !self.line_start() {
+            // A one line expression that doesn't fit (with a following `;`) moves to a new line
+            let len = if c.nl > 0 {None} else {self.lisp_flat(toks).map(|s| s.len())};
+            if c.nl > 0 || len.map_or(false, |n| self.col + n + 2 > WIDTH) {
+              if c.nl >= 2 {self.newline(0)}
+              self.newline(lisp_indent)
+            } else if c.space {self.push(" ")} else {}
+          }
+          self.lisp(toks)
+        }
+      }
+      after_lisp = matches!(c.k, ChunkKind::Lisp(_));
+    }
+  }
+
+  /// Print the comments in a region between statements, and start a new line for the next
+  /// statement, keeping up to one blank line.
+  fn gap(&mut self, sp: Span, last: bool) {
+    let (toks, nl) = lex(self.src.as_bytes(), sp, false);
+    if last && toks.iter().any(|t| !matches!(t.k, TokKind::Comment | TokKind::Doc)) {
+      // Text after an `exit` command is printed as is
+      self.newline(0);
+      let rest = self.src[sp.start..sp.end].trim_start_matches(&[' ', '\n'][..]);
+      return self.push(rest.trim_end())
+    }
+    for t in &toks {
+      if !self.out.is_empty() {
+        if t.nl == 0 {self.push(" ")} else {
+          self.newline(0);
+          if t.nl >= 2 {self.newline(0)}
+        }
+      }
+      let s = self.comment(t);
+      self.push(&s);
+    }
+    if !self.out.is_empty() && !last {
+      self.end_line();
+      if nl >= 2 {self.newline(0)}
+    }
+  }
+
+  fn stmt(&mut self, s: &Stmt) {
+    match &s.k {
+      StmtKind::DocComment(_, s2) => {
+        let chunks = self.chunks((s.span.start..s2.span.start).into(), &[], false);
+        self.fill(&chunks, 0, 0);
+        self.end_line();
+        self.stmt(s2)
+      }
+      StmtKind::Annot(e, s2) => {
+        let chunks = self.chunks((s.span.start..s2.span.start).into(), &[e], false);
+        self.fill(&chunks, 0, 0);
+        if !self.line_start() {
+          if self.text((e.span.end..s2.span.start).into()).contains('\n') {
+            self.newline(0)
+          } else {self.push(" ")}
+        }
+        self.stmt(s2)
+      }
+      k => {
+        // Proofs that start on a new line are written at the start of the line
+        let lisp_indent = if let StmtKind::Decl(_) = k {0} else {2};
+        let chunks = self.chunks(s.span, &lisp_exprs(k), raw_math(k));
+        self.fill(&chunks, 2, lisp_indent)
+      }
+    }
+  }
+}
+
+/// Add the delimiters declared in `ast` to `pe`.
+pub fn add_delimiters(ast: &AST, pe: &mut ParserEnv) {
+  fn go(s: &Stmt, pe: &mut ParserEnv) {
+    match &s.k {
+      StmtKind::Delimiter(Delimiter::Both(cs)) => pe.add_delimiters(cs, cs),
+      StmtKind::Delimiter(Delimiter::LeftRight(ls, rs)) => pe.add_delimiters(ls, rs),
+      StmtKind::DocComment(_, s) | StmtKind::Annot(_, s) => go(s, pe),
+      _ => {}
+    }
+  }
+  for s in &ast.stmts {go(s, pe)}
+}
+
+/// Format the statements of `ast`.
+///
+/// If `range` is given, only the statements overlapping the range are formatted; otherwise
+/// the whole file is formatted. `fe` is the environment of the elaborated file, which is
+/// used to pretty print math strings. Returns the span of the source that was formatted and
+/// its replacement, or `None` if the file has parse errors or the formatted text would not
+/// parse the same as the original.
+#[must_use] pub fn format(ast: &AST, pe: &ParserEnv, fe: Option<FormatEnv<'_>>,
+  range: Option<Span>
+) -> Option<(Span, String)> {
+  if ast.errors.iter().any(|e| matches!(e.level, ErrorLevel::Error)) {return None}
+  let src: &str = &ast.source;
+  let stmts = match range {
+    None => &*ast.stmts,
+    Some(r) => {
+      let i = ast.stmts.iter().position(|s| r.start <= s.span.end)?;
+      let j = ast.stmts.iter().rposition(|s| s.span.start <= r.end)?;
+      if j < i {return None}
+      &ast.stmts[i..=j]
+    }
+  };
+  let notas = notation_decls(ast);
+  let mut f = Formatter {src, pe, fe, notas: &notas, out: String::new(), col: 0};
+  let mut pos = if range.is_none() {0} else {stmts.first()?.span.start};
+  for s in stmts {
+    f.gap((pos..s.span.start).into(), false);
+    f.stmt(s);
+    pos = s.span.end;
+  }
+  let sp = if range.is_none() {
+    f.gap((pos..src.len()).into(), true);
+    f.newline(0);
+    (0..src.len()).into()
+  } else {
+    f.out.truncate(f.out.trim_end().len());
+    (stmts[0].span.start..pos).into()
+  };
+
+  // Check that the parse is unchanged
+  let (_, ast2) = parse(Arc::new(LinedString::from(f.out.clone())), None);
+  if ast2.errors.iter().any(|e| matches!(e.level, ErrorLevel::Error)) ||
+    ast2.stmts.len() != stmts.len() {return None}
+  let (mut old, mut new) = (vec![], vec![]);
+  let env = fe.map(|fe| fe.env);
+  for s in stmts {fingerprint(src, pe, env, s, &mut old)}
+  for s in &ast2.stmts {fingerprint(&f.out, pe, env, s, &mut new)}
+  if old != new {return None}
+  Some((sp, f.out))
+}
+
+/// Read the delimiters from `path` and its imports, recursively.
+fn file_delimiters(path: &Path, pe: &mut ParserEnv, done: &mut Vec<std::path::PathBuf>) -> io::Result<()> {
+  let path = path.canonicalize()?;
+  if done.contains(&path) {return Ok(())}
+  done.push(path.clone());
+  let (_, ast) = parse(Arc::new(fs::read_to_string(&path)?.into()), None);
+  add_delimiters(&ast, pe);
+  for (_, f) in &ast.imports {
+    let f = std::str::from_utf8(f).map_err(|_|
+      io::Error::new(io::ErrorKind::InvalidInput, "invalid utf8"))?;
+    file_delimiters(&path.parent().map_or_else(|| f.into(), |p| p.join(f)), pe, done)?;
+  }
+  Ok(())
+}
+
+/// Main entry point for `mm0-rs fmt` subcommand.
+///
+/// See the [module documentation](self) for the formatting rules.
+///
+/// # Arguments
+///
+/// `mm0-rs fmt <in.mm1> [out.mm1]`, where:
+///
+/// - `in.mm1` (or `in.mm0`) is the file to format
+/// - `out.mm1` is the output location, or stdout if omitted.
+///   With `-i`, the input file is overwritten instead.
+pub fn main(args: &ArgMatches<'_>) -> io::Result<()> {
+  let path = Path::new(args.value_of("INPUT").expect("required arg"));
+  let src = Arc::<LinedString>::new(fs::read_to_string(path)?.into());
+  let (_, ast) = parse(src.clone(), None);
+  // The file is elaborated for the notations used to print math strings. Elaboration
+  // messages go to stderr, since the output may be written to stdout.
+  crate::compiler::set_log_sink(|s| eprintln!("{}", s));
+  let env = crate::compiler::elab_for_result(FileRef::from(fs::canonicalize(path)?))?.1;
+  let mut pe = ParserEnv::default();
+  match &env {
+    Some(env) => {pe = env.pe().clone(); add_delimiters(&ast, &mut pe)}
+    None => file_delimiters(path, &mut pe, &mut vec![])?,
+  }
+  let fe = env.as_ref().map(|env| unsafe { env.format_env(&src) });
+  let (_, out) = format(&ast, &pe, fe, None).ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData,
+    "file has parse errors or could not be formatted without changing the parse"))?;
+  if args.is_present("in_place") {
+    fs::write(path, out)
+  } else {
+    match args.value_of("OUTPUT") {
+      None => { print!("{}", out); Ok(()) }
+      Some(o) => fs::write(o, out),
+    }
+  }
+}
@@ -10,8 +10,12 @@
 //!
 //! SUBCOMMANDS:
 //!     compile    Compile MM1 files into MMB
+//!     debug      MM1 debug adapter (Debug Adapter Protocol over stdio)
+//!     fmt        Format MM0/MM1 files
 //!     help       Prints this message or the help of the given subcommand(s)
 //!     join       Join MM1/MM0 files with imports by concatenation
+//!     repl       Interactive MM1 REPL
+//!     search     Search for theorems by statement
 //!     server     MM1 LSP server
 //! ```
 //!
@@ -50,6 +54,11 @@ pub mod parser;
 #[macro_use] pub mod server;
 pub mod compiler;
 pub mod joiner;
+pub mod formatter;
+pub mod debugger;
+#[cfg(feature = "repl")]
+pub mod repl;
+pub mod search;
 pub mod elab;
 pub mod doc;
 pub mod mmb;
@@ -61,12 +70,41 @@ pub mod mmb;
 pub mod mmu { pub mod import; pub mod export; }
 pub mod mmc;
 
-use std::sync::atomic::{AtomicBool, Ordering};
-use clap::clap_app;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use clap::{clap_app, value_t, ArgMatches};
 
 static CHECK_PROOFS: AtomicBool = AtomicBool::new(true);
 pub(crate) fn get_check_proofs() -> bool { CHECK_PROOFS.load(Ordering::Relaxed) }
 
+static FUEL: AtomicU64 = AtomicU64::new(0);
+pub(crate) fn get_fuel() -> Option<u64> {
+  match FUEL.load(Ordering::Relaxed) { 0 => None, n => Some(n) }
+}
+
+static REPORT_FUEL: AtomicBool = AtomicBool::new(false);
+pub(crate) fn get_report_fuel() -> bool { REPORT_FUEL.load(Ordering::Relaxed) }
+
+static OPTIMIZE: AtomicBool = AtomicBool::new(false);
+pub(crate) fn get_optimize() -> bool { OPTIMIZE.load(Ordering::Relaxed) }
+
+static WARN_UNUSED_HYPS: AtomicBool = AtomicBool::new(false);
+pub(crate) fn get_warn_unused_hyps() -> bool { WARN_UNUSED_HYPS.load(Ordering::Relaxed) }
+
+static BYTECODE: AtomicBool = AtomicBool::new(true);
+pub(crate) fn get_bytecode() -> bool { BYTECODE.load(Ordering::Relaxed) }
+
+/// Set the global elaboration options from the command line flags.
+fn set_elab_options(m: &ArgMatches<'_>) {
+  if m.is_present("no_proofs") { CHECK_PROOFS.store(false, Ordering::Relaxed) }
+  if m.is_present("fuel") {
+    FUEL.store(value_t!(m, "fuel", u64).unwrap_or_else(|e| e.exit()), Ordering::Relaxed)
+  }
+  if m.is_present("report_fuel") { REPORT_FUEL.store(true, Ordering::Relaxed) }
+  if m.is_present("no_bytecode") { BYTECODE.store(false, Ordering::Relaxed) }
+  if m.is_present("optimize") { OPTIMIZE.store(true, Ordering::Relaxed) }
+  if m.is_present("warn_unused_hyps") { WARN_UNUSED_HYPS.store(true, Ordering::Relaxed) }
+}
+
 fn main() -> std::io::Result<()> {
   let app = clap_app!(mm0_rs =>
     (name: "mm0-rs")
@@ -79,6 +117,11 @@ fn main() -> std::io::Result<()> {
     (@subcommand compile =>
       (about: "Compile MM1 files into MMB")
       (@arg no_proofs: -n --("no-proofs") "Disable proof checking until (check-proofs #t)")
+      (@arg fuel: --fuel [N] "Limit each declaration to N evaluation steps instead of using a timeout")
+      (@arg report_fuel: --("report-fuel") "Report the evaluation steps used by each declaration")
+      (@arg no_bytecode: --("no-bytecode") "Interpret lisp code directly instead of compiling it to bytecode")
+      (@arg optimize: --optimize "Optimize theorem proofs before they are stored and written to the output")
+      (@arg warn_unused_hyps: --("warn-unused-hyps") "Warn about theorem hypotheses that are not used in the proof")
       (@arg output: -o --output [FILE] "Print 'output' commands to a file (use '-' to print to stdout)")
       (@arg INPUT: +required "Sets the input file (.mm1 or .mm0)")
       (@arg OUTPUT: "Sets the output file (.mmb or .mmu)"))
@@ -88,6 +131,13 @@ fn main() -> std::io::Result<()> {
       (@arg bare: -b --("bare") "Don't add any comments")
       (@arg INPUT: +required "Sets the input file (.mm1 or .mm0)")
       (@arg OUTPUT: "Sets the output file (.mm1 or .mm0), or stdin if omitted"))
+    (@subcommand fmt =>
+      (about: "Format MM0/MM1 files")
+      (@arg in_place: -i --("in-place") "Overwrite the input file with the result")
+      (@arg INPUT: +required "Sets the input file (.mm1 or .mm0)")
+      (@arg OUTPUT: "Sets the output file (.mm1 or .mm0), or stdout if omitted"))
+    (@subcommand debug =>
+      (about: "MM1 debug adapter (Debug Adapter Protocol over stdio)"))
     (@subcommand doc =>
       (about: "Build documentation pages")
       (@arg INPUT: +required "Sets the input file (.mm1 or .mm0)")
@@ -96,30 +146,62 @@ fn main() -> std::io::Result<()> {
       (@arg order: --("order") <ORDER>
          possible_values(&["pre", "post"]) default_value("post")
          "Proof tree traversal order")
-      (@arg src: --src [URL] "Use URL as the base for source doc links (use - to disable)")));
+      (@arg src: --src [URL] "Use URL as the base for source doc links (use - to disable)"))
+    (@subcommand search =>
+      (about: "Search for theorems by statement")
+      (@arg hyps: --hyps "Also show theorems with a hypothesis matching the pattern")
+      (@arg INPUT: +required "Sets the input file (.mm1 or .mm0)")
+      (@arg PATTERN: +required "The statement to search for, such as 'a + b = b + a'")));
 
   #[cfg(feature = "server")]
   let app = clap_app!(@app (app)
     (@subcommand server =>
       (about: "MM1 LSP server")
       (@arg no_proofs: -n --("no-proofs") "Disable proof checking until (check-proofs #t)")
+      (@arg fuel: --fuel [N] "Limit each declaration to N evaluation steps instead of using a timeout")
+      (@arg report_fuel: --("report-fuel") "Report the evaluation steps used by each declaration")
+      (@arg no_bytecode: --("no-bytecode") "Interpret lisp code directly instead of compiling it to bytecode")
+      (@arg warn_unused_hyps: --("warn-unused-hyps") "Warn about theorem hypotheses that are not used in the proof")
       (@arg debug: -d --debug "Enable debug logging")
       (@arg no_log_errors: -q --quiet "Don't print errors in server output log")));
 
+  #[cfg(feature = "repl")]
+  let app = clap_app!(@app (app)
+    (@subcommand repl =>
+      (about: "Interactive MM1 REPL")
+      (@arg no_proofs: -n --("no-proofs") "Disable proof checking until (check-proofs #t)")
+      (@arg fuel: --fuel [N] "Limit each declaration to N evaluation steps instead of using a timeout")
+      (@arg report_fuel: --("report-fuel") "Report the evaluation steps used by each declaration")
+      (@arg no_bytecode: --("no-bytecode") "Interpret lisp code directly instead of compiling it to bytecode")
+      (@arg warn_unused_hyps: --("warn-unused-hyps") "Warn about theorem hypotheses that are not used in the proof")
+      (@arg INPUT: "Sets the input file (.mm1 or .mm0) to load first")));
+
   let m = app.get_matches();
 
   match m.subcommand() {
     ("compile", Some(m)) => {
-      if m.is_present("no_proofs") { CHECK_PROOFS.store(false, Ordering::Relaxed) }
+      set_elab_options(m);
       compiler::main(m)?
     }
     ("join", Some(m)) => joiner::main(m)?,
+    ("fmt", Some(m)) => formatter::main(m)?,
+    ("debug", Some(_)) => {
+      // The debugger stops at the nodes of the lisp syntax tree, so we don't compile it
+      BYTECODE.store(false, Ordering::Relaxed);
+      debugger::main()?
+    }
     ("doc", Some(m)) => doc::main(m)?,
+    ("search", Some(m)) => search::main(m)?,
     #[cfg(feature = "server")]
     ("server", Some(m)) => {
-      if m.is_present("no_proofs") { CHECK_PROOFS.store(false, Ordering::Relaxed) }
+      set_elab_options(m);
       server::main(m)
     }
+    #[cfg(feature = "repl")]
+    ("repl", Some(m)) => {
+      set_elab_options(m);
+      repl::main(m)?
+    }
     _ => unreachable!()
   }
   Ok(())
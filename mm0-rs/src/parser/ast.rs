@@ -13,7 +13,7 @@ use num::BigUint;
 use crate::lined_string::LinedString;
 use crate::util::{Span, ArcString};
 use crate::elab::lisp::print::{EnvDisplay, FormatEnv};
-use crate::elab::environment::DocComment;
+use crate::elab::environment::{AtomID, DocComment};
 use super::ParseError;
 
 bitflags! {
@@ -274,6 +274,7 @@ pub struct SExpr {
 /// atoms have data `quote`, `unquote` and `:nfx` respectively,
 /// but the span does not contain this text because
 /// these atoms are created implicitly via keywords like `'`.
+/// The [`Macro`](Atom::Macro) atom is produced by macro expansion, and carries its own name.
 #[derive(Copy, Clone, Debug)]
 pub enum Atom {
   /// This indicates that the atom text is a span from the input, i.e. the user wrote
@@ -288,6 +289,11 @@ pub enum Atom {
   /// This is an atom with the text `:nfx` that was generated by a malformed curly list
   /// (see [`curly_transform`]).
   Nfx,
+  /// This is an atom from the template of a macro, generated during macro expansion.
+  /// The span is the span of the macro call, and the `u32` identifies the expansion,
+  /// which is used to keep local variables introduced by the macro separate from
+  /// those at the call site.
+  Macro(AtomID, u32),
 }
 crate::deep_size_0!(Atom);
 
@@ -439,6 +445,7 @@ impl SExpr {
 impl EnvDisplay for SExpr {
   fn fmt(&self, fe: FormatEnv<'_>, f: &mut fmt::Formatter<'_>) -> fmt::Result {
     match &self.k {
+      &SExprKind::Atom(Atom::Macro(a, _)) => a.fmt(fe, f),
       &SExprKind::Atom(a) => {
         unsafe {std::str::from_utf8_unchecked(fe.source.span_atom(self.span, a))}.fmt(f)
       }
@@ -658,10 +665,12 @@ pub struct AST {
 impl LinedString {
   /// Given an [`Atom`] and associated [`Span`], such as those associated with
   /// [`SExprKind::Atom`], construct a string slice with the string contents
-  /// of the atom.
+  /// of the atom. [`Macro`](Atom::Macro) atoms do not come from the source text, and
+  /// their name is stored in the environment, so for them this returns the source text of
+  /// the macro call that produced the atom (the span of a macro atom is the call).
   #[must_use] pub fn span_atom(&self, sp: Span, a: Atom) -> &[u8] {
     match a {
-      Atom::Ident => &self[sp],
+      Atom::Ident | Atom::Macro(_, _) => &self[sp],
       Atom::Quote => b"quote",
       Atom::Unquote => b"unquote",
       Atom::Nfx => b":nfx",
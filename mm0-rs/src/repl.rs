@@ -0,0 +1,226 @@
+//! The interactive MM1 REPL (`mm0-rs repl`).
+//!
+//! The REPL optionally elaborates a file, and then reads input from the terminal and
+//! elaborates it in the resulting environment. The input can be an MM1 statement
+//! (which must end in `;`), or lisp expressions, which are evaluated as if in a `do`
+//! block and the results printed. Input continues onto the next line while brackets or
+//! formulas are unclosed, or a statement is missing its `;`, and an empty line ends it early.
+//!
+//! Commands start with `:`; use `:help` for a list. The `:theorem` command starts an
+//! interactive proof, in which each input is run as a tactic (as in `focus`) against the
+//! current goals, until the proof is finished with `:qed`.
+
+use std::io;
+use std::path::PathBuf;
+use clap::ArgMatches;
+use rustyline::{Editor, error::ReadlineError};
+use crate::elab::{Elaborator, ElabError, repl::ReplResult};
+use crate::parser::ast::{SExpr, StmtKind};
+use crate::util::{FileRef, Span};
+
+/// The width used for pretty printing results.
+const WIDTH: usize = 80;
+
+/// The words that start an MM1 statement (rather than a lisp expression).
+const STMT_KEYWORDS: [&str; 21] = [
+  "sort", "delimiter", "term", "axiom", "theorem", "def", "input", "output",
+  "prefix", "infixl", "infixr", "coercion", "notation", "do", "import",
+  "pure", "strict", "provable", "free", "abstract", "local"];
+
+const HELP: &str = "\
+Enter MM1 statements (ending in ';') or lisp expressions to evaluate them.
+An empty line ends multi-line input early.
+
+Commands:
+  :theorem NAME BINDERS: $ STMT $   start an interactive proof
+  :goals                           show the current goals (in a proof)
+  :qed                             finish the current proof
+  :abort                           abandon the current proof (the theorem
+                                   is added without a proof, as on error)
+  :help                            show this message
+  :quit                            exit the REPL";
+
+/// Returns true if `s` starts with a statement keyword, annotation or doc comment.
+fn is_stmt(s: &str) -> bool {
+  let s = s.trim_start();
+  if s.starts_with("--|") || s.starts_with('@') {return true}
+  let word = s.split(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '-')).next();
+  word.map_or(false, |w| STMT_KEYWORDS.contains(&w))
+}
+
+/// Returns true if the input is not yet complete: there is an unclosed bracket, string
+/// or formula, or it is a statement that does not end in `;`.
+fn incomplete(s: &str) -> bool {
+  let (mut depth, mut math, mut string) = (0_i32, false, false);
+  let mut it = s.bytes().peekable();
+  while let Some(c) = it.next() {
+    if string {
+      match c {
+        b'\\' => {it.next();}
+        b'"' => string = false,
+        _ => {}
+      }
+    } else if math {
+      if c == b'$' {math = false}
+    } else {
+      match c {
+        b'$' => math = true,
+        b'"' => string = true,
+        b'(' | b'[' | b'{' => depth += 1,
+        b')' | b']' | b'}' => depth -= 1,
+        b'-' if it.peek() == Some(&b'-') => while it.next().map_or(false, |c| c != b'\n') {},
+        _ => {}
+      }
+    }
+  }
+  depth > 0 || math || string || is_stmt(s) && !s.trim_end().ends_with(';')
+}
+
+/// Read an input from the terminal, continuing on more lines if it is incomplete.
+/// Returns `None` at the end of input.
+fn read_input(rl: &mut Editor<()>, prompt: &str) -> Option<String> {
+  let mut input = String::new();
+  loop {
+    let p = if input.is_empty() {prompt} else {"... "};
+    match rl.readline(p) {
+      Ok(line) => {
+        if line.trim().is_empty() {
+          if input.is_empty() {continue}
+          break
+        }
+        if !input.is_empty() {input.push('\n')}
+        input.push_str(&line);
+        if !incomplete(&input) {break}
+      }
+      Err(ReadlineError::Interrupted) => {
+        if input.is_empty() {println!("(use :quit to exit)")}
+        input.clear()
+      }
+      Err(_) => {
+        if input.is_empty() {return None}
+        break
+      }
+    }
+  }
+  rl.add_history_entry(input.as_str());
+  Some(input)
+}
+
+fn print_errors(elab: &mut Elaborator) {
+  for e in elab.take_errors() {
+    println!("{}: {}", e.level, e.kind.msg())
+  }
+}
+
+/// Elaborate an imported file and add it to the session.
+fn import(elab: &mut Elaborator, sp: Span, path: &FileRef) {
+  match crate::compiler::elab_for_result(path.clone()) {
+    Ok((_, Some(env))) => elab.repl_import(sp, &env),
+    Ok((_, None)) => println!("error: failed to elaborate {}", path),
+    Err(e) => println!("error: {}: {}", path, e),
+  }
+}
+
+/// The REPL state.
+struct Repl {
+  elab: Elaborator,
+  rl: Editor<()>,
+}
+
+impl Repl {
+  /// Parse `input`, returning the new statements or printing the parse errors.
+  fn parse(&mut self, input: &str) -> Option<Vec<crate::parser::ast::Stmt>> {
+    match self.elab.repl_parse(input) {
+      Ok(stmts) => Some(stmts),
+      Err(errs) => {
+        for e in errs { println!("{}: {}", e.level, e.msg) }
+        None
+      }
+    }
+  }
+
+  /// The interactive proof loop, used as the proof of a `:theorem` command.
+  fn prove(rl: &mut Editor<()>, elab: &mut Elaborator, e: &SExpr) -> crate::elab::Result<()> {
+    print!("{}", elab.repl_goals(WIDTH));
+    loop {
+      let input = match read_input(rl, "proof> ") {
+        Some(input) => input,
+        None => return Err(ElabError::new_e(e.span, "proof aborted")),
+      };
+      match input.trim() {
+        ":qed" => return Ok(()),
+        ":abort" => return Err(ElabError::new_e(e.span, "proof aborted")),
+        ":goals" => {print!("{}", elab.repl_goals(WIDTH)); continue}
+        ":help" => {println!("{}", HELP); continue}
+        _ => {}
+      }
+      match elab.repl_parse(&format!("do {{\n{}\n}};", input)) {
+        Ok(stmts) => for s in &stmts {
+          if let StmtKind::Do(es) = &s.k {
+            for e in es {
+              if let Err(e) = elab.repl_tactic(e) {
+                println!("error: {}", e.kind.msg());
+                break
+              }
+            }
+          }
+        },
+        Err(errs) => for e in errs { println!("{}: {}", e.level, e.msg) }
+      }
+      print_errors(elab);
+      print!("{}", elab.repl_goals(WIDTH));
+    }
+  }
+
+  /// Elaborate a piece of input.
+  fn run(&mut self, input: &str) {
+    let input = if is_stmt(input) {input.into()} else {format!("do {{\n{}\n}};", input)};
+    let stmts = match self.parse(&input) {Some(stmts) => stmts, None => return};
+    for s in &stmts {
+      let Repl {elab, rl} = self;
+      match elab.repl_stmt(s, |elab, e| Self::prove(rl, elab, e)) {
+        Ok(ReplResult::Ok(vals)) => for v in vals {
+          println!("{}", elab.format_env().pp(&v, WIDTH))
+        },
+        Ok(ReplResult::Import(sp, path)) => import(elab, sp, &path),
+        Err(e) => println!("error: {}", e.kind.msg()),
+      }
+      print_errors(&mut self.elab);
+    }
+  }
+}
+
+/// Main entry point for `mm0-rs repl` subcommand.
+///
+/// # Arguments
+///
+/// `mm0-rs repl [file.mm1]`, where `file.mm1` is an MM1 (or MM0) file to elaborate
+/// before starting. The REPL works in the environment resulting from the file.
+pub fn main(args: &ArgMatches<'_>) -> io::Result<()> {
+  let cwd = std::env::current_dir()?;
+  let mut repl = Repl {
+    elab: Elaborator::new_repl(cwd.join("<repl>").into(), crate::get_check_proofs()),
+    rl: Editor::new(),
+  };
+  if let Some(path) = args.value_of("INPUT") {
+    import(&mut repl.elab, (0..0).into(), &std::fs::canonicalize(path)?.into());
+    print_errors(&mut repl.elab);
+  }
+  let history = std::env::var_os("HOME").map(|h| PathBuf::from(h).join(".mm0_history"));
+  if let Some(h) = &history { let _ = repl.rl.load_history(h); }
+  while let Some(input) = read_input(&mut repl.rl, "> ") {
+    match input.trim() {
+      ":q" | ":quit" => break,
+      ":help" => println!("{}", HELP),
+      ":goals" | ":qed" | ":abort" => println!("error: not in a proof"),
+      s if s.starts_with(":theorem ") => {
+        let thm = s[":theorem ".len()..].trim_end_matches(';');
+        repl.run(&format!("theorem {} = _;", thm))
+      }
+      s if s.starts_with(':') => println!("error: unknown command {}, see :help", s),
+      _ => repl.run(&input),
+    }
+  }
+  if let Some(h) = &history { let _ = repl.rl.save_history(h); }
+  Ok(())
+}
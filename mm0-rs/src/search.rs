@@ -0,0 +1,47 @@
+//! Theorem search from the command line (`mm0-rs search`).
+//!
+//! This elaborates a file, and then prints the theorems in the resulting environment whose
+//! conclusion unifies with a pattern, using the same index as the `find-thms` lisp function.
+
+use std::io;
+use clap::ArgMatches;
+use crate::elab::Elaborator;
+use crate::util::FileRef;
+
+/// Main entry point for `mm0-rs search` subcommand.
+///
+/// # Arguments
+///
+/// `mm0-rs search <in.mm1> <pattern>`, where:
+///
+/// - `in.mm1` is the MM1 (or MM0) file to elaborate
+/// - `pattern` is a math expression (without the surrounding `$`), such as `a + b = b + a`.
+///   Variables and `_` in the pattern can match any expression.
+///
+/// With `--hyps`, theorems where a hypothesis matches the pattern are also returned.
+pub fn main(args: &ArgMatches<'_>) -> io::Result<()> {
+  let path = args.value_of("INPUT").expect("required arg");
+  let path: FileRef = std::fs::canonicalize(path)?.into();
+  let pat = args.value_of("PATTERN").expect("required arg");
+  let env = match crate::compiler::elab_for_result(path)?.1 {
+    Some(env) => env,
+    None => std::process::exit(1),
+  };
+  let cwd = std::env::current_dir()?;
+  let mut elab = Elaborator::new_repl(cwd.join("<search>").into(), false);
+  elab.repl_import((0..0).into(), &env);
+  let pat = match elab.repl_formula(pat) {
+    Ok(pat) => pat,
+    Err(e) => {eprintln!("error: {}", e.kind.msg()); std::process::exit(1)}
+  };
+  let errs = elab.take_errors();
+  for e in &errs { eprintln!("{}: {}", e.level, e.kind.msg()) }
+  if !errs.is_empty() {std::process::exit(1)}
+  let found = match elab.find_thms(None, &pat, args.is_present("hyps")) {
+    Ok(found) => found,
+    Err(e) => {eprintln!("error: invalid pattern: {}", elab.print(&e)); std::process::exit(1)}
+  };
+  let fe = elab.format_env();
+  for t in found { println!("{}\n", fe.to(&elab.thms[t])) }
+  Ok(())
+}
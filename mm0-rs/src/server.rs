@@ -13,25 +13,27 @@ use futures::executor::ThreadPool;
 use futures::lock::Mutex as FMutex;
 use lsp_server::{Connection, ErrorCode, Message, Notification, ProtocolError,
   Request, RequestId, Response, ResponseError};
-use serde::ser::Serialize;
 use serde_json::{from_value, to_value};
 use serde_repr::{Serialize_repr, Deserialize_repr};
-use serde::Deserialize;
+use serde::{Serialize, Deserialize};
 #[allow(clippy::wildcard_imports)] use lsp_types::*;
 use crossbeam::channel::{SendError, RecvError};
 use clap::ArgMatches;
 use crate::util::{ArcList, ArcString, BoxError, FileRef, FileSpan, Span,
   MutexExt, CondvarExt};
 use crate::lined_string::LinedString;
-use crate::parser::{AST, parse};
+use crate::parser::{AST, parse, ast::{Decl, DeclKind, LocalKind, StmtKind, Type}};
+use crate::formatter;
 use crate::mmb::import::elab as mmb_elab;
 use crate::mmu::import::elab as mmu_elab;
 use crate::compiler::FileContents;
 use crate::elab::{ElabResult, self, FrozenEnv,
-  environment::{ObjectKind, DeclKey, StmtTrace, AtomID, SortID, TermID, ThmID},
-  FrozenLispKind, FrozenAtomData,
+  environment::{ObjectKind, DeclKey, StmtTrace, AtomID, SortID, TermID, ThmID,
+    TermKind, ThmKind, ExprNode, ProofNode},
+  FrozenLispKind, FrozenLispVal, FrozenAtomData,
   local_context::InferSort, proof::Subst,
-  lisp::{print::FormatEnv, pretty::Pretty, LispKind, Proc, BuiltinProc},
+  lisp::{print::FormatEnv, pretty::Pretty, LispKind, LispVal, Proc, BuiltinProc, RefineSyntax},
+  refine::InferMode,
   spans::Spans};
 
 // Disabled because vscode doesn't handle them properly
@@ -468,6 +470,15 @@ enum RequestType {
   DocumentSymbol(DocumentSymbolParams),
   References(ReferenceParams),
   DocumentHighlight(DocumentHighlightParams),
+  WorkspaceSymbol(WorkspaceSymbolParams),
+  PrepareCallHierarchy(TextDocumentPositionParams),
+  IncomingCalls(CallHierarchyCallsParams),
+  OutgoingCalls(CallHierarchyCallsParams),
+  InlayHint(InlayHintParams),
+  CodeAction(CodeActionParams),
+  Formatting(DocumentFormattingParams),
+  RangeFormatting(DocumentRangeFormattingParams),
+  FindThms(TextDocumentPositionParams),
 }
 
 fn parse_request(Request {id, method, params}: Request) -> Result<Option<(RequestId, RequestType)>> {
@@ -479,6 +490,16 @@ fn parse_request(Request {id, method, params}: Request) -> Result<Option<(Reques
     "textDocument/documentSymbol"    => Some((id, RequestType::DocumentSymbol(from_value(params)?))),
     "textDocument/references"        => Some((id, RequestType::References(from_value(params)?))),
     "textDocument/documentHighlight" => Some((id, RequestType::DocumentHighlight(from_value(params)?))),
+    "workspace/symbol"               => Some((id, RequestType::WorkspaceSymbol(from_value(params)?))),
+    "textDocument/prepareCallHierarchy" =>
+      Some((id, RequestType::PrepareCallHierarchy(from_value(params)?))),
+    "callHierarchy/incomingCalls"    => Some((id, RequestType::IncomingCalls(from_value(params)?))),
+    "callHierarchy/outgoingCalls"    => Some((id, RequestType::OutgoingCalls(from_value(params)?))),
+    "textDocument/inlayHint"         => Some((id, RequestType::InlayHint(from_value(params)?))),
+    "textDocument/codeAction"        => Some((id, RequestType::CodeAction(from_value(params)?))),
+    "textDocument/formatting"        => Some((id, RequestType::Formatting(from_value(params)?))),
+    "textDocument/rangeFormatting"   => Some((id, RequestType::RangeFormatting(from_value(params)?))),
+    "mm0/findThms"                   => Some((id, RequestType::FindThms(from_value(params)?))),
     _ => None
   })
 }
@@ -566,6 +587,24 @@ impl RequestHandler {
         self.finish(references(file.clone(), doc.position, true,
           |range| DocumentHighlight { range, kind: None }).await)
       }
+      RequestType::WorkspaceSymbol(WorkspaceSymbolParams {query, ..}) =>
+        self.finish(Ok(workspace_symbol(&query))),
+      RequestType::PrepareCallHierarchy(TextDocumentPositionParams {text_document: doc, position}) =>
+        self.finish(prepare_call_hierarchy(doc.uri.into(), position).await),
+      RequestType::IncomingCalls(CallHierarchyCallsParams {item}) =>
+        self.finish(incoming_calls(item).await),
+      RequestType::OutgoingCalls(CallHierarchyCallsParams {item}) =>
+        self.finish(outgoing_calls_request(item).await),
+      RequestType::InlayHint(InlayHintParams {text_document: doc, range}) =>
+        self.finish(inlay_hint(doc.uri.into(), range).await),
+      RequestType::CodeAction(CodeActionParams {text_document: doc, range, context, ..}) =>
+        self.finish(code_action(doc.uri.into(), range, context.diagnostics).await),
+      RequestType::Formatting(DocumentFormattingParams {text_document: doc, ..}) =>
+        self.finish(formatting(&doc.uri.into(), None)),
+      RequestType::RangeFormatting(DocumentRangeFormattingParams {text_document: doc, range, ..}) =>
+        self.finish(formatting(&doc.uri.into(), Some(range))),
+      RequestType::FindThms(TextDocumentPositionParams {text_document: doc, position}) =>
+        self.finish(find_thms(doc.uri.into(), position).await),
     }
   }
 
@@ -667,6 +706,15 @@ async fn hover(path: FileRef, pos: Position) -> StdResult<Option<Hover>, Respons
   let fe = FormatEnv { source: &text, env };
   let spans = or!(Ok(None), Spans::find(&env.spans, idx));
 
+  // Add the attributes of a declaration to the end of its documentation.
+  let with_attrs = |doc: &Option<Arc<str>>, k| {
+    let attrs = env.attrs_of(k);
+    if attrs.is_empty() {return doc.clone()}
+    let mut s = doc.as_ref().map_or_else(String::new, |doc| format!("{}\n\n", doc));
+    s += "attributes:";
+    for a in attrs { use std::fmt::Write; write!(s, " `{}`", env.data[a].name).expect("impossible") }
+    Some(s.into())
+  };
   let mut out: Vec<(Span, MarkedString)> = vec![];
   for &(sp, ref k) in spans.find_pos(idx) {
     if let Some((r, doc)) = (|| Some(match k {
@@ -676,11 +724,11 @@ async fn hover(path: FileRef, pos: Position) -> StdResult<Option<Hover>, Respons
       }
       &ObjectKind::Term(t, sp1) => {
         let td = &env.terms[t];
-        ((sp1, mk_mm0(format!("{}", fe.to(td)))), td.doc.clone())
+        ((sp1, mk_mm0(format!("{}", fe.to(td)))), with_attrs(&td.doc, DeclKey::Term(t)))
       }
       &ObjectKind::Thm(t) => {
         let td = &env.thms[t];
-        ((sp, mk_mm0(format!("{}", fe.to(td)))), td.doc.clone())
+        ((sp, mk_mm0(format!("{}", fe.to(td)))), with_attrs(&td.doc, DeclKey::Thm(t)))
       }
       &ObjectKind::Var(x) => ((sp, mk_mm0(match spans.lc.as_ref().and_then(|lc| lc.vars.get(&x)) {
         Some((_, InferSort::Bound(sort))) => format!("{{{}: {}}}", fe.to(&x), fe.to(sort)),
@@ -857,6 +905,30 @@ async fn definition<T>(path: FileRef, pos: Position,
   Ok(res)
 }
 
+/// Get the [`SymbolKind`] to display for a lisp global with value `e`,
+/// or `None` if the global should not be shown.
+fn global_symbol_kind(e: &FrozenLispVal) -> Option<SymbolKind> {
+  Some(match e.unwrap() {
+    FrozenLispKind::Atom(_) |
+    FrozenLispKind::MVar(_, _) |
+    FrozenLispKind::Goal(_) => SymbolKind::Constant,
+    r @ FrozenLispKind::List(_) |
+    r @ FrozenLispKind::DottedList(_, _) =>
+      if r.is_list() {SymbolKind::Array} else {SymbolKind::Object},
+    FrozenLispKind::Number(_) => SymbolKind::Number,
+    FrozenLispKind::String(_) => SymbolKind::String,
+    FrozenLispKind::Bool(_) => SymbolKind::Boolean,
+    FrozenLispKind::Syntax(_) => SymbolKind::Event,
+    FrozenLispKind::Undef => return None,
+    FrozenLispKind::Proc(_) => SymbolKind::Function,
+    FrozenLispKind::Vector(_) => SymbolKind::Array,
+    FrozenLispKind::AtomMap(_) |
+    FrozenLispKind::HashMap(_) |
+    FrozenLispKind::Annot(_, _) |
+    FrozenLispKind::Ref(_) => SymbolKind::Object,
+  })
+}
+
 #[allow(deprecated)] // workaround rust#60681
 async fn document_symbol(path: FileRef) -> StdResult<DocumentSymbolResponse, ResponseError> {
   let file = SERVER.vfs.get(&path).ok_or_else(||
@@ -914,23 +986,7 @@ async fn document_symbol(path: FileRef) -> StdResult<DocumentSymbolResponse, Res
           if let Some((ref fsp, full)) = *ld.src() {
             let e = &**ld;
             push!(fsp, ad.name(), format!("{}", fe.to(unsafe { e.thaw() })), full,
-              match (|| Some(match e.unwrap() {
-                FrozenLispKind::Atom(_) |
-                FrozenLispKind::MVar(_, _) |
-                FrozenLispKind::Goal(_) => SymbolKind::Constant,
-                r @ FrozenLispKind::List(_) |
-                r @ FrozenLispKind::DottedList(_, _) =>
-                  if r.is_list() {SymbolKind::Array} else {SymbolKind::Object},
-                FrozenLispKind::Number(_) => SymbolKind::Number,
-                FrozenLispKind::String(_) => SymbolKind::String,
-                FrozenLispKind::Bool(_) => SymbolKind::Boolean,
-                FrozenLispKind::Syntax(_) => SymbolKind::Event,
-                FrozenLispKind::Undef => return None,
-                FrozenLispKind::Proc(_) => SymbolKind::Function,
-                FrozenLispKind::AtomMap(_) |
-                FrozenLispKind::Annot(_, _) |
-                FrozenLispKind::Ref(_) => SymbolKind::Object,
-              }))() {
+              match global_symbol_kind(e) {
                 Some(sk) => sk,
                 None => continue,
               });
@@ -943,6 +999,67 @@ async fn document_symbol(path: FileRef) -> StdResult<DocumentSymbolResponse, Res
   Ok(DocumentSymbolResponse::Nested(res))
 }
 
+/// Returns true if every character of `query` appears in `name` in order,
+/// ignoring ASCII case. This is the usual "fuzzy" matching used by editors.
+fn fuzzy_match(query: &[u8], name: &[u8]) -> bool {
+  let mut it = name.iter();
+  query.iter().all(|c| it.any(|d| c.eq_ignore_ascii_case(d)))
+}
+
+#[allow(deprecated)] // workaround rust#60681
+fn workspace_symbol(query: &str) -> Vec<SymbolInformation> {
+  let vfs = &SERVER.vfs;
+  let files: Vec<_> = vfs.0.ulock().values().cloned().collect();
+  let query = query.as_bytes();
+  let mut sources: HashMap<FileRef, Option<Arc<LinedString>>> = HashMap::new();
+  let mut seen = HashSet::new();
+  let mut res = vec![];
+  for file in files {
+    let env = match try_old(&file) {Some((_, env)) => env, None => continue};
+    let mut push = |fsp: &FileSpan, name: &ArcString, kind, container: &str| {
+      if !seen.insert((fsp.file.clone(), fsp.span, kind as u8)) {return}
+      let src = sources.entry(fsp.file.clone()).or_insert_with(||
+        vfs.get(&fsp.file).and_then(|f| f.text.ulock().1.try_ascii().cloned()));
+      if let Some(src) = src {
+        res.push(SymbolInformation {
+          name: String::from_utf8_lossy(name).into(),
+          kind,
+          #[allow(deprecated)] deprecated: None,
+          location: src.to_loc(fsp),
+          container_name: Some(container.into()),
+        })
+      }
+    };
+    for ad in env.data().iter() {
+      if !fuzzy_match(query, ad.name()) {continue}
+      if let Some(s) = ad.sort() {
+        push(&env.sort(s).span, ad.name(), SymbolKind::Class, "sort")
+      }
+      match ad.decl() {
+        Some(DeclKey::Term(t)) => {
+          let td = env.term(t);
+          let kind = if let TermKind::Term = td.kind {"term"} else {"def"};
+          push(&td.span, ad.name(), SymbolKind::Constructor, kind)
+        }
+        Some(DeclKey::Thm(t)) => {
+          let td = env.thm(t);
+          let kind = if let ThmKind::Axiom = td.kind {"axiom"} else {"theorem"};
+          push(&td.span, ad.name(), SymbolKind::Method, kind)
+        }
+        None => {}
+      }
+      if let Some(ld) = ad.lisp() {
+        if let Some((ref fsp, _)) = *ld.src() {
+          if let Some(kind) = global_symbol_kind(ld) {
+            push(fsp, ad.name(), kind, "global")
+          }
+        }
+      }
+    }
+  }
+  res
+}
+
 #[derive(Serialize_repr, Deserialize_repr)]
 #[repr(u8)]
 enum TraceKind {Sort, Decl, Global}
@@ -979,6 +1096,8 @@ fn make_completion_item(path: &FileRef, fe: FormatEnv<'_>, ad: &FrozenAtomData,
         FrozenLispKind::String(_) |
         FrozenLispKind::Bool(_) |
         FrozenLispKind::AtomMap(_) |
+        FrozenLispKind::HashMap(_) |
+        FrozenLispKind::Vector(_) |
         FrozenLispKind::Annot(_, _) |
         FrozenLispKind::Ref(_) => CompletionItemKind::Value,
         FrozenLispKind::Syntax(_) => CompletionItemKind::Event,
@@ -1116,6 +1235,716 @@ async fn references<T>(
   Ok(res)
 }
 
+/// An item in the call hierarchy, which is a `term`, `def`, `axiom` or `theorem`.
+/// (We define these locally because `lsp_types` does not yet support call hierarchy.)
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CallHierarchyItem {
+  name: String,
+  kind: SymbolKind,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  detail: Option<String>,
+  uri: Url,
+  range: Range,
+  selection_range: Range,
+  /// The file whose environment was used to resolve the item.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  data: Option<Url>,
+}
+
+#[derive(Debug, Deserialize)]
+struct CallHierarchyCallsParams {
+  item: CallHierarchyItem,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct CallHierarchyIncomingCall {
+  from: CallHierarchyItem,
+  from_ranges: Vec<Range>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct CallHierarchyOutgoingCall {
+  to: CallHierarchyItem,
+  from_ranges: Vec<Range>,
+}
+
+/// Call `f` on every term and theorem referenced in an expression.
+fn expr_uses(e: &ExprNode, f: &mut impl FnMut(DeclKey)) {
+  if let ExprNode::App(t, ref es) = *e {
+    f(DeclKey::Term(t));
+    for e in &**es { expr_uses(e, f) }
+  }
+}
+
+/// Call `f` on every term and theorem referenced in a proof.
+fn proof_uses(p: &ProofNode, f: &mut impl FnMut(DeclKey)) {
+  match p {
+    ProofNode::Ref(_) | ProofNode::Dummy(_, _) => {}
+    ProofNode::Term {term, args} | ProofNode::Cong {term, args} => {
+      f(DeclKey::Term(*term));
+      for p in &**args { proof_uses(p, f) }
+    }
+    ProofNode::Hyp(_, p) | ProofNode::Refl(p) | ProofNode::Sym(p) => proof_uses(p, f),
+    ProofNode::Thm {thm, args, res} => {
+      f(DeclKey::Thm(*thm));
+      for p in &**args { proof_uses(p, f) }
+      proof_uses(res, f)
+    }
+    ProofNode::Conv(p) => {
+      proof_uses(&p.0, f);
+      proof_uses(&p.1, f);
+      proof_uses(&p.2, f)
+    }
+    ProofNode::Unfold {term, args, res} => {
+      f(DeclKey::Term(*term));
+      for p in &**args { proof_uses(p, f) }
+      proof_uses(&res.0, f);
+      proof_uses(&res.1, f);
+      proof_uses(&res.2, f)
+    }
+  }
+}
+
+/// Get the list of calls made by a declaration, without duplicates. For a theorem these are
+/// the theorems used in the proof, and for a definition these are the terms in the body.
+fn outgoing_calls(env: &FrozenEnv, dk: DeclKey) -> Vec<DeclKey> {
+  let mut res = vec![];
+  let mut push = |k: DeclKey| if !res.contains(&k) { res.push(k) };
+  match dk {
+    DeclKey::Term(t) => if let TermKind::Def(Some(e)) = &env.term(t).kind {
+      for e in &*e.heap { expr_uses(e, &mut push) }
+      expr_uses(&e.head, &mut push)
+    },
+    DeclKey::Thm(t) => if let ThmKind::Thm(Some(pf)) = &env.thm(t).kind {
+      let mut push = |k| if let DeclKey::Thm(_) = k { push(k) };
+      for p in &*pf.heap { proof_uses(p, &mut push) }
+      for p in &*pf.hyps { proof_uses(p, &mut push) }
+      proof_uses(&pf.head, &mut push)
+    }
+  }
+  res
+}
+
+/// Get the declaration named by the object at `idx`, if any.
+fn decl_at(env: &FrozenEnv, idx: usize) -> Option<DeclKey> {
+  let spans = env.find(idx)?;
+  let head_decl = |e: &FrozenLispVal| e.uncons().next().unwrap_or(e).as_atom()
+    .and_then(|a| env.data()[a].decl());
+  spans.find_pos(idx).find_map(|(_, k)| match *k {
+    ObjectKind::Term(t, _) => Some(DeclKey::Term(t)),
+    ObjectKind::Thm(t) => Some(DeclKey::Thm(t)),
+    ObjectKind::Expr(ref e) => head_decl(e).filter(|k| matches!(k, DeclKey::Term(_))),
+    ObjectKind::Proof(ref p) => head_decl(p).filter(|k| matches!(k, DeclKey::Thm(_))),
+    ObjectKind::Global(a) => env.data()[a].decl(),
+    _ => None,
+  })
+}
+
+/// Find the ranges inside the declaration `from` (which must be a declaration in the
+/// file `path` elaborated to `env`) that refer to `to`.
+fn call_ranges(env: &FrozenEnv, text: &LinedString, from: &FileSpan, to: DeclKey) -> Vec<Range> {
+  let mut res = vec![];
+  if let Some(spans) = env.find(from.span.start) {
+    for &(sp, ref k) in spans {
+      let e = match k {
+        ObjectKind::Expr(e) | ObjectKind::Proof(e) => e,
+        _ => continue
+      };
+      let head = e.uncons().next().unwrap_or(e);
+      if head.as_atom().and_then(|a| env.data()[a].decl()) == Some(to) {
+        res.push(text.to_range(head.fspan().map_or(sp, |fsp| fsp.span)))
+      }
+    }
+  }
+  if res.is_empty() { res.push(text.to_range(from.span)) }
+  res
+}
+
+fn call_hierarchy_item(env: &FrozenEnv, origin: &FileRef, dk: DeclKey) -> Option<CallHierarchyItem> {
+  let (atom, span, full, kind, detail) = match dk {
+    DeclKey::Term(t) => {
+      let td = env.term(t);
+      let detail = if let TermKind::Term = td.kind {"term"} else {"def"};
+      (td.atom, &td.span, td.full, SymbolKind::Constructor, detail)
+    }
+    DeclKey::Thm(t) => {
+      let td = env.thm(t);
+      let detail = if let ThmKind::Axiom = td.kind {"axiom"} else {"theorem"};
+      (td.atom, &td.span, td.full, SymbolKind::Method, detail)
+    }
+  };
+  let text = SERVER.vfs.get(&span.file)?.text.ulock().1.try_ascii()?.clone();
+  Some(CallHierarchyItem {
+    name: String::from_utf8_lossy(env.data()[atom].name()).into(),
+    kind,
+    detail: Some(detail.into()),
+    uri: span.file.url().clone(),
+    range: text.to_range(full),
+    selection_range: text.to_range(span.span),
+    data: Some(origin.url().clone()),
+  })
+}
+
+/// Get the environment for a file, preferring the last good environment if the user
+/// has chosen to elaborate on save.
+async fn get_env(path: FileRef) -> StdResult<Option<(Arc<LinedString>, FrozenEnv)>, ResponseError> {
+  let file = SERVER.vfs.get(&path).ok_or_else(||
+    response_err(ErrorCode::InvalidRequest, "call hierarchy: nonexistent file"))?;
+  let maybe_old = if SERVER.elab_on().unwrap_or_default() == ElabOn::Save { try_old(&file) } else { None };
+  if let Some((contents, frozen)) = maybe_old {
+    return Ok(contents.try_ascii().map(|text| (text.clone(), frozen)))
+  }
+  let env = elaborate(path, Some(Position::default()), Default::default(), Default::default())
+    .await.map_err(|e| response_err(ErrorCode::InternalError, format!("{:?}", e)))?;
+  Ok(match env.into_response_error()? {
+    None => None,
+    Some((_, env)) => file.text.ulock().1.try_ascii().map(|text| (text.clone(), env))
+  })
+}
+
+/// Resolve a [`CallHierarchyItem`] back to a declaration in `env`.
+fn resolve_item(env: &FrozenEnv, item: &CallHierarchyItem) -> Option<DeclKey> {
+  let dk = env.data()[env.get_atom(item.name.as_bytes())?].decl()?;
+  let file = match dk {
+    DeclKey::Term(t) => &env.term(t).span.file,
+    DeclKey::Thm(t) => &env.thm(t).span.file,
+  };
+  if *file.url() == item.uri {Some(dk)} else {None}
+}
+
+async fn prepare_call_hierarchy(path: FileRef, pos: Position) ->
+    StdResult<Option<Vec<CallHierarchyItem>>, ResponseError> {
+  let (text, env) = match get_env(path.clone()).await? {Some(x) => x, None => return Ok(None)};
+  let idx = match text.to_idx(pos) {Some(idx) => idx, None => return Ok(None)};
+  Ok(decl_at(&env, idx)
+    .and_then(|dk| call_hierarchy_item(&env, &path, dk))
+    .map(|item| vec![item]))
+}
+
+async fn incoming_calls(item: CallHierarchyItem) ->
+    StdResult<Vec<CallHierarchyIncomingCall>, ResponseError> {
+  // Make sure that the originating file is up to date, because most of the
+  // users of a declaration are usually in the same file.
+  if let Some(origin) = &item.data { get_env(origin.clone().into()).await?; }
+  let files: Vec<_> = SERVER.vfs.0.ulock().iter()
+    .map(|(path, file)| (path.clone(), file.clone())).collect();
+  let mut seen = HashSet::new();
+  let mut res = vec![];
+  for (path, file) in files {
+    let (text, env) = match try_old(&file) {Some(x) => x, None => continue};
+    let text = match text.try_ascii() {Some(text) => text, None => continue};
+    let to = match resolve_item(&env, &item) {Some(dk) => dk, None => continue};
+    for s in env.stmts() {
+      let dk = match *s {
+        StmtTrace::Decl(a) => env.data()[a].decl().expect("env well formed"),
+        _ => continue
+      };
+      let span = match dk {
+        DeclKey::Term(t) => &env.term(t).span,
+        DeclKey::Thm(t) => &env.thm(t).span,
+      };
+      if span.file != path || !outgoing_calls(&env, dk).contains(&to) ||
+        !seen.insert((span.file.clone(), span.span)) {continue}
+      if let Some(from) = call_hierarchy_item(&env, &path, dk) {
+        res.push(CallHierarchyIncomingCall {
+          from_ranges: call_ranges(&env, text, span, to),
+          from,
+        })
+      }
+    }
+  }
+  Ok(res)
+}
+
+async fn outgoing_calls_request(item: CallHierarchyItem) ->
+    StdResult<Vec<CallHierarchyOutgoingCall>, ResponseError> {
+  let origin: FileRef = item.data.clone().unwrap_or_else(|| item.uri.clone()).into();
+  let (text, env) = match get_env(origin.clone()).await? {Some(x) => x, None => return Ok(vec![])};
+  let from = match resolve_item(&env, &item) {Some(dk) => dk, None => return Ok(vec![])};
+  let span = match from {
+    DeclKey::Term(t) => &env.term(t).span,
+    DeclKey::Thm(t) => &env.thm(t).span,
+  };
+  Ok(outgoing_calls(&env, from).into_iter().filter_map(|to| {
+    Some(CallHierarchyOutgoingCall {
+      to: call_hierarchy_item(&env, &origin, to)?,
+      from_ranges: if span.file == origin {
+        call_ranges(&env, &text, span, to)
+      } else { vec![] },
+    })
+  }).collect())
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct InlayHintParams {
+  text_document: TextDocumentIdentifier,
+  range: Range,
+}
+
+#[derive(Copy, Clone, Debug, Serialize_repr)]
+#[repr(u8)]
+enum InlayHintKind {Type = 1, Parameter = 2}
+
+/// A theorem found by a `mm0/findThms` request.
+#[derive(Debug, Serialize)]
+struct FoundThm {
+  name: String,
+  /// The statement of the theorem, as it would be printed on hover.
+  statement: String,
+  location: Location,
+}
+
+/// Handle the `mm0/findThms` request, which finds the theorems whose conclusion unifies
+/// with the innermost expression (usually an unsolved goal) at the given position.
+async fn find_thms(path: FileRef, pos: Position) -> StdResult<Vec<FoundThm>, ResponseError> {
+  let (text, env) = match get_env(path.clone()).await? {Some(x) => x, None => return Ok(vec![])};
+  let idx = match text.to_idx(pos) {Some(idx) => idx, None => return Ok(vec![])};
+  let fe = unsafe { env.format_env(&text) };
+  let env = unsafe { env.thaw() };
+  let spans = match Spans::find(&env.spans, idx) {Some(spans) => spans, None => return Ok(vec![])};
+  let e = spans.find_pos(idx).filter_map(|(sp, k)| match k {
+    ObjectKind::Expr(e) => Some((sp.end - sp.start, e)),
+    _ => None,
+  }).min_by_key(|p| p.0);
+  let e = match e {Some((_, e)) => unsafe { e.thaw() }, None => return Ok(vec![])};
+  let ths = env.find_thms(spans.lc.as_ref(), e, false).map_err(|e|
+    response_err(ErrorCode::InvalidParams, format!("invalid pattern: {}", fe.to(&e))))?;
+  Ok(ths.into_iter().map(|t| {
+    let td = &env.thms[t];
+    let location = if td.span.file.ptr_eq(&path) {
+      text.to_loc(&td.span)
+    } else {
+      SERVER.vfs.source(&td.span.file).to_loc(&td.span)
+    };
+    FoundThm {
+      name: String::from_utf8_lossy(&env.data[td.atom].name).into(),
+      statement: format!("{}", fe.to(td)),
+      location,
+    }
+  }).collect())
+}
+
+/// An inlay hint, which is a small piece of text displayed inline in the editor.
+/// (We define these locally because `lsp_types` does not yet support inlay hints.)
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct InlayHint {
+  position: Position,
+  label: String,
+  kind: InlayHintKind,
+  padding_left: bool,
+}
+
+async fn inlay_hint(path: FileRef, range: Range) -> StdResult<Vec<InlayHint>, ResponseError> {
+  let (arg_hints, sort_hints) = {
+    let opts = SERVER.options.ulock();
+    (opts.implicit_arg_hints.unwrap_or(true), opts.inferred_sort_hints.unwrap_or(true))
+  };
+  if !arg_hints && !sort_hints {return Ok(vec![])}
+  let (text, env) = match get_env(path).await? {Some(x) => x, None => return Ok(vec![])};
+  let start = text.to_idx(range.start).unwrap_or(0);
+  let end = text.to_idx(range.end).unwrap_or_else(|| text.len());
+  let fe = unsafe { env.format_env(&text) };
+  let mut res = vec![];
+  for spans in env.spans() {
+    let stmt = spans.stmt();
+    if stmt.end < start || end < stmt.start {continue}
+    let in_range = |sp: Span| start <= sp.end && sp.end <= end;
+    if sort_hints {
+      if let Some(lc) = &spans.lc {
+        for &(sp, a) in &lc.inferred {
+          if !in_range(sp) {continue}
+          if let Some(s) = lc.vars.get(&a).and_then(|(_, is)| is.sort()) {
+            res.push(InlayHint {
+              position: text.to_pos(sp.end),
+              label: format!(": {}", fe.to(&s)),
+              kind: InlayHintKind::Type,
+              padding_left: false,
+            })
+          }
+        }
+      }
+    }
+    if arg_hints {
+      // `(! foo ...)` and `(!! foo ...)` make some of the arguments explicit
+      let mut modes = vec![];
+      for &(sp, ref k) in spans {
+        match *k {
+          ObjectKind::RefineSyntax(RefineSyntax::Explicit) => modes.push((sp.end, InferMode::Explicit)),
+          ObjectKind::RefineSyntax(RefineSyntax::BoundOnly) => modes.push((sp.end, InferMode::BoundOnly)),
+          _ => {}
+        }
+      }
+      for &(sp, ref k) in spans {
+        let p = match k {ObjectKind::Proof(p) if in_range(sp) => p, _ => continue};
+        let mut u = p.uncons();
+        let t = match u.next().and_then(|head| head.as_atom()).and_then(|a| env.data()[a].decl()) {
+          Some(DeclKey::Thm(t)) => t,
+          _ => continue
+        };
+        let mode = modes.iter().find(|&&(end, _)| end <= sp.start &&
+          text.as_bytes()[end..sp.start].iter().all(u8::is_ascii_whitespace))
+          .map_or(InferMode::Regular, |&(_, im)| im);
+        let td = env.thm(t);
+        for &(x, ty) in &*td.args {
+          let e = match u.next() {Some(e) => e, None => break};
+          let explicit = match mode {
+            InferMode::Regular => false,
+            InferMode::Explicit => true,
+            InferMode::BoundOnly => ty.bound(),
+          };
+          if explicit {continue}
+          let name = x.map_or_else(|| "_".into(), |x| String::from_utf8_lossy(env.data()[x].name()));
+          res.push(InlayHint {
+            position: text.to_pos(sp.end),
+            label: format!("{} := {}", name, fe.pp(unsafe { e.thaw() }, 80)),
+            kind: InlayHintKind::Parameter,
+            padding_left: true,
+          })
+        }
+      }
+    }
+  }
+  Ok(res)
+}
+
+/// Get the most recent parse of a file, if it is available.
+fn try_ast(file: &VirtualFile) -> Option<Arc<AST>> {
+  file.parsed.try_lock().and_then(|g| match &*g {
+    Some(FileCache::Ready {ast, ..}) => ast.clone(),
+    _ => None
+  })
+}
+
+/// Get the declaration statement containing position `idx`, along with the span of the
+/// full statement including any doc comments and annotations.
+fn decl_stmt_at(ast: &AST, idx: usize) -> Option<(Span, &Decl)> {
+  let stmt = ast.stmts.iter().find(|s| s.span.start <= idx && idx <= s.span.end)?;
+  let mut s = stmt;
+  loop {
+    match &s.k {
+      StmtKind::Annot(_, s2) | StmtKind::DocComment(_, s2) => s = s2,
+      StmtKind::Decl(d) => return Some((stmt.span, d)),
+      _ => return None
+    }
+  }
+}
+
+/// The Levenshtein distance between two strings.
+fn edit_distance(a: &[u8], b: &[u8]) -> usize {
+  let mut row: Vec<usize> = (0..=b.len()).collect();
+  for (i, &ca) in a.iter().enumerate() {
+    let mut prev = row[0];
+    row[0] = i + 1;
+    for (j, &cb) in b.iter().enumerate() {
+      let cur = row[j + 1];
+      row[j + 1] = if ca == cb {prev} else {1 + prev.min(cur).min(row[j])};
+      prev = cur;
+    }
+  }
+  row[b.len()]
+}
+
+/// True if the atom `a` appears in the expression `e`.
+fn occurs(a: AtomID, e: &LispVal) -> bool {
+  e.unwrapped(|r| match r {
+    &LispKind::Atom(b) => a == b,
+    LispKind::List(es) => es.iter().any(|e| occurs(a, e)),
+    LispKind::DottedList(es, r) => es.iter().any(|e| occurs(a, e)) || occurs(a, r),
+    _ => false,
+  })
+}
+
+fn edit_action(title: String, kind: CodeActionKind, uri: &Url,
+  edits: Vec<TextEdit>, diags: &[Diagnostic]
+) -> CodeActionOrCommand {
+  let mut changes = HashMap::new();
+  changes.insert(uri.clone(), edits);
+  CodeActionOrCommand::CodeAction(CodeAction {
+    title,
+    kind: Some(kind),
+    diagnostics: if diags.is_empty() {None} else {Some(diags.into())},
+    edit: Some(WorkspaceEdit {changes: Some(changes), ..Default::default()}),
+    ..Default::default()
+  })
+}
+
+/// Quick fixes for `unknown theorem 'foo'` and similar errors. We suggest declarations
+/// with similar names, and files in the workspace that declare the missing name.
+fn unknown_ident_actions(path: &FileRef, text: &LinedString, env: &FrozenEnv, ast: Option<&AST>,
+  diag: &Diagnostic, res: &mut Vec<CodeActionOrCommand>
+) {
+  const PREFIXES: [(&str, bool); 4] = [
+    ("unknown theorem/hypothesis '", true),
+    ("unknown theorem '", true),
+    ("unknown hypothesis '", true),
+    ("unknown term '", false),
+  ];
+  let (name, thm) = match PREFIXES.iter().find_map(|&(pfx, thm)|
+    Some((diag.message.strip_prefix(pfx)?.strip_suffix('\'')?, thm))) {
+    Some((name, thm)) => (name.as_bytes(), thm),
+    None => return
+  };
+  let is_kind = |dk: Option<DeclKey>| match dk {
+    Some(DeclKey::Thm(_)) => thm,
+    Some(DeclKey::Term(_)) => !thm,
+    None => false,
+  };
+  let diags = std::slice::from_ref(diag);
+  if let Some(sp) = text.to_idx(diag.range.start).zip(text.to_idx(diag.range.end)) {
+    if text.as_bytes()[sp.0..sp.1] == *name {
+      let max = (name.len() >> 2).max(1);
+      let mut similar: Vec<_> = env.data().iter()
+        .filter(|ad| is_kind(ad.decl()))
+        .map(|ad| (edit_distance(name, ad.name()), ad.name()))
+        .filter(|&(d, _)| d <= max)
+        .collect();
+      similar.sort_by_key(|&(d, _)| d);
+      for (_, name2) in similar.into_iter().take(5) {
+        let name2 = String::from_utf8_lossy(name2);
+        res.push(edit_action(format!("Change to '{}'", name2), CodeActionKind::QUICKFIX,
+          path.url(), vec![TextEdit {range: diag.range, new_text: name2.into()}], diags))
+      }
+    }
+  }
+  let files: Vec<_> = SERVER.vfs.0.ulock().iter()
+    .filter(|&(p, _)| p != path)
+    .map(|(p, file)| (p.clone(), file.clone())).collect();
+  let dir = path.path().parent();
+  for (p, file) in files {
+    let env2 = match try_old(&file) {Some((_, env)) => env, None => continue};
+    let dk = env2.get_atom(name).and_then(|a| env2.data()[a].decl());
+    if !is_kind(dk) {continue}
+    let span = match dk {
+      Some(DeclKey::Term(t)) => &env2.term(t).span,
+      Some(DeclKey::Thm(t)) => &env2.thm(t).span,
+      None => continue,
+    };
+    if span.file != p {continue}
+    let rel = dir.and_then(|dir| pathdiff::diff_paths(p.path(), dir))
+      .unwrap_or_else(|| p.path().clone());
+    let rel = rel.to_string_lossy().replace('\\', "/");
+    let (pos, new_text) = match ast.and_then(|ast| ast.stmts.iter().rev()
+      .find(|s| matches!(s.k, StmtKind::Import(_, _)))) {
+      Some(s) => (text.to_pos(s.span.end), format!("\nimport \"{}\";", rel)),
+      None => (Position::default(), format!("import \"{}\";\n", rel)),
+    };
+    res.push(edit_action(format!("Import \"{}\"", rel), CodeActionKind::QUICKFIX,
+      path.url(), vec![TextEdit {range: Range {start: pos, end: pos}, new_text}], diags))
+  }
+}
+
+/// Quick fixes for unsolved goals (which are reported as `|- goal`), which insert
+/// placeholders for the missing subproofs.
+fn unsolved_goal_actions(path: &FileRef, text: &LinedString, diags: &[Diagnostic],
+  res: &mut Vec<CodeActionOrCommand>
+) {
+  let mut groups: Vec<(Range, Vec<Diagnostic>)> = vec![];
+  for diag in diags.iter().filter(|d| d.message.starts_with("|- ")) {
+    match groups.iter_mut().find(|(r, _)| *r == diag.range) {
+      Some((_, ds)) => ds.push(diag.clone()),
+      None => groups.push((diag.range, vec![diag.clone()])),
+    }
+  }
+  for (range, ds) in groups {
+    let (start, end) = match text.to_idx(range.start).zip(text.to_idx(range.end)) {
+      Some(sp) => sp,
+      None => continue
+    };
+    let s = &text.as_bytes()[start..end];
+    if s == b"_" {
+      res.push(edit_action("Replace `_` with `?`".into(), CodeActionKind::QUICKFIX,
+        path.url(), vec![TextEdit {range, new_text: "?".into()}], &ds))
+    } else if s.len() >= 2 && s[0] == b'(' && s[s.len() - 1] == b')' {
+      let pos = text.to_pos(end - 1);
+      let range = Range {start: pos, end: pos};
+      let n = ds.len();
+      for &ph in &["_", "?"] {
+        let title = if n == 1 {
+          format!("Insert `{}` for the missing subproof", ph)
+        } else {
+          format!("Insert `{}` for the {} missing subproofs", ph, n)
+        };
+        res.push(edit_action(title, CodeActionKind::QUICKFIX, path.url(),
+          vec![TextEdit {range, new_text: format!(" {}", ph).repeat(n)}], &ds))
+      }
+    }
+  }
+}
+
+/// Actions to add binders for variables whose types were inferred.
+fn add_binder_actions(path: &FileRef, text: &LinedString, env: &FrozenEnv, ast: &AST,
+  (start, end): (usize, usize), diags: &[Diagnostic], res: &mut Vec<CodeActionOrCommand>
+) {
+  let spans = match env.find(start) {Some(spans) => spans, None => return};
+  let lc = match &spans.lc {Some(lc) => lc, None => return};
+  let d = match decl_stmt_at(ast, start) {Some((_, d)) => d, None => return};
+  let pos = text.to_pos(d.bis.iter().rev().find(|bi| !matches!(bi.ty, Some(Type::Formula(_))))
+    .map_or(d.id.end, |bi| bi.span.end));
+  for &(sp, a) in &lc.inferred {
+    if sp.end < start || end < sp.start {continue}
+    let (dummy, is) = match lc.vars.get(&a) {Some(v) => v, None => continue};
+    let s = match is.sort() {Some(s) => s, None => continue};
+    let (name, sort) = (String::from_utf8_lossy(env.data()[a].name()), &env.sort(s).name);
+    let new_text = match (dummy, is) {
+      (true, _) => format!(" {{.{}: {}}}", name, sort),
+      (false, InferSort::Bound(_)) => format!(" {{{}: {}}}", name, sort),
+      (false, _) => format!(" ({}: {})", name, sort),
+    };
+    let diags: Vec<_> = diags.iter()
+      .filter(|d| d.range == text.to_range(sp) && d.message.contains("inferred variable type"))
+      .cloned().collect();
+    let kind = if diags.is_empty() {CodeActionKind::REFACTOR} else {CodeActionKind::QUICKFIX};
+    res.push(edit_action(format!("Add binder `{}`", new_text.trim_start()), kind, path.url(),
+      vec![TextEdit {range: Range {start: pos, end: pos}, new_text}], &diags))
+  }
+}
+
+/// The "extract lemma" refactoring, which turns the selected subproof into a separate theorem.
+fn extract_lemma_action(path: &FileRef, text: &LinedString, env: &FrozenEnv, ast: &AST,
+  (start, end): (usize, usize), res: &mut Vec<CodeActionOrCommand>
+) -> Option<()> {
+  use std::fmt::Write;
+  let sel = text.get(start..end)?;
+  let trimmed = sel.trim();
+  if !(trimmed.starts_with('(') && trimmed.ends_with(')')) {return None}
+  let start = start + (sel.len() - sel.trim_start().len());
+  let end = start + trimmed.len();
+  let spans = env.find(start)?;
+  let lc = spans.lc.as_ref()?;
+  let (stmt, decl) = decl_stmt_at(ast, start)?;
+  if decl.k != DeclKind::Thm {return None}
+  let thm = decl.val.as_ref()?;
+  if !(thm.span.start <= start && end <= thm.span.end) {return None}
+  // The head of the selected application is the first proof object in the selection.
+  let (_, p, t) = spans.into_iter()
+    .filter(|(sp, _)| start < sp.start && sp.end < end)
+    .filter_map(|(sp, k)| match k {
+      ObjectKind::Proof(p) => match p.uncons().next()?.as_atom().and_then(|a| env.data()[a].decl()) {
+        Some(DeclKey::Thm(t)) => Some((sp.start, p, t)),
+        _ => None
+      },
+      _ => None
+    })
+    .min_by_key(|&(start, _, _)| start)?;
+  let env_t = unsafe { env.thaw() };
+  let fe = FormatEnv { source: text, env: env_t };
+  let td = env.thm(t);
+  let mut u = p.uncons();
+  u.next();
+  let args = (0..td.args.len()).map(|_| Some(unsafe { u.next()?.thaw() }.clone()))
+    .collect::<Option<Vec<_>>>()?;
+  let mut subst = Subst::new(env_t, &td.heap, args);
+  let ret = subst.subst(&td.ret);
+
+  // The hypotheses of the new lemma are the local hypotheses used in the selection
+  let mut hyps = vec![];
+  let mut dummies = vec![];
+  for (sp, k) in spans {
+    if sp.start < start || end < sp.end {continue}
+    match k {
+      ObjectKind::Proof(p) => if let Some(h) = p.as_atom() {
+        if let Some(&i) = lc.proofs.get(&h) {
+          if !hyps.iter().any(|&(h2, _)| h2 == h) { hyps.push((h, &lc.proof_order[i].1)) }
+        }
+      },
+      &ObjectKind::Var(x) => if let Some(&(true, InferSort::Bound(s))) = lc.vars.get(&x) {
+        if !dummies.iter().any(|&(y, _)| y == x) { dummies.push((x, s)) }
+      },
+      ObjectKind::Expr(e) => if let Some(x) = e.as_atom() {
+        if let Some(&(true, InferSort::Bound(s))) = lc.vars.get(&x) {
+          if !dummies.iter().any(|&(y, _)| y == x) { dummies.push((x, s)) }
+        }
+      },
+      _ => {}
+    }
+  }
+
+  let thm_name = String::from_utf8_lossy(ast.span(decl.id));
+  let mut name = format!("{}_lem", thm_name);
+  let mut i = 1;
+  while env.get_atom(name.as_bytes()).map_or(false, |a| env.data()[a].decl().is_some()) {
+    i += 1;
+    name = format!("{}_lem{}", thm_name, i);
+  }
+
+  let mut lemma = format!("theorem {}", name);
+  for bi in &decl.bis {
+    if matches!(bi.ty, Some(Type::Formula(_))) || bi.kind == LocalKind::Dummy {continue}
+    write!(lemma, " {}", String::from_utf8_lossy(&text.as_bytes()[bi.span.start..bi.span.end])).ok()?;
+  }
+  for &(x, s) in &dummies {
+    let dot = if occurs(x, &ret) || hyps.iter().any(|(_, h)| occurs(x, h)) {""} else {"."};
+    write!(lemma, " {{{}{}: {}}}", dot, fe.to(&x), fe.to(&s)).ok()?;
+  }
+  for &(h, e) in &hyps {
+    write!(lemma, " ({}: $ {} $)", fe.to(&h), fe.pp(e, 80)).ok()?;
+  }
+  write!(lemma, ":\n  $ {} $ =\n'{};\n\n", fe.pp(&ret, 80), trimmed).ok()?;
+
+  let call = if hyps.is_empty() { name } else {
+    let mut call = format!("({}", name);
+    for &(h, _) in &hyps { write!(call, " {}", fe.to(&h)).ok()? }
+    call.push(')');
+    call
+  };
+  let pos = text.to_pos(stmt.start);
+  let range = text.to_range((start..end).into());
+  res.push(edit_action("Extract lemma".into(), CodeActionKind::REFACTOR_EXTRACT, path.url(), vec![
+    TextEdit {range: Range {start: pos, end: pos}, new_text: lemma},
+    TextEdit {range, new_text: call},
+  ], &[]));
+  Some(())
+}
+
+async fn code_action(path: FileRef, range: Range, diags: Vec<Diagnostic>) ->
+    StdResult<CodeActionResponse, ResponseError> {
+  let file = SERVER.vfs.get(&path).ok_or_else(||
+    response_err(ErrorCode::InvalidRequest, "code action: nonexistent file"))?;
+  let (text, env) = match get_env(path.clone()).await? {Some(x) => x, None => return Ok(vec![])};
+  let ast = try_ast(&file).filter(|ast| Arc::ptr_eq(&ast.source, &text));
+  let mut res = vec![];
+  for diag in &diags {
+    unknown_ident_actions(&path, &text, &env, ast.as_deref(), diag, &mut res)
+  }
+  unsolved_goal_actions(&path, &text, &diags, &mut res);
+  if let (Some(ast), Some(start), Some(end)) = (&ast, text.to_idx(range.start), text.to_idx(range.end)) {
+    add_binder_actions(&path, &text, &env, ast, (start, end), &diags, &mut res);
+    if start < end {
+      extract_lemma_action(&path, &text, &env, ast, (start, end), &mut res);
+    }
+  }
+  Ok(res)
+}
+
+/// Format the document, or only the statements overlapping `range`. There are no edits if
+/// the file has parse errors, since then we can't be sure the parse is preserved.
+fn formatting(path: &FileRef, range: Option<Range>) -> StdResult<Vec<TextEdit>, ResponseError> {
+  let file = SERVER.vfs.get(path).ok_or_else(||
+    response_err(ErrorCode::InvalidRequest, "formatting: nonexistent file"))?;
+  let text = file.text.ulock().1.try_ascii().cloned();
+  let text = match text {Some(text) => text, None => return Ok(vec![])};
+  let (_, ast) = parse(text.clone(), None);
+  let env = try_old(&file).map(|(_, env)| env);
+  let mut pe = env.as_ref().map(|env| env.pe().clone()).unwrap_or_default();
+  formatter::add_delimiters(&ast, &mut pe);
+  let fe = env.as_ref().map(|env| unsafe { env.format_env(&text) });
+  let range = match range.map(|r| (text.to_idx(r.start), text.to_idx(r.end))) {
+    None => None,
+    Some((Some(start), Some(end))) => Some((start..end).into()),
+    Some(_) => return Ok(vec![]),
+  };
+  Ok(match formatter::format(&ast, &pe, fe, range) {
+    Some((sp, new_text)) if text[sp] != *new_text.as_bytes() =>
+      vec![TextEdit {range: text.to_range(sp), new_text}],
+    _ => vec![],
+  })
+}
+
 struct Server {
   conn: Connection,
   #[allow(unused)]
@@ -1161,6 +1990,18 @@ impl Capabilities {
       register_options: None,
     });
 
+    // `lsp_types` doesn't have server capabilities for these yet, so we register them dynamically
+    regs.push(Registration {
+      id: String::new(),
+      method: "textDocument/prepareCallHierarchy".into(),
+      register_options: None,
+    });
+    regs.push(Registration {
+      id: String::new(),
+      method: "textDocument/inlayHint".into(),
+      register_options: None,
+    });
+
     if !regs.is_empty() {
       register_capability("regs".into(), regs)?;
       self.reg_id = Some(String::from("regs").into());
@@ -1291,6 +2132,8 @@ struct ServerOptions {
   max_number_of_problems: usize,
   trace: Option<Trace>,
   syntax_docs: Option<bool>,
+  implicit_arg_hints: Option<bool>,
+  inferred_sort_hints: Option<bool>,
 }
 
 impl std::default::Default for ServerOptions {
@@ -1301,6 +2144,8 @@ impl std::default::Default for ServerOptions {
       max_number_of_problems: 100,
       trace: None,
       syntax_docs: None,
+      implicit_arg_hints: None,
+      inferred_sort_hints: None,
     }
   }
 }
@@ -1369,6 +2214,10 @@ impl Server {
         document_symbol_provider: Some(OneOf::Left(true)),
         references_provider: Some(OneOf::Left(true)),
         document_highlight_provider: Some(OneOf::Left(true)),
+        workspace_symbol_provider: Some(OneOf::Left(true)),
+        code_action_provider: Some(CodeActionProviderCapability::Simple(true)),
+        document_formatting_provider: Some(OneOf::Left(true)),
+        document_range_formatting_provider: Some(OneOf::Left(true)),
         ..Default::default()
       })?
     )?)?;
@@ -1,15 +1,44 @@
-import { commands, window, workspace, ExtensionContext, TextDocument, EndOfLine } from 'vscode';
+import { commands, window, workspace, ExtensionContext, TextDocument, EndOfLine,
+	Location, Selection, TextEditorRevealType } from 'vscode';
 
 import {
 	LanguageClient,
 	LanguageClientOptions,
 	ServerOptions,
 	ErrorAction,
-	CloseAction
+	CloseAction,
+	Location as LspLocation
 } from 'vscode-languageclient';
 
 let client: LanguageClient;
 
+/** A theorem returned by the `mm0/findThms` request. */
+interface FoundThm {
+	name: string;
+	statement: string;
+	location: LspLocation;
+}
+
+/** Find the theorems matching the goal under the cursor, and jump to the selected one. */
+async function findThms() {
+	const editor = window.activeTextEditor;
+	if (!editor || editor.document.languageId !== 'metamath-zero') { return; }
+	const thms: FoundThm[] = await client.sendRequest('mm0/findThms',
+		client.code2ProtocolConverter.asTextDocumentPositionParams(
+			editor.document, editor.selection.active));
+	if (thms.length === 0) {
+		window.showInformationMessage('No matching theorems found');
+		return;
+	}
+	const pick = await window.showQuickPick(thms.map(th =>
+		({ label: th.name, detail: th.statement, th })), { matchOnDetail: true });
+	if (!pick) { return; }
+	const loc: Location = client.protocol2CodeConverter.asLocation(pick.th.location);
+	const target = await window.showTextDocument(loc.uri);
+	target.selection = new Selection(loc.range.start, loc.range.start);
+	target.revealRange(loc.range, TextEditorRevealType.InCenter);
+}
+
 function startClient() {
 	let config = workspace.getConfiguration('metamath-zero');
 	let mm0Path: string = config.get('executablePath') || 'mm0-rs';
@@ -55,7 +84,8 @@ export function activate(context: ExtensionContext) {
 		commands.registerCommand('metamath-zero.shutdownServer',
 		  () => client.stop().then(() => {}, () => {})),
 		commands.registerCommand('metamath-zero.restartServer',
-			() => client.stop().then(startClient, startClient))
+			() => client.stop().then(startClient, startClient)),
+		commands.registerCommand('metamath-zero.findThms', findThms)
 	);
 }
 